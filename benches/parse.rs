@@ -0,0 +1,82 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use matrix_url_previewer_bot::{extract_url, opengraph, render};
+
+/// The default `max_dom_nodes`.
+const MAX_DOM_NODES: usize = 1048576;
+
+/// A formatted message near the event size limit, with links in both `href`s and the text.
+fn large_message() -> String {
+    let mut html = String::new();
+    for i in 0..400 {
+        html.push_str(&format!(
+            "<p>See <a href=\"https://example.com/{i}\">this page</a> and \
+             https://example.org/page_({i})#section, (or <code>not-a-url</code>).</p>\n"
+        ));
+    }
+    html
+}
+
+/// A web page of about 1 MiB, with its metadata in the head and a long body.
+fn large_document() -> Vec<u8> {
+    let mut html = String::from(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\">\
+         <title>A long page</title>\
+         <meta property=\"og:title\" content=\"A long page\">\
+         <meta property=\"og:description\" content=\"With many sections.\">\
+         <meta property=\"og:image\" content=\"https://example.com/image.png\">\
+         </head><body>",
+    );
+    for i in 0..4000 {
+        html.push_str(&format!(
+            "<h2 id=\"section-{i}\">Section {i}</h2><div class=\"content\"><p>Some text with \
+             <a href=\"/link/{i}\">a link</a>, <em>emphasis</em> and &amp; entities.</p></div>\n"
+        ));
+    }
+    html.push_str("</body></html>");
+    html.into_bytes()
+}
+
+fn extract_urls_from_html(c: &mut Criterion) {
+    let html = large_message();
+    let mut group = c.benchmark_group("extract_urls_from_html");
+    group.throughput(Throughput::Bytes(html.len() as u64));
+    group.bench_function("large_message", |b| {
+        b.iter(|| extract_url::extract_urls_from_html(black_box(&html), MAX_DOM_NODES))
+    });
+    group.finish();
+}
+
+fn opengraph_parse(c: &mut Criterion) {
+    let document = large_document();
+    let mut group = c.benchmark_group("opengraph_parse");
+    group.throughput(Throughput::Bytes(document.len() as u64));
+    group.sample_size(20);
+    group.bench_function("large_document", |b| {
+        b.iter(|| {
+            let deadline = Instant::now() + Duration::from_secs(60);
+            opengraph::parse(black_box(&document), None, &[], MAX_DOM_NODES, deadline)
+        })
+    });
+    group.finish();
+}
+
+fn collapse_whitespace(c: &mut Criterion) {
+    let text = "  A description\n\n\twith   runs of\r\n whitespace.  ".repeat(1000);
+    let mut group = c.benchmark_group("collapse_whitespace");
+    group.throughput(Throughput::Bytes(text.len() as u64));
+    group.bench_function("description", |b| {
+        b.iter(|| render::collapse_whitespace(black_box(&text)))
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    extract_urls_from_html,
+    opengraph_parse,
+    collapse_whitespace
+);
+criterion_main!(benches);
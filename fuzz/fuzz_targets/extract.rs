@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use matrix_url_previewer_bot::extract_url;
+
+// Messages come from anyone in a room, so finding URLs in them must not panic or recurse without
+// bound, whatever they contain.
+fuzz_target!(|message: &str| {
+    for _ in extract_url::extract_urls_from_text(message) {}
+    extract_url::extract_urls_from_html(message, 1048576);
+});
@@ -0,0 +1,8 @@
+loading = Wird geladen…
+preview-unavailable = Die URL-Vorschau ist nicht verfügbar.
+preview-timeout = Die URL-Vorschau hat zu lange gedauert.
+previewed-in-thread = Vorschau bereits im Thread ↑
+
+digest-title = Die meistgeteilten Links seit der letzten Übersicht
+digest-sites = Websites:
+digest-links = Links:
@@ -0,0 +1,18 @@
+# The bot's own strings. English is the source language; the other catalogs in `locales/` are
+# translated from it, and fall back to it for the messages they don't have yet.
+
+# Shown in the placeholder while the previews are loading.
+loading = Loading…
+# Shown instead of the preview when there is none.
+preview-unavailable = URL preview is unavailable.
+# Shown instead of the preview when it took longer than `preview_timeout`.
+preview-timeout = URL preview took too long.
+# Links to the preview of the same URLs in a thread, instead of previewing them again.
+previewed-in-thread = Already previewed in thread ↑
+
+# The heading of the periodic digest of the most shared links in a room.
+digest-title = Most shared links since the last digest
+# Followed by the most shared sites, one per line.
+digest-sites = Sites:
+# Followed by the most shared links, one per line.
+digest-links = Links:
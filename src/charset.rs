@@ -0,0 +1,102 @@
+use encoding_rs::Encoding;
+
+/// Legacy encodings still common on the sites of some countries, or in some languages, most
+/// common first, as (top-level domains, language tags, encoding labels).
+///
+/// More specific language tags go first, as `zh-tw` must not match `zh`.
+const LEGACY_ENCODINGS: &[(&[&str], &[&str], &[&str])] = &[
+    (&["jp"], &["ja"], &["shift_jis", "euc-jp"]),
+    (
+        &["ru", "by", "ua", "bg", "kz", "kg", "mk", "rs"],
+        &["ru", "uk", "be", "bg", "kk", "ky", "mk", "sr"],
+        &["windows-1251", "koi8-r"],
+    ),
+    (
+        &["tw", "hk", "mo"],
+        &["zh-tw", "zh-hk", "zh-mo", "zh-hant"],
+        &["big5"],
+    ),
+    (&["cn"], &["zh"], &["gb18030"]),
+    (&["kr"], &["ko"], &["euc-kr"]),
+    (&["th"], &["th"], &["windows-874"]),
+    (&["vn"], &["vi"], &["windows-1258"]),
+    (&["gr"], &["el"], &["windows-1253"]),
+    (&["tr"], &["tr"], &["windows-1254"]),
+    (&["il"], &["he"], &["windows-1255"]),
+    (
+        &["ae", "eg", "iq", "ir", "sa"],
+        &["ar", "fa"],
+        &["windows-1256"],
+    ),
+    (
+        &["cz", "hr", "hu", "pl", "ro", "si", "sk"],
+        &["cs", "hr", "hu", "pl", "ro", "sl", "sk"],
+        &["windows-1250"],
+    ),
+    (&["ee", "lt", "lv"], &["et", "lt", "lv"], &["windows-1257"]),
+];
+
+/// Returns the legacy encodings likely used by pages without a declared encoding, from the
+/// top-level domain of `host`, then from the first language of `accept_language`.
+pub fn hints(host: &str, accept_language: &str) -> Vec<&'static Encoding> {
+    let tld = host
+        .trim_end_matches('.')
+        .rsplit('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let language = accept_language
+        .split(',')
+        .next()
+        .and_then(|language| language.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .replace('_', "-")
+        .to_ascii_lowercase();
+    let by_tld = LEGACY_ENCODINGS
+        .iter()
+        .find(|(tlds, _, _)| tlds.contains(&tld.as_str()));
+    let by_language = LEGACY_ENCODINGS.iter().find(|(_, languages, _)| {
+        languages.iter().any(|&tag| {
+            language
+                .strip_prefix(tag)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+        })
+    });
+    let mut hints = Vec::new();
+    for (_, _, labels) in by_tld.into_iter().chain(by_language) {
+        for label in labels.iter() {
+            let encoding = Encoding::for_label(label.as_bytes()).unwrap();
+            if !hints.contains(&encoding) {
+                hints.push(encoding);
+            }
+        }
+    }
+    hints
+}
+
+/// Guesses the encoding of a document that doesn't declare one.
+///
+/// UTF-8 is kept if the document is valid in it. Otherwise, the first hint the document is valid
+/// in is used, or the first hint if none is, as documents may be cut off in the middle of a
+/// character.
+pub fn guess(document: &[u8], hints: &[&'static Encoding]) -> &'static Encoding {
+    let is_utf8 = match std::str::from_utf8(document) {
+        Ok(_) => true,
+        // Cut off in the middle of a character
+        Err(err) => err.error_len().is_none(),
+    };
+    if is_utf8 {
+        return encoding_rs::UTF_8;
+    }
+    hints
+        .iter()
+        .copied()
+        .find(|encoding| {
+            encoding
+                .decode_without_bom_handling_and_without_replacement(document)
+                .is_some()
+        })
+        .or_else(|| hints.first().copied())
+        .unwrap_or(encoding_rs::UTF_8)
+}
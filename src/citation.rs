@@ -0,0 +1,102 @@
+use crate::opengraph::Citation;
+
+/// APA lists up to this many authors, then the first ones, an ellipsis, and the last one.
+const MAX_LISTED_AUTHORS: usize = 20;
+
+/// Formats the metadata of a scholarly article as an APA-style reference, such as
+/// `Vaswani, A., & Shazeer, N. (2017). Attention is all you need. arXiv:1706.03762.`
+///
+/// Returns `None` without a title or authors, as the rest isn't enough to cite the article.
+///
+/// Ref: https://apastyle.apa.org/style-grammar-guidelines/references/examples/journal-article-references
+pub fn format(citation: &Citation) -> Option<String> {
+    let title = citation.title.trim();
+    let authors = citation
+        .authors
+        .iter()
+        .map(|author| format_author(author))
+        .filter(|author| !author.is_empty())
+        .collect::<Vec<_>>();
+    if title.is_empty() || authors.is_empty() {
+        return None;
+    }
+
+    let year = year(&citation.date).unwrap_or("n.d.");
+    let mut reference = format!("{} ({year}). {}", format_authors(&authors), title);
+    if !title.ends_with(['.', '?', '!']) {
+        reference.push('.');
+    }
+
+    let mut source = citation.venue.trim().to_owned();
+    if source.is_empty() && !citation.arxiv_id.is_empty() {
+        source = format!("arXiv:{}", citation.arxiv_id.trim());
+    }
+    if !source.is_empty() {
+        if !citation.volume.is_empty() {
+            source.push_str(&format!(", {}", citation.volume.trim()));
+            if !citation.issue.is_empty() {
+                source.push_str(&format!("({})", citation.issue.trim()));
+            }
+        }
+        match (citation.first_page.trim(), citation.last_page.trim()) {
+            ("", _) => (),
+            (first, "") => source.push_str(&format!(", {first}")),
+            (first, last) => source.push_str(&format!(", {first}\u{2013}{last}")),
+        }
+        reference.push_str(&format!(" {source}."));
+    }
+    let doi = citation.doi.trim();
+    if !doi.is_empty() {
+        let doi = doi
+            .strip_prefix("https://doi.org/")
+            .or_else(|| doi.strip_prefix("doi:"))
+            .unwrap_or(doi);
+        reference.push_str(&format!(" https://doi.org/{doi}"));
+    }
+    Some(reference)
+}
+
+/// Formats an author as `Last, F. M.`, given either `Last, First Middle` or `First Middle Last`.
+fn format_author(name: &str) -> String {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (last, given) = match name.split_once(',') {
+        Some((last, given)) => (last.trim(), given.trim()),
+        None => match name.rsplit_once(' ') {
+            Some((given, last)) => (last, given),
+            None => (name.as_str(), ""),
+        },
+    };
+    let initials = given
+        .split_whitespace()
+        .filter_map(|part| part.chars().next())
+        .map(|initial| format!("{initial}."))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if initials.is_empty() {
+        last.to_owned()
+    } else {
+        format!("{last}, {initials}")
+    }
+}
+
+fn format_authors(authors: &[String]) -> String {
+    match authors {
+        [] => String::new(),
+        [author] => author.clone(),
+        [rest @ .., last] if authors.len() <= MAX_LISTED_AUTHORS => {
+            format!("{}, & {last}", rest.join(", "))
+        }
+        [.., last] => format!(
+            "{}, \u{2026} {last}",
+            authors[..MAX_LISTED_AUTHORS - 1].join(", ")
+        ),
+    }
+}
+
+/// Returns the year of a date such as `2017/06/12`, `2017-06-12`, or `2017`.
+fn year(date: &str) -> Option<&str> {
+    let year = date.trim().get(..4)?;
+    year.bytes()
+        .all(|byte| byte.is_ascii_digit())
+        .then_some(year)
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use eyre::Result;
+use serde_with::DeserializeFromStr;
+use url::{Host, Url};
+
+use crate::domain;
+
+/// What kind of content a URL points to, so later stages can treat them differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, DeserializeFromStr)]
+pub enum UrlClass {
+    /// News, blog posts, and other pages meant to be read.
+    Article,
+    /// Videos, music, and images.
+    Media,
+    /// Source code, repositories, and packages.
+    Code,
+    /// Posts on social networks, including Matrix messages.
+    SocialPost,
+    /// Link shorteners and click trackers, which only redirect elsewhere.
+    Shortener,
+    /// Hosts on the local network, which are never fetched.
+    Internal,
+    /// Anything else.
+    Other,
+}
+
+const CODE_HOSTS: &[&str] = &[
+    "bitbucket.org",
+    "codeberg.org",
+    "crates.io",
+    "docs.rs",
+    "gitea.com",
+    "github.com",
+    "gitlab.com",
+    "npmjs.com",
+    "pypi.org",
+    "sr.ht",
+];
+
+const SOCIAL_HOSTS: &[&str] = &[
+    "bsky.app",
+    "facebook.com",
+    "fixupx.com",
+    "fxbsky.app",
+    "fxtwitter.com",
+    "instagram.com",
+    "mastodon.social",
+    "matrix.to",
+    "reddit.com",
+    "threads.net",
+    "tumblr.com",
+    "twitter.com",
+    "x.com",
+];
+
+const MEDIA_HOSTS: &[&str] = &[
+    "bandcamp.com",
+    "flickr.com",
+    "imgur.com",
+    "soundcloud.com",
+    "spotify.com",
+    "tiktok.com",
+    "twitch.tv",
+    "vimeo.com",
+    "youtu.be",
+    "youtube.com",
+];
+
+const SHORTENER_HOSTS: &[&str] = &[
+    "bit.ly",
+    "buff.ly",
+    "cutt.ly",
+    "goo.gl",
+    "is.gd",
+    "lnkd.in",
+    "ow.ly",
+    "rebrand.ly",
+    "s.id",
+    "shorturl.at",
+    "t.co",
+    "t.ly",
+    "tiny.cc",
+    "tinyurl.com",
+];
+
+const INTERNAL_SUFFIXES: &[&str] = &["home.arpa", "internal", "lan", "local", "localhost"];
+
+const CODE_EXTENSIONS: &[&str] = &[
+    "c", "cpp", "diff", "go", "h", "hpp", "java", "js", "kt", "patch", "py", "rb", "rs", "sh",
+    "swift", "ts",
+];
+
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "avif", "flac", "gif", "jpeg", "jpg", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png",
+    "svg", "wav", "webm", "webp",
+];
+
+/// Classifies a URL by its host and path, before it's fetched.
+pub fn classify(url: &Url) -> UrlClass {
+    let host = match url.host() {
+        Some(Host::Domain(host)) => host,
+        Some(Host::Ipv4(ip)) => return classify_ip(ip.into()),
+        Some(Host::Ipv6(ip)) => return classify_ip(ip.into()),
+        None => return UrlClass::Other,
+    };
+    let host_in = |hosts: &[&str]| hosts.iter().any(|pattern| domain::matches(host, pattern));
+    if host_in(INTERNAL_SUFFIXES) {
+        return UrlClass::Internal;
+    }
+    if host_in(SHORTENER_HOSTS) {
+        return UrlClass::Shortener;
+    }
+
+    let path = url.path();
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    if MEDIA_EXTENSIONS.contains(&extension.as_str()) {
+        return UrlClass::Media;
+    }
+    if host_in(CODE_HOSTS)
+        || CODE_EXTENSIONS.contains(&extension.as_str())
+        || ["/blob/", "/commit/", "/pull/", "/merge_requests/"]
+            .iter()
+            .any(|segment| path.contains(segment))
+    {
+        return UrlClass::Code;
+    }
+    if host_in(SOCIAL_HOSTS) {
+        return UrlClass::SocialPost;
+    }
+    if host_in(MEDIA_HOSTS) {
+        return UrlClass::Media;
+    }
+    // Mastodon and alike: /@user/123456
+    if let Some(rest) = path.strip_prefix("/@")
+        && let Some((_, id)) = rest.split_once('/')
+        && !id.is_empty()
+        && id.bytes().all(|b| b.is_ascii_digit())
+    {
+        return UrlClass::SocialPost;
+    }
+    if [
+        "/article/",
+        "/articles/",
+        "/blog/",
+        "/news/",
+        "/post/",
+        "/posts/",
+    ]
+    .iter()
+    .any(|segment| path.contains(segment))
+        || has_date_in_path(path)
+    {
+        return UrlClass::Article;
+    }
+    UrlClass::Other
+}
+
+/// Refines the class with the `og:type` of the fetched page.
+///
+/// Only pages the URL alone says nothing about, including the targets of shorteners, are refined.
+pub fn refine(class: UrlClass, og_type: &str) -> UrlClass {
+    if class != UrlClass::Other && class != UrlClass::Shortener {
+        return class;
+    }
+    match og_type.split('.').next().unwrap_or_default() {
+        "article" => UrlClass::Article,
+        "video" | "music" | "image" | "gifv" | "audio" => UrlClass::Media,
+        _ => class,
+    }
+}
+
+fn classify_ip(ip: IpAddr) -> UrlClass {
+    // IPv4-mapped addresses, such as `::ffff:127.0.0.1`, reach the IPv4 host.
+    let is_internal = match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                // The shared address space of carrier-grade NAT, 100.64.0.0/10.
+                || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    };
+    if is_internal {
+        UrlClass::Internal
+    } else {
+        UrlClass::Other
+    }
+}
+
+/// Matches paths such as `/2024/05/17/title`, which news sites and blogs use.
+fn has_date_in_path(path: &str) -> bool {
+    let segments = path.split('/').collect::<Vec<_>>();
+    segments.windows(2).any(|pair| {
+        pair[0].len() == 4
+            && pair[0].bytes().all(|b| b.is_ascii_digit())
+            && pair[0].starts_with(['1', '2'])
+            && (1..=2).contains(&pair[1].len())
+            && pair[1].bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+impl fmt::Display for UrlClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UrlClass::Article => "article",
+            UrlClass::Media => "media",
+            UrlClass::Code => "code",
+            UrlClass::SocialPost => "social_post",
+            UrlClass::Shortener => "shortener",
+            UrlClass::Internal => "internal",
+            UrlClass::Other => "other",
+        })
+    }
+}
+
+impl FromStr for UrlClass {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<UrlClass> {
+        match s {
+            "article" => Ok(UrlClass::Article),
+            "media" => Ok(UrlClass::Media),
+            "code" => Ok(UrlClass::Code),
+            "social_post" => Ok(UrlClass::SocialPost),
+            "shortener" => Ok(UrlClass::Shortener),
+            "internal" => Ok(UrlClass::Internal),
+            "other" => Ok(UrlClass::Other),
+            _ => eyre::bail!("Unknown URL class: {}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_addresses() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://0.0.0.0/",
+            "http://0/",
+            "http://10.1.2.3/",
+            "http://100.64.0.1/",
+            "http://100.127.255.255/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::]/",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://[fd00::1]/",
+            "http://printer.local/",
+        ] {
+            assert_eq!(
+                classify(&Url::parse(url).unwrap()),
+                UrlClass::Internal,
+                "{url}"
+            );
+        }
+        for url in [
+            "http://1.1.1.1/",
+            "http://100.128.0.1/",
+            "http://[2001:db8::1]/",
+            "http://[::ffff:1.1.1.1]/",
+        ] {
+            assert_ne!(
+                classify(&Url::parse(url).unwrap()),
+                UrlClass::Internal,
+                "{url}"
+            );
+        }
+    }
+}
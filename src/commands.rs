@@ -0,0 +1,282 @@
+use url::Url;
+
+/// The prefix of all bot commands.
+pub const COMMAND_PREFIX: &str = "!preview";
+
+/// Who may run a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Anyone,
+    /// Room members who can delete others' messages, or admins.
+    Moderator,
+    /// Users listed in `admin_users`.
+    Admin,
+}
+
+pub struct CommandInfo {
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub permission: Permission,
+}
+
+/// All supported commands, used to generate the help text.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        usage: "!preview help",
+        description: "Show what this bot does and how to use it.",
+        permission: Permission::Anyone,
+    },
+    CommandInfo {
+        usage: "!preview refresh",
+        description: "Reply to a preview with this to fetch it again, in case the page has changed.",
+        permission: Permission::Anyone,
+    },
+    CommandInfo {
+        usage: "!preview pin",
+        description: "Reply to a preview with this to pin it along with the message it previews.",
+        permission: Permission::Anyone,
+    },
+    CommandInfo {
+        usage: "!preview disable",
+        description: "Stop previewing links in this room.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview enable",
+        description: "Resume previewing links in this room.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview set <setting> <value>|default",
+        description: "Override `max_description_chars` or `max_urls_per_message` with a number, or `compact_mode` with on or off, in this room. `default` goes back to the bot's configuration.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview dryrun on|dm|off",
+        description: "Only log what would be previewed in this room, without posting anything. `dm` also sends it to you in a direct message.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview language <language>|default",
+        description: "Show the bot's own messages, such as Loading… and the digest, in a language such as `de` in this room. `default` goes back to the bot's configuration.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview blocklist",
+        description: "Show the URLs not previewed in this room.",
+        permission: Permission::Anyone,
+    },
+    CommandInfo {
+        usage: "!preview block <pattern>",
+        description: "Stop previewing a domain, a URL, or URLs starting with a prefix ending with `*` in this room.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview unblock <pattern>",
+        description: "Resume previewing a pattern blocked with `!preview block`.",
+        permission: Permission::Moderator,
+    },
+    CommandInfo {
+        usage: "!preview stats",
+        description: "Show uptime, sync lag, queued previews, cache hit rate, slowest domains, error counts, and downvoted domains.",
+        permission: Permission::Admin,
+    },
+    CommandInfo {
+        usage: "!preview cache stats",
+        description: "Show the number of cached previews.",
+        permission: Permission::Admin,
+    },
+    CommandInfo {
+        usage: "!preview cache purge <url>",
+        description: "Remove the cached previews of a URL.",
+        permission: Permission::Admin,
+    },
+    CommandInfo {
+        usage: "!preview cache warm <url>",
+        description: "Fetch a URL and replace its cached preview.",
+        permission: Permission::Admin,
+    },
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Refresh,
+    Pin,
+    Disable,
+    Enable,
+    Set(Setting),
+    /// Whether to only log previews, and whether to also send them to the sender.
+    DryRun {
+        enabled: bool,
+        report: bool,
+    },
+    /// The language tag as written, or `None` to go back to the configuration.
+    Language(Option<String>),
+    Blocklist,
+    Block(String),
+    Unblock(String),
+    Stats,
+    CacheStats,
+    CachePurge(Url),
+    CacheWarm(Url),
+    Unknown(String),
+}
+
+impl Command {
+    /// Returns `None` if the message is not a command.
+    pub fn parse(body: &str) -> Option<Command> {
+        // Replies from older clients start with a quote of the original message.
+        let body = body
+            .lines()
+            .skip_while(|&line| line.starts_with("> ") || line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut args = body.split_whitespace();
+        if args.next()? != COMMAND_PREFIX {
+            return None;
+        }
+        let command = match (args.next(), args.next(), args.next(), args.next()) {
+            (Some("help"), None, _, _) => Command::Help,
+            (Some("refresh"), None, _, _) => Command::Refresh,
+            (Some("pin"), None, _, _) => Command::Pin,
+            (Some("disable"), None, _, _) => Command::Disable,
+            (Some("enable"), None, _, _) => Command::Enable,
+            (Some("set"), Some(key), Some(value), None) => match Setting::parse(key, value) {
+                Some(setting) => Command::Set(setting),
+                None => Command::Unknown(body.to_owned()),
+            },
+            (Some("dryrun"), Some("on"), None, _) => Command::DryRun {
+                enabled: true,
+                report: false,
+            },
+            (Some("dryrun"), Some("dm"), None, _) => Command::DryRun {
+                enabled: true,
+                report: true,
+            },
+            (Some("dryrun"), Some("off"), None, _) => Command::DryRun {
+                enabled: false,
+                report: false,
+            },
+            (Some("language"), Some("default"), None, _) => Command::Language(None),
+            (Some("language"), Some(language), None, _) => {
+                Command::Language(Some(language.to_owned()))
+            }
+            (Some("blocklist"), None, _, _) => Command::Blocklist,
+            (Some("block"), Some(pattern), None, _) => Command::Block(pattern.to_owned()),
+            (Some("unblock"), Some(pattern), None, _) => Command::Unblock(pattern.to_owned()),
+            (Some("stats"), None, _, _) => Command::Stats,
+            (Some("cache"), Some("stats"), None, _) => Command::CacheStats,
+            (Some("cache"), Some("purge"), Some(url), None) => match Url::parse(url) {
+                Ok(url) => Command::CachePurge(url),
+                Err(_) => Command::Unknown(body.to_owned()),
+            },
+            (Some("cache"), Some("warm"), Some(url), None) => match Url::parse(url) {
+                Ok(url) => Command::CacheWarm(url),
+                Err(_) => Command::Unknown(body.to_owned()),
+            },
+            _ => Command::Unknown(body.to_owned()),
+        };
+        Some(command)
+    }
+
+    pub fn permission(&self) -> Permission {
+        match self {
+            Command::Disable
+            | Command::Enable
+            | Command::Set(_)
+            | Command::DryRun { .. }
+            | Command::Language(_)
+            | Command::Block(_)
+            | Command::Unblock(_) => Permission::Moderator,
+            Command::Stats
+            | Command::CacheStats
+            | Command::CachePurge(_)
+            | Command::CacheWarm(_) => Permission::Admin,
+            // Pinning also requires the permission to pin, checked when running it.
+            Command::Help
+            | Command::Refresh
+            | Command::Pin
+            | Command::Blocklist
+            | Command::Unknown(_) => Permission::Anyone,
+        }
+    }
+}
+
+/// A room setting overridden with `!preview set`, or `None` to go back to the configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Setting {
+    MaxDescriptionChars(Option<usize>),
+    MaxUrlsPerMessage(Option<usize>),
+    CompactMode(Option<bool>),
+}
+
+impl Setting {
+    fn parse(key: &str, value: &str) -> Option<Setting> {
+        let is_default = value == "default";
+        let setting = match key {
+            "max_description_chars" if is_default => Setting::MaxDescriptionChars(None),
+            "max_description_chars" => Setting::MaxDescriptionChars(Some(value.parse().ok()?)),
+            "max_urls_per_message" if is_default => Setting::MaxUrlsPerMessage(None),
+            "max_urls_per_message" => Setting::MaxUrlsPerMessage(Some(value.parse().ok()?)),
+            "compact_mode" => Setting::CompactMode(match value {
+                "default" => None,
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => return None,
+            }),
+            _ => return None,
+        };
+        Some(setting)
+    }
+
+    /// The key in the `room_settings` table.
+    pub fn key(&self) -> &'static str {
+        match self {
+            Setting::MaxDescriptionChars(_) => "max_description_chars",
+            Setting::MaxUrlsPerMessage(_) => "max_urls_per_message",
+            Setting::CompactMode(_) => "compact_mode",
+        }
+    }
+
+    /// The value to store in the `room_settings` table, or `None` to remove it.
+    pub fn value(&self) -> Option<String> {
+        match self {
+            Setting::MaxDescriptionChars(value) | Setting::MaxUrlsPerMessage(value) => {
+                value.map(|value| value.to_string())
+            }
+            Setting::CompactMode(value) => value.map(|value| value.to_string()),
+        }
+    }
+}
+
+/// Lists the commands available to the user.
+pub fn help_text(is_admin: bool) -> String {
+    let mut help = "Available commands:".to_owned();
+    for command in COMMANDS
+        .iter()
+        .filter(|command| is_admin || command.permission != Permission::Admin)
+    {
+        help.push('\n');
+        help.push_str(command.usage);
+        help.push_str(" \u{2013} ");
+        help.push_str(command.description);
+    }
+    help
+}
+
+/// Explains what the bot does, how to opt out, and lists the commands available to the user.
+pub fn help_card(is_admin: bool) -> String {
+    format!(
+        "I post previews of links shared in the rooms I'm in.
+
+Privacy: Messages are only scanned for links. To make a preview, my server fetches each link, \
+and remembers which message the preview belongs to.
+
+To skip a preview, wrap the link in angle brackets, such as <https://example.org>, or end the \
+message with [no preview]. Deleting a message also deletes its preview.
+
+{}",
+        help_text(is_admin)
+    )
+}
@@ -1,6 +1,110 @@
+use std::time::Duration;
+
 pub const MAX_RESPONSE_TEXT_CHARS: usize = 200;
 
 // https://stackoverflow.com/a/417184/2557927
 pub const SAFE_URL_LENGTH: usize = 2048;
 
 pub const MAX_URL_COUNTS_PER_MESSAGE: usize = 10;
+
+// https://spec.matrix.org/v1.14/client-server-api/#size-limits
+// Events are limited to 65536 bytes, so leave room for the rest of the event.
+pub const MAX_PREVIEW_CONTENT_BYTES: usize = 48 * 1024;
+
+pub const SYNC_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+pub const SYNC_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+pub const DIGEST_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How far into a document to look for `<meta charset>`.
+pub const MAX_CHARSET_PROBE_BYTES: usize = 64 * 1024;
+
+/// Documents up to this size are parsed on the async runtime, larger ones on the blocking pool.
+pub const INLINE_PARSE_MAX_BYTES: usize = 32 * 1024;
+
+/// How much of a document the parser takes at a time, between checks of the node limit and the
+/// parse deadline.
+pub const PARSE_CHUNK_BYTES: usize = 16 * 1024;
+
+/// Larger JSON-LD blocks are skipped, as they list many things besides the page.
+pub const MAX_JSON_LD_BYTES: usize = 256 * 1024;
+
+/// How many redirects a fetch follows, as reqwest does by default.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// HTTP clients kept for the transport profiles in `domain_transports`.
+pub const MAX_TRANSPORT_CLIENTS: u64 = 16;
+
+/// How long to remember the events we've responded to, beyond the redeliveries after a reconnect.
+pub const EVENT_CLAIM_RETENTION: Duration = Duration::from_secs(7 * 86400);
+
+pub const EVENT_CLAIM_EXPIRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+pub const DISK_CACHE_COMPRESSION_LEVEL: i32 = 3;
+
+pub const SEND_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+pub const SEND_MAX_ATTEMPTS: u32 = 4;
+
+pub const HEAD_UNSUPPORTED_TTL: Duration = Duration::from_secs(86400);
+
+/// The window of `max_previews_per_thread_per_hour`, starting from the first preview in a thread.
+pub const THREAD_PREVIEW_WINDOW: Duration = Duration::from_secs(3600);
+
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const MAX_ROOM_BLOCKED_URLS: usize = 100;
+
+/// The largest width or height of an image within a preview, in pixels.
+pub const MAX_INLINE_IMAGE_SIZE: u32 = 320;
+
+/// The same for the thumbnail of a `summary` Twitter Card.
+pub const MAX_SMALL_INLINE_IMAGE_SIZE: u32 = 96;
+
+/// The displayed width and height of the icon next to the site name, in pixels.
+pub const FAVICON_SIZE: u32 = 16;
+
+/// How much of a linked image to download, to read its dimensions from its header.
+pub const MEDIA_LINK_PROBE_BYTES: usize = 64 * 1024;
+
+/// How much of a linked PDF document to download, to read its metadata. Larger documents only
+/// have it within the beginning if they are linearized.
+pub const MAX_PDF_PROBE_BYTES: usize = 1024 * 1024;
+
+/// How much of a linked text file to download, for the lines of its snippet.
+pub const TEXT_SNIPPET_PROBE_BYTES: usize = 64 * 1024;
+
+/// Longer lines in the snippet of a text file are cut, such as in minified code.
+pub const MAX_SNIPPET_LINE_CHARS: usize = 160;
+
+/// The primary subtags of the languages written from right to left.
+pub const RTL_LANGUAGES: &[&str] = &[
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ji", "ps", "sd", "syr", "ug", "ur", "yi",
+];
+
+/// The `og:video` types clients can play inline.
+pub const PLAYABLE_VIDEO_TYPES: &[&str] = &["video/mp4", "video/webm"];
+
+/// Larger icons are not worth uploading for 16 pixels.
+pub const MAX_FAVICON_BYTES: usize = 100 * 1024;
+
+pub const MAX_ACCEPT_LANGUAGE_LENGTH: usize = 256;
+
+/// Longer references to scholarly articles are cut, as they have too many authors.
+pub const MAX_CITATION_CHARS: usize = 2000;
+
+/// Longer prices aren't numbers a person would read.
+pub const MAX_PRICE_CHARS: usize = 16;
+
+/// Headings beyond these aren't kept to resolve `#fragment` links, as pages may have thousands.
+pub const MAX_PAGE_SECTIONS: usize = 256;
+
+/// Longer headings of sections are cut, as they become the suffix of the title.
+pub const MAX_SECTION_TITLE_CHARS: usize = 100;
+
+/// Shorter paragraphs, such as bylines or captions, don't describe the page.
+pub const MIN_BODY_PARAGRAPH_CHARS: usize = 80;
+
+/// Shorter link texts, such as "here" or "this", never describe the link.
+pub const MIN_DESCRIBED_LINK_CHARS: usize = 20;
@@ -1,16 +1,47 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use eyre::Result;
-use serde::Deserialize;
-use serde_with::{DurationSeconds, serde_as};
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use serde::{Deserialize, Deserializer, de};
+use serde_with::{DeserializeFromStr, DurationSeconds, serde_as};
+use url::Url;
+
+use crate::classify::{self, UrlClass};
+use crate::common::{MAX_RESPONSE_TEXT_CHARS, MAX_URL_COUNTS_PER_MESSAGE};
+use crate::opengraph::{OpenGraph, OpenGraphMedia};
+use crate::template::Templates;
+use crate::{domain, extract_url, i18n};
 
 #[serde_as]
 #[derive(Clone, Deserialize)]
 pub struct Config {
     pub data_dir: PathBuf,
 
+    #[serde(default)]
+    pub admin_users: Vec<OwnedUserId>,
+
+    #[serde(default)]
+    pub join_greeting: String,
+
+    #[serde(default)]
+    pub database_url: String,
+
+    #[serde(default = "default_true")]
+    pub sync_settings: bool,
+
+    #[serde(default)]
+    pub redact_logs: bool,
+
+    #[serde(default)]
+    pub private_room_mode: bool,
+
+    #[serde(default)]
+    pub metrics_listen: String,
+
     #[serde(default)]
     pub cache_entries: u64,
 
@@ -18,49 +49,660 @@ pub struct Config {
     #[serde(default)]
     pub cache_duration: Duration,
 
+    #[serde(default)]
+    pub cache_max_disk_usage: u64,
+
     #[serde(default)]
     pub crawler_accept_language: String,
 
+    #[serde(default)]
+    pub crawler_accept: String,
+
+    #[serde(default = "default_true")]
+    pub follow_hreflang: bool,
+
     #[serde(default)]
     pub crawler_proxy: String,
 
     #[serde(default)]
     pub crawler_max_size: usize,
 
+    #[serde(default = "default_true")]
+    pub crawler_head_check: bool,
+
+    #[serde(default = "default_true")]
+    pub preview_media_links: bool,
+
+    #[serde(default)]
+    pub crawler_head_skip_domains: Vec<String>,
+
+    #[serde(default)]
+    pub crawler_max_content_length: u64,
+
     #[serde_as(as = "DurationSeconds<f64>")]
     #[serde(default)]
     pub crawler_timeout: Duration,
 
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub crawler_connect_timeout: Duration,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub crawler_first_byte_timeout: Duration,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub crawler_idle_timeout: Duration,
+
     #[serde(default)]
     pub crawler_user_agent: String,
 
+    #[serde(default)]
+    pub crawler_identity: String,
+
+    #[serde(default)]
+    pub crawler_info_url: String,
+
+    #[serde(default)]
+    pub crawler_contact: String,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub crawler_parse_timeout: Duration,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub preview_timeout: Duration,
+
+    #[serde(default)]
+    pub max_dom_nodes: usize,
+
+    #[serde(default)]
+    pub max_concurrent_previews: usize,
+
+    #[serde(default)]
+    pub event_queue_size: usize,
+
+    #[serde(default)]
+    pub event_queue_overflow: OverflowPolicy,
+
+    #[serde(default)]
+    pub unwrap_redirects: Vec<[String; 3]>,
+
     #[serde(default)]
     pub rewrite_url: Vec<[String; 2]>,
+
+    #[serde(default = "default_true")]
+    #[cfg_attr(not(feature = "rewrite"), allow(dead_code))] // Only read by the rewrite rules
+    pub builtin_rewrites: bool,
+
+    #[serde(default)]
+    pub preview_own_messages: bool,
+
+    #[serde(default)]
+    pub test_rooms: Vec<OwnedRoomId>,
+
+    #[serde(default)]
+    pub oracle_rooms: Vec<OwnedRoomId>,
+
+    #[serde(default)]
+    pub bridge_namespaces: Vec<String>,
+
+    /// `None` when set to `"off"`.
+    #[serde(
+        default = "default_dedup_window",
+        deserialize_with = "deserialize_window"
+    )]
+    pub dedup_window: Option<Duration>,
+
+    #[serde(default)]
+    pub max_description_chars: usize,
+
+    #[serde(default)]
+    pub max_urls_per_message: usize,
+
+    #[serde(default)]
+    pub max_previews_per_thread_per_hour: usize,
+
+    #[serde(default)]
+    pub aggregate_thread_previews: bool,
+
+    #[serde(default)]
+    pub max_snippet_lines: usize,
+
+    #[serde(default)]
+    pub compact_mode: bool,
+
+    #[serde(default = "PreviewField::all")]
+    pub preview_fields: Vec<PreviewField>,
+
+    #[serde(default)]
+    pub domain_styles: HashMap<String, StyleProfile>,
+
+    #[serde(default)]
+    pub class_styles: HashMap<UrlClass, StyleProfile>,
+
+    #[serde(default)]
+    pub class_accept: HashMap<UrlClass, String>,
+
+    #[serde(default)]
+    pub preview_overrides: HashMap<String, PreviewOverride>,
+
+    #[serde(default)]
+    pub cache_seed_urls: Vec<String>,
+
+    #[serde(default)]
+    pub domain_transports: HashMap<String, TransportProfile>,
+
+    #[serde(default)]
+    pub blocked_urls: Vec<String>,
+
+    #[serde(default = "default_true")]
+    pub warn_mismatched_links: bool,
+
+    #[serde(default)]
+    pub show_favicons: bool,
+
+    #[serde(default = "default_true")]
+    pub embed_videos: bool,
+
+    #[serde(default)]
+    pub max_video_size: usize,
+
+    #[serde(default = "default_true")]
+    pub embed_audio: bool,
+
+    #[serde(default)]
+    pub max_audio_size: usize,
+
+    #[serde(default)]
+    pub skip_described_links: bool,
+
+    #[serde(default)]
+    pub described_link_similarity: f64,
+
+    #[serde(default)]
+    pub clean_titles: bool,
+
+    #[serde(default)]
+    pub css_class_prefix: String,
+
+    #[serde(default)]
+    pub placeholder_emoji: String,
+
+    #[serde(default)]
+    pub placeholder_text: String,
+
+    #[serde(default)]
+    pub preview_emoji: String,
+
+    #[serde(default)]
+    pub warning_emoji: String,
+
+    #[serde(default)]
+    pub error_text: String,
+
+    #[serde(default)]
+    pub timeout_text: String,
+
+    #[serde(default)]
+    pub thread_pointer_text: String,
+
+    #[serde(default = "default_true")]
+    pub show_backref: bool,
+
+    #[serde(default = "default_true")]
+    pub show_byline: bool,
+
+    #[serde(default)]
+    pub send_preview_data: bool,
+
+    #[serde(default)]
+    pub preview_template_html: String,
+
+    #[serde(default)]
+    pub preview_template_text: String,
+
+    /// Compiled from `preview_template_html` and `preview_template_text`.
+    #[serde(skip)]
+    pub templates: Templates,
+
+    #[serde(default)]
+    pub debug_footer: bool,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub read_receipt_interval: Duration,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub refresh_cooldown: Duration,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub preview_log_retention: Duration,
+
+    #[serde(default)]
+    pub weekly_digest: bool,
+
+    #[serde_as(as = "DurationSeconds<f64>")]
+    #[serde(default)]
+    pub digest_interval: Duration,
+
+    #[serde(default)]
+    pub digest_top_count: usize,
+}
+
+fn default_dedup_window() -> Option<Duration> {
+    Some(Duration::from_secs(30))
+}
+
+/// Reads a number of seconds, or `"off"` as `None`.
+fn deserialize_window<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Window {
+        Seconds(f64),
+        Keyword(String),
+    }
+
+    match Window::deserialize(deserializer)? {
+        Window::Seconds(secs) => Duration::try_from_secs_f64(secs)
+            .map(Some)
+            .map_err(de::Error::custom),
+        Window::Keyword(keyword) if keyword == "off" => Ok(None),
+        Window::Keyword(keyword) => Err(de::Error::custom(format!(
+            "expected a number of seconds or \"off\", found \"{keyword}\""
+        ))),
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Parses a URL of `cache_seed_urls`, or of a seed list given to the `warm` subcommand.
+pub fn parse_seed_url(s: &str) -> Result<Url> {
+    match Url::parse(s.trim()) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(url),
+        _ => eyre::bail!("Invalid seed URL: {}", s),
+    }
+}
+
+/// A component of the rendered preview.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DeserializeFromStr)]
+pub enum PreviewField {
+    Title,
+    SiteName,
+    Description,
+    Image,
+    Author,
+    Date,
+    Price,
+    Duration,
+}
+
+impl PreviewField {
+    pub fn all() -> Vec<PreviewField> {
+        vec![
+            PreviewField::Title,
+            PreviewField::SiteName,
+            PreviewField::Description,
+            PreviewField::Image,
+            PreviewField::Author,
+            PreviewField::Date,
+            PreviewField::Price,
+            PreviewField::Duration,
+        ]
+    }
+
+    /// Parses a comma-separated list, such as `title,site_name`.
+    pub fn parse_list(s: &str) -> Result<Vec<PreviewField>> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(PreviewField::from_str)
+            .collect()
+    }
+}
+
+impl FromStr for PreviewField {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<PreviewField> {
+        match s {
+            "title" => Ok(PreviewField::Title),
+            "site_name" => Ok(PreviewField::SiteName),
+            "description" => Ok(PreviewField::Description),
+            "image" => Ok(PreviewField::Image),
+            "author" => Ok(PreviewField::Author),
+            "date" => Ok(PreviewField::Date),
+            "price" => Ok(PreviewField::Price),
+            "duration" => Ok(PreviewField::Duration),
+            _ => eyre::bail!("Unknown preview field: {}", s),
+        }
+    }
+}
+
+/// A named set of preview components, selected per domain by `domain_styles`, or per room by the
+/// `style` room setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DeserializeFromStr)]
+pub enum StyleProfile {
+    /// Everything in `preview_fields`, even in compact mode.
+    Full,
+    /// A one-liner with the title and the site name.
+    Compact,
+    /// The title, the site name, and the image, without the description.
+    ImageFirst,
+    /// Everything in `preview_fields` except for the image.
+    TextOnly,
+    /// Scholarly articles with a reference to copy instead of their description, and other pages
+    /// as usual.
+    Citation,
+}
+
+impl StyleProfile {
+    /// Returns the compact mode and the components to render, given the ones of the room.
+    pub fn apply(self, compact_mode: bool, fields: &[PreviewField]) -> (bool, Vec<PreviewField>) {
+        match self {
+            StyleProfile::Full => (false, fields.to_vec()),
+            StyleProfile::Compact => (
+                true,
+                fields
+                    .iter()
+                    .copied()
+                    .filter(|field| [PreviewField::Title, PreviewField::SiteName].contains(field))
+                    .collect(),
+            ),
+            StyleProfile::ImageFirst => (true, fields.to_vec()),
+            StyleProfile::TextOnly => (
+                compact_mode,
+                fields
+                    .iter()
+                    .copied()
+                    .filter(|&field| field != PreviewField::Image)
+                    .collect(),
+            ),
+            StyleProfile::Citation => (compact_mode, fields.to_vec()),
+        }
+    }
+}
+
+impl FromStr for StyleProfile {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<StyleProfile> {
+        match s {
+            "full" => Ok(StyleProfile::Full),
+            "compact" => Ok(StyleProfile::Compact),
+            "image_first" => Ok(StyleProfile::ImageFirst),
+            "text_only" => Ok(StyleProfile::TextOnly),
+            "citation" => Ok(StyleProfile::Citation),
+            _ => eyre::bail!("Unknown style profile: {}", s),
+        }
+    }
+}
+
+/// What to drop when the queue of incoming messages is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, DeserializeFromStr)]
+pub enum OverflowPolicy {
+    /// The message waiting the longest, as its sender may have moved on.
+    #[default]
+    DropOldest,
+    /// The new message.
+    DropNewest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<OverflowPolicy> {
+        match s {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            _ => eyre::bail!("Unknown overflow policy: {}", s),
+        }
+    }
+}
+
+/// How to reach the domains matching a pattern in `domain_transports`. Empty fields use the
+/// `crawler_*` options.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default)]
+pub struct TransportProfile {
+    /// A proxy URL, or `none` to connect directly.
+    pub proxy: String,
+    pub user_agent: String,
+}
+
+/// A fixed preview of the URLs matching a pattern in `preview_overrides`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PreviewOverride {
+    pub title: String,
+    pub description: String,
+    pub site_name: String,
+    pub image: String,
+}
+
+impl PreviewOverride {
+    /// Returns the metadata to preview `url` with, as if the page had it.
+    pub fn to_opengraph(&self, url: &Url) -> OpenGraph {
+        OpenGraph {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            site_name: self.site_name.clone(),
+            url: url.to_string(),
+            images: (!self.image.is_empty())
+                .then(|| OpenGraphMedia {
+                    url: self.image.clone(),
+                    ..Default::default()
+                })
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
 }
 
 impl Config {
+    /// Returns the style profile of the most specific domain pattern matching `host`.
+    pub fn style_profile(&self, host: &str) -> Option<StyleProfile> {
+        self.domain_styles
+            .iter()
+            .filter(|(pattern, _)| domain::matches(host, pattern))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, &profile)| profile)
+    }
+
+    /// Returns the `Accept` header for fetching `url`, from `class_accept` if its kind of link is
+    /// listed, or `crawler_accept`.
+    pub fn accept(&self, url: &Url) -> &str {
+        self.class_accept
+            .get(&classify::classify(url))
+            .unwrap_or(&self.crawler_accept)
+    }
+
+    pub fn seed_urls(&self) -> Result<Vec<Url>> {
+        self.cache_seed_urls
+            .iter()
+            .map(String::as_str)
+            .map(parse_seed_url)
+            .collect()
+    }
+
+    /// Returns the transport profile of the most specific domain matching `host`.
+    pub fn transport_profile(&self, host: &str) -> Option<&TransportProfile> {
+        self.domain_transports
+            .iter()
+            .filter(|(pattern, _)| domain::matches(host, pattern))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, profile)| profile)
+    }
+
+    /// Returns the override of the most specific pattern matching `url`.
+    ///
+    /// A pattern ending with `*` matches every URL starting with the rest, others match the URL
+    /// itself.
+    pub fn preview_override(&self, url: &Url) -> Option<&PreviewOverride> {
+        self.preview_overrides
+            .iter()
+            .filter(|(pattern, _)| match pattern.strip_suffix('*') {
+                Some(prefix) => url.as_str().starts_with(prefix),
+                None => Url::parse(pattern).is_ok_and(|pattern| pattern == *url),
+            })
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, preview)| preview)
+    }
+
     pub async fn new(path: &Path) -> Result<Arc<Config>> {
         let config_str = tokio::fs::read_to_string(path).await?;
         let mut config: Config = toml::from_str(&config_str)?;
+        for pattern in config.blocked_urls.iter() {
+            if !extract_url::is_valid_block_pattern(pattern) {
+                eyre::bail!("Invalid pattern in blocked_urls: {}", pattern);
+            }
+        }
+        config.seed_urls()?;
+        for (pattern, profile) in config.domain_transports.iter() {
+            if !profile.proxy.is_empty()
+                && profile.proxy != "none"
+                && reqwest::Proxy::all(&profile.proxy).is_err()
+            {
+                eyre::bail!("Invalid proxy in domain_transports for {}", pattern);
+            }
+        }
+        for pattern in config.preview_overrides.keys() {
+            if !pattern.ends_with('*') && Url::parse(pattern).is_err() {
+                eyre::bail!("Invalid URL in preview_overrides: {}", pattern);
+            }
+        }
         if config.cache_entries == 0 {
             config.cache_entries = 1024;
         }
         if config.cache_duration.is_zero() {
             config.cache_duration = Duration::from_secs(3600);
         }
+        if config.crawler_accept.is_empty() {
+            config.crawler_accept = "text/html,application/xhtml+xml,*/*;q=0.8".to_owned();
+        }
+        for accept in std::iter::once(&config.crawler_accept).chain(config.class_accept.values()) {
+            if reqwest::header::HeaderValue::from_str(accept).is_err() {
+                eyre::bail!("Invalid Accept header: {}", accept);
+            }
+        }
         if config.crawler_accept_language.is_empty() {
             config.crawler_accept_language = "en-US,en;q=0.9".to_owned();
         }
         if config.crawler_max_size == 0 {
             config.crawler_max_size = 10 * 1048576;
         }
+        if config.max_video_size == 0 {
+            config.max_video_size = 50 * 1048576;
+        }
+        if config.max_audio_size == 0 {
+            config.max_audio_size = 50 * 1048576;
+        }
         if config.crawler_timeout.is_zero() {
             config.crawler_timeout = Duration::from_secs(30);
         }
+        if config.crawler_connect_timeout.is_zero() {
+            config.crawler_connect_timeout = Duration::from_secs(10);
+        }
+        if config.crawler_first_byte_timeout.is_zero() {
+            config.crawler_first_byte_timeout = Duration::from_secs(15);
+        }
+        if config.crawler_idle_timeout.is_zero() {
+            config.crawler_idle_timeout = Duration::from_secs(10);
+        }
         if config.crawler_user_agent.is_empty() {
             config.crawler_user_agent =
                 "Mozilla/5.0 (compatible; Matrix-URL-Previewer-Bot; +https://github.com/m13253/matrix-url-previewer-bot; like Discordbot, TelegramBot, Twitterbot)".to_owned();
         }
+        if !config.crawler_info_url.is_empty()
+            && Url::parse(&config.crawler_info_url).map_or(true, |url| url.scheme() != "https")
+        {
+            eyre::bail!("crawler_info_url must be an https:// URL.");
+        }
+        if config.crawler_parse_timeout.is_zero() {
+            config.crawler_parse_timeout = Duration::from_secs(5);
+        }
+        if config.preview_timeout.is_zero() {
+            config.preview_timeout = Duration::from_secs(300);
+        }
+        if config.max_dom_nodes == 0 {
+            config.max_dom_nodes = 1048576;
+        }
+        if config.max_concurrent_previews == 0 {
+            config.max_concurrent_previews = 8;
+        }
+        if config.event_queue_size == 0 {
+            config.event_queue_size = 1024;
+        }
+        if config.refresh_cooldown.is_zero() {
+            config.refresh_cooldown = Duration::from_secs(60);
+        }
+        if config.preview_log_retention.is_zero() {
+            config.preview_log_retention = Duration::from_secs(7 * 86400);
+        }
+        if config.digest_interval.is_zero() {
+            config.digest_interval = Duration::from_secs(7 * 86400);
+        }
+        if config.digest_top_count == 0 {
+            config.digest_top_count = 5;
+        }
+        if config.placeholder_emoji.is_empty() {
+            config.placeholder_emoji = "\u{23f3}\u{fe0f}".to_owned();
+        }
+        if config.placeholder_text.is_empty() {
+            config.placeholder_text = i18n::message(i18n::DEFAULT_LANGUAGE, "loading");
+        }
+        if config.preview_emoji.is_empty() {
+            config.preview_emoji = "\u{1f517}\u{fe0f}".to_owned();
+        }
+        if config.warning_emoji.is_empty() {
+            config.warning_emoji = "\u{26a0}\u{fe0f}".to_owned();
+        }
+        if config.error_text.is_empty() {
+            config.error_text = i18n::message(i18n::DEFAULT_LANGUAGE, "preview-unavailable");
+        }
+        if config.timeout_text.is_empty() {
+            config.timeout_text = i18n::message(i18n::DEFAULT_LANGUAGE, "preview-timeout");
+        }
+        if config.thread_pointer_text.is_empty() {
+            config.thread_pointer_text =
+                i18n::message(i18n::DEFAULT_LANGUAGE, "previewed-in-thread");
+        }
+        if config.described_link_similarity <= 0.0 {
+            config.described_link_similarity = 0.8;
+        }
+        if config.css_class_prefix.is_empty() {
+            config.css_class_prefix = "m13253-url-preview".to_owned();
+        }
+        if !config
+            .css_class_prefix
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            eyre::bail!(
+                "css_class_prefix may only contain ASCII letters, digits, hyphens and underscores."
+            );
+        }
+        config.templates =
+            Templates::new(&config.preview_template_html, &config.preview_template_text)?;
+        if config.max_description_chars == 0 {
+            config.max_description_chars = MAX_RESPONSE_TEXT_CHARS;
+        }
+        if config.max_snippet_lines == 0 {
+            config.max_snippet_lines = 8;
+        }
+        if config.max_urls_per_message == 0 {
+            config.max_urls_per_message = MAX_URL_COUNTS_PER_MESSAGE;
+        }
         Ok(Arc::new(config))
     }
 }
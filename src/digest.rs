@@ -0,0 +1,72 @@
+use std::fmt::Write;
+
+use deadpool_sqlite::Pool;
+use eyre::{Report, Result};
+use matrix_sdk::ruma::RoomId;
+
+use crate::{html_escape, i18n};
+
+/// Renders the most shared domains and URLs in a room since `since`, in plain text and HTML, with
+/// the headings in `language`.
+///
+/// Returns `None` if nothing was shared.
+pub async fn report(
+    db: &Pool,
+    room_id: &RoomId,
+    since: i64,
+    top_count: usize,
+    language: &str,
+) -> Result<Option<(String, String)>> {
+    let stmt_domains = "SELECT domain, COUNT(*) FROM preview_sources
+WHERE room_id = ? AND timestamp >= ?
+GROUP BY domain ORDER BY COUNT(*) DESC, domain ASC LIMIT ?;";
+    let stmt_urls = "SELECT url, COUNT(*) FROM preview_sources
+WHERE room_id = ? AND timestamp >= ? AND url != '' AND url NOT LIKE 'sha256:%'
+GROUP BY url ORDER BY COUNT(*) DESC, url ASC LIMIT ?;";
+    let conn = db.get().await?;
+
+    let room_id_str = room_id.to_string();
+    let (domains, urls) = conn
+        .interact(move |conn| {
+            let query = |stmt_query| {
+                conn.prepare_cached(stmt_query)?
+                    .query_map((&room_id_str, since, top_count as i64), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            Ok::<_, Report>((query(stmt_domains)?, query(stmt_urls)?))
+        })
+        .await
+        .unwrap()?;
+    if domains.is_empty() {
+        return Ok(None);
+    }
+
+    let title = i18n::message(language, "digest-title");
+    let sites = i18n::message(language, "digest-sites");
+    let links = i18n::message(language, "digest-links");
+    let mut text = format!("\u{1f4ca}\u{fe0f} {title}\n\n{sites}\n");
+    let mut html = format!(
+        "<p>\u{1f4ca}\u{fe0f} <strong>{}</strong></p><p>{}</p><ol>",
+        html_escape::text(&title),
+        html_escape::text(&sites)
+    );
+    for (domain, count) in domains {
+        _ = writeln!(text, "{domain} ({count})");
+        _ = write!(html, "<li>{} ({count})</li>", html_escape::text(&domain));
+    }
+    _ = writeln!(text, "\n{links}");
+    _ = write!(html, "</ol><p>{}</p><ol>", html_escape::text(&links));
+    for (url, count) in urls {
+        _ = writeln!(text, "{url} ({count})");
+        _ = write!(
+            html,
+            "<li><a href=\"{}\">{}</a> ({count})</li>",
+            html_escape::attr(&url),
+            html_escape::text(&url)
+        );
+    }
+    html.push_str("</ol>");
+    Ok(Some((text.trim_end().to_owned(), html)))
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use deadpool_sqlite::Pool;
+use deadpool_sqlite::rusqlite::OptionalExtension;
+use eyre::{Report, Result};
+
+use crate::common::DISK_CACHE_COMPRESSION_LEVEL;
+use crate::opengraph::OpenGraph;
+
+/// Looks up a preview stored within `ttl`, and marks it as recently used.
+///
+/// Returns `None` if it's not stored, and `Some(None)` if the absence of a preview is stored.
+pub async fn get(db: &Pool, key: &str, ttl: Duration) -> Result<Option<Option<OpenGraph>>> {
+    let stmt_query = "SELECT id, data FROM preview_cache WHERE key = ? AND created >= ?;";
+    let stmt_touch = "UPDATE preview_cache SET accessed = ? WHERE id = ?;";
+    let conn = db.get().await?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let expiry = now.saturating_sub(ttl).as_secs() as i64;
+    let key = key.to_owned();
+    conn.interact(move |conn| {
+        let Some((id, data)) = conn
+            .prepare_cached(stmt_query)?
+            .query_row((key, expiry), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        conn.prepare_cached(stmt_touch)?
+            .execute((now.as_secs() as i64, id))?;
+        let data = zstd::decode_all(data.as_slice())?;
+        Ok::<_, Report>(Some(serde_json::from_slice(&data)?))
+    })
+    .await
+    .unwrap()
+}
+
+/// Stores a preview, then evicts the expired previews and the least recently used ones until the
+/// stored data fits in `max_usage` bytes.
+pub async fn insert(
+    db: &Pool,
+    key: &str,
+    url: &str,
+    preview: &Option<OpenGraph>,
+    ttl: Duration,
+    max_usage: u64,
+) -> Result<()> {
+    let stmt_insert = "INSERT OR REPLACE INTO preview_cache (key, url, data, size, created, accessed) VALUES (?, ?, ?, ?, ?, ?);";
+    let stmt_expire = "DELETE FROM preview_cache WHERE created < ?;";
+    let stmt_evict = "DELETE FROM preview_cache WHERE id IN (
+    SELECT id FROM (
+        SELECT id, SUM(size) OVER (ORDER BY accessed DESC, id DESC) AS usage FROM preview_cache
+    ) WHERE usage > ?
+);";
+    let conn = db.get().await?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let expiry = now.saturating_sub(ttl).as_secs() as i64;
+    let data = serde_json::to_vec(preview)?;
+    let (key, url) = (key.to_owned(), url.to_owned());
+    conn.interact(move |conn| {
+        let data = zstd::encode_all(data.as_slice(), DISK_CACHE_COMPRESSION_LEVEL)?;
+        let size = (key.len() + url.len() + data.len()) as i64;
+        let now = now.as_secs() as i64;
+        conn.prepare_cached(stmt_insert)?
+            .execute((key, url, data, size, now, now))?;
+        conn.prepare_cached(stmt_expire)?.execute((expiry,))?;
+        conn.prepare_cached(stmt_evict)?
+            .execute((max_usage as i64,))?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Forgets the stored previews of a URL. Returns how many were forgotten.
+pub async fn purge(db: &Pool, url: &str) -> Result<usize> {
+    let stmt_delete = "DELETE FROM preview_cache WHERE url = ?;";
+    let conn = db.get().await?;
+
+    let url = url.to_owned();
+    conn.interact(move |conn| Ok::<_, Report>(conn.prepare_cached(stmt_delete)?.execute((url,))?))
+        .await
+        .unwrap()
+}
+
+/// Returns the number of stored previews and their total size in bytes.
+pub async fn usage(db: &Pool) -> Result<(u64, u64)> {
+    let stmt_query = "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM preview_cache;";
+    let conn = db.get().await?;
+
+    conn.interact(move |conn| {
+        Ok::<_, Report>(conn.prepare_cached(stmt_query)?.query_row((), |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64))
+        })?)
+    })
+    .await
+    .unwrap()
+}
@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use eyre::Result;
+use publicsuffix::{List, Psl};
+use tracing::{error, info, instrument, warn};
+
+const PUBLIC_SUFFIX_LIST_URL: &str = "https://publicsuffix.org/list/public_suffix_list.dat";
+
+const PUBLIC_SUFFIX_LIST_MAX_AGE: Duration = Duration::from_secs(7 * 86400);
+
+/// How long to wait before downloading the Public Suffix List again after a failure.
+const PUBLIC_SUFFIX_LIST_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+static PUBLIC_SUFFIX_LIST: RwLock<Option<List>> = RwLock::new(None);
+
+/// Loads the Public Suffix List from the copy cached in `data_dir`, without waiting for the
+/// network. [`refresh_public_suffix_list`] downloads it if missing or stale.
+///
+/// If the list is unavailable, the helpers in this module fall back to treating the last two
+/// labels of a host as its registrable domain.
+pub fn load_public_suffix_list(data_dir: &Path) {
+    let path = data_dir.join("public_suffix_list.dat");
+    match std::fs::read(&path) {
+        Ok(data) => set_public_suffix_list(&data),
+        Err(err) => warn!("Public Suffix List is not cached yet: {}", err),
+    }
+}
+
+/// Downloads the Public Suffix List whenever the copy cached in `data_dir` is missing or stale.
+#[instrument(skip_all)]
+pub async fn refresh_public_suffix_list(data_dir: PathBuf, client: reqwest::Client) -> Result<()> {
+    let path = data_dir.join("public_suffix_list.dat");
+    loop {
+        let age = tokio::fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if let Some(age) = age
+            && age < PUBLIC_SUFFIX_LIST_MAX_AGE
+        {
+            tokio::time::sleep(PUBLIC_SUFFIX_LIST_MAX_AGE - age).await;
+            continue;
+        }
+        match download_public_suffix_list(&client).await {
+            Ok(data) => {
+                set_public_suffix_list(&data);
+                if let Err(err) = tokio::fs::write(&path, &data).await {
+                    warn!("Failed to cache the Public Suffix List: {}", err);
+                }
+                tokio::time::sleep(PUBLIC_SUFFIX_LIST_MAX_AGE).await;
+            }
+            Err(err) => {
+                warn!("Failed to download the Public Suffix List: {}", err);
+                tokio::time::sleep(PUBLIC_SUFFIX_LIST_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+fn set_public_suffix_list(data: &[u8]) {
+    match List::from_bytes(data) {
+        Ok(list) => {
+            *PUBLIC_SUFFIX_LIST.write().unwrap() = Some(list);
+            info!("Loaded the Public Suffix List.");
+        }
+        Err(err) => error!("Failed to parse the Public Suffix List: {}", err),
+    }
+}
+
+async fn download_public_suffix_list(client: &reqwest::Client) -> Result<Vec<u8>> {
+    Ok(client
+        .get(PUBLIC_SUFFIX_LIST_URL)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec())
+}
+
+/// Returns the registrable domain of a host, for example, `example.co.uk` for
+/// `www.example.co.uk`.
+pub fn registrable_domain(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if let Some(list) = PUBLIC_SUFFIX_LIST.read().unwrap().as_ref() {
+        let domain = list.domain(host.as_bytes())?;
+        return Some(String::from_utf8_lossy(domain.as_bytes()).into_owned());
+    }
+    let mut labels = host.rsplitn(3, '.');
+    let tld = labels.next().filter(|label| !label.is_empty())?;
+    let sld = labels.next().filter(|label| !label.is_empty())?;
+    Some(format!("{sld}.{tld}"))
+}
+
+/// Returns whether two hosts belong to the same site, such as `www.example.com` and
+/// `cdn.example.com`.
+#[cfg(feature = "extract")]
+pub fn same_registrable_domain(a: &str, b: &str) -> bool {
+    match (registrable_domain(a), registrable_domain(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.eq_ignore_ascii_case(b),
+    }
+}
+
+/// Returns whether `host` is `pattern` or one of its subdomains.
+pub fn matches(host: &str, pattern: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    let pattern = pattern.trim_end_matches('.');
+    host.len()
+        .checked_sub(pattern.len())
+        .is_some_and(|prefix_len| {
+            host[prefix_len..].eq_ignore_ascii_case(pattern)
+                && (prefix_len == 0 || host.as_bytes()[prefix_len - 1] == b'.')
+        })
+}
@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::config::OverflowPolicy;
+
+/// A bounded queue that drops items instead of waiting when it's full, so the sync loop never
+/// waits for the handling of earlier events.
+pub struct EventQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    notify: Notify,
+}
+
+impl<T> EventQueue<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> EventQueue<T> {
+        EventQueue {
+            items: Mutex::new(VecDeque::new()),
+            capacity,
+            policy,
+            notify: Notify::new(),
+        }
+    }
+
+    /// Adds an item. Returns the item dropped to make room if the queue is full, which may be the
+    /// new one.
+    pub fn push(&self, item: T) -> Option<T> {
+        let mut items = self.items.lock().unwrap();
+        let dropped = if items.len() < self.capacity {
+            None
+        } else {
+            match self.policy {
+                OverflowPolicy::DropOldest => items.pop_front(),
+                OverflowPolicy::DropNewest => return Some(item),
+            }
+        };
+        items.push_back(item);
+        drop(items);
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Waits for the oldest item. Only one consumer is supported.
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.items.lock().unwrap().pop_front() {
+                return item;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -1,34 +1,92 @@
+use std::collections::{HashMap, HashSet};
+
 use indexmap::IndexSet;
-use nom::branch::alt;
-use nom::bytes::complete::{tag, take_while1};
-use nom::character::complete::{anychar, char, satisfy};
-use nom::combinator::{iterator, opt, recognize, value};
-use nom::multi::many0_count;
-use nom::{IResult, Parser};
-use scraper::{Html, Node};
+#[cfg(feature = "extract")]
+use matrix_sdk::ruma::events::room::message::MessageFormat;
+use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
+use matrix_sdk::ruma::{
+    MatrixToUri, MatrixUri, OwnedEventId, OwnedRoomOrAliasId, matrix_uri::MatrixId,
+};
+#[cfg(feature = "extract")]
+use nom::{
+    IResult, Parser,
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{anychar, char, satisfy},
+    combinator::{iterator, opt, recognize, value},
+    multi::many0_count,
+};
+#[cfg(feature = "extract")]
+use scraper::{ElementRef, Html, Node};
 use tracing::instrument;
+#[cfg(feature = "extract")]
+use tracing::warn;
 use url::{Host, Url};
 
 use crate::common::SAFE_URL_LENGTH;
+use crate::{domain, redact};
+
+/// The URLs in a message, along with what the sender wrote about them.
+#[derive(Clone, Debug, Default)]
+pub struct MessageLinks {
+    pub urls: IndexSet<Url>,
+    /// Links whose text looks like a URL on a different site than the `href`.
+    pub mismatched: HashSet<Url>,
+    /// The text of each <a href="URL">, unless it's the URL itself.
+    pub texts: HashMap<Url, String>,
+    /// The `#fragment` of each link to a section of the page, by the URL without it, in order.
+    pub sections: HashMap<Url, Vec<String>>,
+}
+
+#[cfg(feature = "extract")]
+impl MessageLinks {
+    fn insert(&mut self, url: Url, section: Option<String>) {
+        if let Some(section) = section {
+            let sections = self.sections.entry(url.clone()).or_default();
+            if !sections.contains(&section) {
+                sections.push(section);
+            }
+        }
+        self.urls.insert(url);
+    }
+}
 
 /// Extracts URLs from *both* <a href="URL"> and the text contents.
 ///
 /// Text contents are processed by [`extract_urls_from_text`].
-#[instrument]
-pub fn extract_urls_from_html(html: &str) -> IndexSet<Url> {
+/// Only the first `max_nodes` DOM nodes are visited.
+#[cfg(feature = "extract")]
+#[instrument(skip(html))]
+pub fn extract_urls_from_html(html: &str, max_nodes: usize) -> MessageLinks {
     let dom = Html::parse_fragment(html);
-    let mut links = IndexSet::new();
+    let mut links = MessageLinks::default();
     let mut stack = Vec::new();
     let mut node = dom.tree.root();
-    for _ in 0..1048576_usize {
+    for _ in 0..max_nodes {
         let mut skip_children = false;
         match node.value() {
-            Node::Text(text) => links.extend(extract_urls_from_text(&text)),
+            Node::Text(text) => {
+                for (url, section) in extract_urls_from_text(text) {
+                    links.insert(url, section);
+                }
+            }
             Node::Element(element) => match element.name() {
                 "a" => {
                     if let Some(href) = element.attr("href") {
                         skip_children = true;
-                        links.extend(validate_url(href));
+                        if let Some(url) = validate_url(href) {
+                            let text = ElementRef::wrap(node)
+                                .map(|element| element.text().collect::<String>())
+                                .unwrap_or_default();
+                            if is_link_text_mismatched(&text, &url) {
+                                links.mismatched.insert(url.clone());
+                            }
+                            let text = text.trim();
+                            if !text.is_empty() && validate_url(text).as_ref() != Some(&url) {
+                                links.texts.insert(url.clone(), text.to_owned());
+                            }
+                            links.insert(url, section_fragment(href));
+                        }
                     }
                 }
                 "code" | "del" | "mx-reply" | "pre" => skip_children = true,
@@ -36,12 +94,10 @@ pub fn extract_urls_from_html(html: &str) -> IndexSet<Url> {
             },
             _ => (),
         }
-        if !skip_children {
-            if let Some(child) = node.first_child() {
-                stack.push(node);
-                node = child;
-                continue;
-            }
+        if !skip_children && let Some(child) = node.first_child() {
+            stack.push(node);
+            node = child;
+            continue;
         }
         loop {
             if let Some(sibling) = node.next_sibling() {
@@ -54,67 +110,222 @@ pub fn extract_urls_from_html(html: &str) -> IndexSet<Url> {
             }
         }
     }
-    // A Matrix message can only be 64 KiB long.
-    panic!("Bug: HTML extractor didn't stop after visiting 1 million DOM nodes.");
+    warn!(
+        "HTML extractor stopped after visiting {} DOM nodes, using partial result.",
+        max_nodes
+    );
+    links
+}
+
+/// Returns whether the text of a link looks like a URL, but on a different site than `href`.
+#[cfg(feature = "extract")]
+fn is_link_text_mismatched(text: &str, href: &Url) -> bool {
+    let text = text.trim();
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        return false;
+    }
+    let Some(text_url) = Url::parse(text)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .or_else(|| Url::parse(&format!("https://{text}")).ok())
+    else {
+        return false;
+    };
+    let (Some(text_domain), Some(href_host)) = (text_url.domain(), href.host_str()) else {
+        return false;
+    };
+    // Something like "v1.2" or "file.txt" is not meant as a domain.
+    let Some((_, tld)) = text_domain.rsplit_once('.') else {
+        return false;
+    };
+    if !(tld.len() >= 2 && tld.bytes().all(|b| b.is_ascii_alphabetic()) || tld.starts_with("xn--"))
+    {
+        return false;
+    }
+    !domain::same_registrable_domain(text_domain, href_host)
 }
 
 /// We follow the behavior of Element to extract URLs:
 /// 1. Containing no whitespace.
 /// 2. Containing balanced amounts of "()", "<>", "[]", "{}".
-#[instrument]
-pub fn extract_urls_from_text(text: &str) -> impl Iterator<Item = Url> {
+///
+/// Each URL comes with its `#fragment`, if it points to a section of the page.
+#[cfg(feature = "extract")]
+#[instrument(skip_all, fields(text = %redact::text(text)))]
+pub fn extract_urls_from_text(text: &str) -> impl Iterator<Item = (Url, Option<String>)> {
     iterator(
         text,
         alt((parse_url_from_text.map(Option::Some), value(None, anychar))),
     )
     .flatten()
-    .filter_map(validate_url)
+    .filter_map(|url| Some((validate_url(url)?, section_fragment(url))))
 }
 
+/// Returns the `#fragment` of a URL, as written, if it may be the ID of a section of the page.
+///
+/// Single-page applications route with fragments such as `#/path` or `#!/path`, and text
+/// fragments such as `#:~:text=` highlight a quote instead.
+#[cfg(feature = "extract")]
+fn section_fragment(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    let fragment = url.fragment()?;
+    (!fragment.is_empty() && !fragment.starts_with(['/', '!']) && !fragment.contains(":~:"))
+        .then(|| fragment.to_owned())
+}
+
+#[cfg(feature = "extract")]
 fn parse_url_from_text(input: &str) -> IResult<&str, &str> {
     recognize((
-        satisfy(|c| matches!(c, 'A'..='Z' | 'a'..='z')),
+        satisfy(|c: char| c.is_ascii_alphabetic()),
         many0_count(satisfy(
             |c| matches!(c, '+' | '-' | '.' | '0'..='9' | 'A'..='Z' | 'a'..='z'),
         )),
         char(':'),
         many0_count(char('/')),
-        many0_count(parse_delimited),
+        many0_count(nested(0)),
     ))
     .parse(input)
 }
 
-fn parse_delimited(input: &str) -> IResult<&str, ()> {
+/// Brackets nested deeper than this end the URL, so untrusted input can't recurse unboundedly.
+#[cfg(feature = "extract")]
+const MAX_BRACKET_DEPTH: usize = 32;
+
+#[cfg(feature = "extract")]
+fn parse_delimited(input: &str, depth: usize) -> IResult<&str, ()> {
+    let text = take_while1(|c| {
+        !matches!(c, '(' | ')' | '<' | '>' | '[' | ']' | '{' | '}') && !char::is_whitespace(c)
+    });
+    if depth >= MAX_BRACKET_DEPTH {
+        return value((), text).parse(input);
+    }
     alt((
-        value((), (tag("("), many0_count(parse_delimited), opt(tag(")")))),
-        value((), (tag("<"), many0_count(parse_delimited), opt(tag(">")))),
-        value((), (tag("["), many0_count(parse_delimited), opt(tag("]")))),
-        value((), (tag("{"), many0_count(parse_delimited), opt(tag("}")))),
-        value(
-            (),
-            take_while1(|c| {
-                !matches!(c, '(' | ')' | '<' | '>' | '[' | ']' | '{' | '}')
-                    && !char::is_whitespace(c)
-            }),
-        ),
+        value((), (tag("("), many0_count(nested(depth)), opt(tag(")")))),
+        value((), (tag("<"), many0_count(nested(depth)), opt(tag(">")))),
+        value((), (tag("["), many0_count(nested(depth)), opt(tag("]")))),
+        value((), (tag("{"), many0_count(nested(depth)), opt(tag("}")))),
+        value((), text),
     ))
     .parse(input)
 }
 
-#[instrument]
+#[cfg(feature = "extract")]
+fn nested(depth: usize) -> impl FnMut(&str) -> IResult<&str, ()> {
+    move |input| parse_delimited(input, depth + 1)
+}
+
+/// Extracts URLs from a text message, preferring its HTML body.
+///
+/// URLs the sender wrapped in `<` and `>` are left out.
+#[cfg(feature = "extract")]
+pub fn extract_urls_from_message(text: &TextMessageEventContent, max_nodes: usize) -> MessageLinks {
+    let html = text
+        .formatted
+        .as_ref()
+        .filter(|formatted| formatted.format == MessageFormat::Html);
+    let mut links = if let Some(html) = html {
+        extract_urls_from_html(&html.body, max_nodes)
+    } else {
+        let mut links = MessageLinks::default();
+        for (url, section) in text
+            .body
+            .lines()
+            .skip_while(|&line| line.starts_with("> "))
+            .flat_map(extract_urls_from_text)
+        {
+            links.insert(url, section);
+        }
+        links
+    };
+    let suppressed_urls = extract_suppressed_urls(&text.body);
+    links.urls.retain(|url| !suppressed_urls.contains(url));
+    links
+}
+
+/// Finds no URLs, as finding them in messages needs the `extract` feature.
+#[cfg(not(feature = "extract"))]
+pub fn extract_urls_from_message(
+    _text: &TextMessageEventContent,
+    _max_nodes: usize,
+) -> MessageLinks {
+    MessageLinks::default()
+}
+
+/// Returns whether a URL matches a pattern of `blocked_urls` or `!preview block`.
+///
+/// A pattern ending with `*` matches every URL starting with the rest, a URL matches itself, and
+/// a domain matches itself and its subdomains.
+pub fn is_blocked_by(url: &Url, pattern: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix('*') {
+        url.as_str().starts_with(prefix)
+    } else if pattern.contains('/') {
+        Url::parse(pattern).is_ok_and(|pattern| pattern == *url)
+    } else {
+        url.host_str()
+            .is_some_and(|host| domain::matches(host, pattern))
+    }
+}
+
+/// Returns whether a pattern can be used with [`is_blocked_by`].
+pub fn is_valid_block_pattern(pattern: &str) -> bool {
+    if pattern.ends_with('*') {
+        pattern.len() > 1
+    } else if pattern.contains('/') {
+        Url::parse(pattern).is_ok()
+    } else {
+        !pattern.is_empty()
+            && pattern
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+    }
+}
+
+/// Returns whether the message ends with a `[no preview]` marker, suppressing all previews.
+pub fn has_no_preview_marker(body: &str) -> bool {
+    const MARKER: &str = "[no preview]";
+    let body = body.trim_end();
+    body.len() >= MARKER.len()
+        && body.is_char_boundary(body.len() - MARKER.len())
+        && body[body.len() - MARKER.len()..].eq_ignore_ascii_case(MARKER)
+}
+
+/// Extracts URLs wrapped in `<` and `>` from the plain-text body, which the sender doesn't want
+/// previewed.
+#[cfg(feature = "extract")]
+fn extract_suppressed_urls(body: &str) -> HashSet<Url> {
+    body.split('<')
+        .skip(1)
+        .filter_map(|segment| {
+            let (url, _) = segment.split_once('>')?;
+            if url.contains(char::is_whitespace) {
+                return None;
+            }
+            validate_url(url)
+        })
+        .collect()
+}
+
+#[instrument(skip_all, fields(url = %redact::url_str(url)))]
 pub fn validate_url(url: &str) -> Option<Url> {
     let mut url = Url::parse(url).ok()?;
     // https://stackoverflow.com/a/417184/2557927
     if url.as_str().len() > SAFE_URL_LENGTH {
         return None;
     }
+    // Event permalinks are previewed by quoting the event, which needs the `#fragment` part.
+    if parse_event_permalink(&url).is_some() {
+        return Some(url);
+    }
     if !matches!(url.scheme(), "http" | "https") {
         return None;
     }
     let host = url.host()?;
     if let Host::Domain(domain) = host {
         // Matrix mentions generate <a href="https://matrix.to/#[...]"> links. Ignore them.
-        if domain.eq_ignore_ascii_case("matrix.to") {
+        if domain::matches(domain, "matrix.to") {
             return None;
         }
     }
@@ -122,3 +333,94 @@ pub fn validate_url(url: &str) -> Option<Url> {
     url.set_fragment(None);
     Some(url)
 }
+
+/// Parses a matrix.to or `matrix:` URI pointing to an event.
+///
+/// Links to rooms and users are not events, and return `None`.
+pub fn parse_event_permalink(url: &Url) -> Option<(OwnedRoomOrAliasId, OwnedEventId)> {
+    let id = match url.scheme() {
+        "matrix" => MatrixUri::parse(url.as_str()).ok()?.id().clone(),
+        "http" | "https" if url.host_str()?.eq_ignore_ascii_case("matrix.to") => {
+            MatrixToUri::parse(url.as_str()).ok()?.id().clone()
+        }
+        _ => return None,
+    };
+    match id {
+        MatrixId::Event(room_id, event_id) => Some((room_id, event_id)),
+        _ => None,
+    }
+}
+
+#[cfg(all(test, feature = "extract"))]
+mod tests {
+    use super::*;
+
+    fn urls(text: &str) -> Vec<String> {
+        extract_urls_from_text(text)
+            .map(|(url, _)| url.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn nesting_within_the_limit_is_kept() {
+        let depth = MAX_BRACKET_DEPTH - 1;
+        let url = format!(
+            "https://example.com/{}a{}",
+            "(".repeat(depth),
+            ")".repeat(depth)
+        );
+        assert_eq!(urls(&url), [Url::parse(&url).unwrap().to_string()]);
+    }
+
+    #[test]
+    fn nesting_past_the_limit_ends_the_url() {
+        let depth = MAX_BRACKET_DEPTH + 1;
+        let text = format!(
+            "https://example.com/{}a{}",
+            "(".repeat(depth),
+            ")".repeat(depth)
+        );
+        let found = urls(&text);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("https://example.com/"));
+        assert!(found[0].len() < text.len());
+    }
+
+    #[test]
+    fn deep_nesting_does_not_overflow() {
+        for open in ["(", "<", "[", "{"] {
+            let text = format!("https://example.com/{}", open.repeat(500));
+            assert_eq!(urls(&text).len(), 1);
+            // Longer than `SAFE_URL_LENGTH`, so it's left out.
+            let text = format!("https://example.com/{}", open.repeat(100_000));
+            assert!(urls(&text).is_empty());
+        }
+    }
+
+    #[test]
+    fn unbalanced_brackets() {
+        assert_eq!(
+            urls("(see https://example.com/a_(b))"),
+            ["https://example.com/a_(b)"]
+        );
+        assert_eq!(urls("https://example.com/a)b"), ["https://example.com/a"]);
+        assert_eq!(urls("https://example.com/a]"), ["https://example.com/a"]);
+        assert_eq!(urls("<https://example.com/a>"), ["https://example.com/a"]);
+        assert_eq!(
+            urls("https://example.com/a_(b c)"),
+            ["https://example.com/a_(b"]
+        );
+    }
+
+    #[test]
+    fn large_message_does_not_panic() {
+        let pattern = "https://example.com/(a)[b]{c}<d> x:(( ]]> &amp; <a href=\"https://ex";
+        let text: String = pattern.chars().cycle().take(64 * 1024).collect();
+        assert!(!urls(&text).is_empty());
+        extract_urls_from_html(&text, 1048576);
+
+        let brackets = format!("https://example.com/{}", "([{<".repeat(16 * 1024));
+        assert!(urls(&brackets).is_empty());
+        extract_urls_from_html(&brackets, 1048576);
+    }
+}
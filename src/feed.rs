@@ -0,0 +1,245 @@
+use std::sync::LazyLock;
+
+use encoding_rs::Encoding;
+use mime::Mime;
+use regex::Regex;
+use scraper::Html;
+
+use crate::common::MAX_CHARSET_PROBE_BYTES;
+use crate::opengraph::{FeedEntry, OpenGraph, OpenGraphMedia};
+
+/// Whether a response may be a feed. Only RSS and Atom feeds are previewed, other XML documents
+/// are treated as web pages.
+pub fn is_feed(content_type: &Mime) -> bool {
+    matches!(
+        content_type.essence_str(),
+        "application/rss+xml" | "application/atom+xml" | "application/xml" | "text/xml"
+    )
+}
+
+/// Previews an RSS or Atom feed by its title and description, along with its latest entry, or
+/// returns `None` if the document isn't a feed. The `<enclosure>` of the latest episode of a
+/// podcast becomes the media.
+///
+/// Feeds are matched with regular expressions rather than parsed, as only a few elements are
+/// needed, and the HTML parser would drop their `CDATA` sections.
+///
+/// Ref: https://www.rssboard.org/rss-specification
+pub fn parse(document: &[u8], charset: Option<&'static Encoding>) -> Option<OpenGraph> {
+    static RSS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<rss[\s>]").unwrap());
+    static ATOM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<feed[\s>]").unwrap());
+
+    let charset = declared_encoding(document)
+        .or(charset)
+        .unwrap_or(encoding_rs::UTF_8);
+    let xml = charset.decode(document).0;
+    if RSS.is_match(&xml) {
+        Some(parse_rss(&xml))
+    } else if ATOM.is_match(&xml) {
+        Some(parse_atom(&xml))
+    } else {
+        None
+    }
+}
+
+fn parse_rss(xml: &str) -> OpenGraph {
+    static ITEM: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?s)<item[\s>].*?(?:</item\s*>|\z)").unwrap());
+
+    let (channel, item) = match ITEM.find(xml) {
+        Some(item) => (&xml[..item.start()], Some(item.as_str())),
+        None => (xml, None),
+    };
+    let mut og = OpenGraph {
+        title: element(channel, "title").map(text).unwrap_or_default(),
+        description: element(channel, "description")
+            .or_else(|| element(channel, "itunes:summary"))
+            .map(text)
+            .unwrap_or_default(),
+        url: element(channel, "link").map(text).unwrap_or_default(),
+        language: element(channel, "language").map(text).unwrap_or_default(),
+        ..Default::default()
+    };
+    let Some(item) = item else {
+        og.images.extend(channel_image(channel));
+        return og;
+    };
+
+    og.latest_entry = Some(FeedEntry {
+        title: element(item, "title").map(text).unwrap_or_default(),
+        url: element(item, "link").map(text).unwrap_or_default(),
+        date: element(item, "pubDate")
+            .and_then(|date| parse_rfc2822_date(&text(date)))
+            .unwrap_or_default(),
+    });
+    og.duration =
+        element(item, "itunes:duration").and_then(|duration| parse_duration(&text(duration)));
+    og.images.extend(
+        channel_image(channel)
+            .or_else(|| attribute(item, "itunes:image", "href").map(|href| image(text(href)))),
+    );
+    if let Some(url) = attribute(item, "enclosure", "url") {
+        let media = OpenGraphMedia {
+            url: text(url),
+            content_type: attribute(item, "enclosure", "type")
+                .map(text)
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        if media.content_type.starts_with("video/") {
+            og.videos.push(media);
+        } else {
+            og.audios.push(media);
+        }
+    }
+    og
+}
+
+/// Ref: https://www.rfc-editor.org/rfc/rfc4287
+fn parse_atom(xml: &str) -> OpenGraph {
+    static ENTRY: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?s)<entry[\s>].*?(?:</entry\s*>|\z)").unwrap());
+
+    let (feed, entry) = match ENTRY.find(xml) {
+        Some(entry) => (&xml[..entry.start()], Some(entry.as_str())),
+        None => (xml, None),
+    };
+    let mut og = OpenGraph {
+        title: element(feed, "title").map(text).unwrap_or_default(),
+        description: element(feed, "subtitle").map(text).unwrap_or_default(),
+        url: atom_link(feed).unwrap_or_default(),
+        ..Default::default()
+    };
+    og.images.extend(
+        element(feed, "logo")
+            .or_else(|| element(feed, "icon"))
+            .map(|url| image(text(url))),
+    );
+    og.latest_entry = entry.map(|entry| FeedEntry {
+        title: element(entry, "title").map(text).unwrap_or_default(),
+        url: atom_link(entry).unwrap_or_default(),
+        date: element(entry, "published")
+            .or_else(|| element(entry, "updated"))
+            .map(text)
+            .unwrap_or_default(),
+    });
+    og
+}
+
+/// Returns the `href` of the first `<link>` to the page, which either has no `rel`, or
+/// `rel="alternate"`.
+fn atom_link(xml: &str) -> Option<String> {
+    static LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<link\s[^>]*>").unwrap());
+    LINK.find_iter(xml)
+        .map(|link| link.as_str())
+        .find(|link| attribute(link, "link", "rel").is_none_or(|rel| rel.trim() == "alternate"))
+        .and_then(|link| attribute(link, "link", "href"))
+        .map(text)
+}
+
+/// Finds the encoding declared by `<?xml encoding>`.
+fn declared_encoding(document: &[u8]) -> Option<&'static Encoding> {
+    static XML_DECLARATION: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"^\s*<\?xml\s[^>]*encoding\s*=\s*["']([^"']+)["']"#).unwrap()
+    });
+    let probe = &document[..document.len().min(MAX_CHARSET_PROBE_BYTES)];
+    // Declarations are ASCII, so any ASCII-compatible encoding finds them.
+    let probe = encoding_rs::UTF_8.decode(probe).0;
+    let label = XML_DECLARATION.captures(&probe)?.get(1)?.as_str();
+    Encoding::for_label(label.as_bytes())
+}
+
+/// `<itunes:image href>`, or `<image><url>`.
+fn channel_image(channel: &str) -> Option<OpenGraphMedia> {
+    attribute(channel, "itunes:image", "href")
+        .or_else(|| element(channel, "image").and_then(|image| element(image, "url")))
+        .map(|url| image(text(url)))
+}
+
+fn image(url: String) -> OpenGraphMedia {
+    OpenGraphMedia {
+        url,
+        ..Default::default()
+    }
+}
+
+/// Returns the raw content of the first `<name>` element.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let name = regex::escape(name);
+    let pattern = format!(r"(?s)<{name}(?:\s[^>]*)?>(.*?)</{name}\s*>");
+    Regex::new(&pattern)
+        .ok()?
+        .captures(xml)?
+        .get(1)
+        .map(|content| content.as_str())
+}
+
+/// Returns the raw value of an attribute of the first `<name>` element.
+fn attribute<'a>(xml: &'a str, name: &str, attribute: &str) -> Option<&'a str> {
+    let name = regex::escape(name);
+    let attribute = regex::escape(attribute);
+    let pattern = format!(r#"<{name}\s[^>]*?\b{attribute}\s*=\s*(?:"([^"]*)"|'([^']*)')"#);
+    let captures = Regex::new(&pattern).ok()?.captures(xml)?;
+    captures
+        .get(1)
+        .or_else(|| captures.get(2))
+        .map(|value| value.as_str())
+}
+
+/// Decodes the content of an element into plain text, removing any HTML within.
+fn text(raw: &str) -> String {
+    let raw = raw.trim();
+    let raw = raw
+        .strip_prefix("<![CDATA[")
+        .and_then(|raw| raw.strip_suffix("]]>"))
+        .unwrap_or(raw);
+    let decode = |s: &str| {
+        Html::parse_fragment(s)
+            .root_element()
+            .text()
+            .collect::<String>()
+    };
+    let text = decode(raw);
+    // Escaped HTML is left with tags after decoding the XML.
+    if text.contains('<') {
+        decode(&text)
+    } else {
+        text
+    }
+}
+
+/// Converts an RFC 2822 date, such as `Wed, 01 May 2024 09:30:00 GMT`, to `2024-05-01`.
+fn parse_rfc2822_date(s: &str) -> Option<String> {
+    const MONTHS: &[&str] = &[
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    // The day of the week is optional.
+    let s = s.split_once(',').map_or(s, |(_, rest)| rest);
+    let mut parts = s.split_whitespace();
+    let day = parts.next()?.parse::<u32>().ok()?;
+    let month = parts.next()?.get(..3)?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|&name| name == month)? + 1;
+    let year = parts.next()?.parse::<u32>().ok()?;
+    // Two-digit years are obsolete, but still seen.
+    let year = match year {
+        0..50 => year + 2000,
+        50..100 => year + 1900,
+        _ => year,
+    };
+    (1..=31)
+        .contains(&day)
+        .then(|| format!("{year:04}-{month:02}-{day:02}"))
+}
+
+/// Parses `<itunes:duration>`, which is either seconds or `[HH:]MM:SS`.
+fn parse_duration(s: &str) -> Option<u32> {
+    let parts = s.trim().split(':').collect::<Vec<_>>();
+    if parts.len() > 3 {
+        return None;
+    }
+    parts.iter().try_fold(0u32, |total, part| {
+        total
+            .checked_mul(60)?
+            .checked_add(part.trim().parse().ok()?)
+    })
+}
@@ -0,0 +1,169 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use deadpool_sqlite::Pool;
+use eyre::{Report, Result};
+use matrix_sdk::ruma::{EventId, RoomId, UserId};
+
+const WORST_DOMAINS_COUNT: usize = 5;
+
+/// Parses a reaction key into a vote, ignoring the optional emoji variation selector.
+pub fn parse_vote(key: &str) -> Option<i64> {
+    match key.trim_end_matches('\u{fe0f}') {
+        "\u{1f44d}" => Some(1),
+        "\u{1f44e}" => Some(-1),
+        _ => None,
+    }
+}
+
+/// Which domain and handler produced the preview of a URL, so votes can be attributed, and which
+/// URL was shared, for the digest.
+pub struct PreviewSource {
+    pub domain: String,
+    pub handler: &'static str,
+    pub url: String,
+}
+
+/// Remembers the sources of the URLs in a preview, replacing the ones from before an edit unless
+/// the previews were appended to the message.
+pub async fn insert_sources(
+    db: &Pool,
+    room_id: &RoomId,
+    response_id: &EventId,
+    sources: Vec<PreviewSource>,
+    is_appended: bool,
+) -> Result<()> {
+    let stmt_delete = "DELETE FROM preview_sources WHERE room_id = ? AND response_id = ?;";
+    let stmt_insert = "INSERT OR REPLACE INTO preview_sources (room_id, response_id, domain, handler, url, timestamp) VALUES (?, ?, ?, ?, ?, ?);";
+    let conn = db.get().await?;
+
+    let room_id_str = room_id.to_string();
+    let response_id_str = response_id.to_string();
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    conn.interact(move |conn| {
+        let tx = conn.transaction()?;
+        if !is_appended {
+            tx.prepare_cached(stmt_delete)?
+                .execute((&room_id_str, &response_id_str))?;
+        }
+        {
+            let mut stmt = tx.prepare_cached(stmt_insert)?;
+            for source in sources {
+                stmt.execute((
+                    &room_id_str,
+                    &response_id_str,
+                    source.domain,
+                    source.handler,
+                    source.url,
+                    timestamp,
+                ))?;
+            }
+        }
+        tx.commit()?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Records a vote on each source of a preview. Each user has one vote per preview, the latest one
+/// wins.
+///
+/// Returns `false` if the reaction is not on a preview.
+pub async fn insert_vote(
+    db: &Pool,
+    room_id: &RoomId,
+    response_id: &EventId,
+    reaction_id: &EventId,
+    sender: &UserId,
+    vote: i64,
+) -> Result<bool> {
+    let stmt_query = "SELECT DISTINCT domain, handler FROM preview_sources WHERE room_id = ? AND response_id = ?;";
+    let stmt_insert = "INSERT OR REPLACE INTO feedback (room_id, response_id, reaction_id, user_id, domain, handler, vote) VALUES (?, ?, ?, ?, ?, ?, ?);";
+    let conn = db.get().await?;
+
+    let room_id_str = room_id.to_string();
+    let response_id_str = response_id.to_string();
+    let reaction_id_str = reaction_id.to_string();
+    let sender_str = sender.to_string();
+    conn.interact(move |conn| {
+        let sources = conn
+            .prepare_cached(stmt_query)?
+            .query_map((&room_id_str, &response_id_str), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        if sources.is_empty() {
+            return Ok(false);
+        }
+        let mut stmt = conn.prepare_cached(stmt_insert)?;
+        for (domain, handler) in sources {
+            stmt.execute((
+                &room_id_str,
+                &response_id_str,
+                &reaction_id_str,
+                &sender_str,
+                domain,
+                handler,
+                vote,
+            ))?;
+        }
+        Ok::<_, Report>(true)
+    })
+    .await
+    .unwrap()
+}
+
+/// Withdraws a vote whose reaction got redacted.
+pub async fn remove_vote(db: &Pool, room_id: &RoomId, reaction_id: &EventId) -> Result<()> {
+    let stmt_delete = "DELETE FROM feedback WHERE room_id = ? AND reaction_id = ?;";
+    let conn = db.get().await?;
+
+    let room_id_str = room_id.to_string();
+    let reaction_id_str = reaction_id.to_string();
+    conn.interact(move |conn| {
+        let mut stmt = conn.prepare_cached(stmt_delete)?;
+        stmt.execute((room_id_str, reaction_id_str))?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Renders the domains with the most downvotes, which likely need site handlers or overrides.
+pub async fn report(db: &Pool) -> Result<String> {
+    let stmt_query = "SELECT domain, handler, SUM(vote > 0), SUM(vote < 0) FROM feedback
+GROUP BY domain, handler HAVING SUM(vote < 0) > 0
+ORDER BY SUM(vote < 0) DESC, SUM(vote > 0) ASC LIMIT ?;";
+    let conn = db.get().await?;
+
+    let rows = conn
+        .interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            let rows = stmt
+                .query_map((WORST_DOMAINS_COUNT as i64,), |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, i64>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, Report>(rows)
+        })
+        .await
+        .unwrap()?;
+
+    let mut report = "Most downvoted:".to_owned();
+    if rows.is_empty() {
+        report.push_str(" None");
+    }
+    for (domain, handler, upvotes, downvotes) in rows {
+        _ = write!(
+            report,
+            " {domain} [{handler}] (\u{1f44d}\u{fe0f}{upvotes} \u{1f44e}\u{fe0f}{downvotes})"
+        );
+    }
+    Ok(report)
+}
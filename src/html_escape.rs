@@ -1,40 +1,91 @@
+/// Escapes a string for use inside an attribute value, whether single-quoted, double-quoted, or
+/// unquoted. An empty value needs quotes.
 pub fn attr(s: &str) -> String {
-    let mut result = Vec::with_capacity(
-        s.bytes()
-            .map(|b| match b {
-                b'"' => 6,
-                b'&' => 5,
-                _ => 1,
-            })
-            .sum(),
-    );
-    for b in s.bytes() {
-        match b {
-            b'"' => result.extend(b"&quot;"),
-            b'&' => result.extend(b"&amp;"),
-            _ => result.push(b),
+    escape(s, true)
+}
+
+/// Escapes a string for use as the text content of an element.
+pub fn text(s: &str) -> String {
+    escape(s, false)
+}
+
+fn escape(s: &str, in_attr: bool) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            // Unquoted attribute values also end at whitespace, `=`, and backtick.
+            '\t' if in_attr => result.push_str("&#9;"),
+            '\n' if in_attr => result.push_str("&#10;"),
+            '\r' if in_attr => result.push_str("&#13;"),
+            ' ' if in_attr => result.push_str("&#32;"),
+            '=' if in_attr => result.push_str("&#61;"),
+            '`' if in_attr => result.push_str("&#96;"),
+            '\t' | '\n' | '\r' => result.push(c),
+            // Control characters are parse errors in HTML, and some clients choke on them.
+            c if c.is_control() => result.push('\u{fffd}'),
+            c => result.push(c),
         }
     }
-    String::from_utf8(result).unwrap()
+    result
 }
 
-pub fn text(s: &str) -> String {
-    let mut result = Vec::with_capacity(
-        s.bytes()
-            .map(|b| match b {
-                b'&' => 5,
-                b'<' | b'>' => 4,
-                _ => 1,
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use scraper::{Html, Selector};
+
+    use super::*;
+
+    /// Strings mixing arbitrary characters with the ones that need escaping.
+    fn tricky_string() -> impl Strategy<Value = String> {
+        let special = prop::sample::select(vec![
+            '&', '<', '>', '"', '\'', '=', '`', ' ', '\t', '\n', '\r', '\0', '\u{7f}', '\u{85}',
+        ]);
+        prop::collection::vec(prop_oneof![any::<char>(), special], 0..64)
+            .prop_map(String::from_iter)
+    }
+
+    /// What's left of `s` after escaping, where control characters become U+FFFD.
+    fn sanitized(s: &str) -> String {
+        s.chars()
+            .map(|c| match c {
+                '\t' | '\n' | '\r' => c,
+                c if c.is_control() => '\u{fffd}',
+                c => c,
             })
-            .sum(),
-    );
-    for b in s.bytes() {
-        match b {
-            b'&' => result.extend(b"&amp;"),
-            b'<' => result.extend(b"&lt;"),
-            b'>' => result.extend(b"&gt;"),
-            _ => result.push(b),
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn text_round_trips(s in tricky_string()) {
+            let fragment = Html::parse_fragment(&format!("<p>{}</p>", text(&s)));
+            let p = fragment.select(&Selector::parse("p").unwrap()).next().unwrap();
+            // The parser turns raw line breaks into `\n`.
+            let expected = sanitized(&s).replace("\r\n", "\n").replace('\r', "\n");
+            prop_assert_eq!(p.text().collect::<String>(), expected);
+            prop_assert_eq!(fragment.select(&Selector::parse("p *").unwrap()).count(), 0);
+        }
+
+        #[test]
+        fn attr_round_trips(s in tricky_string()) {
+            let escaped = attr(&s);
+            let expected = sanitized(&s);
+            let quotes: &[&str] = if s.is_empty() { &["\"", "'"] } else { &["", "\"", "'"] };
+            for quote in quotes {
+                let fragment = Html::parse_fragment(&format!(
+                    "<a title={quote}{escaped}{quote} href=x>link</a>"
+                ));
+                let a = fragment.select(&Selector::parse("a").unwrap()).next().unwrap();
+                prop_assert_eq!(a.value().attr("title"), Some(expected.as_str()));
+                prop_assert_eq!(a.value().attr("href"), Some("x"));
+                prop_assert_eq!(a.value().attrs().count(), 2);
+            }
         }
     }
-    String::from_utf8(result).unwrap()
 }
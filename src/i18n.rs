@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use fluent_bundle::FluentResource;
+use fluent_bundle::concurrent::FluentBundle;
+use unic_langid::LanguageIdentifier;
+
+/// The language of the bot's own strings, unless a room sets another with `!preview language`.
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// The Fluent catalogs of the bot's own strings, one per language, as laid out for Weblate.
+/// A new translation in `locales/` also needs a line here.
+const CATALOGS: &[(&str, &str)] = &[
+    ("de", include_str!("../locales/de/bot.ftl")),
+    ("en", include_str!("../locales/en/bot.ftl")),
+];
+
+static BUNDLES: LazyLock<HashMap<&'static str, FluentBundle<FluentResource>>> =
+    LazyLock::new(|| {
+        CATALOGS
+            .iter()
+            .map(|&(language, catalog)| {
+                let resource = FluentResource::try_new(catalog.to_owned())
+                    .unwrap_or_else(|_| panic!("Invalid catalog for {language}"));
+                let language_id = language.parse::<LanguageIdentifier>().unwrap();
+                let mut bundle = FluentBundle::new_concurrent(vec![language_id]);
+                // The strings are shown on their own, so they don't need bidi isolation marks.
+                bundle.set_use_isolating(false);
+                bundle
+                    .add_resource(resource)
+                    .unwrap_or_else(|_| panic!("Duplicate messages in the catalog for {language}"));
+                (language, bundle)
+            })
+            .collect()
+    });
+
+/// The languages with a catalog.
+pub fn languages() -> impl Iterator<Item = &'static str> {
+    CATALOGS.iter().map(|&(language, _)| language)
+}
+
+/// Finds the catalog for a language tag such as `de`, `de-AT`, or `pt_BR`, falling back to its
+/// primary language.
+pub fn find(tag: &str) -> Option<&'static str> {
+    let tag = tag.replace('_', "-").to_ascii_lowercase();
+    let primary = tag.split('-').next().unwrap_or_default();
+    languages()
+        .find(|language| language.eq_ignore_ascii_case(&tag))
+        .or_else(|| languages().find(|&language| language == primary))
+}
+
+/// Returns the message `id` in `language`, or in English if it isn't translated yet.
+pub fn message(language: &str, id: &str) -> String {
+    let format = |language: &str| {
+        let bundle = BUNDLES.get(find(language)?)?;
+        let pattern = bundle.get_message(id)?.value()?;
+        let mut errors = Vec::new();
+        Some(
+            bundle
+                .format_pattern(pattern, None, &mut errors)
+                .into_owned(),
+        )
+    };
+    format(language)
+        .or_else(|| format(DEFAULT_LANGUAGE))
+        .unwrap_or_else(|| id.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_translate_every_message() {
+        let (_, english) = CATALOGS
+            .iter()
+            .find(|&&(language, _)| language == DEFAULT_LANGUAGE)
+            .unwrap();
+        let ids = english
+            .lines()
+            .filter_map(|line| line.split_once(" = "))
+            .map(|(id, _)| id);
+        for id in ids {
+            for language in languages() {
+                assert!(BUNDLES[language].has_message(id), "{language} lacks {id}");
+            }
+        }
+    }
+
+    #[test]
+    fn finds_languages() {
+        assert_eq!(find("de"), Some("de"));
+        assert_eq!(find("DE_at"), Some("de"));
+        assert_eq!(find("en-GB"), Some("en"));
+        assert_eq!(find("xx"), None);
+        assert_eq!(message("de-CH", "loading"), "Wird geladen…");
+        assert_eq!(message("xx", "loading"), "Loading…");
+    }
+}
@@ -0,0 +1,170 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::common::MAX_JSON_LD_BYTES;
+
+/// Schema.org types describing the page itself, rather than something it mentions.
+const MAIN_ENTITY_TYPES: &[&str] = &[
+    "Article",
+    "NewsArticle",
+    "BlogPosting",
+    "Report",
+    "ScholarlyArticle",
+    "TechArticle",
+    "Recipe",
+    "Product",
+    "Event",
+    "Movie",
+    "Book",
+    "VideoObject",
+    "PodcastEpisode",
+    "AudioObject",
+    "MusicRecording",
+];
+
+/// Metadata from the `<script type="application/ld+json">` blocks of a page.
+///
+/// Ref: https://schema.org
+#[derive(Debug, Default)]
+pub struct JsonLd {
+    /// `headline`, or `name` if missing.
+    pub headline: String,
+    pub description: String,
+    /// `publisher.name`.
+    pub publisher_name: String,
+    pub url: String,
+    /// `contentUrl` of the entity itself, of `associatedMedia`, or of `audio`.
+    pub audio_url: String,
+    /// `duration` or `timeRequired`, in seconds.
+    pub duration: Option<u32>,
+    /// The first of `offers`, if it has a price or an availability.
+    pub offer: Option<Offer>,
+}
+
+/// A schema.org `Offer`, or the lowest price of an `AggregateOffer`.
+#[derive(Debug, Default)]
+pub struct Offer {
+    pub price_amount: String,
+    pub price_currency: String,
+    pub availability: String,
+}
+
+/// Extracts the metadata of the first main entity among the JSON-LD blocks. Blocks that are
+/// invalid, or larger than `MAX_JSON_LD_BYTES`, are skipped.
+pub fn parse<'a>(scripts: impl IntoIterator<Item = &'a str>) -> JsonLd {
+    for script in scripts {
+        if script.len() > MAX_JSON_LD_BYTES {
+            continue;
+        }
+        let Ok(value) = serde_json::from_str::<Value>(script) else {
+            continue;
+        };
+        if let Some(entity) = find_main_entity(&value) {
+            return JsonLd {
+                headline: string(&entity["headline"])
+                    .or_else(|| string(&entity["name"]))
+                    .unwrap_or_default(),
+                description: string(&entity["description"]).unwrap_or_default(),
+                publisher_name: first(&entity["publisher"])
+                    .and_then(|publisher| string(&publisher["name"]))
+                    .unwrap_or_default(),
+                url: string(&entity["url"]).unwrap_or_default(),
+                audio_url: audio(entity)
+                    .and_then(|audio| string(&audio["contentUrl"]))
+                    .unwrap_or_default(),
+                duration: [&entity["duration"], &entity["timeRequired"]]
+                    .into_iter()
+                    .chain(audio(entity).map(|audio| &audio["duration"]))
+                    .find_map(|duration| parse_duration(&string(duration)?)),
+                offer: first(&entity["offers"]).and_then(offer),
+            };
+        }
+    }
+    JsonLd::default()
+}
+
+/// Finds the first node of a main entity type, looking into arrays and `@graph`.
+fn find_main_entity(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Array(items) => items.iter().find_map(find_main_entity),
+        Value::Object(object) => {
+            let is_main_entity = match &object.get("@type") {
+                Some(Value::String(kind)) => MAIN_ENTITY_TYPES.contains(&kind.as_str()),
+                Some(Value::Array(kinds)) => kinds
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|kind| MAIN_ENTITY_TYPES.contains(&kind)),
+                _ => false,
+            };
+            if is_main_entity {
+                Some(value)
+            } else {
+                object.get("@graph").and_then(find_main_entity)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Finds the audio file of an entity, such as the episode of a `PodcastEpisode`.
+fn audio(entity: &Value) -> Option<&Value> {
+    if string(&entity["@type"]).as_deref() == Some("AudioObject") {
+        return Some(entity);
+    }
+    first(&entity["associatedMedia"]).or_else(|| first(&entity["audio"]))
+}
+
+fn offer(offer: &Value) -> Option<Offer> {
+    let price_amount = [&offer["price"], &offer["lowPrice"]]
+        .into_iter()
+        .find_map(|price| match first(price)? {
+            Value::Number(number) => Some(number.to_string()),
+            price => string(price),
+        })
+        .unwrap_or_default();
+    let offer = Offer {
+        price_amount,
+        price_currency: string(&offer["priceCurrency"]).unwrap_or_default(),
+        availability: string(&offer["availability"]).unwrap_or_default(),
+    };
+    (!offer.price_amount.is_empty() || !offer.availability.is_empty()).then_some(offer)
+}
+
+/// Parses an ISO 8601 duration, such as `PT1H2M3S`, into seconds.
+fn parse_duration(s: &str) -> Option<u32> {
+    static DURATION: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^P(?:(\d+)D)?(?:T(?:(\d+)H)?(?:(\d+)M)?(?:(\d+)(?:\.\d+)?S)?)?$").unwrap()
+    });
+    let captures = DURATION.captures(s.trim())?;
+    let part = |index| {
+        captures
+            .get(index)
+            .map_or(Some(0), |part| part.as_str().parse::<u32>().ok())
+    };
+    let seconds = part(1)?
+        .checked_mul(86400)?
+        .checked_add(part(2)?.checked_mul(3600)?)?
+        .checked_add(part(3)?.checked_mul(60)?)?
+        .checked_add(part(4)?)?;
+    // "P" and "PT" alone are invalid.
+    (captures.iter().skip(1).any(|part| part.is_some())).then_some(seconds)
+}
+
+/// Unwraps a value that may be given once or as an array.
+fn first(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Array(items) => items.first(),
+        Value::Null => None,
+        value => Some(value),
+    }
+}
+
+fn string(value: &Value) -> Option<String> {
+    first(value)
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+}
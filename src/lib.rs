@@ -0,0 +1,20 @@
+//! The parts of the bot that work without a homeserver: finding URLs in messages, reading the
+//! metadata of web pages, and rendering previews. The bot itself is in `main.rs`; the library lets
+//! the benchmarks and fuzz targets reach these.
+
+pub mod charset;
+pub mod citation;
+pub mod classify;
+pub mod common;
+pub mod config;
+pub mod domain;
+pub mod extract_url;
+pub mod html_escape;
+pub mod i18n;
+pub mod json_ld;
+pub mod limit;
+pub mod opengraph;
+pub mod redact;
+pub mod render;
+pub mod template;
+pub mod title;
@@ -7,7 +7,7 @@ pub fn length_in_bytes(mut s: String, max_bytes: usize) -> String {
         if s.is_char_boundary(i) {
             s.truncate(i);
             if !s.ends_with("…") {
-                s.push_str("…");
+                s.push('…');
             }
             return s;
         }
@@ -21,7 +21,7 @@ pub fn length_in_chars(mut s: String, max_chars: usize) -> String {
         if idx_char >= max_chars {
             s.truncate(prev_idx_byte);
             if !s.ends_with("…") {
-                s.push_str("…");
+                s.push('…');
             }
             return s;
         }
@@ -29,3 +29,54 @@ pub fn length_in_chars(mut s: String, max_chars: usize) -> String {
     }
     s
 }
+
+/// Like [`length_in_chars`], but prefers ending at a sentence boundary, then at a word boundary,
+/// as long as it doesn't lose more than a quarter of `max_chars`.
+pub fn length_in_chars_at_boundary(s: String, max_chars: usize) -> String {
+    // Leave room for the ellipsis
+    let Some((cut, _)) = s.char_indices().nth(max_chars.saturating_sub(1)) else {
+        return s;
+    };
+    if s[cut..].chars().nth(1).is_none() {
+        // Exactly `max_chars` long
+        return s;
+    }
+    let min_chars = (max_chars - max_chars / 4).min(max_chars - 1);
+    let window_start = s
+        .char_indices()
+        .nth(min_chars)
+        .map_or(cut, |(idx_byte, _)| idx_byte);
+    let window = &s[window_start..cut];
+
+    let sentence_end = window
+        .char_indices()
+        .filter(|&(idx_byte, c)| match c {
+            '.' | '!' | '?' => s[window_start + idx_byte + 1..].starts_with(char::is_whitespace),
+            '。' | '！' | '？' => true,
+            _ => false,
+        })
+        .map(|(idx_byte, c)| window_start + idx_byte + c.len_utf8())
+        .next_back();
+    if let Some(end) = sentence_end {
+        let mut s = s;
+        s.truncate(end);
+        return s;
+    }
+
+    let word_end = window
+        .char_indices()
+        .filter(|&(_, c)| c.is_whitespace())
+        .map(|(idx_byte, _)| window_start + idx_byte)
+        .next_back();
+    if let Some(end) = word_end {
+        let mut s = s;
+        s.truncate(end);
+        s.truncate(s.trim_end().len());
+        if !s.ends_with("…") {
+            s.push('…');
+        }
+        return s;
+    }
+
+    length_in_chars(s, max_chars)
+}
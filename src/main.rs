@@ -2,28 +2,61 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use eyre::Result;
-use indexmap::IndexSet;
 use matrix_sdk::config::SyncSettings;
 use matrix_sdk::event_handler::{Ctx, RawEvent};
+use matrix_sdk::reqwest::StatusCode;
+use matrix_sdk::ruma::OwnedEventId;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
 use matrix_sdk::ruma::api::client::filter::FilterDefinition;
+use matrix_sdk::ruma::events::reaction::OriginalSyncReactionEvent;
 use matrix_sdk::ruma::events::room::encrypted::OriginalSyncRoomEncryptedEvent;
 use matrix_sdk::ruma::events::room::member::{MembershipState, SyncRoomMemberEvent};
 use matrix_sdk::ruma::events::room::message::{
-    MessageFormat, MessageType, OriginalSyncRoomMessageEvent, Relation,
+    MessageType, OriginalSyncRoomMessageEvent, Relation,
 };
+use matrix_sdk::ruma::events::room::power_levels::OriginalSyncRoomPowerLevelsEvent;
 use matrix_sdk::ruma::events::room::redaction::OriginalSyncRoomRedactionEvent;
-use matrix_sdk::{Client, Room, RoomState};
-use tracing::{Instrument, error, info, instrument, warn};
+use matrix_sdk::{Client, HttpError, Room, RoomState, RumaApiError};
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use tracing::{error, info, instrument, warn};
 use tracing_subscriber::{EnvFilter, prelude::*};
-use url::Url;
 
-use crate::worker::Worker;
+use matrix_url_previewer_bot::{
+    charset, classify, common, config, domain, extract_url, html_escape, i18n, limit, opengraph,
+    redact, render, title,
+};
+
+use crate::common::{SYNC_MAX_BACKOFF, SYNC_MIN_BACKOFF};
+use crate::worker::{IncomingMessage, Worker};
 
-mod common;
-mod config;
-mod extract_url;
-mod html_escape;
-mod limit;
+mod commands;
+mod digest;
+mod disk_cache;
+mod event_queue;
+mod feed;
+mod feedback;
+mod media_link;
+mod message_store;
+mod metrics;
+mod oracle;
+mod outbox;
+mod pdf;
+mod pinned;
+mod pipe;
+mod preview_log;
+mod receipts;
+#[cfg(feature = "rewrite")]
+mod rewrite;
+mod room_cleanup;
+mod room_settings;
+mod scheduler;
+mod settings_sync;
+mod snippet;
+mod storage;
+mod tasks;
+mod thread_aggregate;
+mod transport;
 mod worker;
 
 #[derive(clap::Parser)]
@@ -68,12 +101,73 @@ enum Command {
         )]
         config_path: PathBuf,
     },
+    #[clap(about = "Show the lifecycle of the preview of a message")]
+    PreviewLog {
+        #[clap(
+            long = "config",
+            value_name = "PATH",
+            help = "Path to the configuration file"
+        )]
+        config_path: PathBuf,
+        #[clap(
+            value_name = "EVENT_ID",
+            help = "Event ID of the message or of its preview"
+        )]
+        event_id: OwnedEventId,
+    },
+    #[clap(about = "Load the previews of a list of URLs into the disk cache")]
+    Warm {
+        #[clap(
+            long = "config",
+            value_name = "PATH",
+            help = "Path to the configuration file"
+        )]
+        config_path: PathBuf,
+        #[clap(
+            value_name = "PATH",
+            help = "File with one URL per line, where lines starting with # are ignored"
+        )]
+        urls_path: PathBuf,
+    },
+    #[cfg(feature = "rewrite")]
+    #[clap(about = "Show how the rewrite rules transform a URL")]
+    RewriteTest {
+        #[clap(
+            long = "config",
+            value_name = "PATH",
+            help = "Path to the configuration file"
+        )]
+        config_path: PathBuf,
+        #[clap(value_name = "URL", help = "URL to rewrite")]
+        url: url::Url,
+    },
+    #[clap(about = "Preview the URLs in the text read from the standard input")]
+    Pipe {
+        #[clap(
+            long = "config",
+            value_name = "PATH",
+            help = "Path to the configuration file"
+        )]
+        config_path: PathBuf,
+        #[clap(
+            long,
+            value_enum,
+            default_value = "json",
+            help = "Output format of the previews"
+        )]
+        format: pipe::Format,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    matrixbot_ezlogin::DuplexLog::init();
+    let args: Args = clap::Parser::parse();
+    // `pipe` owns the standard input and output, so it logs to the standard error instead.
+    let is_pipe = matches!(args.command, Command::Pipe { .. });
+    if !is_pipe {
+        matrixbot_ezlogin::DuplexLog::init();
+    }
     tracing_subscriber::registry()
         .with(tracing_error::ErrorLayer::default())
         .with({
@@ -92,12 +186,16 @@ async fn main() -> Result<()> {
             filter
         })
         .with(
-            tracing_subscriber::fmt::layer().with_writer(matrixbot_ezlogin::DuplexLog::get_writer),
+            tracing_subscriber::fmt::layer().with_writer(move || -> Box<dyn std::io::Write> {
+                if is_pipe {
+                    Box::new(std::io::stderr())
+                } else {
+                    matrixbot_ezlogin::DuplexLog::get_writer()
+                }
+            }),
         )
         .init();
 
-    let args: Args = clap::Parser::parse();
-
     match args.command {
         Command::Setup {
             config_path,
@@ -114,53 +212,234 @@ async fn main() -> Result<()> {
             let config = config::Config::new(&config_path).await?;
             matrixbot_ezlogin::logout(&config.data_dir).await?
         }
+        Command::PreviewLog {
+            config_path,
+            event_id,
+        } => {
+            let config = config::Config::new(&config_path).await?;
+            let db = Worker::open_db(&config)?;
+            print!("{}", preview_log::report(&db, &event_id).await?);
+        }
+        Command::Warm {
+            config_path,
+            urls_path,
+        } => {
+            let config = config::Config::new(&config_path).await?;
+            if config.cache_max_disk_usage == 0 {
+                eyre::bail!("Warming the cache needs `cache_max_disk_usage` to be set.");
+            }
+            let urls = tokio::fs::read_to_string(&urls_path)
+                .await?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(config::parse_seed_url)
+                .collect::<Result<Vec<_>>>()?;
+            let total = urls.len();
+            let worker = Worker::new(config).await?;
+            let count = worker.clone().warm_cache(urls).await;
+            println!("Cached the previews of {count} of {total} URLs.");
+            worker.shutdown().await;
+        }
+        #[cfg(feature = "rewrite")]
+        Command::RewriteTest { config_path, url } => {
+            let config = config::Config::new(&config_path).await?;
+            rewrite::Rewriter::new(&config)?.print_trace(&url);
+        }
+        Command::Pipe {
+            config_path,
+            format,
+        } => {
+            let config = config::Config::new(&config_path).await?;
+            let mut input = String::new();
+            tokio::io::stdin().read_to_string(&mut input).await?;
+            let worker = Worker::new(config).await?;
+            let result = pipe::run(worker.clone(), &input, format).await;
+            worker.shutdown().await;
+            result?;
+        }
     };
     Ok(())
 }
 
 async fn run(config: Arc<config::Config>) -> Result<()> {
     let worker = Worker::new(config.clone()).await?;
-    let (client, sync_helper) = matrixbot_ezlogin::login(&config.data_dir).await?;
+    let seed_urls = config.seed_urls()?;
+    if !seed_urls.is_empty() {
+        worker.spawn("warm_cache", {
+            let worker = worker.clone();
+            async move {
+                let total = seed_urls.len();
+                let count = worker.warm_cache(seed_urls).await;
+                info!("Warmed the cache with {} of {} seed URLs.", count, total);
+                Ok(())
+            }
+        });
+    }
+
+    tokio::select! {
+        result = sync(config, worker.clone()) => result,
+        result = shutdown_signal() => {
+            result?;
+            info!("Shutting down.");
+            worker.shutdown().await;
+            Ok(())
+        }
+    }
+}
 
-    // We don't ignore joining and leaving events happened during downtime.
-    client.add_event_handler_context(worker);
-    client.add_event_handler(on_leave);
+/// Resolves on Ctrl-C, or on SIGTERM on Unix.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = sigterm.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
 
+async fn sync(config: Arc<config::Config>, worker: Arc<Worker>) -> Result<()> {
     // Enable room members lazy-loading, it will speed up the initial sync a lot with accounts in lots of rooms.
     // https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members
     let sync_settings =
         SyncSettings::default().filter(FilterDefinition::with_lazy_loading().into());
 
-    info!(
-        "Skipping messages since last logout. May take longer depending on the number of rooms joined."
-    );
-    sync_helper
-        .sync_once(&client, sync_settings.clone())
-        .await?;
+    let mut is_first_login = true;
+    let mut backoff = SYNC_MIN_BACKOFF;
+    loop {
+        let (client, sync_helper) = matrixbot_ezlogin::login(&config.data_dir).await?;
 
-    client.add_event_handler(on_message);
-    client.add_event_handler(on_deletion);
-    client.add_event_handler(on_utd);
+        // We don't ignore joining and leaving events happened during downtime.
+        client.add_event_handler_context(worker.clone());
+        client.add_event_handler(on_leave);
 
-    // Forget rooms that we already left
-    let left_rooms = client.left_rooms();
-    tokio::spawn(
-        async move {
-            for room in left_rooms {
-                info!("Forgetting room {}.", room.room_id());
-                match room.forget().await {
-                    Ok(_) => info!("Forgot room {}.", room.room_id()),
-                    Err(err) => error!("Failed to forget room {}: {}", room.room_id(), err),
+        if is_first_login {
+            is_first_login = false;
+
+            info!(
+                "Skipping messages since last logout. May take longer depending on the number of rooms joined."
+            );
+            let response = sync_helper
+                .sync_once(&client, sync_settings.clone())
+                .await?;
+            worker.record_sync(&response);
+
+            if let Err(err) = worker.restore_settings(&client).await {
+                error!("Failed to restore room settings from account data: {}", err);
+            }
+            if let Err(err) = worker.clone().reconcile_pending_jobs(&client).await {
+                error!("Failed to complete pending URL previews: {}", err);
+            }
+        }
+
+        client.add_event_handler(on_join);
+        client.add_event_handler(on_message);
+        client.add_event_handler(on_deletion);
+        client.add_event_handler(on_reaction);
+        client.add_event_handler(on_preview_request);
+        client.add_event_handler(on_utd);
+        client.add_event_handler(on_power_levels);
+
+        // Forget rooms that we already left
+        worker.spawn("forget_left_rooms", {
+            let worker = worker.clone();
+            let client = client.clone();
+            async move {
+                worker.forget_left_rooms(client).await;
+                Ok(())
+            }
+        });
+
+        // Post the digests with this session only, as it's dropped before logging in again.
+        let digests = worker.spawn_service("digests", {
+            let worker = worker.clone();
+            let client = client.clone();
+            async move {
+                worker.send_digests(client).await;
+                Ok(())
+            }
+        });
+
+        info!("Starting sync.");
+        loop {
+            let err = match sync_helper.sync_once(&client, sync_settings.clone()).await {
+                Ok(response) => {
+                    worker.record_sync(&response);
+                    backoff = SYNC_MIN_BACKOFF;
+                    continue;
                 }
+                Err(err) => err,
+            };
+            match SyncFailure::classify(&err) {
+                SyncFailure::Transient => {
+                    warn!("Sync failed, retrying in {:?}: {}", backoff, err);
+                }
+                SyncFailure::SoftLogout => {
+                    warn!(
+                        "Session expired, logging in again in {:?}: {}",
+                        backoff, err
+                    );
+                    break;
+                }
+                SyncFailure::Fatal => return Err(err.into()),
             }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(SYNC_MAX_BACKOFF);
         }
-        .in_current_span(),
-    );
 
-    info!("Starting sync.");
-    sync_helper.sync(&client, sync_settings).await?;
+        // The session database can only be opened by one client at a time.
+        digests.abort();
+        drop(sync_helper);
+        drop(client);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(SYNC_MAX_BACKOFF);
+    }
+}
 
-    Ok(())
+enum SyncFailure {
+    /// Network errors, rate limits, and server errors. Retry after a while.
+    Transient,
+    /// The access token was invalidated, but the session can be restored.
+    SoftLogout,
+    /// Anything else, including being logged out by another device.
+    Fatal,
+}
+
+impl SyncFailure {
+    fn classify(err: &matrix_sdk::Error) -> SyncFailure {
+        match err.client_api_error_kind() {
+            Some(ErrorKind::UnknownToken { soft_logout: true }) => return SyncFailure::SoftLogout,
+            Some(ErrorKind::LimitExceeded { .. }) => return SyncFailure::Transient,
+            Some(ErrorKind::UnknownToken { .. } | ErrorKind::UserDeactivated) => {
+                return SyncFailure::Fatal;
+            }
+            _ => (),
+        }
+        let matrix_sdk::Error::Http(err) = err else {
+            return SyncFailure::Fatal;
+        };
+        let status_code = match err.as_ref() {
+            HttpError::Reqwest(_) => return SyncFailure::Transient,
+            HttpError::RefreshToken(_) => return SyncFailure::SoftLogout,
+            _ => match err.as_ruma_api_error() {
+                Some(RumaApiError::ClientApi(err)) => err.status_code,
+                Some(RumaApiError::Other(err)) => err.status_code,
+                _ => return SyncFailure::Fatal,
+            },
+        };
+        if status_code.is_server_error() || status_code == StatusCode::TOO_MANY_REQUESTS {
+            SyncFailure::Transient
+        } else {
+            SyncFailure::Fatal
+        }
+    }
 }
 
 // https://spec.matrix.org/v1.14/client-server-api/#mroommessage
@@ -169,9 +448,10 @@ async fn on_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
     client: Client,
+    raw_event: RawEvent,
     ctx: Ctx<Arc<Worker>>,
 ) -> Result<()> {
-    if event.sender == client.user_id().unwrap() {
+    if event.sender == client.user_id().unwrap() && !ctx.0.previews_own_messages(&room) {
         // Ignore my own message
         return Ok(());
     }
@@ -184,6 +464,17 @@ async fn on_message(
         return Ok(());
     }
 
+    ctx.0.mark_processed(&room, event.event_id.clone());
+
+    let is_edit = matches!(event.content.relates_to, Some(Relation::Replacement(_)));
+    let in_reply_to = match event.content.relates_to {
+        Some(Relation::Reply { ref in_reply_to }) => Some(in_reply_to.event_id.clone()),
+        Some(Relation::Thread(ref thread)) if !thread.is_falling_back => thread
+            .in_reply_to
+            .as_ref()
+            .map(|in_reply_to| in_reply_to.event_id.clone()),
+        _ => None,
+    };
     let (original_event_id, thread_id, latest_content) = match event.content.relates_to {
         Some(Relation::Replacement(replacement)) => {
             (replacement.event_id, None, replacement.new_content)
@@ -198,31 +489,67 @@ async fn on_message(
     let MessageType::Text(text) = latest_content.msgtype else {
         return Ok(());
     };
-    let html = text
-        .formatted
-        .filter(|formatted| formatted.format == MessageFormat::Html);
-    let urls = if let Some(html) = html {
-        extract_url::extract_urls_from_html(&html.body)
-    } else {
-        // This code causes Internal Compiler Error on Rustc 1.87.0:
-        // text.body
-        //     .lines()
-        //     .skip_while(|&line| line.starts_with("> "))
-        //     .flat_map(extract_url::extract_urls_from_text)
-        //     .collect::<IndexSet<Url>>()
-        text.body
-            .lines()
-            .skip_while(|&line| line.starts_with("> "))
-            .flat_map(|line| extract_url::extract_urls_from_text(line))
-            .collect::<IndexSet<Url>>()
-    };
+    if let Some(command) = commands::Command::parse(&text.body) {
+        if !is_edit {
+            ctx.0
+                .on_command(room, &event.sender, original_event_id, in_reply_to, command)
+                .await?;
+        }
+        return Ok(());
+    }
+
+    if Worker::is_direct_chat(&room).await {
+        if !is_edit {
+            ctx.0
+                .on_direct_message(room, &event.sender, original_event_id)
+                .await?;
+        }
+        return Ok(());
+    }
+
+    if has_no_preview_flag(&raw_event) || extract_url::has_no_preview_marker(&text.body) {
+        info!("Not previewing {}: Sender opted out.", original_event_id);
+        return Ok(());
+    }
+    let links = extract_url::extract_urls_from_message(&text, ctx.0.config().max_dom_nodes);
 
     ctx.0
-        .on_message(room, thread_id, original_event_id, urls)
-        .await?;
+        .enqueue_message(IncomingMessage {
+            room,
+            sender: event.sender,
+            thread_id,
+            original_event_id,
+            is_edit,
+            links,
+        })
+        .await;
     Ok(())
 }
 
+/// Returns whether the message, or its latest edit, has `"com.m13253.no_preview": true` in its
+/// content.
+fn has_no_preview_flag(raw_event: &RawEvent) -> bool {
+    #[derive(Deserialize)]
+    struct Event {
+        content: Content,
+    }
+    #[derive(Deserialize)]
+    struct Content {
+        #[serde(rename = "com.m13253.no_preview", default)]
+        no_preview: bool,
+        #[serde(rename = "m.new_content")]
+        new_content: Option<Box<Content>>,
+    }
+
+    serde_json::from_str::<Event>(raw_event.get()).is_ok_and(|event| {
+        event.content.no_preview
+            || event
+                .content
+                .new_content
+                .is_some_and(|new_content| new_content.no_preview)
+    })
+}
+
 #[instrument(skip_all)]
 async fn on_deletion(
     event: OriginalSyncRoomRedactionEvent,
@@ -230,7 +557,7 @@ async fn on_deletion(
     client: Client,
     ctx: Ctx<Arc<Worker>>,
 ) -> Result<()> {
-    if event.sender == client.user_id().unwrap() {
+    if event.sender == client.user_id().unwrap() && !ctx.0.previews_own_messages(&room) {
         // Ignore my own message
         return Ok(());
     }
@@ -245,12 +572,62 @@ async fn on_deletion(
 
     let room_version = room.clone_info().room_version_or_default();
     let original_event_id = event.redacts(&room_version);
-    ctx.0.on_deletion(room, &original_event_id).await?;
+    ctx.0.on_deletion(room, original_event_id).await?;
     Ok(())
 }
 
-// https://spec.matrix.org/v1.14/client-server-api/#mroomencrypted
+// https://spec.matrix.org/v1.14/client-server-api/#mreaction
 #[instrument(skip_all)]
+async fn on_reaction(
+    event: OriginalSyncReactionEvent,
+    room: Room,
+    client: Client,
+    ctx: Ctx<Arc<Worker>>,
+) -> Result<()> {
+    if event.sender == client.user_id().unwrap() {
+        // Ignore my own reaction
+        return Ok(());
+    }
+    if room.state() != RoomState::Joined {
+        return Ok(());
+    }
+
+    let annotation = event.content.relates_to;
+    ctx.0
+        .on_reaction(
+            room,
+            &event.sender,
+            &event.event_id,
+            &annotation.event_id,
+            &annotation.key,
+        )
+        .await?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn on_preview_request(
+    event: oracle::OriginalSyncPreviewRequestEvent,
+    room: Room,
+    client: Client,
+    ctx: Ctx<Arc<Worker>>,
+) -> Result<()> {
+    if event.sender == client.user_id().unwrap()
+        || room.state() != RoomState::Joined
+        || !ctx.0.is_oracle_room(&room)
+    {
+        return Ok(());
+    }
+    ctx.0.spawn(
+        "preview_request",
+        ctx.0
+            .clone()
+            .on_preview_request(room, event.event_id, event.content),
+    );
+    Ok(())
+}
+
+// https://spec.matrix.org/v1.14/client-server-api/#mroomencrypted
 async fn on_utd(_event: OriginalSyncRoomEncryptedEvent, room: Room, raw_event: RawEvent) {
     error!(
         "Unable to decrypt: room {}, event {}",
@@ -261,7 +638,47 @@ async fn on_utd(_event: OriginalSyncRoomEncryptedEvent, room: Room, raw_event: R
 
 // https://spec.matrix.org/v1.14/client-server-api/#mroommember
 #[instrument(skip_all)]
-async fn on_leave(event: SyncRoomMemberEvent, room: Room) {
+async fn on_join(
+    event: SyncRoomMemberEvent,
+    room: Room,
+    client: Client,
+    ctx: Ctx<Arc<Worker>>,
+) -> Result<()> {
+    let SyncRoomMemberEvent::Original(event) = event else {
+        return Ok(());
+    };
+    if Some(event.state_key.as_ref()) != client.user_id()
+        || event.content.membership != MembershipState::Join
+    {
+        return Ok(());
+    }
+    // Profile changes are also join events.
+    if event
+        .unsigned
+        .prev_content
+        .is_some_and(|prev_content| prev_content.membership == MembershipState::Join)
+    {
+        return Ok(());
+    }
+    ctx.0.on_join(room).await
+}
+
+// https://spec.matrix.org/v1.14/client-server-api/#mroompower_levels
+#[instrument(skip_all)]
+async fn on_power_levels(
+    _event: OriginalSyncRoomPowerLevelsEvent,
+    room: Room,
+    ctx: Ctx<Arc<Worker>>,
+) -> Result<()> {
+    if room.state() != RoomState::Joined {
+        return Ok(());
+    }
+    ctx.0.on_power_levels(room).await
+}
+
+// https://spec.matrix.org/v1.14/client-server-api/#mroommember
+#[instrument(skip_all)]
+async fn on_leave(event: SyncRoomMemberEvent, room: Room, ctx: Ctx<Arc<Worker>>) {
     if !matches!(
         event.membership(),
         MembershipState::Leave | MembershipState::Ban
@@ -271,37 +688,31 @@ async fn on_leave(event: SyncRoomMemberEvent, room: Room) {
 
     match room.state() {
         RoomState::Joined => {
-            tokio::spawn(
-                async move {
-                    if let Err(err) = room.sync_members().await {
-                        warn!("Failed to sync members of {}: {}", room.room_id(), err);
-                    }
-                    // Only I remain in the room.
-                    if room.joined_members_count() <= 1 {
-                        info!("Leaving room {}.", room.room_id());
-                        match room.leave().await {
-                            Ok(_) => info!("Left room {}.", room.room_id()),
-                            Err(err) => {
-                                error!("Failed to leave room {}: {}", room.room_id(), err)
-                            }
-                        }
+            ctx.0.spawn("leave_empty_room", async move {
+                if let Err(err) = room.sync_members().await {
+                    warn!("Failed to sync members of {}: {}", room.room_id(), err);
+                }
+                // Only I remain in the room.
+                if room.joined_members_count() <= 1 {
+                    info!("Leaving room {}.", room.room_id());
+                    match room.leave().await {
+                        Ok(_) => info!("Left room {}.", room.room_id()),
+                        Err(err) => error!("Failed to leave room {}: {}", room.room_id(), err),
                     }
                 }
-                .in_current_span(),
-            );
+                Ok(())
+            });
         }
         RoomState::Banned | RoomState::Left => {
             // Either I successfully left the room, or someone kicked me out.
-            tokio::spawn(
-                async move {
-                    info!("Forgetting room {}.", room.room_id());
-                    match room.forget().await {
-                        Ok(_) => info!("Forgot room {}.", room.room_id()),
-                        Err(err) => error!("Failed to forget room {}: {}", room.room_id(), err),
-                    }
+            ctx.0.spawn("forget_room", async move {
+                info!("Forgetting room {}.", room.room_id());
+                match room.forget().await {
+                    Ok(_) => info!("Forgot room {}.", room.room_id()),
+                    Err(err) => error!("Failed to forget room {}: {}", room.room_id(), err),
                 }
-                .in_current_span(),
-            );
+                Ok(())
+            });
         }
         _ => (),
     }
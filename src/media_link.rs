@@ -0,0 +1,100 @@
+use std::io::Cursor;
+
+use image::ImageReader;
+use mime::Mime;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::opengraph::{OpenGraph, OpenGraphMedia};
+use crate::pdf;
+
+/// Describes a link to a file rather than a web page, such as `https://example.com/photo.jpg`.
+///
+/// The title is the file name, and the description is the media type, the dimensions of images,
+/// and the size. `head` is the beginning of the file, enough to read the header of an image, or
+/// the metadata of a PDF document, which provides the title, the author, and the page count.
+/// The file itself becomes the image, video, or audio of the preview.
+pub fn describe(url: &Url, content_type: &Mime, total_size: Option<u64>, head: &[u8]) -> OpenGraph {
+    let file_name = file_name(url);
+    let dimensions = (content_type.type_() == mime::IMAGE)
+        .then(|| {
+            // Only the header is read, as the rest of the image wasn't downloaded.
+            ImageReader::new(Cursor::new(head))
+                .with_guessed_format()
+                .ok()?
+                .into_dimensions()
+                .ok()
+        })
+        .flatten();
+
+    let pdf = (content_type.essence_str() == "application/pdf").then(|| pdf::parse(head));
+
+    let mut description = Vec::new();
+    if let Some(pdf) = &pdf {
+        for value in [&pdf.subject, &pdf.author] {
+            if !value.is_empty() {
+                description.push(value.clone());
+            }
+        }
+        match pdf.page_count {
+            Some(1) => description.push("1 page".to_owned()),
+            Some(count) => description.push(format!("{count} pages")),
+            None => (),
+        }
+    }
+    description.push(content_type.essence_str().to_owned());
+    if let Some((width, height)) = dimensions {
+        description.push(format!("{width} \u{d7} {height}"));
+    }
+    if let Some(size) = total_size {
+        description.push(format_size(size));
+    }
+    let title = pdf
+        .map(|pdf| pdf.title)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(file_name);
+    let mut og = OpenGraph {
+        title,
+        description: description.join(" \u{b7} "),
+        url: url.to_string(),
+        ..Default::default()
+    };
+    let media = OpenGraphMedia {
+        url: url.to_string(),
+        content_type: content_type.essence_str().to_owned(),
+        width: dimensions.map(|(width, _)| width),
+        height: dimensions.map(|(_, height)| height),
+        ..Default::default()
+    };
+    match content_type.type_() {
+        mime::IMAGE => og.images.push(media),
+        mime::VIDEO => og.videos.push(media),
+        mime::AUDIO => og.audios.push(media),
+        _ => (),
+    }
+    og
+}
+
+/// Returns the last segment of the path of `url`, or its host if the path ends with a slash.
+pub fn file_name(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .filter(|file_name| !file_name.is_empty())
+        .unwrap_or_else(|| url.host_str().unwrap_or_default().to_owned())
+}
+
+/// Formats a number of bytes, such as `2.4 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
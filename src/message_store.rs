@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+
+use eyre::{Result, eyre};
+use matrix_sdk::ruma::{EventId, OwnedEventId, RoomId};
+use moka::future::{Cache, CacheBuilder};
+use moka::policy::EvictionPolicy;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, debug, error};
+
+use crate::common::EVENT_CLAIM_EXPIRY_INTERVAL;
+use crate::storage::{MessageKey, Response, Storage};
+
+const MAX_WRITE_BATCH_SIZE: usize = 256;
+/// How many insertions can wait for the writer before new ones wait for room.
+const MAX_PENDING_WRITES: usize = 4096;
+
+/// Maps original events to our response events, backed by a [`Storage`].
+///
+/// Lookups are cached in memory, including the negative ones, as most messages don't contain any
+/// URLs. Insertions are visible immediately, and written to the storage in batches by a background
+/// task, until [`MessageStore::shutdown`].
+pub struct MessageStore {
+    storage: Arc<dyn Storage>,
+    responses: Cache<MessageKey, Option<Response>>,
+    /// The recently claimed events, so redeliveries are caught without the storage.
+    claims: Cache<MessageKey, ()>,
+    /// Taken on shutdown, so the writer stops once it has saved the pending insertions.
+    write_tx: Mutex<Option<mpsc::Sender<(MessageKey, Response)>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MessageStore {
+    pub fn new(storage: Arc<dyn Storage>, cache_entries: u64) -> MessageStore {
+        let (write_tx, write_rx) = mpsc::channel(MAX_PENDING_WRITES);
+        let writer = tokio::spawn(Self::write_behind(storage.clone(), write_rx).in_current_span());
+        MessageStore {
+            storage,
+            responses: CacheBuilder::new(cache_entries).build(),
+            claims: CacheBuilder::new(cache_entries)
+                .eviction_policy(EvictionPolicy::lru())
+                .build(),
+            write_tx: Mutex::new(Some(write_tx)),
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    /// Stops accepting insertions, and waits for the pending ones to be saved.
+    pub async fn shutdown(&self) {
+        drop(self.write_tx.lock().unwrap().take());
+        let Some(writer) = self.writer.lock().unwrap().take() else {
+            return;
+        };
+        if let Err(err) = writer.await {
+            error!("Failed to save pending responses: {}", err);
+        }
+    }
+
+    pub async fn get_response(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<Response>> {
+        let key = (room_id.to_owned(), event_id.to_owned());
+        self.responses
+            .try_get_with_by_ref(&key, self.storage.get_response(room_id, event_id))
+            .await
+            .map_err(|err| eyre!("{}", err))
+    }
+
+    pub async fn get_response_id(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        Ok(self
+            .get_response(room_id, event_id)
+            .await?
+            .map(|response| response.response_id))
+    }
+
+    /// Finds the original event that `response_id` responds to. Not cached, as it is rarely used.
+    pub async fn get_original_event_id(
+        &self,
+        room_id: &RoomId,
+        response_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        self.storage
+            .get_original_event_id(room_id, response_id)
+            .await
+    }
+
+    /// Claims an event before responding to it. Returns `false` if it was already claimed, by
+    /// this or a previous run.
+    pub async fn claim(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        let key = (room_id.to_owned(), event_id.to_owned());
+        if !self.claims.entry(key).or_insert(()).await.is_fresh() {
+            return Ok(false);
+        }
+        self.storage.claim_event(room_id, event_id).await
+    }
+
+    /// Periodically forgets the old claims, so the sync loop doesn't wait for it.
+    pub async fn expire_claims(&self) -> Result<()> {
+        let mut ticker = tokio::time::interval(EVENT_CLAIM_EXPIRY_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(err) = self.storage.expire_claims().await {
+                error!("Failed to expire event claims: {}", err);
+            }
+        }
+    }
+
+    pub async fn insert(&self, room_id: &RoomId, event_id: &EventId, response_id: &EventId) {
+        self.put(
+            room_id,
+            event_id,
+            Response {
+                response_id: response_id.to_owned(),
+                urls_hash: None,
+            },
+        )
+        .await;
+    }
+
+    /// Records that the URLs with `urls_hash` were successfully previewed.
+    pub async fn set_urls_hash(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        response_id: &EventId,
+        urls_hash: i64,
+    ) {
+        self.put(
+            room_id,
+            event_id,
+            Response {
+                response_id: response_id.to_owned(),
+                urls_hash: Some(urls_hash),
+            },
+        )
+        .await;
+    }
+
+    async fn put(&self, room_id: &RoomId, event_id: &EventId, response: Response) {
+        let key = (room_id.to_owned(), event_id.to_owned());
+        self.responses
+            .insert(key.clone(), Some(response.clone()))
+            .await;
+        let write_tx = self.write_tx.lock().unwrap().clone();
+        let is_sent = match write_tx {
+            Some(write_tx) => write_tx.send((key, response.clone())).await.is_ok(),
+            None => false,
+        };
+        if !is_sent {
+            error!(
+                "Failed to save response {}: Writer has stopped.",
+                response.response_id
+            );
+        }
+    }
+
+    async fn write_behind(
+        storage: Arc<dyn Storage>,
+        mut write_rx: mpsc::Receiver<(MessageKey, Response)>,
+    ) {
+        let mut batch = Vec::with_capacity(MAX_WRITE_BATCH_SIZE);
+        while write_rx.recv_many(&mut batch, MAX_WRITE_BATCH_SIZE).await != 0 {
+            let rows = std::mem::take(&mut batch);
+            let count = rows.len();
+            match storage.insert_responses(rows).await {
+                Ok(()) => debug!("Saved {} responses.", count),
+                Err(err) => error!("Failed to save {} responses: {}", count, err),
+            }
+        }
+    }
+}
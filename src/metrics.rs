@@ -0,0 +1,448 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use eyre::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::tasks::Supervisor;
+
+const SLOWEST_DOMAINS_COUNT: usize = 5;
+
+const PREFIX: &str = "url_previewer";
+
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The state of the URL preview cache, at the time of a report.
+pub struct CacheSnapshot {
+    pub entry_count: u64,
+    pub weighted_size: u64,
+    pub capacity: u64,
+}
+
+/// The state of the preview job queue, at the time of a report.
+pub struct QueueSnapshot {
+    pub running: usize,
+    pub waiting: usize,
+    /// Incoming messages not handled yet.
+    pub events: usize,
+    pub events_capacity: usize,
+}
+
+/// In-process counters, readable by the `!preview stats` command without requiring Prometheus.
+pub struct Metrics {
+    started_at: Instant,
+    cache_lookups: AtomicU64,
+    cache_misses: AtomicU64,
+    events_dropped: AtomicU64,
+    /// (Days since the Unix epoch, previews served on that day)
+    previews_today: Mutex<(u64, u64)>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// Removal cause => count
+    cache_evictions: Mutex<BTreeMap<&'static str, u64>>,
+    /// Domain => (fetch count, total fetch duration)
+    domain_latency: Mutex<HashMap<String, (u32, Duration)>>,
+    /// (When the last sync succeeded, the to-device messages it carried)
+    last_sync: Mutex<Option<(Instant, usize)>>,
+    /// (Task label, outcome) => count
+    tasks: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            started_at: Instant::now(),
+            cache_lookups: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            events_dropped: AtomicU64::new(0),
+            previews_today: Mutex::new((0, 0)),
+            errors: Mutex::new(BTreeMap::new()),
+            cache_evictions: Mutex::new(BTreeMap::new()),
+            domain_latency: Mutex::new(HashMap::new()),
+            last_sync: Mutex::new(None),
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_cache_lookup(&self) {
+        self.cache_lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_event_dropped(&self) {
+        self.events_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_preview_served(&self) {
+        let today = Self::days_since_epoch();
+        let mut previews_today = self.previews_today.lock().unwrap();
+        if previews_today.0 != today {
+            *previews_today = (today, 0);
+        }
+        previews_today.1 += 1;
+    }
+
+    pub fn record_error(&self, kind: &'static str) {
+        *self.errors.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn record_cache_eviction(&self, cause: &'static str) {
+        *self
+            .cache_evictions
+            .lock()
+            .unwrap()
+            .entry(cause)
+            .or_default() += 1;
+    }
+
+    pub fn record_fetch_duration(&self, domain: &str, duration: Duration) {
+        let mut domain_latency = self.domain_latency.lock().unwrap();
+        let entry = domain_latency.entry(domain.to_owned()).or_default();
+        entry.0 += 1;
+        entry.1 += duration;
+    }
+
+    pub fn record_sync(&self, to_device_messages: usize) {
+        *self.last_sync.lock().unwrap() = Some((Instant::now(), to_device_messages));
+    }
+
+    /// Records that a background task started, or how it ended.
+    pub fn record_task(&self, label: &'static str, outcome: &'static str) {
+        *self
+            .tasks
+            .lock()
+            .unwrap()
+            .entry((label, outcome))
+            .or_default() += 1;
+    }
+
+    /// Returns the number of running background tasks, and of those that failed or panicked.
+    fn task_counts(&self) -> (u64, u64, u64) {
+        let (mut started, mut ended, mut failed, mut panicked) = (0_u64, 0, 0, 0);
+        for (&(_, outcome), &count) in self.tasks.lock().unwrap().iter() {
+            match outcome {
+                "started" => started += count,
+                "failed" => failed += count,
+                "panicked" => panicked += count,
+                _ => (),
+            }
+            if outcome != "started" {
+                ended += count;
+            }
+        }
+        (started.saturating_sub(ended), failed, panicked)
+    }
+
+    /// Renders a human-readable report.
+    pub fn report(&self, cache: &CacheSnapshot, queue: &QueueSnapshot) -> String {
+        let mut report = String::new();
+
+        let uptime = self.started_at.elapsed().as_secs();
+        _ = writeln!(
+            report,
+            "Uptime: {}d {}h {}m",
+            uptime / 86400,
+            uptime / 3600 % 24,
+            uptime / 60 % 60
+        );
+
+        match *self.last_sync.lock().unwrap() {
+            Some((at, to_device_messages)) => {
+                _ = writeln!(
+                    report,
+                    "Last sync: {} s ago ({} to-device messages)",
+                    at.elapsed().as_secs(),
+                    to_device_messages
+                )
+            }
+            None => _ = writeln!(report, "Last sync: N/A"),
+        }
+        _ = writeln!(
+            report,
+            "Preview jobs: {} running, {} waiting",
+            queue.running, queue.waiting
+        );
+        _ = writeln!(
+            report,
+            "Event queue: {} / {} ({} dropped)",
+            queue.events,
+            queue.events_capacity,
+            self.events_dropped.load(Ordering::Relaxed)
+        );
+        let (running, failed, panicked) = self.task_counts();
+        _ = writeln!(
+            report,
+            "Background tasks: {running} running, {failed} failed, {panicked} panicked"
+        );
+
+        let previews_today = *self.previews_today.lock().unwrap();
+        _ = writeln!(
+            report,
+            "Previews served today: {}",
+            if previews_today.0 == Self::days_since_epoch() {
+                previews_today.1
+            } else {
+                0
+            }
+        );
+
+        let lookups = self.cache_lookups.load(Ordering::Relaxed);
+        let hits = lookups.saturating_sub(self.cache_misses.load(Ordering::Relaxed));
+        if lookups == 0 {
+            _ = writeln!(report, "Cache hit rate: N/A");
+        } else {
+            _ = writeln!(
+                report,
+                "Cache hit rate: {:.1}% ({} of {})",
+                hits as f64 * 100.0 / lookups as f64,
+                hits,
+                lookups
+            );
+        }
+
+        _ = writeln!(
+            report,
+            "Cache entries: {} / {} (weighted size {})",
+            cache.entry_count, cache.capacity, cache.weighted_size
+        );
+        report.push_str("Cache evictions:");
+        let cache_evictions = self.cache_evictions.lock().unwrap();
+        if cache_evictions.is_empty() {
+            report.push_str(" None");
+        }
+        for (cause, count) in cache_evictions.iter() {
+            _ = write!(report, " {cause} {count}");
+        }
+        report.push('\n');
+        drop(cache_evictions);
+
+        let mut domain_latency = self
+            .domain_latency
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, &(count, total))| (domain.clone(), total / count))
+            .collect::<Vec<_>>();
+        domain_latency.sort_unstable_by_key(|&(_, average)| Reverse(average));
+        report.push_str("Slowest domains:");
+        if domain_latency.is_empty() {
+            report.push_str(" N/A");
+        }
+        for (domain, average) in domain_latency.iter().take(SLOWEST_DOMAINS_COUNT) {
+            _ = write!(report, " {} ({:.1} s)", domain, average.as_secs_f64());
+        }
+        report.push('\n');
+
+        report.push_str("Errors:");
+        let errors = self.errors.lock().unwrap();
+        if errors.is_empty() {
+            report.push_str(" None");
+        }
+        for (kind, count) in errors.iter() {
+            _ = write!(report, " {kind} {count}");
+        }
+        report
+    }
+
+    /// Renders the counters in the OpenMetrics text format.
+    ///
+    /// Ref: https://prometheus.io/docs/specs/om/open_metrics_spec/
+    pub fn openmetrics(&self, cache: &CacheSnapshot, queue: &QueueSnapshot) -> String {
+        let mut report = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            _ = writeln!(report, "# TYPE {PREFIX}_{name} {kind}");
+            _ = writeln!(report, "# HELP {PREFIX}_{name} {help}");
+            let suffix = if kind == "counter" { "_total" } else { "" };
+            for (labels, value) in samples {
+                _ = writeln!(report, "{PREFIX}_{name}{suffix}{labels} {value}");
+            }
+        };
+        let labeled = |label: &str, counts: &BTreeMap<&'static str, u64>| {
+            counts
+                .iter()
+                .map(|(key, &count)| (format!("{{{label}=\"{key}\"}}"), count))
+                .collect::<Vec<_>>()
+        };
+
+        family(
+            "uptime_seconds",
+            "gauge",
+            "Seconds since startup.",
+            &[(String::new(), self.started_at.elapsed().as_secs())],
+        );
+        let last_sync = *self.last_sync.lock().unwrap();
+        family(
+            "sync_age_seconds",
+            "gauge",
+            "Seconds since the last successful sync.",
+            &last_sync
+                .map(|(at, _)| (String::new(), at.elapsed().as_secs()))
+                .into_iter()
+                .collect::<Vec<_>>(),
+        );
+        family(
+            "sync_to_device_messages",
+            "gauge",
+            "To-device messages in the last successful sync.",
+            &last_sync
+                .map(|(_, to_device_messages)| (String::new(), to_device_messages as u64))
+                .into_iter()
+                .collect::<Vec<_>>(),
+        );
+        family(
+            "preview_jobs_running",
+            "gauge",
+            "Preview jobs running.",
+            &[(String::new(), queue.running as u64)],
+        );
+        family(
+            "preview_jobs_waiting",
+            "gauge",
+            "Preview jobs waiting for a free slot.",
+            &[(String::new(), queue.waiting as u64)],
+        );
+        family(
+            "event_queue_length",
+            "gauge",
+            "Incoming messages not handled yet.",
+            &[(String::new(), queue.events as u64)],
+        );
+        family(
+            "event_queue_capacity",
+            "gauge",
+            "Maximum number of incoming messages waiting to be handled.",
+            &[(String::new(), queue.events_capacity as u64)],
+        );
+        family(
+            "events_dropped",
+            "counter",
+            "Incoming messages dropped because the event queue was full.",
+            &[(String::new(), self.events_dropped.load(Ordering::Relaxed))],
+        );
+        family(
+            "tasks",
+            "counter",
+            "Background tasks started, and how they ended, by kind.",
+            &self
+                .tasks
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&(label, outcome), &count)| {
+                    (format!("{{task=\"{label}\",outcome=\"{outcome}\"}}"), count)
+                })
+                .collect::<Vec<_>>(),
+        );
+        family(
+            "tasks_running",
+            "gauge",
+            "Background tasks running.",
+            &[(String::new(), self.task_counts().0)],
+        );
+        family(
+            "cache_lookups",
+            "counter",
+            "Lookups of the URL preview cache.",
+            &[(String::new(), self.cache_lookups.load(Ordering::Relaxed))],
+        );
+        family(
+            "cache_misses",
+            "counter",
+            "Lookups of the URL preview cache that had to fetch.",
+            &[(String::new(), self.cache_misses.load(Ordering::Relaxed))],
+        );
+        family(
+            "cache_entries",
+            "gauge",
+            "Entries in the URL preview cache.",
+            &[(String::new(), cache.entry_count)],
+        );
+        family(
+            "cache_weighted_size",
+            "gauge",
+            "Weighted size of the URL preview cache.",
+            &[(String::new(), cache.weighted_size)],
+        );
+        family(
+            "cache_capacity",
+            "gauge",
+            "Maximum weighted size of the URL preview cache.",
+            &[(String::new(), cache.capacity)],
+        );
+        family(
+            "cache_evictions",
+            "counter",
+            "Entries removed from the URL preview cache, by cause.",
+            &labeled("cause", &self.cache_evictions.lock().unwrap()),
+        );
+        family(
+            "errors",
+            "counter",
+            "Errors, by kind.",
+            &labeled("kind", &self.errors.lock().unwrap()),
+        );
+        report.push_str("# EOF\n");
+        report
+    }
+
+    fn days_since_epoch() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 86400
+    }
+}
+
+/// Serves `render()` at `/metrics` over plain HTTP, for Prometheus and alike to scrape.
+///
+/// Each connection is handled by a task of `tasks`.
+pub async fn serve(
+    listen: &str,
+    render: Arc<dyn Fn() -> String + Send + Sync>,
+    tasks: Arc<Supervisor>,
+) -> Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!("Serving metrics at http://{}/metrics", listen);
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Failed to accept metrics connection: {}", err);
+                continue;
+            }
+        };
+        let render = render.clone();
+        tasks.spawn("metrics_connection", async move {
+            let mut request = [0; 1024];
+            let Ok(Ok(len)) =
+                tokio::time::timeout(METRICS_REQUEST_TIMEOUT, stream.read(&mut request)).await
+            else {
+                return Ok(());
+            };
+                let request_line = request[..len].split(|&c| c == b'\r').next();
+                let response = match request_line {
+                    Some(b"GET /metrics HTTP/1.0" | b"GET /metrics HTTP/1.1") => {
+                        let body = render();
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                    }
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_owned(),
+                };
+            _ = stream.write_all(response.as_bytes()).await;
+            Ok(())
+        });
+    }
+}
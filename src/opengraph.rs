@@ -0,0 +1,916 @@
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use encoding_rs::Encoding;
+use html5ever::driver;
+use html5ever::tendril::{StrTendril, TendrilSink};
+use mime::Mime;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use scraper::{ElementRef, Html, HtmlTreeSink, Selector};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::common::{
+    MAX_CHARSET_PROBE_BYTES, MAX_PAGE_SECTIONS, MAX_SECTION_TITLE_CHARS, MIN_BODY_PARAGRAPH_CHARS,
+    PARSE_CHUNK_BYTES, PLAYABLE_VIDEO_TYPES, RTL_LANGUAGES,
+};
+use crate::{charset, json_ld, limit};
+
+/// Metadata of a web page, following the Open Graph protocol.
+///
+/// Ref: https://ogp.me
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OpenGraph {
+    /// `og:type`, for example, `website`, `article`, or `video.movie`.
+    pub og_type: String,
+    pub title: String,
+    pub description: String,
+    pub site_name: String,
+    pub url: String,
+    pub locale: String,
+    /// `<html lang>`, or `og:locale` if missing.
+    pub language: String,
+    /// `<html dir>` or `<body dir>`, either `ltr` or `rtl`, if set.
+    pub direction: String,
+    /// `<link rel="alternate" hreflang>`, as the language and the URL.
+    pub alternates: Vec<(String, String)>,
+    /// Titles and descriptions in other languages, from `<meta lang>`.
+    pub localized: Vec<LocalizedText>,
+    /// `<link rel="icon">`, which may be relative to the page.
+    pub icon: String,
+    /// `<h1>` to `<h6>` with an `id`, as the ID and the text, to resolve `#fragment` links.
+    pub sections: Vec<(String, String)>,
+    pub images: Vec<OpenGraphMedia>,
+    pub videos: Vec<OpenGraphMedia>,
+    pub audios: Vec<OpenGraphMedia>,
+    pub article: Option<Article>,
+    pub product: Option<Product>,
+    pub twitter: Option<TwitterCard>,
+    pub citation: Option<Citation>,
+    /// The most recent entry of a feed.
+    pub latest_entry: Option<FeedEntry>,
+    /// The length of the video or audio, in seconds, from `music:duration` or `video:duration`.
+    pub duration: Option<u32>,
+    /// The first lines of a text file, shown preformatted instead of the description.
+    pub snippet: String,
+    /// How the page was fetched, set by the worker rather than the parser.
+    pub fetch: Option<FetchInfo>,
+}
+
+/// The title and the description of a page in one language, from `<meta>` elements with a `lang`
+/// attribute.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LocalizedText {
+    pub language: String,
+    pub title: String,
+    pub description: String,
+}
+
+/// The final HTTP response of a page, shown in the debug footer.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FetchInfo {
+    pub status: u16,
+    /// The MIME type, without parameters.
+    pub content_type: String,
+    /// From the request to the end of the download, before parsing.
+    pub duration_ms: u64,
+}
+
+/// An `og:image`, `og:video`, or `og:audio`, along with its structured properties.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct OpenGraphMedia {
+    pub url: String,
+    pub secure_url: String,
+    pub content_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// The `article:*` properties shown in the byline.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Article {
+    pub published_time: String,
+    pub authors: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Product {
+    pub price_amount: String,
+    /// An ISO 4217 code, such as `EUR`.
+    pub price_currency: String,
+    /// Such as `instock` or `https://schema.org/InStock`.
+    pub availability: String,
+}
+
+/// An item of an RSS feed, or an entry of an Atom feed.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FeedEntry {
+    pub title: String,
+    pub url: String,
+    /// An ISO 8601 date, which may be followed by the time.
+    pub date: String,
+}
+
+/// The `citation_*` properties of scholarly articles, which arXiv and most publishers, where DOIs
+/// lead to, provide for indexing.
+///
+/// Ref: https://scholar.google.com/intl/en/scholar/inclusion.html#indexing
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Citation {
+    pub title: String,
+    /// Either `Last, First` or `First Last`.
+    pub authors: Vec<String>,
+    /// `citation_publication_date`, such as `2017/06/12`, or just the year.
+    pub date: String,
+    /// The journal, the conference, or the book the article appears in.
+    pub venue: String,
+    pub volume: String,
+    pub issue: String,
+    pub first_page: String,
+    pub last_page: String,
+    pub doi: String,
+    pub arxiv_id: String,
+}
+
+/// The `twitter:*` properties describing how the page wants to be shown.
+///
+/// Ref: https://developer.x.com/en/docs/x-for-websites/cards/guides/getting-started
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TwitterCard {
+    /// `twitter:card`, for example, `summary`, `summary_large_image`, or `player`.
+    pub card: String,
+    /// `twitter:site`, the account of the site.
+    pub site: String,
+    /// `twitter:creator`, the account of the author.
+    pub creator: String,
+    /// `twitter:player`, the URL of a page playing the media.
+    pub player: String,
+}
+
+#[derive(Clone, Copy)]
+enum MediaKind {
+    Image,
+    Video,
+    Audio,
+}
+
+impl OpenGraph {
+    /// Returns the heading of the section that a `#fragment`, as written in the URL, points to.
+    pub fn find_section(&self, fragment: &str) -> Option<&str> {
+        let id = percent_decode_str(fragment).decode_utf8().ok()?;
+        self.sections
+            .iter()
+            .find(|(section_id, _)| *section_id == id)
+            .map(|(_, heading)| heading.as_str())
+    }
+
+    /// Returns the first `og:video` that clients can play, which is a direct MP4 or WebM file
+    /// rather than an embedded player page.
+    pub fn playable_video(&self) -> Option<&OpenGraphMedia> {
+        self.videos.iter().find(|video| {
+            if video.content_type.is_empty() {
+                let path = video
+                    .best_url()
+                    .split(['?', '#'])
+                    .next()
+                    .unwrap_or_default();
+                let extension = path.rsplit_once('.').map(|(_, extension)| extension);
+                extension.is_some_and(|extension| {
+                    extension.eq_ignore_ascii_case("mp4") || extension.eq_ignore_ascii_case("webm")
+                })
+            } else {
+                PLAYABLE_VIDEO_TYPES
+                    .contains(&video.content_type.trim().to_ascii_lowercase().as_str())
+            }
+        })
+    }
+
+    /// Returns the first `og:audio`, or podcast enclosure, that isn't declared as something else
+    /// than audio.
+    pub fn playable_audio(&self) -> Option<&OpenGraphMedia> {
+        self.audios.iter().find(|audio| {
+            audio.content_type.is_empty()
+                || audio
+                    .content_type
+                    .trim()
+                    .to_ascii_lowercase()
+                    .starts_with("audio/")
+        })
+    }
+
+    /// Returns whether the text is written from right to left, by `dir`, or else by the language.
+    pub fn is_right_to_left(&self) -> bool {
+        match self.direction.as_str() {
+            "rtl" => true,
+            "ltr" => false,
+            _ => {
+                let language = self.language.replace('_', "-").to_ascii_lowercase();
+                let primary = language.split('-').next().unwrap_or_default();
+                RTL_LANGUAGES.contains(&primary)
+            }
+        }
+    }
+
+    /// Returns the URL of the version of the page in the first language of `accept_language`, if
+    /// the page is in another language.
+    pub fn find_alternate(&self, accept_language: &str) -> Option<&str> {
+        if !self.is_in_other_language(accept_language) {
+            return None;
+        }
+        let wanted = self.other_language(accept_language)?;
+        let alternates = self
+            .alternates
+            .iter()
+            .filter(|(language, _)| !language.eq_ignore_ascii_case("x-default"))
+            .collect::<Vec<_>>();
+        let index = find_language(alternates.iter().map(|(language, _)| language), &wanted)?;
+        Some(&alternates[index].1)
+    }
+
+    /// Replaces the title and the description with the ones in the first language of
+    /// `accept_language` from `<meta lang>`, if the page is in another language and has them.
+    ///
+    /// Each is looked up on its own, as pages may tag them differently, such as `fr` and `fr-FR`.
+    pub fn localize(&mut self, accept_language: &str) {
+        let Some(wanted) = self.other_language(accept_language) else {
+            return;
+        };
+        let find = |text: fn(&LocalizedText) -> &String| {
+            let candidates = self
+                .localized
+                .iter()
+                .filter(|localized| !text(localized).is_empty())
+                .collect::<Vec<_>>();
+            let index = find_language(
+                candidates.iter().map(|localized| &localized.language),
+                &wanted,
+            )?;
+            Some((
+                candidates[index].language.clone(),
+                text(candidates[index]).clone(),
+            ))
+        };
+        let title = find(|localized| &localized.title);
+        let description = find(|localized| &localized.description);
+        // The title is more prominent, so its language wins.
+        if let Some((language, description)) = description {
+            self.description = description;
+            self.language = language;
+        }
+        if let Some((language, title)) = title {
+            self.title = title;
+            self.language = language;
+        }
+    }
+
+    /// Returns whether the page is known to be in another language than the first one of
+    /// `accept_language`.
+    pub fn is_in_other_language(&self, accept_language: &str) -> bool {
+        !self.language.is_empty() && self.other_language(accept_language).is_some()
+    }
+
+    /// Returns the first language of `accept_language`, such as `fr-FR`, unless the page is
+    /// already in that language.
+    fn other_language(&self, accept_language: &str) -> Option<String> {
+        let wanted = accept_language
+            .split(',')
+            .next()?
+            .split(';')
+            .next()?
+            .trim()
+            .replace('_', "-");
+        if wanted.is_empty() || wanted == "*" {
+            return None;
+        }
+        if !self.language.is_empty()
+            && primary_subtag(&self.language.replace('_', "-")) == primary_subtag(&wanted)
+        {
+            return None;
+        }
+        Some(wanted)
+    }
+
+    fn media_list(&mut self, kind: MediaKind) -> &mut Vec<OpenGraphMedia> {
+        match kind {
+            MediaKind::Image => &mut self.images,
+            MediaKind::Video => &mut self.videos,
+            MediaKind::Audio => &mut self.audios,
+        }
+    }
+}
+
+/// Returns the index of the language tag equal to `wanted`, or else of the first one with the
+/// same primary language, such as `fr-CA` for `fr-FR`. Underscores count as hyphens.
+fn find_language<S: AsRef<str>>(tags: impl IntoIterator<Item = S>, wanted: &str) -> Option<usize> {
+    let tags = tags
+        .into_iter()
+        .map(|tag| tag.as_ref().replace('_', "-"))
+        .collect::<Vec<_>>();
+    tags.iter()
+        .position(|tag| tag.eq_ignore_ascii_case(wanted))
+        .or_else(|| {
+            tags.iter()
+                .position(|tag| primary_subtag(tag) == primary_subtag(wanted))
+        })
+}
+
+/// Returns the primary language of a language tag, such as `fr` for `fr-FR`, lowercased.
+fn primary_subtag(tag: &str) -> String {
+    tag.split('-')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+impl OpenGraphMedia {
+    /// Returns the URL to download, preferring `og:image:secure_url` and alike.
+    pub fn best_url(&self) -> &str {
+        if self.secure_url.is_empty() {
+            &self.url
+        } else {
+            &self.secure_url
+        }
+    }
+}
+
+/// Extracts the Open Graph metadata from an HTML document.
+///
+/// `charset` is the encoding from the HTTP headers, which `<meta charset>` overrides. If neither
+/// declares one, it's guessed with the help of `charset_hints`.
+/// Parsing stops once the document has more than `max_dom_nodes` nodes, or at `deadline`, and the
+/// metadata is taken from the part parsed so far.
+pub fn parse(
+    document: &[u8],
+    charset: Option<&'static Encoding>,
+    charset_hints: &[&'static Encoding],
+    max_dom_nodes: usize,
+    deadline: Instant,
+) -> OpenGraph {
+    let dom = parse_dom(document, charset, charset_hints, max_dom_nodes, deadline);
+    let og = extract(&dom);
+    info!(og.og_type);
+    og
+}
+
+fn parse_dom(
+    document: &[u8],
+    charset: Option<&'static Encoding>,
+    charset_hints: &[&'static Encoding],
+    max_dom_nodes: usize,
+    deadline: Instant,
+) -> Html {
+    let charset = declared_charset(document)
+        .or(charset)
+        .unwrap_or_else(|| charset::guess(document, charset_hints));
+    let text = charset.decode(document).0;
+
+    // Feed the parser a chunk at a time, so neither the tree nor the time spent grows past the
+    // limits before we notice.
+    let mut parser =
+        driver::parse_document(HtmlTreeSink::new(Html::new_document()), Default::default());
+    let mut rest = &*text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(PARSE_CHUNK_BYTES);
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        parser.process(StrTendril::from_slice(chunk));
+
+        if rest.is_empty() {
+            break;
+        }
+        let node_count = parser.tokenizer.sink.sink.0.borrow().tree.nodes().len();
+        if node_count > max_dom_nodes {
+            warn!(
+                "Document has over {} DOM nodes in its first {} bytes. Only parsing its beginning.",
+                max_dom_nodes,
+                text.len() - rest.len()
+            );
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "Ran out of time after parsing {} of {} bytes. Only using its beginning.",
+                text.len() - rest.len(),
+                text.len()
+            );
+            break;
+        }
+    }
+    parser.finish()
+}
+
+/// Finds the encoding declared by `<meta charset>` or `<meta http-equiv="Content-Type">`.
+///
+/// Only the `<head>`, within the first `MAX_CHARSET_PROBE_BYTES`, is parsed, so the whole
+/// document is parsed once, in the right encoding.
+fn declared_charset(document: &[u8]) -> Option<&'static Encoding> {
+    static META_CHARSET: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("meta[charset]").unwrap());
+    static META_HTTP_EQUIV_CONTENT_TYPE: LazyLock<Selector> =
+        LazyLock::new(|| Selector::parse("meta[http-equiv=\"Content-Type\" i]").unwrap());
+
+    let probe = &document[..document.len().min(MAX_CHARSET_PROBE_BYTES)];
+    let head_end = probe
+        .windows(b"</head".len())
+        .position(|window| window.eq_ignore_ascii_case(b"</head"))
+        .unwrap_or(probe.len());
+    // Declarations are ASCII, so any ASCII-compatible encoding finds them.
+    let head = Html::parse_document(&encoding_rs::UTF_8.decode(&probe[..head_end]).0);
+    head.select(&META_CHARSET)
+        .filter_map(|element| Encoding::for_label(element.attr("charset")?.as_bytes()))
+        .next()
+        .or_else(|| {
+            head.select(&META_HTTP_EQUIV_CONTENT_TYPE)
+                .filter_map(|element| {
+                    Encoding::for_label(
+                        Mime::from_str(element.attr("content")?)
+                            .ok()?
+                            .get_param(mime::CHARSET)?
+                            .as_str()
+                            .as_bytes(),
+                    )
+                })
+                .next()
+        })
+}
+
+/// The parts of a document `extract` looks at, collected in a single walk over the DOM instead
+/// of one per selector.
+#[derive(Default)]
+struct DocumentValues<'a> {
+    /// The `property` or `name`, lowercased, and the `content` of each `<meta>`, in order.
+    metas: Vec<(String, &'a str)>,
+    /// The `lang`, the `property` or `name`, lowercased, and the `content` of each `<meta>` with
+    /// a `lang` attribute, in order.
+    localized_metas: Vec<(&'a str, String, &'a str)>,
+    /// The first non-empty text of `<title>`, `<h1>`, `<h2>`, and `<h3>`, as title fallbacks.
+    headings: [Option<String>; 4],
+    /// The ID and the text of each heading with an `id`, or with an anchor such as
+    /// `<a id>` or `<a name>` as its child.
+    sections: Vec<(&'a str, String)>,
+    /// `<link rel="canonical">`.
+    canonical: Option<&'a str>,
+    /// `<html lang>`.
+    language: Option<&'a str>,
+    /// `<html dir>`, or `<body dir>`.
+    direction: Option<&'a str>,
+    /// `<link rel="alternate" hreflang>`, as the language and the URL.
+    alternates: Vec<(String, String)>,
+    /// `<link rel="icon">`, or `<link rel="shortcut icon">`.
+    icon: Option<&'a str>,
+    /// The largest `<link rel="apple-touch-icon">`, and its width if given, as an image fallback.
+    touch_icon: Option<(&'a str, u32)>,
+    /// The text of each `<script type="application/ld+json">`.
+    json_ld: Vec<String>,
+    /// The first paragraph long enough to describe the page, within `<article>` or `<main>`, and
+    /// anywhere else, as description fallbacks.
+    paragraphs: [Option<String>; 2],
+}
+
+impl<'a> DocumentValues<'a> {
+    fn collect(dom: &'a Html) -> DocumentValues<'a> {
+        let mut values = DocumentValues::default();
+        for node in dom.root_element().descendants() {
+            let Some(element) = ElementRef::wrap(node) else {
+                continue;
+            };
+            let attr = |name| element.value().attr(name);
+            match element.value().name() {
+                "meta" => {
+                    if let Some(key) = attr("property").or_else(|| attr("name"))
+                        && let Some(content) = attr("content")
+                    {
+                        let key = key.trim().to_ascii_lowercase();
+                        if let Some(language) = attr("lang")
+                            .map(str::trim)
+                            .filter(|language| !language.is_empty())
+                        {
+                            values
+                                .localized_metas
+                                .push((language, key.clone(), content));
+                        }
+                        values.metas.push((key, content));
+                    }
+                }
+                "link" => {
+                    let rel = attr("rel").unwrap_or_default();
+                    if rel.eq_ignore_ascii_case("canonical") {
+                        if values.canonical.is_none()
+                            && let Some(href) = attr("href").filter(|href| !href.is_empty())
+                        {
+                            values.canonical = Some(href);
+                        }
+                    } else if rel.eq_ignore_ascii_case("alternate")
+                        && let Some(language) = attr("hreflang").map(str::trim)
+                        && let Some(url) = attr("href").map(str::trim)
+                        && !language.is_empty()
+                        && !url.is_empty()
+                    {
+                        values
+                            .alternates
+                            .push((language.to_owned(), url.to_owned()));
+                    } else if rel.eq_ignore_ascii_case("apple-touch-icon")
+                        || rel.eq_ignore_ascii_case("apple-touch-icon-precomposed")
+                    {
+                        if let Some(href) =
+                            attr("href").map(str::trim).filter(|href| !href.is_empty())
+                        {
+                            // Such as `180x180`, while a missing or unknown size comes last.
+                            let width = attr("sizes")
+                                .and_then(|sizes| sizes.split_ascii_whitespace().next())
+                                .and_then(|size| size.split(['x', 'X']).next())
+                                .and_then(|width| width.parse().ok())
+                                .unwrap_or(0);
+                            if values.touch_icon.is_none_or(|(_, largest)| width > largest) {
+                                values.touch_icon = Some((href, width));
+                            }
+                        }
+                    } else if values.icon.is_none()
+                        && rel
+                            .split_ascii_whitespace()
+                            .any(|rel| rel.eq_ignore_ascii_case("icon"))
+                        && let Some(href) =
+                            attr("href").map(str::trim).filter(|href| !href.is_empty())
+                    {
+                        values.icon = Some(href);
+                    }
+                }
+                "script" => {
+                    if attr("type")
+                        .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/ld+json"))
+                    {
+                        values.json_ld.push(element.text().collect());
+                    }
+                }
+                "html" => {
+                    if values.language.is_none() {
+                        values.language =
+                            attr("lang").map(str::trim).filter(|lang| !lang.is_empty());
+                    }
+                    values.direction = values.direction.or(attr("dir"));
+                }
+                "body" => values.direction = values.direction.or(attr("dir")),
+                "p" => {
+                    if values.paragraphs[0].is_none()
+                        && let Some((text, is_main)) = body_paragraph(element)
+                    {
+                        values.paragraphs[usize::from(!is_main)].get_or_insert(text);
+                    }
+                }
+                name => {
+                    if matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+                        && values.sections.len() < MAX_PAGE_SECTIONS
+                        && let Some(id) = section_id(element)
+                    {
+                        let text = element.text().collect::<String>();
+                        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+                        if !text.is_empty() {
+                            values
+                                .sections
+                                .push((id, limit::length_in_chars(text, MAX_SECTION_TITLE_CHARS)));
+                        }
+                    }
+                    let Some(index) = ["title", "h1", "h2", "h3"]
+                        .iter()
+                        .position(|&heading| heading == name)
+                    else {
+                        continue;
+                    };
+                    if values.headings[index].is_none() {
+                        let text = element.text().collect::<String>();
+                        if !text.is_empty() {
+                            values.headings[index] = Some(text);
+                        }
+                    }
+                }
+            }
+        }
+        values
+    }
+}
+
+/// Returns the ID a heading can be linked to by, from the heading or an anchor within it.
+fn section_id<'a>(heading: ElementRef<'a>) -> Option<&'a str> {
+    let id = |element: ElementRef<'a>| {
+        let element = element.value();
+        element
+            .attr("id")
+            .or_else(|| {
+                (element.name() == "a")
+                    .then(|| element.attr("name"))
+                    .flatten()
+            })
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+    };
+    id(heading).or_else(|| heading.children().filter_map(ElementRef::wrap).find_map(id))
+}
+
+/// Returns the text of a `<p>`, and whether it's within `<article>` or `<main>`, if it's long
+/// enough and outside of navigation, comments, and alike.
+///
+/// Ref: https://github.com/mozilla/readability
+fn body_paragraph(element: ElementRef) -> Option<(String, bool)> {
+    static UNLIKELY_CANDIDATES: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i)banner|breadcrumb|comment|cookie|footer|menu|modal|nav|popup|share|sidebar|social|subscribe").unwrap()
+    });
+    let mut is_main = false;
+    for ancestor in element.ancestors().filter_map(ElementRef::wrap) {
+        let ancestor = ancestor.value();
+        match ancestor.name() {
+            "article" | "main" => is_main = true,
+            "nav" | "header" | "footer" | "aside" | "form" | "noscript" | "template" => {
+                return None;
+            }
+            _ => (),
+        }
+        let class_and_id = [ancestor.attr("class"), ancestor.attr("id")];
+        if class_and_id
+            .into_iter()
+            .flatten()
+            .any(|name| UNLIKELY_CANDIDATES.is_match(name))
+        {
+            return None;
+        }
+    }
+    let text = element
+        .text()
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ");
+    (text.chars().count() >= MIN_BODY_PARAGRAPH_CHARS).then_some((text, is_main))
+}
+
+fn extract(dom: &Html) -> OpenGraph {
+    let values = DocumentValues::collect(dom);
+
+    // Ref: https://github.com/element-hq/synapse/blob/v1.132.0/synapse/media/preview_html.py#L237
+    let mut og = OpenGraph::default();
+    let mut twitter_title = String::new();
+    let mut twitter_description = String::new();
+    let mut meta_description = String::new();
+    let mut tile_image = String::new();
+    for (key, content) in values.metas {
+        let content = content.trim();
+        if content.is_empty() {
+            continue;
+        }
+        // Media are arrays, and structured properties apply to the latest media.
+        let media_kind = match key.as_str() {
+            "og:image" | "og:image:url" | "twitter:image" => Some(MediaKind::Image),
+            "og:video" | "og:video:url" | "twitter:player:stream" => Some(MediaKind::Video),
+            "og:audio" | "og:audio:url" => Some(MediaKind::Audio),
+            _ => None,
+        };
+        if let Some(kind) = media_kind {
+            let list = og.media_list(kind);
+            if !list.iter().any(|media| media.url == content) {
+                list.push(OpenGraphMedia {
+                    url: content.to_owned(),
+                    ..Default::default()
+                });
+            }
+            continue;
+        }
+        let media_property = match key.as_str() {
+            "og:image:secure_url"
+            | "og:image:type"
+            | "og:image:width"
+            | "og:image:height"
+            | "og:image:alt"
+            | "twitter:image:type"
+            | "twitter:image:alt" => Some(MediaKind::Image),
+            "og:video:secure_url"
+            | "og:video:type"
+            | "og:video:width"
+            | "og:video:height"
+            | "twitter:player:stream:type" => Some(MediaKind::Video),
+            "og:audio:secure_url" | "og:audio:type" => Some(MediaKind::Audio),
+            _ => None,
+        };
+        if let Some(kind) = media_property {
+            if let Some(media) = og.media_list(kind).last_mut() {
+                let property = key.rsplit(':').next().unwrap_or_default();
+                set_media_property(media, property, content);
+            }
+            continue;
+        }
+
+        match key.as_str() {
+            "og:type" => set_once(&mut og.og_type, content),
+            "og:title" => set_once(&mut og.title, content),
+            "twitter:title" => set_once(&mut twitter_title, content),
+            "og:description" => set_once(&mut og.description, content),
+            "twitter:description" => set_once(&mut twitter_description, content),
+            "description" => set_once(&mut meta_description, content),
+            "msapplication-tileimage" => set_once(&mut tile_image, content),
+            "og:site_name" => set_once(&mut og.site_name, content),
+            "twitter:card" => set_once(&mut og.twitter.get_or_insert_default().card, content),
+            "twitter:site" => set_once(&mut og.twitter.get_or_insert_default().site, content),
+            "twitter:creator" => set_once(&mut og.twitter.get_or_insert_default().creator, content),
+            "twitter:player" => set_once(&mut og.twitter.get_or_insert_default().player, content),
+            "music:duration" | "video:duration" => {
+                og.duration = og.duration.or(content.parse().ok());
+            }
+            "og:url" => set_once(&mut og.url, content),
+            "og:locale" => set_once(&mut og.locale, content),
+            "article:published_time" => set_once(
+                &mut og.article.get_or_insert_default().published_time,
+                content,
+            ),
+            "article:author" => og
+                .article
+                .get_or_insert_default()
+                .authors
+                .push(content.to_owned()),
+            "product:price:amount" | "og:price:amount" => set_once(
+                &mut og.product.get_or_insert_default().price_amount,
+                content,
+            ),
+            "product:price:currency" | "og:price:currency" => set_once(
+                &mut og.product.get_or_insert_default().price_currency,
+                content,
+            ),
+            "product:availability" | "og:availability" => set_once(
+                &mut og.product.get_or_insert_default().availability,
+                content,
+            ),
+            "citation_title" => set_once(&mut og.citation.get_or_insert_default().title, content),
+            "citation_author" => og
+                .citation
+                .get_or_insert_default()
+                .authors
+                .push(content.to_owned()),
+            "citation_publication_date" | "citation_date" | "citation_online_date" => {
+                set_once(&mut og.citation.get_or_insert_default().date, content)
+            }
+            "citation_journal_title"
+            | "citation_conference_title"
+            | "citation_inbook_title"
+            | "citation_dissertation_institution"
+            | "citation_technical_report_institution" => {
+                set_once(&mut og.citation.get_or_insert_default().venue, content)
+            }
+            "citation_volume" => set_once(&mut og.citation.get_or_insert_default().volume, content),
+            "citation_issue" => set_once(&mut og.citation.get_or_insert_default().issue, content),
+            "citation_firstpage" => {
+                set_once(&mut og.citation.get_or_insert_default().first_page, content)
+            }
+            "citation_lastpage" => {
+                set_once(&mut og.citation.get_or_insert_default().last_page, content)
+            }
+            "citation_doi" => set_once(&mut og.citation.get_or_insert_default().doi, content),
+            "citation_arxiv_id" => {
+                set_once(&mut og.citation.get_or_insert_default().arxiv_id, content)
+            }
+            _ => (),
+        }
+    }
+
+    // Some sites only describe their pages in JSON-LD.
+    let json_ld = json_ld::parse(values.json_ld.iter().map(String::as_str));
+    if og.title.is_empty() {
+        og.title = twitter_title;
+    }
+    if og.title.is_empty() {
+        og.title = json_ld.headline;
+    }
+    if og.title.is_empty() {
+        og.title = values
+            .headings
+            .into_iter()
+            .flatten()
+            .next()
+            .unwrap_or_default();
+    }
+    if og.description.is_empty() {
+        og.description = twitter_description;
+    }
+    if og.description.is_empty() {
+        og.description = json_ld.description;
+    }
+    if og.description.is_empty() {
+        og.description = meta_description;
+    }
+    if og.description.is_empty() {
+        let [main, other] = values.paragraphs;
+        og.description = main.or(other).unwrap_or_default();
+    }
+    if og.site_name.is_empty() {
+        og.site_name = json_ld.publisher_name;
+    }
+    // Sites without an image still have an icon for home screens and tiles, which is larger and
+    // more recognizable than the favicon.
+    if og.images.is_empty() {
+        let icon_url = values
+            .touch_icon
+            .map_or(tile_image, |(href, _)| href.to_owned());
+        if !icon_url.is_empty() {
+            og.images.push(OpenGraphMedia {
+                url: icon_url,
+                ..Default::default()
+            });
+        }
+    }
+    if og.audios.is_empty() && !json_ld.audio_url.is_empty() {
+        og.audios.push(OpenGraphMedia {
+            url: json_ld.audio_url,
+            ..Default::default()
+        });
+    }
+    if og.duration.is_none() {
+        og.duration = json_ld.duration;
+    }
+    if let Some(offer) = json_ld.offer {
+        let product = og.product.get_or_insert_default();
+        if product.price_amount.is_empty() {
+            product.price_amount = offer.price_amount;
+            product.price_currency = offer.price_currency;
+        }
+        if product.availability.is_empty() {
+            product.availability = offer.availability;
+        }
+    }
+    if og.site_name.is_empty()
+        && let Some(twitter) = &og.twitter
+    {
+        og.site_name = twitter.site.clone();
+    }
+    if og.url.is_empty() {
+        og.url = values.canonical.unwrap_or_default().to_owned();
+    }
+    if og.url.is_empty() {
+        og.url = json_ld.url;
+    }
+    og.language = values.language.unwrap_or(&og.locale).to_owned();
+    og.direction = values
+        .direction
+        .map(|direction| direction.trim().to_ascii_lowercase())
+        .filter(|direction| direction == "ltr" || direction == "rtl")
+        .unwrap_or_default();
+    og.alternates = values.alternates;
+    for (language, key, content) in values.localized_metas {
+        let content = content.trim();
+        let index = match og
+            .localized
+            .iter()
+            .position(|text| text.language.eq_ignore_ascii_case(language))
+        {
+            Some(index) => index,
+            None => {
+                og.localized.push(LocalizedText {
+                    language: language.to_owned(),
+                    ..Default::default()
+                });
+                og.localized.len() - 1
+            }
+        };
+        let text = &mut og.localized[index];
+        match key.as_str() {
+            "og:title" | "twitter:title" => set_once(&mut text.title, content),
+            "og:description" | "twitter:description" | "description" => {
+                set_once(&mut text.description, content)
+            }
+            _ => (),
+        }
+    }
+    og.localized
+        .retain(|text| !text.title.is_empty() || !text.description.is_empty());
+    og.icon = values.icon.unwrap_or_default().to_owned();
+    og.sections = values
+        .sections
+        .into_iter()
+        .map(|(id, heading)| (id.to_owned(), heading))
+        .collect();
+    og
+}
+
+fn set_media_property(media: &mut OpenGraphMedia, property: &str, content: &str) {
+    match property {
+        "secure_url" => set_once(&mut media.secure_url, content),
+        "type" => set_once(&mut media.content_type, content),
+        "width" => media.width = media.width.or(content.parse().ok()),
+        "height" => media.height = media.height.or(content.parse().ok()),
+        _ => (),
+    }
+}
+
+/// Keeps the first non-empty value, as most pages put the most relevant one first.
+fn set_once(field: &mut String, content: &str) {
+    if field.is_empty() {
+        content.clone_into(field);
+    }
+}
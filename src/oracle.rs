@@ -0,0 +1,103 @@
+use matrix_sdk::ruma::events::macros::EventContent;
+use matrix_sdk::ruma::events::relation::Reference;
+use matrix_sdk::ruma::{OwnedEventId, OwnedMxcUri};
+use serde::{Deserialize, Serialize};
+
+use crate::extract_url;
+use crate::opengraph::OpenGraph;
+
+/// Asks the bot for the preview of a URL, in one of the `oracle_rooms`.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "com.m13253.url_preview.request", kind = MessageLike)]
+pub struct PreviewRequestEventContent {
+    pub url: String,
+    /// The languages to ask the site for, or the `accept_language` of the room if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_language: Option<String>,
+}
+
+/// The answer to a preview request, with either `preview` or `error`.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "com.m13253.url_preview.response", kind = MessageLike)]
+pub struct PreviewResponseEventContent {
+    /// The event ID of the request.
+    pub request_id: OwnedEventId,
+    /// The URL as requested, before any rewrite.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview: Option<PreviewData>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The parts of a preview shared with other bots, which is a stable subset of [`OpenGraph`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PreviewData {
+    /// The canonical URL of the page.
+    pub url: String,
+    /// `og:type`, for example, `website`, `article`, or `video.movie`.
+    #[serde(rename = "type")]
+    pub og_type: String,
+    pub title: String,
+    pub description: String,
+    pub site_name: String,
+    pub language: String,
+    /// The URL of the first image, not uploaded to the media repository.
+    pub image: String,
+    /// The image as shown within the preview, if it was uploaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_mxc: Option<OwnedMxcUri>,
+}
+
+/// The previews within an `m.notice` of the bot, for other bots and custom clients, which would
+/// otherwise have to scrape its HTML. Sent after each version of the notice, which it refers to.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "com.m13253.url_preview", kind = MessageLike)]
+pub struct UrlPreviewEventContent {
+    /// In the order of the URLs in the message, leaving out those without a preview.
+    pub previews: Vec<PreviewData>,
+    #[serde(rename = "m.relates_to")]
+    pub relates_to: Reference,
+}
+
+impl PreviewData {
+    pub fn from_opengraph(preview: &OpenGraph) -> PreviewData {
+        PreviewData {
+            // Left empty rather than passing on a `javascript:` or `data:` URL.
+            url: extract_url::validate_url(&preview.url)
+                .map(String::from)
+                .unwrap_or_default(),
+            og_type: preview.og_type.clone(),
+            title: preview.title.clone(),
+            description: preview.description.clone(),
+            site_name: preview.site_name.clone(),
+            language: preview.language.clone(),
+            image: preview
+                .images
+                .first()
+                .map(|image| image.best_url().to_owned())
+                .unwrap_or_default(),
+            image_mxc: None,
+        }
+    }
+}
+
+impl PreviewResponseEventContent {
+    pub fn preview(request_id: OwnedEventId, url: String, preview: &OpenGraph) -> Self {
+        PreviewResponseEventContent {
+            request_id,
+            url,
+            preview: Some(PreviewData::from_opengraph(preview)),
+            error: None,
+        }
+    }
+
+    pub fn error(request_id: OwnedEventId, url: String, error: &str) -> Self {
+        PreviewResponseEventContent {
+            request_id,
+            url,
+            preview: None,
+            error: Some(error.to_owned()),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use deadpool_sqlite::Pool;
+use eyre::{Report, Result};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, RoomId};
+
+/// A finished preview that couldn't be sent.
+pub struct UnsentPreview {
+    pub room_id: OwnedRoomId,
+    pub original_event_id: OwnedEventId,
+    pub response_id: OwnedEventId,
+    pub urls_hash: Option<i64>,
+    pub content: RoomMessageEventContent,
+}
+
+/// Saves a finished preview that couldn't be sent, to deliver it on the next startup.
+pub async fn insert(
+    db: &Pool,
+    room_id: &RoomId,
+    original_event_id: &EventId,
+    response_id: &EventId,
+    urls_hash: Option<i64>,
+    content: &RoomMessageEventContent,
+) -> Result<()> {
+    let stmt_insert = "INSERT OR REPLACE INTO unsent_previews (room_id, original_event_id, response_id, urls_hash, content) VALUES (?, ?, ?, ?, ?);";
+    let conn = db.get().await?;
+
+    let params = (
+        room_id.to_string(),
+        original_event_id.to_string(),
+        response_id.to_string(),
+        urls_hash,
+        serde_json::to_string(content)?,
+    );
+    conn.interact(move |conn| {
+        conn.prepare_cached(stmt_insert)?.execute(params)?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+pub async fn list(db: &Pool) -> Result<Vec<UnsentPreview>> {
+    let stmt_query = "SELECT room_id, original_event_id, response_id, urls_hash, content FROM unsent_previews ORDER BY id;";
+    let conn = db.get().await?;
+
+    let rows = conn
+        .interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            let rows = stmt
+                .query_map((), |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, Option<i64>>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, Report>(rows)
+        })
+        .await
+        .unwrap()?;
+    rows.into_iter()
+        .map(
+            |(room_id, original_event_id, response_id, urls_hash, content)| {
+                Ok(UnsentPreview {
+                    room_id: room_id.try_into()?,
+                    original_event_id: original_event_id.try_into()?,
+                    response_id: response_id.try_into()?,
+                    urls_hash,
+                    content: serde_json::from_str(&content)?,
+                })
+            },
+        )
+        .collect()
+}
+
+pub async fn remove(db: &Pool, room_id: &RoomId, response_id: &EventId) -> Result<()> {
+    let stmt_delete = "DELETE FROM unsent_previews WHERE room_id = ? AND response_id = ?;";
+    let conn = db.get().await?;
+
+    let params = (room_id.to_string(), response_id.to_string());
+    conn.interact(move |conn| {
+        conn.prepare_cached(stmt_delete)?.execute(params)?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
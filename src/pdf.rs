@@ -0,0 +1,219 @@
+use std::sync::LazyLock;
+
+use regex::bytes::Regex;
+
+/// Metadata of a PDF document.
+#[derive(Debug, Default)]
+pub struct PdfInfo {
+    pub title: String,
+    pub author: String,
+    pub subject: String,
+    pub page_count: Option<u32>,
+}
+
+/// Reads the metadata from the beginning of a PDF document, preferring the XMP metadata over the
+/// document information dictionary.
+///
+/// Only uncompressed objects are read, so documents storing their metadata in compressed object
+/// streams, or at the end of the file, may yield nothing.
+///
+/// Ref: ISO 32000-1, sections 14.3 and 7.3.4
+pub fn parse(head: &[u8]) -> PdfInfo {
+    let xmp = xmp_metadata(head);
+    let info_dictionary = info_dictionary(head);
+    let info = |key| {
+        info_dictionary
+            .and_then(|dictionary| info_string(dictionary, key))
+            .unwrap_or_default()
+    };
+    let xmp_value = |element| xmp.and_then(|xmp| xmp_value(xmp, element));
+    PdfInfo {
+        title: xmp_value("dc:title").unwrap_or_else(|| info("Title")),
+        author: xmp_value("dc:creator").unwrap_or_else(|| info("Author")),
+        subject: xmp_value("dc:description").unwrap_or_else(|| info("Subject")),
+        page_count: xmp_value("xmpTPg:NPages")
+            .and_then(|count| count.parse().ok())
+            .or_else(|| page_count(head)),
+    }
+}
+
+/// Returns the `<x:xmpmeta>` packet.
+fn xmp_metadata(head: &[u8]) -> Option<&str> {
+    static XMP: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?s)<x:xmpmeta[\s>].*?</x:xmpmeta>").unwrap());
+    std::str::from_utf8(XMP.find(head)?.as_bytes()).ok()
+}
+
+/// Returns the text of an XMP property, or of its first `<rdf:li>` if it's a list.
+fn xmp_value(xmp: &str, element: &str) -> Option<String> {
+    let element = regex::escape(element);
+    let pattern = format!(r"(?s)<{element}(?:\s[^>]*)?>(.*?)</{element}>");
+    let content = regex::Regex::new(&pattern)
+        .ok()?
+        .captures(xmp)?
+        .get(1)?
+        .as_str();
+    static LIST_ITEM: LazyLock<regex::Regex> =
+        LazyLock::new(|| regex::Regex::new(r"(?s)<rdf:li(?:\s[^>]*)?>(.*?)</rdf:li>").unwrap());
+    let content = match LIST_ITEM.captures(content) {
+        Some(item) => item.get(1)?.as_str(),
+        None => content,
+    };
+    let text = decode_xml_entities(content.trim());
+    (!text.is_empty()).then_some(text)
+}
+
+fn decode_xml_entities(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Returns the object holding the document information dictionary, which the trailer refers to.
+///
+/// Without the trailer, the first object with keys only found in that dictionary is used, as
+/// bookmarks also have a `/Title`.
+fn info_dictionary(head: &[u8]) -> Option<&[u8]> {
+    static INFO_REFERENCE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?-u)/Info\s+(\d+)\s+(\d+)\s+R").unwrap());
+    static INFO_KEYS: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?-u)/(?:Producer|CreationDate|ModDate)\b").unwrap());
+    static OBJECT_START: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?-u)\d+\s+\d+\s+obj\b").unwrap());
+
+    let start = match INFO_REFERENCE.captures(head) {
+        Some(reference) => {
+            let number = std::str::from_utf8(reference.get(1)?.as_bytes()).ok()?;
+            let generation = std::str::from_utf8(reference.get(2)?.as_bytes()).ok()?;
+            let pattern = format!(r"(?-u)(?:^|\D){number}\s+{generation}\s+obj\b");
+            Regex::new(&pattern).ok()?.find(head)?.end()
+        }
+        None => {
+            let key = INFO_KEYS.find(head)?.start();
+            OBJECT_START.find_iter(&head[..key]).last()?.end()
+        }
+    };
+    let end = head[start..]
+        .windows(b"endobj".len())
+        .position(|window| window == b"endobj")
+        .map_or(head.len(), |end| start + end);
+    Some(&head[start..end])
+}
+
+/// Returns a text string of the document information dictionary, such as `/Title (Report)`.
+fn info_string(dictionary: &[u8], key: &str) -> Option<String> {
+    let pattern = format!(r"(?-u)/{}\s*([(<])", regex::escape(key));
+    let captures = Regex::new(&pattern).ok()?.captures(dictionary)?;
+    let start = captures.get(1)?;
+    let bytes = if start.as_bytes() == b"(" {
+        literal_string(&dictionary[start.end()..])?
+    } else {
+        hex_string(&dictionary[start.end()..])?
+    };
+    let text = decode_text_string(&bytes);
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_owned())
+}
+
+/// Reads a literal string, after its opening parenthesis, with its escapes decoded.
+fn literal_string(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut string = Vec::new();
+    let mut depth = 0;
+    let mut iter = bytes.iter().copied().peekable();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'\\' => match iter.next()? {
+                b'n' => string.push(b'\n'),
+                b'r' => string.push(b'\r'),
+                b't' => string.push(b'\t'),
+                b'b' => string.push(0x08),
+                b'f' => string.push(0x0c),
+                // A line break after a backslash continues the line.
+                b'\r' => {
+                    iter.next_if_eq(&b'\n');
+                }
+                b'\n' => (),
+                digit @ b'0'..=b'7' => {
+                    let mut value = u32::from(digit - b'0');
+                    for _ in 0..2 {
+                        match iter.next_if(|byte| matches!(byte, b'0'..=b'7')) {
+                            Some(digit) => value = value * 8 + u32::from(digit - b'0'),
+                            None => break,
+                        }
+                    }
+                    string.push(value as u8);
+                }
+                byte => string.push(byte),
+            },
+            b'(' => {
+                depth += 1;
+                string.push(byte);
+            }
+            b')' if depth == 0 => return Some(string),
+            b')' => {
+                depth -= 1;
+                string.push(byte);
+            }
+            byte => string.push(byte),
+        }
+    }
+    None
+}
+
+/// Reads a hexadecimal string, such as `<FEFF0041>`, after its opening angle bracket.
+fn hex_string(bytes: &[u8]) -> Option<Vec<u8>> {
+    let end = bytes.iter().position(|&byte| byte == b'>')?;
+    let mut digits = bytes[..end]
+        .iter()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .map(|&byte| char::from(byte).to_digit(16))
+        .collect::<Option<Vec<_>>>()?;
+    // A missing last digit is zero.
+    if digits.len() % 2 == 1 {
+        digits.push(0);
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| (pair[0] * 16 + pair[1]) as u8)
+            .collect(),
+    )
+}
+
+/// Decodes a text string, which is either UTF-16BE with a byte order mark, UTF-8 with a byte
+/// order mark, or PDFDocEncoding, approximated by Windows-1252.
+fn decode_text_string(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(b"\xfe\xff") {
+        encoding_rs::UTF_16BE
+            .decode_without_bom_handling(utf16)
+            .0
+            .into_owned()
+    } else if let Some(utf8) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        String::from_utf8_lossy(utf8).into_owned()
+    } else {
+        encoding_rs::WINDOWS_1252
+            .decode_without_bom_handling(bytes)
+            .0
+            .into_owned()
+    }
+}
+
+/// Finds the number of pages in the linearization dictionary, or in the root of the page tree,
+/// which has the largest `/Count`.
+fn page_count(head: &[u8]) -> Option<u32> {
+    static LINEARIZED: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?s-u)<<[^>]*/Linearized[^>]*/N\s+(\d+)").unwrap());
+    static PAGES_COUNT: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?s-u)<<(?:[^>]*?/Type\s*/Pages\b[^>]*?/Count\s+(\d+)|[^>]*?/Count\s+(\d+)[^>]*?/Type\s*/Pages\b)").unwrap()
+    });
+    let number = |captures: regex::bytes::Captures| {
+        let digits = captures.iter().skip(1).flatten().next()?;
+        std::str::from_utf8(digits.as_bytes()).ok()?.parse().ok()
+    };
+    LINEARIZED
+        .captures(head)
+        .and_then(number)
+        .or_else(|| PAGES_COUNT.captures_iter(head).filter_map(number).max())
+}
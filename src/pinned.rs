@@ -0,0 +1,71 @@
+use deadpool_sqlite::Pool;
+use eyre::{Report, Result};
+use matrix_sdk::Room;
+use matrix_sdk::ruma::events::room::pinned_events::RoomPinnedEventsEventContent;
+use matrix_sdk::ruma::{EventId, OwnedEventId, RoomId};
+
+/// Records a preview pinned by `!preview pin`, along with the message it previews.
+pub async fn insert(
+    db: &Pool,
+    room_id: &RoomId,
+    original_event_id: &EventId,
+    response_id: &EventId,
+) -> Result<()> {
+    let stmt_insert = "INSERT OR REPLACE INTO pinned_previews (room_id, original_event_id, response_id) VALUES (?, ?, ?);";
+    let conn = db.get().await?;
+
+    let params = (
+        room_id.to_string(),
+        original_event_id.to_string(),
+        response_id.to_string(),
+    );
+    conn.interact(move |conn| {
+        conn.prepare_cached(stmt_insert)?.execute(params)?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Forgets a pinned preview. Returns whether it was pinned.
+pub async fn remove(db: &Pool, room_id: &RoomId, response_id: &EventId) -> Result<bool> {
+    let stmt_delete = "DELETE FROM pinned_previews WHERE room_id = ? AND response_id = ?;";
+    let conn = db.get().await?;
+
+    let params = (room_id.to_string(), response_id.to_string());
+    conn.interact(move |conn| {
+        Ok::<_, Report>(conn.prepare_cached(stmt_delete)?.execute(params)? != 0)
+    })
+    .await
+    .unwrap()
+}
+
+/// Adds the events to the pinned events of the room, keeping the ones already pinned.
+pub async fn pin(room: &Room, event_ids: &[OwnedEventId]) -> Result<()> {
+    let mut pinned = room.load_pinned_events().await?.unwrap_or_default();
+    let count = pinned.len();
+    for event_id in event_ids {
+        if !pinned.contains(event_id) {
+            pinned.push(event_id.clone());
+        }
+    }
+    if pinned.len() != count {
+        room.send_state_event(RoomPinnedEventsEventContent::new(pinned))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Removes the events from the pinned events of the room.
+pub async fn unpin(room: &Room, event_ids: &[OwnedEventId]) -> Result<()> {
+    let Some(mut pinned) = room.load_pinned_events().await? else {
+        return Ok(());
+    };
+    let count = pinned.len();
+    pinned.retain(|event_id| !event_ids.contains(event_id));
+    if pinned.len() != count {
+        room.send_state_event(RoomPinnedEventsEventContent::new(pinned))
+            .await?;
+    }
+    Ok(())
+}
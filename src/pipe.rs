@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use eyre::Result;
+#[cfg(feature = "extract")]
+use matrix_sdk::ruma::events::room::message::TextMessageEventContent;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+use url::Url;
+
+use crate::classify;
+use crate::config::StyleProfile;
+use crate::extract_url;
+use crate::oracle::PreviewData;
+use crate::redact;
+use crate::render::{self, RenderOptions};
+use crate::room_settings::RoomSettings;
+use crate::worker::Worker;
+
+/// The output of the `pipe` command.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    /// One JSON object per URL and line, with either `preview` or `error`.
+    Json,
+    /// The HTML body of the reply the bot would send.
+    Html,
+    /// The plain text body of the reply the bot would send.
+    Text,
+}
+
+/// A line of the JSON output, shaped like a `com.m13253.url_preview.response` without the
+/// request ID.
+#[derive(Serialize)]
+struct PipeResult {
+    /// The URL as found in the input, before any rewrite.
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview: Option<PreviewData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Previews the URLs in `input`, which is read like the plain text of a message, or as one URL per
+/// line without the `extract` feature, and writes the result to the standard output.
+///
+/// Unlike in a room, the number of URLs isn't limited, and images aren't shown within the HTML,
+/// as there's no media repository to upload them to.
+pub async fn run(worker: Arc<Worker>, input: &str, format: Format) -> Result<()> {
+    let config = worker.config();
+    #[cfg(feature = "extract")]
+    let links = extract_url::extract_urls_from_message(
+        &TextMessageEventContent::plain(input),
+        config.max_dom_nodes,
+    );
+    // Without finding URLs in text, each line is a URL.
+    #[cfg(not(feature = "extract"))]
+    let links = extract_url::MessageLinks {
+        urls: input
+            .lines()
+            .filter_map(|line| extract_url::validate_url(line.trim()))
+            .collect(),
+        ..Default::default()
+    };
+    let mut stdout = tokio::io::stdout();
+
+    if let Format::Json = format {
+        for url in links.urls {
+            let result = match worker.clone().preview_url(url.as_str()).await {
+                Ok(preview) => PipeResult {
+                    url: url.to_string(),
+                    preview: Some(PreviewData::from_opengraph(&preview)),
+                    error: None,
+                },
+                Err(reason) => PipeResult {
+                    url: url.to_string(),
+                    preview: None,
+                    error: Some(reason.to_owned()),
+                },
+            };
+            let mut line = serde_json::to_vec(&result)?;
+            line.push(b'\n');
+            // Written as each preview is ready, so consumers can stream them.
+            stdout.write_all(&line).await?;
+            stdout.flush().await?;
+        }
+        return Ok(());
+    }
+
+    let room_settings = RoomSettings::default();
+    let compact_mode = room_settings.compact_mode(config);
+    let preview_fields = room_settings.preview_fields(config);
+    let class_prefix = &config.css_class_prefix;
+    let failure_options = RenderOptions {
+        class_prefix,
+        preview_emoji: &config.preview_emoji,
+        warning_emoji: &config.warning_emoji,
+        backref: None,
+        clean_titles: config.clean_titles,
+        compact_mode,
+        preview_fields,
+        max_description_chars: room_settings.max_description_chars(config),
+        debug_footer: room_settings.debug_footer(config),
+        byline: config.show_byline,
+        citation: false,
+    };
+    let mut previews = Vec::new();
+    let mut available_count = 0;
+    for url in links.urls {
+        let is_mismatched = config.warn_mismatched_links && links.mismatched.contains(&url);
+        let sections = links.sections.get(&url).map_or(&[][..], Vec::as_slice);
+        let preview = match worker.clone().preview_url(url.as_str()).await {
+            Ok(preview) => preview,
+            Err(reason) => {
+                info!("No preview for {}: {}", redact::url(&url), reason);
+                previews.push(render::failure_block(
+                    &url,
+                    &config.error_text,
+                    &failure_options,
+                ));
+                continue;
+            }
+        };
+        let source_url = Url::parse(&preview.url).unwrap_or_else(|_| url.clone());
+        let content_class = classify::refine(classify::classify(&source_url), &preview.og_type);
+        let style = source_url
+            .host_str()
+            .and_then(|host| config.style_profile(host))
+            .or_else(|| config.class_styles.get(&content_class).copied());
+        let (compact_mode, preview_fields) = match style {
+            Some(profile) => profile.apply(compact_mode, preview_fields),
+            None => (compact_mode, preview_fields.to_vec()),
+        };
+        let render_options = RenderOptions {
+            compact_mode,
+            preview_fields: &preview_fields,
+            citation: style == Some(StyleProfile::Citation),
+            ..failure_options
+        };
+        previews.extend(render::section_blocks(
+            &preview,
+            url,
+            sections,
+            is_mismatched,
+            None,
+            None,
+            &render_options,
+        ));
+        available_count += 1;
+    }
+
+    if previews.is_empty() {
+        return Ok(());
+    }
+    let (text, html) = if available_count != 0 {
+        render::render_previews(&previews, class_prefix, &config.templates)
+    } else {
+        render::error_card(
+            class_prefix,
+            &config.warning_emoji,
+            &config.error_text,
+            None,
+        )
+    };
+    let mut output = match format {
+        Format::Html => html,
+        _ => text,
+    };
+    output.push('\n');
+    stdout.write_all(output.as_bytes()).await?;
+    stdout.flush().await?;
+    Ok(())
+}
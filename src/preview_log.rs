@@ -0,0 +1,122 @@
+use std::fmt::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use deadpool_sqlite::Pool;
+use deadpool_sqlite::rusqlite::OptionalExtension;
+use eyre::{Report, Result};
+use matrix_sdk::ruma::{EventId, RoomId};
+
+/// A transition in the lifecycle of a preview.
+#[derive(Clone, Copy, Debug)]
+pub enum State {
+    /// The message was not previewed, for example, because the room disabled previews.
+    Skipped,
+    /// The loading placeholder was sent.
+    Placeholder,
+    /// The preview was fetched and sent.
+    Fetched,
+    /// The preview couldn't be fetched or sent.
+    Failed,
+    /// The message was edited, so the preview is being refreshed.
+    Edited,
+    /// The message was deleted, so the preview is being deleted.
+    Redacted,
+    /// The placeholder was left behind by a previous run.
+    Expired,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            State::Skipped => "skipped",
+            State::Placeholder => "placeholder",
+            State::Fetched => "fetched",
+            State::Failed => "failed",
+            State::Edited => "edited",
+            State::Redacted => "redacted",
+            State::Expired => "expired",
+        })
+    }
+}
+
+/// Records a transition, and forgets the ones older than `retention`.
+pub async fn insert(
+    db: &Pool,
+    room_id: &RoomId,
+    event_id: &EventId,
+    response_id: Option<&EventId>,
+    state: State,
+    detail: &str,
+    retention: Duration,
+) -> Result<()> {
+    let stmt_insert = "INSERT INTO preview_log (room_id, event_id, response_id, state, detail, timestamp) VALUES (?, ?, ?, ?, ?, ?);";
+    let stmt_delete = "DELETE FROM preview_log WHERE timestamp < ?;";
+    let conn = db.get().await?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let expiry = now.saturating_sub(retention).as_secs() as i64;
+    let params = (
+        room_id.to_string(),
+        event_id.to_string(),
+        response_id.map(EventId::to_string),
+        state.to_string(),
+        detail.to_owned(),
+        now.as_secs() as i64,
+    );
+    conn.interact(move |conn| {
+        conn.prepare_cached(stmt_insert)?.execute(params)?;
+        conn.prepare_cached(stmt_delete)?.execute((expiry,))?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Renders the lifecycle of the preview of a message, given either the message or the preview.
+pub async fn report(db: &Pool, event_id: &EventId) -> Result<String> {
+    let stmt_resolve = "SELECT event_id FROM preview_log WHERE response_id = ? LIMIT 1;";
+    let stmt_query = "SELECT datetime(timestamp, 'unixepoch'), room_id, response_id, state, detail FROM preview_log
+WHERE event_id = ? ORDER BY id;";
+    let conn = db.get().await?;
+
+    let event_id_str = event_id.to_string();
+    let rows = conn
+        .interact(move |conn| {
+            let event_id_str = conn
+                .prepare_cached(stmt_resolve)?
+                .query_row((&event_id_str,), |row| row.get::<_, String>(0))
+                .optional()?
+                .unwrap_or(event_id_str);
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            let rows = stmt
+                .query_map((event_id_str,), |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Option<String>>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, Report>(rows)
+        })
+        .await
+        .unwrap()?;
+
+    if rows.is_empty() {
+        return Ok(format!("No preview log for {event_id}."));
+    }
+    let mut report = String::new();
+    for (timestamp, room_id, response_id, state, detail) in rows {
+        _ = write!(report, "{timestamp} UTC {room_id} {state:<11}");
+        if let Some(response_id) = response_id {
+            _ = write!(report, " {response_id}");
+        }
+        if !detail.is_empty() {
+            _ = write!(report, " {detail}");
+        }
+        report.push('\n');
+    }
+    Ok(report)
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eyre::Result;
+use matrix_sdk::Room;
+use matrix_sdk::room::Receipts;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
+use tracing::{debug, warn};
+
+/// Advances the read receipt and the fully-read marker of each room to the latest processed
+/// message, so the bot's account doesn't accumulate unbounded unread counts.
+///
+/// Receipts are sent periodically instead of once per message to save requests in busy rooms.
+pub struct ReceiptTracker {
+    interval: Duration,
+    pending: Mutex<HashMap<OwnedRoomId, (Room, OwnedEventId)>>,
+}
+
+impl ReceiptTracker {
+    /// Disabled if `interval` is zero.
+    pub fn new(interval: Duration) -> Arc<ReceiptTracker> {
+        Arc::new(ReceiptTracker {
+            interval,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    pub fn mark_processed(&self, room: &Room, event_id: OwnedEventId) {
+        if !self.enabled() {
+            return;
+        }
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(room.room_id().to_owned(), (room.clone(), event_id));
+    }
+
+    /// Sends the pending receipts every `interval`, until cancelled.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Sends the pending receipts now, such as on shutdown.
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (room, event_id) in pending.into_values() {
+            let receipts = Receipts::new()
+                .fully_read_marker(event_id.clone())
+                .public_read_receipt(event_id.clone());
+            match room.send_multiple_receipts(receipts).await {
+                Ok(()) => debug!("Marked {} as read in room {}.", event_id, room.room_id()),
+                Err(err) => warn!(
+                    "Failed to send read receipt to room {}: {}",
+                    room.room_id(),
+                    err
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use sha2::{Digest, Sha256};
+use url::Url;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static IN_PRIVATE_ROOM: bool;
+}
+
+/// Enables or disables redaction, according to `redact_logs` in the configuration.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+        || IN_PRIVATE_ROOM
+            .try_with(|&private| private)
+            .unwrap_or(false)
+}
+
+/// Runs `future` with redaction enabled if `private` is true, regardless of `redact_logs`.
+///
+/// Tasks spawned by `future` are not covered.
+pub async fn scope<F: Future>(private: bool, future: F) -> F::Output {
+    IN_PRIVATE_ROOM.scope(private, future).await
+}
+
+/// Hashes a URL to be stored in place of itself.
+///
+/// The hash still tells whether two URLs are the same, without revealing either of them.
+pub fn hash(url_str: &str) -> String {
+    format!("sha256:{:x}", Sha256::digest(url_str))
+}
+
+/// Formats a URL for logs.
+///
+/// If enabled, the user info, the query string, and the fragment are removed, as they may contain
+/// tokens or personal information.
+pub fn url(url: &Url) -> Cow<'_, str> {
+    if !is_enabled() {
+        return Cow::Borrowed(url.as_str());
+    }
+    let mut url = url.clone();
+    _ = url.set_username("");
+    _ = url.set_password(None);
+    if url.query().is_some() {
+        url.set_query(Some("REDACTED"));
+    }
+    url.set_fragment(None);
+    Cow::Owned(url.into())
+}
+
+/// Like [`url`], but for URLs that haven't been parsed yet.
+pub fn url_str(url_str: &str) -> Cow<'_, str> {
+    if !is_enabled() {
+        return Cow::Borrowed(url_str);
+    }
+    match Url::parse(url_str) {
+        Ok(parsed) => Cow::Owned(url(&parsed).into_owned()),
+        Err(_) => Cow::Borrowed("(invalid URL)"),
+    }
+}
+
+/// Formats free text that may contain URLs, such as a message body, for logs.
+///
+/// If enabled, only the length is kept.
+#[cfg(feature = "extract")]
+pub fn text(text: &str) -> Cow<'_, str> {
+    if is_enabled() {
+        Cow::Owned(format!("({} bytes)", text.len()))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Removes the URL from a [`reqwest::Error`] if enabled.
+pub fn reqwest_error(err: reqwest::Error) -> reqwest::Error {
+    if is_enabled() { err.without_url() } else { err }
+}
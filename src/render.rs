@@ -0,0 +1,1028 @@
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+use matrix_sdk::ruma::{MxcUri, OwnedMxcUri};
+use regex::Regex;
+use tracing::warn;
+use url::Url;
+
+use crate::common::{
+    FAVICON_SIZE, MAX_CITATION_CHARS, MAX_INLINE_IMAGE_SIZE, MAX_PREVIEW_CONTENT_BYTES,
+    MAX_PRICE_CHARS, MAX_RESPONSE_TEXT_CHARS, MAX_SMALL_INLINE_IMAGE_SIZE,
+};
+use crate::config::PreviewField;
+use crate::opengraph::{FeedEntry, OpenGraph, Product};
+use crate::template::{ImageContext, LatestEntryContext, PreviewContext, Templates};
+use crate::{citation, extract_url, html_escape, limit, title};
+
+/// The preview of a single URL, before it's combined into the reply.
+pub struct PreviewBlock {
+    /// Everything but the description, in plain text.
+    pub head_text: String,
+    /// Everything but the description, in HTML, within an unclosed `<blockquote>`.
+    pub head_html: String,
+    pub description: String,
+    /// Whether the description is the beginning of a text file, shown preformatted.
+    pub is_snippet: bool,
+    /// The `lang` and `dir` attributes of the title and the description, such as
+    /// ` lang="he" dir="rtl"`.
+    pub text_attributes: String,
+    /// What the templates show, without the description, or `None` for a URL without a preview.
+    pub context: Option<PreviewContext>,
+}
+
+/// How to lay out a preview, following the type of its Twitter Card.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardLayout {
+    /// `summary`: A small thumbnail.
+    Summary,
+    /// `summary_large_image`, or no card: A large image.
+    LargeImage,
+    /// `player`: A large image, and a link to play the media.
+    Player,
+}
+
+impl CardLayout {
+    pub fn of(preview: &OpenGraph) -> CardLayout {
+        match preview
+            .twitter
+            .as_ref()
+            .map(|twitter| twitter.card.as_str())
+        {
+            Some("summary") => CardLayout::Summary,
+            Some("player") => CardLayout::Player,
+            _ => CardLayout::LargeImage,
+        }
+    }
+
+    /// The largest width or height of the image within the preview, in pixels.
+    pub fn max_image_size(self) -> u32 {
+        match self {
+            CardLayout::Summary => MAX_SMALL_INLINE_IMAGE_SIZE,
+            CardLayout::LargeImage | CardLayout::Player => MAX_INLINE_IMAGE_SIZE,
+        }
+    }
+}
+
+/// An image uploaded to the media repository, shown within the preview.
+pub struct InlineImage {
+    pub url: OwnedMxcUri,
+    /// The displayed size, scaled down to the `max_image_size` of the layout.
+    pub width: u32,
+    pub height: u32,
+}
+
+/// How to render a preview, from the config, the room settings, and the style profile.
+pub struct RenderOptions<'a> {
+    pub class_prefix: &'a str,
+    pub preview_emoji: &'a str,
+    pub warning_emoji: &'a str,
+    /// The link to the original message, if the emoji should link back to it.
+    pub backref: Option<&'a str>,
+    pub clean_titles: bool,
+    pub compact_mode: bool,
+    pub preview_fields: &'a [PreviewField],
+    pub max_description_chars: usize,
+    /// Whether to show how the page was fetched, if known.
+    pub debug_footer: bool,
+    /// Whether to show the authors and the publication date of articles under the title.
+    pub byline: bool,
+    /// Whether scholarly articles get a reference instead of their description.
+    pub citation: bool,
+}
+
+/// Renders the preview of `url`, keeping each field's length limited.
+///
+/// `is_mismatched` adds a warning that the link text doesn't match the destination.
+pub fn preview_block(
+    preview: &OpenGraph,
+    url: Url,
+    is_mismatched: bool,
+    image: Option<&InlineImage>,
+    icon: Option<&MxcUri>,
+    options: &RenderOptions,
+) -> PreviewBlock {
+    let class_prefix = options.class_prefix;
+    // Pages choose their own `og:url`, which may be `javascript:` or `data:`.
+    let canonical_url = extract_url::validate_url(&preview.url).unwrap_or(url);
+    let title = if options.preview_fields.contains(&PreviewField::Title) {
+        let title = if options.clean_titles {
+            Cow::Owned(title::clean(&preview.title))
+        } else {
+            Cow::Borrowed(preview.title.as_str())
+        };
+        limit::length_in_chars(collapse_whitespace(&title), MAX_RESPONSE_TEXT_CHARS)
+    } else {
+        // Something has to be clickable.
+        limit::length_in_chars(canonical_url.to_string(), MAX_RESPONSE_TEXT_CHARS)
+    };
+    let site_name = if options.preview_fields.contains(&PreviewField::SiteName) {
+        limit::length_in_chars(
+            collapse_whitespace(&preview.site_name),
+            MAX_RESPONSE_TEXT_CHARS,
+        )
+    } else {
+        String::new()
+    };
+    let citation = preview
+        .citation
+        .as_ref()
+        .filter(|_| options.citation)
+        .and_then(citation::format);
+    let is_snippet = citation.is_none()
+        && !options.compact_mode
+        && options.preview_fields.contains(&PreviewField::Description)
+        && !preview.snippet.is_empty();
+    let description = if let Some(citation) = citation {
+        // Not shortened at a word boundary, as a partial reference is useless.
+        limit::length_in_chars(collapse_whitespace(&citation), MAX_CITATION_CHARS)
+    } else if is_snippet {
+        // Already limited in lines when fetched, and whitespace matters in code.
+        preview.snippet.clone()
+    } else if options.compact_mode || !options.preview_fields.contains(&PreviewField::Description) {
+        String::new()
+    } else {
+        limit::length_in_chars_at_boundary(
+            collapse_whitespace(&preview.description),
+            options.max_description_chars,
+        )
+    };
+
+    let (language, direction) = text_direction(preview);
+    let text_attributes = text_attributes(&language, direction);
+    let creator = preview
+        .twitter
+        .as_ref()
+        .filter(|_| options.preview_fields.contains(&PreviewField::Author))
+        .map(|twitter| {
+            limit::length_in_chars(
+                collapse_whitespace(&twitter.creator),
+                MAX_RESPONSE_TEXT_CHARS,
+            )
+        })
+        .unwrap_or_default();
+    let duration = preview
+        .duration
+        .filter(|_| options.preview_fields.contains(&PreviewField::Duration))
+        .map(format_duration);
+    let price = preview
+        .product
+        .as_ref()
+        .filter(|_| options.preview_fields.contains(&PreviewField::Price))
+        .map(price)
+        .unwrap_or_default();
+    let byline = if options.byline && !options.compact_mode {
+        byline(preview, options.preview_fields)
+    } else {
+        String::new()
+    };
+    let latest_entry = preview
+        .latest_entry
+        .as_ref()
+        .filter(|_| !options.compact_mode)
+        .and_then(|entry| latest_entry(entry, options.preview_fields));
+    let player_url = (CardLayout::of(preview) == CardLayout::Player)
+        .then(|| {
+            preview
+                .twitter
+                .as_ref()
+                .and_then(|twitter| extract_url::validate_url(&twitter.player))
+        })
+        .flatten();
+    let footer = preview
+        .fetch
+        .as_ref()
+        .filter(|_| options.debug_footer)
+        .map(|fetch| {
+            format!(
+                "HTTP {} \u{b7} {} \u{b7} {} ms",
+                fetch.status,
+                limit::length_in_chars(
+                    collapse_whitespace(&fetch.content_type),
+                    MAX_RESPONSE_TEXT_CHARS
+                ),
+                fetch.duration_ms
+            )
+        });
+
+    let (mut head_text, mut head_html) = if title.is_empty() {
+        let head_html = format!(
+            "<blockquote><div class=\"{class_prefix}-headline\">{} <em><a class=\"{class_prefix}-empty-title\" href=\"{}\">No title</a></em>",
+            headline_emoji(class_prefix, options.warning_emoji, options.backref),
+            html_escape::attr(canonical_url.as_str())
+        );
+        (format!("{} (No title)", options.warning_emoji), head_html)
+    } else {
+        let head_html = format!(
+            "<blockquote><div class=\"{class_prefix}-headline\">{} <strong><a class=\"{class_prefix}-title\" href=\"{}\"{text_attributes}>{}</a></strong>",
+            headline_emoji(class_prefix, options.preview_emoji, options.backref),
+            html_escape::attr(canonical_url.as_str()),
+            html_escape::text(&title)
+        );
+        (format!("{} {title}", options.preview_emoji), head_html)
+    };
+    if !site_name.is_empty() {
+        head_text.push_str(" \u{2013} ");
+        head_text.push_str(&site_name);
+        head_html.push_str(" \u{2013} ");
+        if let Some(icon) = icon {
+            head_html.push_str(&format!(
+                "<img class=\"{class_prefix}-icon\" src=\"{}\" width=\"{FAVICON_SIZE}\" height=\"{FAVICON_SIZE}\" alt=\"\"> ",
+                html_escape::attr(icon.as_str())
+            ));
+        }
+        head_html.push_str(&format!("<span class=\"{class_prefix}-site-name\">"));
+        head_html.push_str(&html_escape::text(&site_name));
+        head_html.push_str("</span>");
+    }
+    if !creator.is_empty() {
+        head_text.push_str(" \u{b7} ");
+        head_text.push_str(&creator);
+        head_html.push_str(&format!(" \u{b7} <span class=\"{class_prefix}-creator\">"));
+        head_html.push_str(&html_escape::text(&creator));
+        head_html.push_str("</span>");
+    }
+    if let Some(duration) = &duration {
+        head_text.push_str(&format!(" \u{b7} {duration}"));
+        head_html.push_str(&format!(
+            " \u{b7} <span class=\"{class_prefix}-duration\">{duration}</span>"
+        ));
+    }
+    if !price.is_empty() {
+        head_text.push_str(&format!(" \u{b7} {price}"));
+        head_html.push_str(&format!(
+            " \u{b7} <span class=\"{class_prefix}-price\">{}</span>",
+            html_escape::text(&price)
+        ));
+    }
+    head_html.push_str("</div>");
+    if !byline.is_empty() {
+        head_text.push_str(&format!("\n{byline}"));
+        head_html.push_str(&format!(
+            "<div class=\"{class_prefix}-byline\"><sub>{}</sub></div>",
+            html_escape::text(&byline)
+        ));
+    }
+    if let Some(entry) = &latest_entry {
+        head_text.push_str(&format!("\nLatest: {}", entry.title));
+        head_html.push_str(&format!("<div class=\"{class_prefix}-latest\">Latest: "));
+        match &entry.url {
+            Some(url) => head_html.push_str(&format!(
+                "<a href=\"{}\">{}</a>",
+                html_escape::attr(url),
+                html_escape::text(&entry.title)
+            )),
+            None => head_html.push_str(&html_escape::text(&entry.title)),
+        }
+        if let Some(date) = &entry.date {
+            head_text.push_str(&format!(" \u{b7} {date}"));
+            head_html.push_str(&format!(" \u{b7} {date}"));
+        }
+        head_html.push_str("</div>");
+    }
+    if let Some(image) = image {
+        head_html.push_str(&format!(
+            "<div class=\"{class_prefix}-image\"><img src=\"{}\" width=\"{}\" height=\"{}\" alt=\"{}\"></div>",
+            html_escape::attr(image.url.as_str()),
+            image.width,
+            image.height,
+            html_escape::attr(&title)
+        ));
+    }
+    if let Some(player_url) = &player_url {
+        head_text.push_str(&format!("\n\u{25b6} Play: {player_url}"));
+        head_html.push_str(&format!(
+            "<div class=\"{class_prefix}-player\">\u{25b6} <a href=\"{}\">Play</a></div>",
+            html_escape::attr(player_url.as_str())
+        ));
+    }
+    if is_mismatched {
+        let warning_emoji = options.warning_emoji;
+        head_text.push_str(&format!(
+            "\n{warning_emoji} Link text doesn't match destination"
+        ));
+        head_html.push_str(&format!(
+            "<div class=\"{class_prefix}-warning\">{} <em>Link text doesn't match destination</em></div>",
+            html_escape::text(warning_emoji)
+        ));
+    }
+    if let Some(footer) = &footer {
+        head_text.push_str(&format!("\n{footer}"));
+        head_html.push_str(&format!(
+            "<div class=\"{class_prefix}-debug\"><sub>{}</sub></div>",
+            html_escape::text(footer)
+        ));
+    }
+
+    let context = PreviewContext {
+        class_prefix: class_prefix.to_owned(),
+        emoji: if title.is_empty() {
+            options.warning_emoji
+        } else {
+            options.preview_emoji
+        }
+        .to_owned(),
+        warning_emoji: options.warning_emoji.to_owned(),
+        backref: options.backref.map(str::to_owned),
+        url: canonical_url.to_string(),
+        title,
+        site_name,
+        icon: icon
+            .filter(|_| options.preview_fields.contains(&PreviewField::SiteName))
+            .map(|icon| icon.to_string()),
+        creator,
+        duration,
+        price,
+        byline,
+        latest_entry,
+        image: image.map(|image| ImageContext {
+            url: image.url.to_string(),
+            width: image.width,
+            height: image.height,
+        }),
+        player_url: player_url.map(String::from),
+        is_mismatched,
+        debug: footer,
+        language,
+        direction: direction.to_owned(),
+        og_type: preview.og_type.clone(),
+        description: String::new(),
+        is_snippet,
+    };
+    PreviewBlock {
+        head_text,
+        head_html,
+        description,
+        is_snippet,
+        text_attributes,
+        context: Some(context),
+    }
+}
+
+/// Returns the language tag of a page if valid, and its direction, which is `rtl`, `ltr`, or
+/// empty if it's neither stated nor implied by the language.
+fn text_direction(preview: &OpenGraph) -> (String, &'static str) {
+    let mut language = preview.language.trim().replace('_', "-");
+    if !language
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+    {
+        language.clear();
+    }
+    let direction = if preview.is_right_to_left() {
+        "rtl"
+    } else if !preview.direction.is_empty() {
+        "ltr"
+    } else {
+        ""
+    };
+    (language, direction)
+}
+
+/// Returns the `lang` and `dir` attributes of the text of a page, so clients lay out right-to-left
+/// text, such as Hebrew or Arabic, correctly.
+fn text_attributes(language: &str, direction: &str) -> String {
+    let mut attributes = String::new();
+    if !language.is_empty() {
+        attributes.push_str(&format!(" lang=\"{language}\""));
+    }
+    if !direction.is_empty() {
+        attributes.push_str(&format!(" dir=\"{direction}\""));
+    }
+    attributes
+}
+
+/// Returns the latest entry of a feed, shown as `Latest: Release 1.2 · 2024-05-01`.
+fn latest_entry(entry: &FeedEntry, preview_fields: &[PreviewField]) -> Option<LatestEntryContext> {
+    let title = limit::length_in_chars(collapse_whitespace(&entry.title), MAX_RESPONSE_TEXT_CHARS);
+    if title.is_empty() {
+        return None;
+    }
+    let url = extract_url::validate_url(&entry.url).map(String::from);
+    let date = preview_fields
+        .contains(&PreviewField::Date)
+        .then(|| format_date(&entry.date))
+        .flatten()
+        .map(str::to_owned);
+    Some(LatestEntryContext { title, url, date })
+}
+
+/// Formats the authors and the publication date of an article, such as `by Jane Doe • 2024-05-01`.
+///
+/// Authors given as the URLs of their profiles are left out.
+fn byline(preview: &OpenGraph, preview_fields: &[PreviewField]) -> String {
+    let Some(article) = &preview.article else {
+        return String::new();
+    };
+    let mut parts = Vec::new();
+    if preview_fields.contains(&PreviewField::Author) {
+        let authors = article
+            .authors
+            .iter()
+            .map(|author| collapse_whitespace(author))
+            .filter(|author| {
+                !author.is_empty()
+                    && !author.starts_with("http://")
+                    && !author.starts_with("https://")
+            })
+            .collect::<Vec<_>>();
+        if !authors.is_empty() {
+            parts.push(format!(
+                "by {}",
+                limit::length_in_chars(authors.join(", "), MAX_RESPONSE_TEXT_CHARS)
+            ));
+        }
+    }
+    if preview_fields.contains(&PreviewField::Date)
+        && let Some(date) = format_date(&article.published_time)
+    {
+        parts.push(date.to_owned());
+    }
+    parts.join(" \u{2022} ")
+}
+
+/// Formats the price and the availability of a product, such as `€49.99 – In stock`.
+fn price(product: &Product) -> String {
+    let mut parts = Vec::new();
+    let amount = product.price_amount.trim();
+    if !amount.is_empty()
+        && amount.len() <= MAX_PRICE_CHARS
+        && amount
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || byte == b'.' || byte == b',')
+    {
+        let currency = product.price_currency.trim().to_ascii_uppercase();
+        parts.push(match currency.as_str() {
+            "EUR" => format!("\u{20ac}{amount}"),
+            "USD" => format!("${amount}"),
+            "GBP" => format!("\u{a3}{amount}"),
+            "JPY" | "CNY" => format!("\u{a5}{amount}"),
+            currency
+                if currency.len() == 3
+                    && currency.bytes().all(|byte| byte.is_ascii_uppercase()) =>
+            {
+                format!("{amount} {currency}")
+            }
+            _ => amount.to_owned(),
+        });
+    }
+    if let Some(availability) = availability(&product.availability) {
+        parts.push(availability.to_owned());
+    }
+    parts.join(" \u{2013} ")
+}
+
+/// Describes the availability of a product, given either as in Open Graph, such as `instock` or
+/// `out of stock`, or as in schema.org, such as `https://schema.org/InStock`.
+fn availability(availability: &str) -> Option<&'static str> {
+    let name = availability
+        .trim()
+        .rsplit('/')
+        .next()?
+        .to_ascii_lowercase()
+        .replace([' ', '_', '-'], "");
+    Some(match name.as_str() {
+        "instock" => "In stock",
+        "oos" | "outofstock" => "Out of stock",
+        "soldout" => "Sold out",
+        "limitedavailability" => "Limited availability",
+        "preorder" => "Pre-order",
+        "presale" => "Pre-sale",
+        "backorder" | "availablefororder" => "Available to order",
+        "instoreonly" => "In store only",
+        "onlineonly" => "Online only",
+        "discontinued" => "Discontinued",
+        _ => return None,
+    })
+}
+
+/// Returns the date of an ISO 8601 timestamp, such as `2024-05-01T09:30:00+00:00`.
+fn format_date(timestamp: &str) -> Option<&str> {
+    let date = timestamp.trim().get(..10)?;
+    let is_date = date.char_indices().all(|(i, c)| match i {
+        4 | 7 => c == '-',
+        _ => c.is_ascii_digit(),
+    });
+    is_date.then_some(date)
+}
+
+/// Renders an entry for each section of the page that `sections`, the `#fragment` parts of the
+/// links to it, point to, with the heading of the section after the title. Only the first entry
+/// shows the image and the description, as the others are the same page.
+///
+/// Renders the page itself if no section is found, such as when the fragments don't match the
+/// IDs of headings.
+pub fn section_blocks(
+    preview: &OpenGraph,
+    url: Url,
+    sections: &[String],
+    is_mismatched: bool,
+    image: Option<&InlineImage>,
+    icon: Option<&MxcUri>,
+    options: &RenderOptions,
+) -> Vec<PreviewBlock> {
+    let sections = sections
+        .iter()
+        .filter_map(|fragment| Some((fragment.as_str(), preview.find_section(fragment)?)))
+        .collect::<Vec<_>>();
+    if sections.is_empty() {
+        return vec![preview_block(
+            preview,
+            url,
+            is_mismatched,
+            image,
+            icon,
+            options,
+        )];
+    }
+    let page_url = extract_url::validate_url(&preview.url).unwrap_or(url);
+    // Cleaned first, as the heading takes the place of the site name that cleaning removes.
+    let page_title = if options.clean_titles {
+        title::clean(&preview.title)
+    } else {
+        preview.title.clone()
+    };
+    sections
+        .into_iter()
+        .enumerate()
+        .map(|(index, (fragment, heading))| {
+            let mut section_url = page_url.clone();
+            section_url.set_fragment(Some(fragment));
+            let section = OpenGraph {
+                title: if page_title.is_empty() {
+                    heading.to_owned()
+                } else {
+                    format!("{page_title} \u{a7} {heading}")
+                },
+                // Links to the section instead of the page.
+                url: String::new(),
+                ..preview.clone()
+            };
+            let options = RenderOptions {
+                compact_mode: options.compact_mode || index != 0,
+                ..*options
+            };
+            preview_block(
+                &section,
+                section_url,
+                is_mismatched,
+                image.filter(|_| index == 0),
+                icon,
+                &options,
+            )
+        })
+        .collect()
+}
+
+/// Renders a URL that has no preview, among others that have one.
+pub fn failure_block(url: &Url, error_text: &str, options: &RenderOptions) -> PreviewBlock {
+    let class_prefix = options.class_prefix;
+    let url_text = limit::length_in_chars(url.to_string(), MAX_RESPONSE_TEXT_CHARS);
+    PreviewBlock {
+        head_text: format!("{} {url_text} ({error_text})", options.warning_emoji),
+        head_html: format!(
+            "<blockquote><div class=\"{class_prefix}-headline\">{} <a class=\"{class_prefix}-failed\" href=\"{}\">{}</a> <span class=\"{class_prefix}-error\"><em>{}</em></span></div>",
+            headline_emoji(class_prefix, options.warning_emoji, options.backref),
+            html_escape::attr(url.as_str()),
+            html_escape::text(&url_text),
+            html_escape::text(error_text)
+        ),
+        description: String::new(),
+        is_snippet: false,
+        text_attributes: String::new(),
+        context: None,
+    }
+}
+
+/// Renders the reply when there's no preview at all, as the plain text and HTML.
+pub fn error_card(
+    class_prefix: &str,
+    warning_emoji: &str,
+    error_text: &str,
+    backref: Option<&str>,
+) -> (String, String) {
+    (
+        format!("{warning_emoji} ({error_text})"),
+        format!(
+            "<blockquote><div class=\"{class_prefix}-headline\">{} <span class=\"{class_prefix}-error\"><em>{}</em></span></div></blockquote>",
+            headline_emoji(class_prefix, warning_emoji, backref),
+            html_escape::text(error_text)
+        ),
+    )
+}
+
+/// Combines the previews into the plain text and HTML of the reply, with the templates if set.
+pub fn render_previews(
+    previews: &[PreviewBlock],
+    class_prefix: &str,
+    templates: &Templates,
+) -> (String, String) {
+    let mut reply_text = String::new();
+    let mut reply_html = String::new();
+    for preview in previews {
+        if !reply_text.is_empty() {
+            reply_text.push_str("\n\n");
+        }
+        // The description is added last, as it may have been shortened to fit the event.
+        let context = preview.context.clone().map(|context| PreviewContext {
+            description: preview.description.clone(),
+            ..context
+        });
+        match context
+            .as_ref()
+            .and_then(|context| templates.render_text(context))
+        {
+            Some(text) => reply_text.push_str(&text),
+            None => render_text(&mut reply_text, preview),
+        }
+        match context
+            .as_ref()
+            .and_then(|context| templates.render_html(context))
+        {
+            Some(html) => reply_html.push_str(&html),
+            None => render_html(&mut reply_html, preview, class_prefix),
+        }
+    }
+    (reply_text, reply_html)
+}
+
+/// Renders a preview in the built-in plain text layout.
+fn render_text(reply_text: &mut String, preview: &PreviewBlock) {
+    reply_text.push_str(&preview.head_text);
+    if !preview.description.is_empty() {
+        let lines = if preview.is_snippet {
+            preview.description.lines().collect()
+        } else {
+            vec![preview.description.as_str()]
+        };
+        for line in lines {
+            reply_text.push_str("\n> ");
+            reply_text.push_str(line);
+        }
+    }
+}
+
+/// Renders a preview in the built-in HTML layout.
+fn render_html(reply_html: &mut String, preview: &PreviewBlock, class_prefix: &str) {
+    reply_html.push_str(&preview.head_html);
+    if preview.is_snippet && !preview.description.is_empty() {
+        reply_html.push_str(&format!(
+            "<pre class=\"{class_prefix}-snippet\"><code>{}</code></pre>",
+            html_escape::text(&preview.description)
+        ));
+    } else if !preview.description.is_empty() {
+        reply_html.push_str(&format!(
+            "<div class=\"{class_prefix}-description\"{}>",
+            preview.text_attributes
+        ));
+        reply_html.push_str(&html_escape::text(&preview.description));
+        reply_html.push_str("</div>");
+    }
+    reply_html.push_str("</blockquote>");
+}
+
+/// Keeps the reply safely under the event size limit, first by dropping trailing previews,
+/// then by shortening the description of the remaining one.
+pub fn fit_event_size(previews: &mut Vec<PreviewBlock>, class_prefix: &str, templates: &Templates) {
+    let content_size = |previews: &[PreviewBlock]| {
+        let (reply_text, reply_html) = render_previews(previews, class_prefix, templates);
+        content_size(&reply_text, &reply_html)
+    };
+    while previews.len() > 1 && content_size(previews) > MAX_PREVIEW_CONTENT_BYTES {
+        warn!("Preview is too large, dropping the last URL.");
+        previews.pop();
+    }
+    while content_size(previews) > MAX_PREVIEW_CONTENT_BYTES {
+        let Some(preview) = previews
+            .last_mut()
+            .filter(|preview| !preview.description.is_empty())
+        else {
+            break;
+        };
+        warn!("Preview is too large, shortening the description.");
+        let chars = preview.description.chars().count();
+        if chars <= 1 {
+            preview.description.clear();
+        } else {
+            preview.description =
+                limit::length_in_chars(std::mem::take(&mut preview.description), chars / 2);
+        }
+    }
+}
+
+/// The size of the edit replacing a preview with `reply_text` and `reply_html`, which must stay
+/// below [`MAX_PREVIEW_CONTENT_BYTES`].
+pub fn content_size(reply_text: &str, reply_html: &str) -> usize {
+    let escaped_len = |s: &str| serde_json::to_string(s).map_or(usize::MAX, |s| s.len());
+    // The edit carries both the fallback and `m.new_content`, so everything appears twice.
+    2 * (escaped_len(reply_text) + escaped_len(reply_html))
+}
+
+/// Renders the emoji leading the headline, which links back to the original message if
+/// `backref` is set.
+pub fn headline_emoji(class_prefix: &str, emoji: &str, backref: Option<&str>) -> String {
+    let Some(original_event_link) = backref else {
+        return html_escape::text(emoji);
+    };
+    format!(
+        "<a class=\"{}-backref\" href=\"{}\">{}</a>",
+        class_prefix,
+        html_escape::attr(original_event_link),
+        html_escape::text(emoji)
+    )
+}
+
+/// Formats seconds as `H:MM:SS`, or `M:SS` if shorter than an hour.
+fn format_duration(seconds: u32) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours == 0 {
+        format!("{minutes}:{seconds:02}")
+    } else {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    }
+}
+
+pub fn collapse_whitespace(s: &str) -> String {
+    // https://developer.mozilla.org/en-US/docs/Glossary/Whitespace
+    static CONSECUTIVE_WHITESPACES: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new("[\t\n\x0c\r ]+").unwrap());
+    CONSECUTIVE_WHITESPACES
+        .replace_all(s, " ")
+        .trim()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opengraph::{Article, Citation, TwitterCard};
+
+    use super::*;
+
+    const CLASS_PREFIX: &str = "org.example";
+
+    fn options(preview_fields: &[PreviewField]) -> RenderOptions<'_> {
+        RenderOptions {
+            class_prefix: CLASS_PREFIX,
+            preview_emoji: "\u{1f517}",
+            warning_emoji: "\u{26a0}\u{fe0f}",
+            backref: None,
+            clean_titles: true,
+            compact_mode: false,
+            preview_fields,
+            max_description_chars: 200,
+            debug_footer: false,
+            byline: true,
+            citation: true,
+        }
+    }
+
+    fn page() -> OpenGraph {
+        OpenGraph {
+            og_type: "article".to_owned(),
+            title: "Announcing Rust 1.80.0 | Rust Blog".to_owned(),
+            description: "The Rust team is happy to announce a new version of Rust,\n\n  1.80.0."
+                .to_owned(),
+            site_name: "Rust Blog".to_owned(),
+            url: "https://blog.rust-lang.org/2024/07/25/Rust-1.80.0.html".to_owned(),
+            language: "en".to_owned(),
+            article: Some(Article {
+                published_time: "2024-07-25T00:00:00+00:00".to_owned(),
+                authors: vec!["The Rust Release Team".to_owned()],
+            }),
+            twitter: Some(TwitterCard {
+                creator: "@rustlang".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn url() -> Url {
+        Url::parse("https://blog.rust-lang.org/2024/07/25/Rust-1.80.0.html").unwrap()
+    }
+
+    fn render(previews: &[PreviewBlock]) -> String {
+        let (text, html) = render_previews(previews, CLASS_PREFIX, &Templates::default());
+        format!("{text}\n\n{html}")
+    }
+
+    fn render_page(preview: &OpenGraph, options: &RenderOptions) -> String {
+        render(&[preview_block(preview, url(), false, None, None, options)])
+    }
+
+    #[test]
+    fn full_preview() {
+        let fields = PreviewField::all();
+        insta::assert_snapshot!(render_page(&page(), &options(&fields)));
+    }
+
+    #[test]
+    fn no_title() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            title: String::new(),
+            ..page()
+        };
+        insta::assert_snapshot!(render_page(&preview, &options(&fields)));
+    }
+
+    #[test]
+    fn long_title_and_description() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            title: "A very long title ".repeat(20),
+            description: "A sentence that goes on and on. ".repeat(20),
+            ..page()
+        };
+        insta::assert_snapshot!(render_page(&preview, &options(&fields)));
+    }
+
+    #[test]
+    fn right_to_left() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            title: "\u{5e9}\u{5dc}\u{5d5}\u{5dd} \u{5e2}\u{5d5}\u{5dc}\u{5dd}".to_owned(),
+            description: "\u{5d6}\u{5d4}\u{5d5} \u{5ea}\u{5d9}\u{5d0}\u{5d5}\u{5e8}.".to_owned(),
+            language: "he_IL".to_owned(),
+            ..page()
+        };
+        insta::assert_snapshot!(render_page(&preview, &options(&fields)));
+    }
+
+    #[test]
+    fn entities() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            title: "<script>alert(\"hi\")</script> & 'friends'".to_owned(),
+            description: "Tom &amp; Jerry <b>bold</b> \u{1}".to_owned(),
+            site_name: "A \"quoted\" <site>".to_owned(),
+            url: "https://example.com/?a=1&b=\"2\"".to_owned(),
+            ..page()
+        };
+        insta::assert_snapshot!(render_page(&preview, &options(&fields)));
+    }
+
+    #[test]
+    fn missing_description() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            description: String::new(),
+            ..page()
+        };
+        insta::assert_snapshot!(render_page(&preview, &options(&fields)));
+    }
+
+    #[test]
+    fn compact_mode() {
+        let fields = PreviewField::all();
+        let options = RenderOptions {
+            compact_mode: true,
+            ..options(&fields)
+        };
+        insta::assert_snapshot!(render_page(&page(), &options));
+    }
+
+    #[test]
+    fn citation() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            citation: Some(Citation {
+                title: "Attention Is All You Need".to_owned(),
+                authors: vec!["Vaswani, Ashish".to_owned(), "Shazeer, Noam".to_owned()],
+                date: "2017/06/12".to_owned(),
+                arxiv_id: "1706.03762".to_owned(),
+                ..Default::default()
+            }),
+            ..page()
+        };
+        insta::assert_snapshot!(render_page(&preview, &options(&fields)));
+    }
+
+    #[test]
+    fn backref() {
+        let fields = PreviewField::all();
+        let options = RenderOptions {
+            backref: Some("https://matrix.to/#/!room:example.org/$event?via=example.org"),
+            ..options(&fields)
+        };
+        insta::assert_snapshot!(render_page(&page(), &options));
+    }
+
+    #[test]
+    fn title_only() {
+        insta::assert_snapshot!(render_page(&page(), &options(&[PreviewField::Title])));
+    }
+
+    #[test]
+    fn without_title() {
+        let fields = [PreviewField::SiteName, PreviewField::Description];
+        insta::assert_snapshot!(render_page(&page(), &options(&fields)));
+    }
+
+    #[test]
+    fn sections() {
+        let fields = PreviewField::all();
+        let preview = OpenGraph {
+            sections: vec![
+                ("stabilized-apis".to_owned(), "Stabilized APIs".to_owned()),
+                ("other-changes".to_owned(), "Other changes".to_owned()),
+            ],
+            ..page()
+        };
+        let sections = [
+            "other-changes".to_owned(),
+            "missing".to_owned(),
+            "stabilized-apis".to_owned(),
+        ];
+        let blocks = section_blocks(
+            &preview,
+            url(),
+            &sections,
+            false,
+            None,
+            None,
+            &options(&fields),
+        );
+        insta::assert_snapshot!(render(&blocks));
+    }
+
+    #[test]
+    fn sections_not_found() {
+        let fields = PreviewField::all();
+        let blocks = section_blocks(
+            &page(),
+            url(),
+            &["missing".to_owned()],
+            false,
+            None,
+            None,
+            &options(&fields),
+        );
+        insta::assert_snapshot!(render(&blocks));
+    }
+
+    #[test]
+    fn with_failure() {
+        let fields = PreviewField::all();
+        let options = options(&fields);
+        let failed = Url::parse("https://example.com/<missing>").unwrap();
+        let blocks = [
+            preview_block(&page(), url(), true, None, None, &options),
+            failure_block(&failed, "HTTP 404", &options),
+        ];
+        insta::assert_snapshot!(render(&blocks));
+    }
+
+    #[test]
+    fn error_cards() {
+        let (text, html) = error_card(
+            CLASS_PREFIX,
+            "\u{26a0}\u{fe0f}",
+            "Timed out & gave up",
+            None,
+        );
+        let (backref_text, backref_html) = error_card(
+            CLASS_PREFIX,
+            "\u{26a0}\u{fe0f}",
+            "Timed out",
+            Some("https://matrix.to/#/!room:example.org/$event"),
+        );
+        insta::assert_snapshot!(format!("{text}\n{html}\n\n{backref_text}\n{backref_html}"));
+    }
+
+    #[test]
+    fn fit_event_size_drops_and_shortens() {
+        let fields = PreviewField::all();
+        let options = RenderOptions {
+            max_description_chars: usize::MAX,
+            ..options(&fields)
+        };
+        let preview = OpenGraph {
+            description: "Lorem ipsum dolor sit amet. ".repeat(1000),
+            ..page()
+        };
+        let mut blocks = (0..4)
+            .map(|_| preview_block(&preview, url(), false, None, None, &options))
+            .collect::<Vec<_>>();
+        fit_event_size(&mut blocks, CLASS_PREFIX, &Templates::default());
+        let (text, html) = render_previews(&blocks, CLASS_PREFIX, &Templates::default());
+        assert!(content_size(&text, &html) <= MAX_PREVIEW_CONTENT_BYTES);
+        let summary = blocks
+            .iter()
+            .map(|block| format!("{} chars", block.description.chars().count()))
+            .collect::<Vec<_>>();
+        insta::assert_debug_snapshot!(summary);
+    }
+
+    #[test]
+    fn fit_event_size_keeps_small_previews() {
+        let fields = PreviewField::all();
+        let options = options(&fields);
+        let mut blocks = (0..3)
+            .map(|_| preview_block(&page(), url(), false, None, None, &options))
+            .collect::<Vec<_>>();
+        fit_event_size(&mut blocks, CLASS_PREFIX, &Templates::default());
+        assert_eq!(blocks.len(), 3);
+        insta::assert_snapshot!(render(&blocks));
+    }
+}
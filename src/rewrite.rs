@@ -0,0 +1,205 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+
+use eyre::Result;
+use regex::Regex;
+use tracing::{debug, error};
+use url::Url;
+
+use crate::config::Config;
+use crate::{domain, redact};
+
+/// Rewrite rules compiled into the binary, applied after the user's `rewrite_url` rules.
+///
+/// Keep each rule anchored, so it can't fire on an unrelated URL that merely contains a match.
+const BUILTIN_RULES: &[(&str, &str)] = &[
+    // AMP pages served by Google, and the AMP cache
+    (
+        r"(?i)^https?://(?:www\.)?google\.[a-z.]+/amp/s/(.*)",
+        "https://$1",
+    ),
+    (
+        r"(?i)^https?://[a-z0-9-]+\.cdn\.ampproject\.org/[a-z]/s/(.*)",
+        "https://$1",
+    ),
+    // Mobile sites
+    (
+        r"(?i)^https?://([a-z-]+)\.m\.(wikipedia|wiktionary|wikimedia|wikiquote|wikivoyage)\.org/(.*)",
+        "https://$1.$2.org/$3",
+    ),
+    (
+        r"(?i)^https?://(?:m|mobile)\.(facebook\.com|reddit\.com|twitter\.com|x\.com|youtube\.com)/(.*)",
+        "https://$1/$2",
+    ),
+    // Link preview enhance services
+    (
+        r"(?i)^https?://(?:www\.)?bsky\.app/(.*)",
+        "https://fxbsky.app/$1",
+    ),
+    (
+        r"(?i)^https?://(?:www\.)?twitter\.com/(.*)",
+        "https://fxtwitter.com/$1",
+    ),
+    (
+        r"(?i)^https?://(?:www\.)?x\.com/(.*)",
+        "https://fixupx.com/$1",
+    ),
+];
+
+/// Tracking redirects, as the domain, including its subdomains, the path, and the query parameter
+/// holding the target URL.
+///
+/// The target is percent-encoded, which a regex replacement can't decode, so they are handled
+/// separately.
+const BUILTIN_REDIRECTS: &[(&str, &str, &str)] = &[
+    ("google.com", "/url", "q"),
+    ("l.facebook.com", "/l.php", "u"),
+    ("lm.facebook.com", "/l.php", "u"),
+    ("l.instagram.com", "/", "u"),
+    ("l.messenger.com", "/l.php", "u"),
+    ("out.reddit.com", "/", "url"),
+    ("safelinks.protection.outlook.com", "/", "url"),
+    ("steamcommunity.com", "/linkfilter/", "url"),
+    ("www.youtube.com", "/redirect", "q"),
+];
+
+/// Redirects wrapping other redirects, such as SafeLinks around a Google result, are unwrapped up
+/// to this many times.
+const MAX_REDIRECT_UNWRAPS: usize = 4;
+
+pub struct Rewriter {
+    /// The user's `unwrap_redirects`, then the built-in ones.
+    redirects: Vec<(String, String, String)>,
+    rules: Vec<(Regex, String)>,
+    builtin_rules: Vec<(Regex, String)>,
+}
+
+impl Rewriter {
+    pub fn new(config: &Config) -> Result<Self> {
+        let rules = config
+            .rewrite_url
+            .iter()
+            .map(|[from, to]| Ok((Regex::new(from)?, to.clone())))
+            .collect::<Result<Vec<_>>>()?;
+        let mut redirects = config
+            .unwrap_redirects
+            .iter()
+            .map(|[domain, path, param]| (domain.clone(), path.clone(), param.clone()))
+            .collect::<Vec<_>>();
+        let builtin_rules = if config.builtin_rewrites {
+            redirects.extend(BUILTIN_REDIRECTS.iter().map(|&(domain, path, param)| {
+                (domain.to_owned(), path.to_owned(), param.to_owned())
+            }));
+            BUILTIN_RULES
+                .iter()
+                .map(|&(from, to)| (Regex::new(from).unwrap(), to.to_owned()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            redirects,
+            rules,
+            builtin_rules,
+        })
+    }
+
+    /// Applies the URL rewrite rules. Returns `None` if the result is not a valid URL.
+    pub fn apply(&self, url: Url) -> Option<Url> {
+        let url_str = self.run(url.as_str(), |rule, input, output| {
+            if let Some(output) = output {
+                debug!(
+                    "URL rewrite: {} => {} => {}",
+                    redact::url_str(input),
+                    rule,
+                    redact::url_str(output)
+                );
+            }
+        });
+        match url_str {
+            Cow::Borrowed(_) => Some(url),
+            Cow::Owned(url_str) => match Url::parse(&url_str) {
+                Ok(url) => Some(url),
+                Err(err) => {
+                    error!("Failed to parse the URL after rewrite: {}", err);
+                    None
+                }
+            },
+        }
+    }
+
+    /// Prints every rule, whether it matched, and the intermediate and final URL.
+    pub fn print_trace(&self, url: &Url) {
+        println!("Input:    {}", url);
+        let url_str = self.run(url.as_str(), |rule, _, output| match output {
+            Some(output) => println!("Matched:  {}\n       => {}", rule, output),
+            None => println!("Skipped:  {}", rule),
+        });
+        match Url::parse(&url_str) {
+            Ok(url) => println!("Result:   {}", url),
+            Err(err) => println!("Result:   {} (invalid URL: {})", url_str, err),
+        }
+    }
+
+    /// Runs the rules in order, calling `report` with each rule, its input, and its output if it
+    /// matched.
+    fn run<'a>(
+        &self,
+        url_str: &'a str,
+        mut report: impl FnMut(&dyn Display, &str, Option<&str>),
+    ) -> Cow<'a, str> {
+        let mut url_str = Cow::from(url_str);
+        if !self.redirects.is_empty() {
+            for _ in 0..MAX_REDIRECT_UNWRAPS {
+                let target = self.unwrap_redirect(&url_str);
+                report(&"(tracking redirects)", &url_str, target.as_deref());
+                let Some(target) = target else {
+                    break;
+                };
+                url_str = target.into();
+            }
+        }
+        for (from, to) in self.rules.iter() {
+            rewrite_once(&mut url_str, from, to, &mut report);
+        }
+        for (from, to) in self.builtin_rules.iter() {
+            rewrite_once(&mut url_str, from, to, &mut report);
+        }
+        url_str
+    }
+}
+
+fn rewrite_once(
+    url_str: &mut Cow<'_, str>,
+    from: &Regex,
+    to: &str,
+    report: &mut impl FnMut(&dyn Display, &str, Option<&str>),
+) {
+    let rule = format_args!("{} => {}", from, to);
+    match from.replace_all(url_str, to) {
+        Cow::Borrowed(_) => report(&rule, url_str, None),
+        Cow::Owned(result) => {
+            report(&rule, url_str, Some(&result));
+            *url_str = result.into()
+        }
+    }
+}
+
+impl Rewriter {
+    /// Returns the target of a known tracking redirect.
+    fn unwrap_redirect(&self, url_str: &str) -> Option<String> {
+        let url = Url::parse(url_str).ok()?;
+        let host = url.host_str()?;
+        let (_, _, param) = self
+            .redirects
+            .iter()
+            .find(|(domain, path, _)| domain::matches(host, domain) && url.path() == path)?;
+        let target = url
+            .query_pairs()
+            .find(|(key, _)| key == param)
+            .map(|(_, value)| value)?;
+        // Only follow absolute web URLs
+        let target = Url::parse(&target).ok()?;
+        matches!(target.scheme(), "http" | "https").then(|| target.into())
+    }
+}
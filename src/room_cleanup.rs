@@ -0,0 +1,159 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_sqlite::Pool;
+use eyre::{Report, Result};
+use indexmap::IndexSet;
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use matrix_sdk::ruma::api::client::membership::forget_room;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use matrix_sdk::{Client, RoomState};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{Instrument, error, info, instrument, warn};
+
+const FORGET_CONCURRENCY: usize = 4;
+
+const FORGET_MAX_ATTEMPTS: u32 = 3;
+
+/// Forgets the rooms we already left, including those we failed to forget during the last run.
+///
+/// Rooms that still fail are saved to the `forget_failures` table to retry on the next run.
+#[instrument(skip_all)]
+pub async fn forget_left_rooms(client: Client, db: Pool) {
+    let mut room_ids = client
+        .left_rooms()
+        .iter()
+        .map(|room| room.room_id().to_owned())
+        .collect::<IndexSet<_>>();
+    match load_failures(&db).await {
+        Ok(failures) => room_ids.extend(failures.into_iter().filter(|room_id| {
+            // We may have joined the room again since then.
+            client
+                .get_room(room_id)
+                .is_none_or(|room| matches!(room.state(), RoomState::Left | RoomState::Banned))
+        })),
+        Err(err) => error!("Failed to load rooms to forget: {}", err),
+    }
+    if room_ids.is_empty() {
+        return;
+    }
+    info!("Forgetting {} rooms.", room_ids.len());
+
+    let semaphore = Arc::new(Semaphore::new(FORGET_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for room_id in room_ids {
+        let client = client.clone();
+        let semaphore = semaphore.clone();
+        tasks.spawn(
+            async move {
+                let _permit = semaphore.acquire_owned().await.unwrap();
+                let result = forget_room(&client, &room_id).await;
+                (room_id, result)
+            }
+            .in_current_span(),
+        );
+    }
+
+    let mut forgotten = 0_usize;
+    let mut failures = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((room_id, Ok(()))) => {
+                info!("Forgot room {}.", room_id);
+                forgotten += 1;
+            }
+            Ok((room_id, Err(err))) => {
+                error!("Failed to forget room {}: {}", room_id, err);
+                failures.push(room_id);
+            }
+            Err(err) => error!("Room forgetting task failed: {}", err),
+        }
+    }
+    info!(
+        "Forgot {} rooms, {} will be retried on the next run.",
+        forgotten,
+        failures.len()
+    );
+    if let Err(err) = save_failures(&db, failures).await {
+        error!("Failed to save rooms to forget: {}", err);
+    }
+}
+
+async fn forget_room(client: &Client, room_id: &RoomId) -> matrix_sdk::Result<()> {
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        let result = match client.get_room(room_id) {
+            Some(room) => room.forget().await,
+            // The room is gone from the local store, but the server may still remember it.
+            None => client
+                .send(forget_room::v3::Request::new(room_id.to_owned()))
+                .await
+                .map(|_| ())
+                .map_err(Into::into),
+        };
+        let err = match result {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let Some(ErrorKind::LimitExceeded { retry_after }) = err.client_api_error_kind() else {
+            return Err(err);
+        };
+        if attempt >= FORGET_MAX_ATTEMPTS {
+            return Err(err);
+        }
+        let delay = match retry_after {
+            Some(RetryAfter::Delay(delay)) => *delay,
+            _ => backoff,
+        };
+        warn!(
+            "Rate limited while forgetting room {}, retrying in {:?}.",
+            room_id, delay
+        );
+        tokio::time::sleep(delay).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+async fn load_failures(db: &Pool) -> Result<Vec<OwnedRoomId>> {
+    let stmt_query = "SELECT room_id FROM forget_failures;";
+    let conn = db.get().await?;
+
+    let room_ids = conn
+        .interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            let rows = stmt
+                .query_map((), |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, Report>(rows)
+        })
+        .await
+        .unwrap()?;
+    Ok(room_ids
+        .into_iter()
+        .filter_map(|room_id| OwnedRoomId::try_from(room_id).ok())
+        .collect())
+}
+
+async fn save_failures(db: &Pool, room_ids: Vec<OwnedRoomId>) -> Result<()> {
+    let stmt_delete = "DELETE FROM forget_failures;";
+    let stmt_insert = "INSERT OR IGNORE INTO forget_failures (room_id) VALUES (?);";
+    let conn = db.get().await?;
+
+    conn.interact(move |conn| {
+        let tx = conn.transaction()?;
+        tx.execute(stmt_delete, ())?;
+        {
+            let mut stmt = tx.prepare_cached(stmt_insert)?;
+            for room_id in room_ids {
+                stmt.execute((room_id.as_str(),))?;
+            }
+        }
+        tx.commit()?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
@@ -0,0 +1,219 @@
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use tracing::warn;
+
+use crate::config::{Config, PreviewField, StyleProfile};
+use crate::i18n;
+
+/// Per-room overrides of the global configuration.
+///
+/// Stored as key-value pairs in the `room_settings` table. A missing key falls back to the
+/// global configuration.
+#[derive(Clone, Debug, Default)]
+pub struct RoomSettings {
+    /// Set by `!preview set`, as are `max_urls_per_message` and `compact_mode`.
+    pub max_description_chars: Option<usize>,
+    pub max_urls_per_message: Option<usize>,
+    pub compact_mode: Option<bool>,
+    pub preview_fields: Option<Vec<PreviewField>>,
+    /// The style profile of every link, over `domain_styles` and `class_styles`.
+    pub style: Option<StyleProfile>,
+    pub accept_language: Option<String>,
+    /// The language of the bot's own strings, set by `!preview language`.
+    pub language: Option<String>,
+    pub enabled: Option<bool>,
+    pub read_only: Option<bool>,
+    pub dry_run: Option<bool>,
+    /// Who to send dry run reports to, set by `!preview dryrun dm`.
+    pub dry_run_report_to: Option<OwnedUserId>,
+    pub show_backref: Option<bool>,
+    pub debug_footer: Option<bool>,
+    pub weekly_digest: Option<bool>,
+    /// When the last digest was sent, in seconds since the Unix epoch.
+    pub digest_last_sent: Option<i64>,
+    /// Set by `!preview block` and `!preview unblock`, stored one per line.
+    pub blocked_urls: Option<Vec<String>>,
+    /// Set once the help card was sent to a direct chat.
+    pub help_sent: Option<bool>,
+}
+
+impl RoomSettings {
+    pub fn from_rows(rows: impl IntoIterator<Item = (String, String)>) -> RoomSettings {
+        let mut settings = RoomSettings::default();
+        for (key, value) in rows {
+            let ok = match key.as_str() {
+                "max_description_chars" => value
+                    .parse()
+                    .map(|value| settings.max_description_chars = Some(value))
+                    .is_ok(),
+                "max_urls_per_message" => value
+                    .parse()
+                    .map(|value| settings.max_urls_per_message = Some(value))
+                    .is_ok(),
+                "compact_mode" => value
+                    .parse()
+                    .map(|value| settings.compact_mode = Some(value))
+                    .is_ok(),
+                "preview_fields" => PreviewField::parse_list(&value)
+                    .map(|value| settings.preview_fields = Some(value))
+                    .is_ok(),
+                "style" => value
+                    .parse()
+                    .map(|value| settings.style = Some(value))
+                    .is_ok(),
+                "enabled" => value
+                    .parse()
+                    .map(|value| settings.enabled = Some(value))
+                    .is_ok(),
+                "show_backref" => value
+                    .parse()
+                    .map(|value| settings.show_backref = Some(value))
+                    .is_ok(),
+                "debug_footer" => value
+                    .parse()
+                    .map(|value| settings.debug_footer = Some(value))
+                    .is_ok(),
+                "weekly_digest" => value
+                    .parse()
+                    .map(|value| settings.weekly_digest = Some(value))
+                    .is_ok(),
+                "digest_last_sent" => value
+                    .parse()
+                    .map(|value| settings.digest_last_sent = Some(value))
+                    .is_ok(),
+                "dry_run" => value
+                    .parse()
+                    .map(|value| settings.dry_run = Some(value))
+                    .is_ok(),
+                "dry_run_report_to" if value.is_empty() => {
+                    settings.dry_run_report_to = None;
+                    true
+                }
+                "dry_run_report_to" => OwnedUserId::try_from(value.as_str())
+                    .map(|value| settings.dry_run_report_to = Some(value))
+                    .is_ok(),
+                "read_only" => value
+                    .parse()
+                    .map(|value| settings.read_only = Some(value))
+                    .is_ok(),
+                "help_sent" => value
+                    .parse()
+                    .map(|value| settings.help_sent = Some(value))
+                    .is_ok(),
+                "blocked_urls" => {
+                    settings.blocked_urls = Some(value.lines().map(str::to_owned).collect());
+                    true
+                }
+                "accept_language" => {
+                    settings.accept_language = Some(value.clone());
+                    !value.is_empty()
+                }
+                "language" => i18n::find(&value)
+                    .map(|value| settings.language = Some(value.to_owned()))
+                    .is_some(),
+                _ => {
+                    warn!("Unknown room setting: {}", key);
+                    true
+                }
+            };
+            if !ok {
+                warn!("Invalid value for room setting {}: {:?}", key, value);
+            }
+        }
+        settings
+    }
+
+    pub fn max_description_chars(&self, config: &Config) -> usize {
+        self.max_description_chars
+            .unwrap_or(config.max_description_chars)
+    }
+
+    pub fn max_urls_per_message(&self, config: &Config) -> usize {
+        self.max_urls_per_message
+            .unwrap_or(config.max_urls_per_message)
+    }
+
+    pub fn compact_mode(&self, config: &Config) -> bool {
+        self.compact_mode.unwrap_or(config.compact_mode)
+    }
+
+    pub fn preview_fields<'a>(&'a self, config: &'a Config) -> &'a [PreviewField] {
+        self.preview_fields
+            .as_deref()
+            .unwrap_or(&config.preview_fields)
+    }
+
+    pub fn show_backref(&self, config: &Config) -> bool {
+        self.show_backref.unwrap_or(config.show_backref)
+    }
+
+    pub fn debug_footer(&self, config: &Config) -> bool {
+        self.debug_footer.unwrap_or(config.debug_footer)
+    }
+
+    pub fn weekly_digest(&self, config: &Config) -> bool {
+        self.weekly_digest.unwrap_or(config.weekly_digest)
+    }
+
+    pub fn accept_language<'a>(&'a self, config: &'a Config) -> &'a str {
+        self.accept_language
+            .as_deref()
+            .filter(|value| !value.is_empty())
+            .unwrap_or(&config.crawler_accept_language)
+    }
+
+    /// The language of the bot's own strings, such as the digest.
+    pub fn language(&self) -> &str {
+        self.language.as_deref().unwrap_or(i18n::DEFAULT_LANGUAGE)
+    }
+
+    pub fn placeholder_text(&self, config: &Config) -> String {
+        self.text("loading", &config.placeholder_text)
+    }
+
+    pub fn error_text(&self, config: &Config) -> String {
+        self.text("preview-unavailable", &config.error_text)
+    }
+
+    pub fn timeout_text(&self, config: &Config) -> String {
+        self.text("preview-timeout", &config.timeout_text)
+    }
+
+    pub fn thread_pointer_text(&self, config: &Config) -> String {
+        self.text("previewed-in-thread", &config.thread_pointer_text)
+    }
+
+    /// Translates a string if the room chose a language, and otherwise keeps the configured one.
+    fn text(&self, id: &str, configured: &str) -> String {
+        match &self.language {
+            Some(language) => i18n::message(language, id),
+            None => configured.to_owned(),
+        }
+    }
+
+    pub fn blocked_urls(&self) -> &[String] {
+        self.blocked_urls.as_deref().unwrap_or_default()
+    }
+
+    /// Set by `!preview disable` and `!preview enable`.
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Set by `!preview dryrun`.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.unwrap_or(false)
+    }
+
+    pub fn dry_run_report_to(&self) -> Option<&UserId> {
+        self.dry_run_report_to.as_deref()
+    }
+
+    /// Set when the bot isn't allowed to send messages, until the power levels change.
+    pub fn read_only(&self) -> bool {
+        self.read_only.unwrap_or(false)
+    }
+
+    pub fn help_sent(&self) -> bool {
+        self.help_sent.unwrap_or(false)
+    }
+}
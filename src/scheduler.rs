@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+/// The class of a preview job. Earlier classes are served first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// A message that was just posted.
+    Live,
+    /// An edited message, or a `!preview refresh`.
+    Edit,
+    /// A preview left unfinished by a previous run.
+    Retry,
+    /// A message from before the bot joined or started, or a URL warming the cache.
+    Backfill,
+}
+
+impl Priority {
+    const ALL: [Priority; 4] = [
+        Priority::Live,
+        Priority::Edit,
+        Priority::Retry,
+        Priority::Backfill,
+    ];
+
+    /// The share of the job slots this class may use at once, in quarters.
+    ///
+    /// Lower classes can't fill up every slot, so live messages never wait for a full backlog.
+    fn share_quarters(self) -> usize {
+        match self {
+            Priority::Live => 4,
+            Priority::Edit => 2,
+            Priority::Retry => 1,
+            Priority::Backfill => 1,
+        }
+    }
+}
+
+/// Limits how many preview jobs run at once, handing free slots to the highest waiting class.
+pub struct Scheduler {
+    state: Mutex<State>,
+}
+
+struct State {
+    slots: usize,
+    running: [usize; 4],
+    waiting: [VecDeque<oneshot::Sender<Permit>>; 4],
+}
+
+/// A job slot, released when dropped.
+pub struct Permit {
+    scheduler: Option<Arc<Scheduler>>,
+    priority: Priority,
+}
+
+impl Scheduler {
+    pub fn new(slots: usize) -> Arc<Scheduler> {
+        Arc::new(Scheduler {
+            state: Mutex::new(State {
+                slots,
+                running: [0; 4],
+                waiting: Default::default(),
+            }),
+        })
+    }
+
+    /// Waits for a free slot for a job of the class.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            let is_queue_empty = Priority::ALL[..=priority as usize]
+                .iter()
+                .all(|&priority| state.waiting[priority as usize].is_empty());
+            if is_queue_empty && state.has_room(priority) {
+                state.running[priority as usize] += 1;
+                return Permit {
+                    scheduler: Some(self.clone()),
+                    priority,
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.waiting[priority as usize].push_back(sender);
+            receiver
+        };
+        // The sender is only dropped after sending.
+        receiver.await.unwrap()
+    }
+
+    /// Returns the number of running and waiting jobs.
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (
+            state.running.iter().sum(),
+            state.waiting.iter().map(VecDeque::len).sum(),
+        )
+    }
+
+    fn release(self: &Arc<Self>, priority: Priority) {
+        let mut state = self.state.lock().unwrap();
+        state.running[priority as usize] -= 1;
+        for priority in Priority::ALL {
+            while state.has_room(priority)
+                && let Some(sender) = state.waiting[priority as usize].pop_front()
+            {
+                let permit = Permit {
+                    scheduler: Some(self.clone()),
+                    priority,
+                };
+                match sender.send(permit) {
+                    Ok(()) => state.running[priority as usize] += 1,
+                    // The job was cancelled while waiting
+                    Err(mut permit) => permit.scheduler = None,
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    fn has_room(&self, priority: Priority) -> bool {
+        let share = (self.slots * priority.share_quarters() / 4).max(1);
+        self.running.iter().sum::<usize>() < self.slots && self.running[priority as usize] < share
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release(self.priority);
+        }
+    }
+}
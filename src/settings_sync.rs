@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use deadpool_sqlite::Pool;
+use eyre::{Report, Result};
+use matrix_sdk::Client;
+use matrix_sdk::ruma::serde::Raw;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+/// The type of the global account data event holding a copy of the room settings.
+const EVENT_TYPE: &str = "com.m13253.url_preview.settings";
+
+/// Room settings describing the room itself rather than what its members chose, which are found
+/// out again after moving.
+const LOCAL_KEYS: &[&str] = &["read_only"];
+
+/// Uploads replace the whole copy, so they must not overtake each other.
+static UPLOAD_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Default, Deserialize, Serialize)]
+struct SyncedSettings {
+    /// The settings of each room, as in the `room_settings` table.
+    #[serde(default)]
+    rooms: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+pub fn is_synced(key: &str) -> bool {
+    !LOCAL_KEYS.contains(&key)
+}
+
+/// Replaces the copy in the account data with the room settings in the database.
+pub async fn upload(client: &Client, db: &Pool) -> Result<()> {
+    let stmt_query = "SELECT room_id, key, value FROM room_settings;";
+    let _guard = UPLOAD_LOCK.lock().await;
+    let conn = db.get().await?;
+
+    let rows = conn
+        .interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            let rows = stmt
+                .query_map((), |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, Report>(rows)
+        })
+        .await
+        .unwrap()?;
+    let mut settings = SyncedSettings::default();
+    for (room_id, key, value) in rows {
+        if is_synced(&key) {
+            settings
+                .rooms
+                .entry(room_id)
+                .or_default()
+                .insert(key, value);
+        }
+    }
+    client
+        .account()
+        .set_account_data_raw(EVENT_TYPE.into(), Raw::new(&settings)?.cast())
+        .await?;
+    Ok(())
+}
+
+/// Restores the room settings missing from the database from the copy in the account data, such
+/// as after moving to a new data directory. Returns how many were restored.
+///
+/// Settings already in the database are newer, so they are kept.
+pub async fn restore(client: &Client, db: &Pool) -> Result<usize> {
+    let stmt_insert = "INSERT OR IGNORE INTO room_settings (room_id, key, value) VALUES (?, ?, ?);";
+    let Some(content) = client
+        .account()
+        .fetch_account_data(EVENT_TYPE.into())
+        .await?
+    else {
+        return Ok(0);
+    };
+    let settings = content.deserialize_as::<SyncedSettings>()?;
+    let conn = db.get().await?;
+
+    conn.interact(move |conn| {
+        let tx = conn.transaction()?;
+        let mut restored = 0;
+        {
+            let mut stmt = tx.prepare_cached(stmt_insert)?;
+            for (room_id, room_settings) in settings.rooms {
+                for (key, value) in room_settings {
+                    if is_synced(&key) {
+                        restored += stmt.execute((&room_id, key, value))?;
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok::<_, Report>(restored)
+    })
+    .await
+    .unwrap()
+}
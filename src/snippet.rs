@@ -0,0 +1,70 @@
+use encoding_rs::Encoding;
+use mime::Mime;
+use url::Url;
+
+use crate::common::MAX_SNIPPET_LINE_CHARS;
+use crate::limit;
+use crate::media_link;
+use crate::opengraph::OpenGraph;
+
+/// Source code types without a `text/` prefix.
+const SOURCE_CODE_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/json",
+    "application/toml",
+    "application/x-httpd-php",
+    "application/x-perl",
+    "application/x-python",
+    "application/x-ruby",
+    "application/x-sh",
+    "application/x-yaml",
+    "application/yaml",
+];
+
+/// Whether a response is plain text or source code, which has no metadata but can be quoted.
+pub fn is_text(content_type: &Mime) -> bool {
+    let essence = content_type.essence_str();
+    matches!(
+        essence,
+        "text/plain" | "text/markdown" | "text/css" | "text/javascript" | "text/yaml"
+    ) || essence.starts_with("text/x-")
+        || SOURCE_CODE_TYPES.contains(&essence)
+}
+
+/// Previews a text file by its first `max_lines` non-empty lines, with the file name as the
+/// title.
+pub fn describe(
+    url: &Url,
+    content_type: &Mime,
+    total_size: Option<u64>,
+    document: &[u8],
+    charset: Option<&'static Encoding>,
+    max_lines: usize,
+) -> OpenGraph {
+    // A byte order mark overrides the charset.
+    let text = charset.unwrap_or(encoding_rs::UTF_8).decode(document).0;
+    let snippet = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .take(max_lines)
+        .map(|line| {
+            limit::length_in_chars(
+                line.trim_end().replace('\t', "    "),
+                MAX_SNIPPET_LINE_CHARS,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut description = vec![content_type.essence_str().to_owned()];
+    if let Some(size) = total_size {
+        description.push(media_link::format_size(size));
+    }
+    OpenGraph {
+        title: media_link::file_name(url),
+        description: description.join(" \u{b7} "),
+        url: url.to_string(),
+        snippet,
+        ..Default::default()
+    }
+}
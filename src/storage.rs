@@ -0,0 +1,339 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use deadpool_sqlite::Pool;
+use deadpool_sqlite::rusqlite::OptionalExtension;
+use eyre::{Report, Result};
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, RoomId};
+
+use crate::common::EVENT_CLAIM_RETENTION;
+
+pub type MessageKey = (OwnedRoomId, OwnedEventId);
+
+/// Our response to an original event.
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub response_id: OwnedEventId,
+    /// The hash of the URLs in the latest version of the original event that was successfully
+    /// previewed.
+    pub urls_hash: Option<i64>,
+}
+
+/// A backend that persists the mapping from original events to our response events.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_response(&self, room_id: &RoomId, event_id: &EventId) -> Result<Option<Response>>;
+
+    /// Finds the original event that `response_id` responds to.
+    async fn get_original_event_id(
+        &self,
+        room_id: &RoomId,
+        response_id: &EventId,
+    ) -> Result<Option<OwnedEventId>>;
+
+    /// Inserts or replaces the rows in a single transaction.
+    async fn insert_responses(&self, rows: Vec<(MessageKey, Response)>) -> Result<()>;
+
+    /// Records that we're responding to an event. Returns `false` if the event was already
+    /// claimed.
+    ///
+    /// Unlike responses, claims are written before sending anything, so an event redelivered
+    /// before its response is saved is still answered once.
+    async fn claim_event(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool>;
+
+    /// Forgets the claims older than [`EVENT_CLAIM_RETENTION`].
+    async fn expire_claims(&self) -> Result<()>;
+}
+
+/// The oldest claim to keep, in seconds since the Unix epoch.
+fn claim_expiry() -> Result<(i64, i64)> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let expiry = now.saturating_sub(EVENT_CLAIM_RETENTION);
+    Ok((now.as_secs() as i64, expiry.as_secs() as i64))
+}
+
+/// The default backend, sharing the SQLite database in `data_dir`.
+pub struct SqliteStorage {
+    db: Pool,
+}
+
+impl SqliteStorage {
+    pub fn new(db: Pool) -> SqliteStorage {
+        SqliteStorage { db }
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_response(&self, room_id: &RoomId, event_id: &EventId) -> Result<Option<Response>> {
+        let stmt_query =
+            "SELECT response_id, urls_hash FROM messages WHERE room_id = ? AND event_id = ?;";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room_id.to_string();
+        let event_id_str = event_id.to_string();
+        let row = conn
+            .interact(move |conn| {
+                let mut stmt = conn.prepare_cached(stmt_query)?;
+                Ok::<_, Report>(
+                    stmt.query_row((room_id_str, event_id_str), |row| {
+                        Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?))
+                    })
+                    .optional()?,
+                )
+            })
+            .await
+            .unwrap()?;
+        row.map(|(response_id, urls_hash)| {
+            Ok(Response {
+                response_id: OwnedEventId::try_from(response_id)?,
+                urls_hash,
+            })
+        })
+        .transpose()
+    }
+
+    async fn get_original_event_id(
+        &self,
+        room_id: &RoomId,
+        response_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        let stmt_query = "SELECT event_id FROM messages WHERE room_id = ? AND response_id = ?;";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room_id.to_string();
+        let response_id_str = response_id.to_string();
+        let event_id = conn
+            .interact(move |conn| {
+                let mut stmt = conn.prepare_cached(stmt_query)?;
+                Ok::<_, Report>(
+                    stmt.query_row((room_id_str, response_id_str), |row| {
+                        row.get::<_, String>(0)
+                    })
+                    .optional()?,
+                )
+            })
+            .await
+            .unwrap()?;
+        Ok(event_id.map(OwnedEventId::try_from).transpose()?)
+    }
+
+    async fn insert_responses(&self, rows: Vec<(MessageKey, Response)>) -> Result<()> {
+        let stmt_insert = "INSERT OR REPLACE INTO messages (room_id, event_id, response_id, urls_hash) VALUES (?, ?, ?, ?)";
+        let conn = self.db.get().await?;
+
+        conn.interact(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(stmt_insert)?;
+                for ((room_id, event_id), response) in rows {
+                    stmt.execute((
+                        room_id.as_str(),
+                        event_id.as_str(),
+                        response.response_id.as_str(),
+                        response.urls_hash,
+                    ))?;
+                }
+            }
+            tx.commit()?;
+            Ok::<_, Report>(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn claim_event(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        let stmt_insert = "INSERT INTO event_claims (room_id, event_id, timestamp) VALUES (?, ?, ?)
+ON CONFLICT (room_id, event_id) DO NOTHING;";
+        let conn = self.db.get().await?;
+
+        let (now, _) = claim_expiry()?;
+        let params = (room_id.to_string(), event_id.to_string(), now);
+        conn.interact(move |conn| {
+            let inserted = conn.prepare_cached(stmt_insert)?.execute(params)?;
+            Ok::<_, Report>(inserted != 0)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn expire_claims(&self) -> Result<()> {
+        let stmt_delete = "DELETE FROM event_claims WHERE timestamp < ?;";
+        let conn = self.db.get().await?;
+
+        let (_, expiry) = claim_expiry()?;
+        conn.interact(move |conn| {
+            conn.prepare_cached(stmt_delete)?.execute((expiry,))?;
+            Ok::<_, Report>(())
+        })
+        .await
+        .unwrap()
+    }
+}
+
+/// An alternative backend for deployments that already run PostgreSQL.
+///
+/// Selected by setting `database_url` to a `postgres://` URL. Connections use TLS as `sslmode`
+/// asks, through the same TLS feature as the rest of the bot.
+#[cfg(feature = "postgres")]
+pub struct PostgresStorage {
+    db: deadpool_postgres::Pool,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresStorage {
+    pub async fn new(url: &str) -> Result<PostgresStorage> {
+        let db_config = deadpool_postgres::Config {
+            url: Some(url.to_owned()),
+            ..Default::default()
+        };
+        let db = db_config.create_pool(
+            Some(deadpool_postgres::Runtime::Tokio1),
+            Self::make_tls(url)?,
+        )?;
+        let client = db.get().await?;
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS messages (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    urls_hash BIGINT,
+    UNIQUE(room_id, event_id)
+);
+ALTER TABLE messages ADD COLUMN IF NOT EXISTS urls_hash BIGINT;
+CREATE INDEX IF NOT EXISTS messages_response_id ON messages (room_id, response_id);
+CREATE TABLE IF NOT EXISTS event_claims (
+    id BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    timestamp BIGINT NOT NULL,
+    UNIQUE(room_id, event_id)
+);
+CREATE INDEX IF NOT EXISTS event_claims_timestamp ON event_claims (timestamp);
+",
+            )
+            .await?;
+        Ok(PostgresStorage { db })
+    }
+
+    /// Verifies the server against the system's certificates.
+    #[cfg(feature = "native-tls")]
+    fn make_tls(_url: &str) -> Result<postgres_native_tls::MakeTlsConnector> {
+        Ok(postgres_native_tls::MakeTlsConnector::new(
+            native_tls::TlsConnector::new()?,
+        ))
+    }
+
+    /// Verifies the server against the Mozilla root certificates.
+    #[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+    fn make_tls(_url: &str) -> Result<tokio_postgres_rustls::MakeRustlsConnect> {
+        let root_store = rustls::RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls_config = rustls::ClientConfig::builder_with_provider(std::sync::Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+        Ok(tokio_postgres_rustls::MakeRustlsConnect::new(tls_config))
+    }
+
+    #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+    fn make_tls(url: &str) -> Result<deadpool_postgres::tokio_postgres::NoTls> {
+        use deadpool_postgres::tokio_postgres::config::{Config, SslMode};
+
+        if url.parse::<Config>()?.get_ssl_mode() == SslMode::Require {
+            eyre::bail!(
+                "database_url has sslmode=require, but the bot was built without the native-tls or rustls-tls feature"
+            );
+        }
+        Ok(deadpool_postgres::tokio_postgres::NoTls)
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn get_response(&self, room_id: &RoomId, event_id: &EventId) -> Result<Option<Response>> {
+        let stmt_query =
+            "SELECT response_id, urls_hash FROM messages WHERE room_id = $1 AND event_id = $2;";
+        let client = self.db.get().await?;
+
+        let stmt = client.prepare_cached(stmt_query).await?;
+        let row = client
+            .query_opt(&stmt, &[&room_id.as_str(), &event_id.as_str()])
+            .await?;
+        row.map(|row| {
+            Ok(Response {
+                response_id: OwnedEventId::try_from(row.get::<_, String>(0))?,
+                urls_hash: row.get(1),
+            })
+        })
+        .transpose()
+    }
+
+    async fn get_original_event_id(
+        &self,
+        room_id: &RoomId,
+        response_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        let stmt_query = "SELECT event_id FROM messages WHERE room_id = $1 AND response_id = $2;";
+        let client = self.db.get().await?;
+
+        let stmt = client.prepare_cached(stmt_query).await?;
+        let row = client
+            .query_opt(&stmt, &[&room_id.as_str(), &response_id.as_str()])
+            .await?;
+        Ok(row
+            .map(|row| OwnedEventId::try_from(row.get::<_, String>(0)))
+            .transpose()?)
+    }
+
+    async fn insert_responses(&self, rows: Vec<(MessageKey, Response)>) -> Result<()> {
+        let stmt_insert = "INSERT INTO messages (room_id, event_id, response_id, urls_hash) VALUES ($1, $2, $3, $4)
+ON CONFLICT (room_id, event_id) DO UPDATE SET response_id = EXCLUDED.response_id, urls_hash = EXCLUDED.urls_hash;";
+        let mut client = self.db.get().await?;
+
+        let tx = client.transaction().await?;
+        let stmt = tx.prepare_cached(stmt_insert).await?;
+        for ((room_id, event_id), response) in rows {
+            tx.execute(
+                &stmt,
+                &[
+                    &room_id.as_str(),
+                    &event_id.as_str(),
+                    &response.response_id.as_str(),
+                    &response.urls_hash,
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn claim_event(&self, room_id: &RoomId, event_id: &EventId) -> Result<bool> {
+        let stmt_insert =
+            "INSERT INTO event_claims (room_id, event_id, timestamp) VALUES ($1, $2, $3)
+ON CONFLICT (room_id, event_id) DO NOTHING;";
+        let client = self.db.get().await?;
+
+        let (now, _) = claim_expiry()?;
+        let inserted = client
+            .execute(stmt_insert, &[&room_id.as_str(), &event_id.as_str(), &now])
+            .await?;
+        Ok(inserted != 0)
+    }
+
+    async fn expire_claims(&self) -> Result<()> {
+        let stmt_delete = "DELETE FROM event_claims WHERE timestamp < $1;";
+        let client = self.db.get().await?;
+
+        let (_, expiry) = claim_expiry()?;
+        client.execute(stmt_delete, &[&expiry]).await?;
+        Ok(())
+    }
+}
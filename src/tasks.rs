@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eyre::Result;
+use tokio::task::AbortHandle;
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, error, info, warn};
+
+use crate::metrics::Metrics;
+
+/// Runs background tasks, so their errors and panics are logged and counted instead of vanishing,
+/// and the unfinished ones can be waited for on shutdown.
+pub struct Supervisor {
+    tracker: TaskTracker,
+    metrics: Arc<Metrics>,
+    /// Tasks running until shutdown, which are cancelled then.
+    services: Mutex<Vec<AbortHandle>>,
+}
+
+impl Supervisor {
+    pub fn new(metrics: Arc<Metrics>) -> Supervisor {
+        Supervisor {
+            tracker: TaskTracker::new(),
+            metrics,
+            services: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Runs a task in the background. `label` names the kind of task in logs and metrics.
+    pub fn spawn<F>(&self, label: &'static str, task: F) -> AbortHandle
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.metrics.record_task(label, "started");
+        // A separate task, so panics are caught by its JoinHandle.
+        let handle = tokio::spawn(task.in_current_span());
+        let abort_handle = handle.abort_handle();
+        let metrics = self.metrics.clone();
+        self.tracker.spawn(
+            async move {
+                let outcome = match handle.await {
+                    Ok(Ok(())) => "succeeded",
+                    Ok(Err(err)) => {
+                        error!("Task {} failed: {}", label, err);
+                        "failed"
+                    }
+                    Err(err) if err.is_panic() => {
+                        error!("Task {} panicked: {}", label, err);
+                        "panicked"
+                    }
+                    Err(_) => "cancelled",
+                };
+                metrics.record_task(label, outcome);
+            }
+            .in_current_span(),
+        );
+        abort_handle
+    }
+
+    /// Runs a task that never finishes by itself, such as a server, to cancel it on shutdown.
+    pub fn spawn_service<F>(&self, label: &'static str, task: F) -> AbortHandle
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let abort_handle = self.spawn(label, task);
+        let mut services = self.services.lock().unwrap();
+        services.retain(|service| !service.is_finished());
+        services.push(abort_handle.clone());
+        abort_handle
+    }
+
+    /// Cancels the services, and waits up to `timeout` for the other tasks to finish.
+    pub async fn shutdown(&self, timeout: Duration) {
+        for service in self.services.lock().unwrap().drain(..) {
+            service.abort();
+        }
+        self.tracker.close();
+        if self.tracker.is_empty() {
+            return;
+        }
+        info!("Waiting for {} background tasks.", self.tracker.len());
+        if tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Giving up on {} background tasks after {:?}.",
+                self.tracker.len(),
+                timeout
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use eyre::{Result, WrapErr};
+use minijinja::Environment;
+use serde::Serialize;
+use tracing::warn;
+
+/// Escaped as HTML, following the extension.
+const HTML_TEMPLATE: &str = "preview.html";
+const TEXT_TEMPLATE: &str = "preview.txt";
+
+/// The layouts of a single preview set by `preview_template_html` and `preview_template_text`,
+/// which replace the built-in layout.
+#[derive(Clone, Default)]
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+/// What a template can show, after the preview fields, the style profile, and the length limits
+/// of the room were applied. Hidden fields are empty.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PreviewContext {
+    pub class_prefix: String,
+    /// The emoji leading the preview, which is the warning emoji if the page has no title.
+    pub emoji: String,
+    pub warning_emoji: String,
+    /// The link to the original message, if the emoji should link back to it.
+    pub backref: Option<String>,
+    pub url: String,
+    /// Empty if the page has no title.
+    pub title: String,
+    pub site_name: String,
+    /// The favicon, uploaded to the media repository.
+    pub icon: Option<String>,
+    pub creator: String,
+    /// Such as `1:02:03`.
+    pub duration: Option<String>,
+    /// Such as `€49.99 – In stock`.
+    pub price: String,
+    /// Such as `by Jane Doe • 2024-05-01`.
+    pub byline: String,
+    pub latest_entry: Option<LatestEntryContext>,
+    /// The image within the preview, uploaded to the media repository.
+    pub image: Option<ImageContext>,
+    pub player_url: Option<String>,
+    /// Whether the link text doesn't match the destination.
+    pub is_mismatched: bool,
+    /// The debug footer, such as `HTTP 200 · text/html · 120 ms`.
+    pub debug: Option<String>,
+    /// The BCP 47 language tag of the page, if valid.
+    pub language: String,
+    /// `rtl`, `ltr`, or empty if unknown.
+    pub direction: String,
+    #[serde(rename = "type")]
+    pub og_type: String,
+    pub description: String,
+    /// Whether the description is the beginning of a text file, to be shown preformatted.
+    pub is_snippet: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LatestEntryContext {
+    pub title: String,
+    pub url: Option<String>,
+    pub date: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageContext {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Templates {
+    /// Compiles the templates, where an empty one keeps the built-in layout.
+    pub fn new(html: &str, text: &str) -> Result<Templates> {
+        let mut env = Environment::new();
+        // Block tags may have lines of their own, which is easier to read in the config.
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        if !html.is_empty() {
+            env.add_template_owned(HTML_TEMPLATE, html.to_owned())
+                .wrap_err("Invalid preview_template_html")?;
+        }
+        if !text.is_empty() {
+            env.add_template_owned(TEXT_TEMPLATE, text.to_owned())
+                .wrap_err("Invalid preview_template_text")?;
+        }
+        // Unknown filters and functions are only found when rendering.
+        for (name, option) in [
+            (HTML_TEMPLATE, "preview_template_html"),
+            (TEXT_TEMPLATE, "preview_template_text"),
+        ] {
+            if let Ok(template) = env.get_template(name) {
+                template
+                    .render(PreviewContext::default())
+                    .wrap_err_with(|| format!("Invalid {option}"))?;
+            }
+        }
+        Ok(Templates { env })
+    }
+
+    /// Renders a preview in HTML, or returns `None` to use the built-in layout.
+    pub fn render_html(&self, context: &PreviewContext) -> Option<String> {
+        self.render(HTML_TEMPLATE, context)
+    }
+
+    /// Renders a preview in plain text, or returns `None` to use the built-in layout.
+    pub fn render_text(&self, context: &PreviewContext) -> Option<String> {
+        self.render(TEXT_TEMPLATE, context)
+    }
+
+    fn render(&self, name: &str, context: &PreviewContext) -> Option<String> {
+        let template = self.env.get_template(name).ok()?;
+        match template.render(context) {
+            Ok(output) => Some(output),
+            Err(err) => {
+                warn!(
+                    "Failed to render {}, using the built-in layout: {}",
+                    name, err
+                );
+                None
+            }
+        }
+    }
+}
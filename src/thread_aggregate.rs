@@ -0,0 +1,89 @@
+use deadpool_sqlite::Pool;
+use deadpool_sqlite::rusqlite::OptionalExtension;
+use eyre::{Report, Result};
+use matrix_sdk::ruma::{EventId, OwnedEventId, RoomId};
+
+/// The message collecting the previews of a thread, set by `aggregate_thread_previews`.
+pub struct ThreadAggregate {
+    pub response_id: OwnedEventId,
+    /// The previews already in the message, empty while it's still a placeholder.
+    pub text: String,
+    pub html: String,
+}
+
+/// Finds the message collecting the previews of the thread rooted at `thread_id`.
+pub async fn get(
+    db: &Pool,
+    room_id: &RoomId,
+    thread_id: &EventId,
+) -> Result<Option<ThreadAggregate>> {
+    let stmt_query = "SELECT response_id, text, html FROM thread_aggregates WHERE room_id = ? AND thread_id = ?;";
+    let conn = db.get().await?;
+
+    let params = (room_id.to_string(), thread_id.to_string());
+    let row = conn
+        .interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            Ok::<_, Report>(
+                stmt.query_row(params, |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })
+                .optional()?,
+            )
+        })
+        .await
+        .unwrap()?;
+    row.map(|(response_id, text, html)| {
+        Ok(ThreadAggregate {
+            response_id: response_id.try_into()?,
+            text,
+            html,
+        })
+    })
+    .transpose()
+}
+
+/// Makes `aggregate` the message collecting the previews of the thread, replacing the previous
+/// one.
+pub async fn insert(
+    db: &Pool,
+    room_id: &RoomId,
+    thread_id: &EventId,
+    aggregate: &ThreadAggregate,
+) -> Result<()> {
+    let stmt_insert = "INSERT OR REPLACE INTO thread_aggregates (room_id, thread_id, response_id, text, html) VALUES (?, ?, ?, ?, ?);";
+    let conn = db.get().await?;
+
+    let params = (
+        room_id.to_string(),
+        thread_id.to_string(),
+        aggregate.response_id.to_string(),
+        aggregate.text.clone(),
+        aggregate.html.clone(),
+    );
+    conn.interact(move |conn| {
+        conn.prepare_cached(stmt_insert)?.execute(params)?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Forgets the message collecting the previews of the thread, so the next preview starts a new
+/// one.
+pub async fn remove(db: &Pool, room_id: &RoomId, thread_id: &EventId) -> Result<()> {
+    let stmt_delete = "DELETE FROM thread_aggregates WHERE room_id = ? AND thread_id = ?;";
+    let conn = db.get().await?;
+
+    let params = (room_id.to_string(), thread_id.to_string());
+    conn.interact(move |conn| {
+        conn.prepare_cached(stmt_delete)?.execute(params)?;
+        Ok::<_, Report>(())
+    })
+    .await
+    .unwrap()
+}
@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+
+/// Removes the decorations some sites stuff their titles with for SEO, such as
+/// "🔥🔥 BEST deal ▷▷ Shop now".
+///
+/// Runs of two or more emoji or symbols are removed, and runs of separators are collapsed into
+/// one. A single emoji or separator is kept, as it's likely meaningful.
+pub fn clean(title: &str) -> String {
+    let mut result = String::with_capacity(title.len());
+    let mut chars = title.chars().peekable();
+    while let Some(c) = chars.next() {
+        if !is_decoration(c) && !is_separator(c) {
+            result.push(c);
+            continue;
+        }
+        let mut run = String::from(c);
+        while let Some(&c) = chars.peek()
+            && (is_decoration(c) || is_separator(c) || is_joiner(c) || c.is_whitespace())
+        {
+            run.push(c);
+            chars.next();
+        }
+        // Emoji joined by ZWJ count as one
+        let symbols = run
+            .chars()
+            .zip(run.chars().skip(1))
+            .filter(|&(_, c)| !is_joiner(c) && !c.is_whitespace())
+            .filter(|&(prev, _)| prev != '\u{200d}')
+            .count()
+            + 1;
+        if symbols <= 1 {
+            result.push_str(&run);
+        } else if let Some(separator) = run.chars().find(|&c| is_separator(c)) {
+            result.push(' ');
+            result.push(separator);
+            result.push(' ');
+        } else {
+            result.push(' ');
+        }
+    }
+    result
+        .trim_matches(|c: char| c.is_whitespace() || is_separator(c))
+        .to_owned()
+}
+
+/// Emoji and pictographs.
+fn is_decoration(c: char) -> bool {
+    matches!(c,
+        '\u{2600}'..='\u{27bf}' // Miscellaneous Symbols, Dingbats
+        | '\u{2b00}'..='\u{2bff}' // Miscellaneous Symbols and Arrows
+        | '\u{1f000}'..='\u{1faff}' // Emoji
+    )
+}
+
+/// Characters used to separate the parts of a title.
+fn is_separator(c: char) -> bool {
+    matches!(c,
+        '|' | '¦' | '-' | '~' | '•' | '·' | '»' | '«' | '›' | '‹' | '–' | '—' | '―'
+        | '\u{2190}'..='\u{21ff}' // Arrows
+        | '\u{25a0}'..='\u{25ff}' // Geometric Shapes
+    )
+}
+
+/// Characters modifying the previous emoji, which don't count on their own.
+fn is_joiner(c: char) -> bool {
+    matches!(c, '\u{200d}' | '\u{20e3}' | '\u{fe0e}' | '\u{fe0f}')
+}
+
+/// How much of the shorter text's words also appear in the other, from 0 to 1.
+///
+/// Case and punctuation are ignored, so "Rust 1.80 is out!" and "Rust 1.80 is out | Rust Blog"
+/// are fully similar.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let words = |text: &str| {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect::<HashSet<_>>()
+    };
+    let (a, b) = (words(a), words(b));
+    let shorter = a.len().min(b.len());
+    if shorter == 0 {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f64 / shorter as f64
+}
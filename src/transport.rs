@@ -0,0 +1,153 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use eyre::{Result, bail};
+use moka::future::{Cache, CacheBuilder};
+use moka::policy::EvictionPolicy;
+use tracing::error;
+use url::Url;
+
+use crate::classify::{self, UrlClass};
+use crate::common::{MAX_REDIRECTS, MAX_TRANSPORT_CLIENTS};
+use crate::config::{Config, TransportProfile};
+
+/// The HTTP clients for URL preview requests, one for each transport profile in
+/// `domain_transports`, so requests to a domain reuse its connections.
+pub struct ClientRegistry {
+    config: Arc<Config>,
+    /// The client of the domains without a profile.
+    default: reqwest::Client,
+    /// Built when first needed, and dropped when the least recently used if there are too many.
+    clients: Cache<TransportProfile, reqwest::Client>,
+    /// The hosts `rewrite_url` rules point to, which may be on the local network on purpose.
+    rewrite_targets: Arc<HashSet<String>>,
+}
+
+impl ClientRegistry {
+    pub fn new(config: Arc<Config>) -> Result<ClientRegistry> {
+        let rewrite_targets = Arc::new(
+            config
+                .rewrite_url
+                .iter()
+                .filter_map(|[_, replacement]| Url::parse(replacement).ok())
+                .filter_map(|url| url.host_str().map(str::to_owned))
+                .collect::<HashSet<_>>(),
+        );
+        let default = build(&config, &TransportProfile::default(), &rewrite_targets)?;
+        let clients = CacheBuilder::new(MAX_TRANSPORT_CLIENTS)
+            .eviction_policy(EvictionPolicy::lru())
+            .build();
+        Ok(ClientRegistry {
+            config,
+            default,
+            clients,
+            rewrite_targets,
+        })
+    }
+
+    pub fn default_client(&self) -> &reqwest::Client {
+        &self.default
+    }
+
+    /// Returns the client for the transport profile of the host of `url`.
+    ///
+    /// Fails for hosts on the local network, whichever page or message the URL came from, so
+    /// users can't make the bot reach them, unless a rewrite rule points there.
+    pub async fn get(&self, url: &Url) -> Result<reqwest::Client> {
+        if is_forbidden(url, &self.rewrite_targets) {
+            bail!("Internal host");
+        }
+        let Some(profile) = url
+            .host_str()
+            .and_then(|host| self.config.transport_profile(host))
+        else {
+            return Ok(self.default.clone());
+        };
+        Ok(self
+            .clients
+            .try_get_with_by_ref(profile, async {
+                build(&self.config, profile, &self.rewrite_targets)
+            })
+            .await
+            .unwrap_or_else(|err| {
+                error!("Failed to build HTTP client: {}", err);
+                self.default.clone()
+            }))
+    }
+}
+
+/// Builds a client from the `crawler_*` options, with the fields set in `profile` replacing them.
+fn build(
+    config: &Config,
+    profile: &TransportProfile,
+    rewrite_targets: &Arc<HashSet<String>>,
+) -> Result<reqwest::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::ACCEPT_LANGUAGE,
+        config.crawler_accept_language.parse()?,
+    );
+    if !config.crawler_contact.is_empty() {
+        headers.insert(reqwest::header::FROM, config.crawler_contact.parse()?);
+    }
+    let user_agent = if profile.user_agent.is_empty() {
+        identified_user_agent(config)
+    } else {
+        profile.user_agent.clone()
+    };
+    let proxy = if profile.proxy.is_empty() {
+        &config.crawler_proxy
+    } else {
+        &profile.proxy
+    };
+    let mut builder = reqwest::ClientBuilder::new()
+        .default_headers(headers)
+        .user_agent(user_agent)
+        .connect_timeout(config.crawler_connect_timeout)
+        .redirect(redirect_policy(rewrite_targets.clone()));
+    match proxy.as_str() {
+        "" => (),
+        "none" => builder = builder.no_proxy(),
+        proxy => builder = builder.proxy(reqwest::Proxy::all(proxy)?),
+    }
+    Ok(builder.build()?)
+}
+
+fn is_forbidden(url: &Url, rewrite_targets: &HashSet<String>) -> bool {
+    classify::classify(url) == UrlClass::Internal
+        && !url
+            .host_str()
+            .is_some_and(|host| rewrite_targets.contains(host))
+}
+
+/// Follows redirects like reqwest does by default, but not to hosts on the local network, so a
+/// public URL can't lead the bot to one.
+fn redirect_policy(rewrite_targets: Arc<HashSet<String>>) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("Too many redirects")
+        } else if is_forbidden(attempt.url(), &rewrite_targets) {
+            attempt.error("Redirected to an internal host")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Appends `crawler_identity` and `crawler_info_url` to `crawler_user_agent` as a comment, such as
+/// `Mozilla/5.0 (...) (example.org previewer; +https://example.org/bot)`, so sites can tell
+/// deployments apart and look up how to verify them.
+fn identified_user_agent(config: &Config) -> String {
+    let mut comment = Vec::new();
+    if !config.crawler_identity.is_empty() {
+        comment.push(config.crawler_identity.clone());
+    }
+    if !config.crawler_info_url.is_empty() {
+        comment.push(format!("+{}", config.crawler_info_url));
+    }
+    if comment.is_empty() {
+        config.crawler_user_agent.clone()
+    } else {
+        format!("{} ({})", config.crawler_user_agent, comment.join("; "))
+    }
+}
@@ -1,48 +1,166 @@
 use image::ImageReader;
-use matrix_sdk::attachment::{AttachmentConfig, Thumbnail};
-use std::borrow::Cow;
+use matrix_sdk::attachment::{
+    AttachmentConfig, AttachmentInfo, BaseAudioInfo, BaseVideoInfo, Thumbnail,
+};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::Cursor;
 use std::str::FromStr;
-use std::sync::{Arc, LazyLock};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use deadpool_sqlite::rusqlite::OptionalExtension;
 use deadpool_sqlite::{Pool, Runtime};
 use encoding_rs::Encoding;
-use eyre::{Report, Result};
+use eyre::{Report, Result, eyre};
 use indexmap::IndexSet;
-use matrix_sdk::Room;
-use matrix_sdk::ruma::events::Mentions;
-use matrix_sdk::ruma::events::relation::{Replacement, Thread};
-use matrix_sdk::ruma::events::room::message::{Relation, RoomMessageEventContentWithoutRelation};
-use matrix_sdk::ruma::{EventId, OwnedEventId, UInt};
+use matrix_sdk::room::reply::{EnforceThread, Reply};
+use matrix_sdk::ruma::api::client::error::{ErrorKind, RetryAfter};
+use matrix_sdk::ruma::api::client::message::send_message_event;
+use matrix_sdk::ruma::events::relation::{InReplyTo, Reference, Replacement, Thread};
+use matrix_sdk::ruma::events::room::history_visibility::HistoryVisibility;
+use matrix_sdk::ruma::events::room::message::{
+    MessageType, Relation, RoomMessageEventContent, RoomMessageEventContentWithoutRelation,
+};
+use matrix_sdk::ruma::events::{
+    AnySyncMessageLikeEvent, AnySyncTimelineEvent, Mentions, MessageLikeEventContent,
+    StateEventType, SyncMessageLikeEvent,
+};
+use matrix_sdk::ruma::{
+    EventId, OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedRoomOrAliasId, OwnedUserId, RoomId,
+    TransactionId, UInt, UserId,
+};
+use matrix_sdk::sync::SyncResponse;
+use matrix_sdk::{
+    Client, EncryptionState, HttpError, Room, RoomMemberships, RoomState, RumaApiError,
+};
 use mime::Mime;
 use moka::future::{Cache, CacheBuilder};
+use moka::notification::RemovalCause;
 use regex::Regex;
-use scraper::{Html, Selector};
-use tracing::{Instrument, debug, error, info, instrument, warn};
+use tokio::task::AbortHandle;
+use tracing::{Span, debug, error, info, instrument, warn};
 use url::Url;
 
-use crate::common::{MAX_RESPONSE_TEXT_CHARS, MAX_URL_COUNTS_PER_MESSAGE, SAFE_URL_LENGTH};
-use crate::{config, html_escape, limit};
+use crate::classify::UrlClass;
+use crate::commands::{Command, Permission};
+use crate::common::{
+    DIGEST_CHECK_INTERVAL, HEAD_UNSUPPORTED_TTL, INLINE_PARSE_MAX_BYTES,
+    MAX_ACCEPT_LANGUAGE_LENGTH, MAX_FAVICON_BYTES, MAX_PDF_PROBE_BYTES, MAX_PREVIEW_CONTENT_BYTES,
+    MAX_RESPONSE_TEXT_CHARS, MAX_ROOM_BLOCKED_URLS, MAX_URL_COUNTS_PER_MESSAGE,
+    MEDIA_LINK_PROBE_BYTES, MIN_DESCRIBED_LINK_CHARS, PLAYABLE_VIDEO_TYPES, SAFE_URL_LENGTH,
+    SEND_MAX_ATTEMPTS, SEND_MIN_BACKOFF, SHUTDOWN_TIMEOUT, TEXT_SNIPPET_PROBE_BYTES,
+    THREAD_PREVIEW_WINDOW,
+};
+use crate::config::{PreviewField, StyleProfile};
+use crate::event_queue::EventQueue;
+use crate::extract_url::MessageLinks;
+use crate::message_store::MessageStore;
+use crate::metrics::{self, CacheSnapshot, Metrics, QueueSnapshot};
+use crate::opengraph::{self, FetchInfo, OpenGraph};
+use crate::oracle::{
+    PreviewData, PreviewRequestEventContent, PreviewResponseEventContent, UrlPreviewEventContent,
+};
+use crate::receipts::ReceiptTracker;
+use crate::render::{self, CardLayout, InlineImage, RenderOptions};
+#[cfg(feature = "rewrite")]
+use crate::rewrite::Rewriter;
+use crate::room_settings::RoomSettings;
+use crate::scheduler::{Priority, Scheduler};
+#[cfg(feature = "postgres")]
+use crate::storage::PostgresStorage;
+use crate::storage::{SqliteStorage, Storage};
+use crate::tasks::Supervisor;
+use crate::thread_aggregate::ThreadAggregate;
+use crate::transport::ClientRegistry;
+use crate::{
+    charset, classify, commands, config, digest, disk_cache, domain, extract_url, feed, feedback,
+    html_escape, i18n, limit, media_link, outbox, pinned, preview_log, redact, room_cleanup,
+    settings_sync, snippet, thread_aggregate, title,
+};
 
 pub struct Worker {
-    cache: Cache<Url, Option<OpenGraph>>,
+    bridge_namespaces: Vec<Regex>,
+    cache: Cache<CacheKey, Option<OpenGraph>>,
     config: Arc<config::Config>,
     db: Pool,
-    reqwest_client: reqwest::Client,
-    rewrite_url: Vec<(Regex, String)>,
+    dedup: Option<Cache<DedupKey, DedupEntry>>,
+    /// Hosts that failed a HEAD request, so only GET is used for them.
+    head_unsupported: Cache<String, ()>,
+    /// The uploaded icons of sites, by the URL of the icon, or `None` if it couldn't be fetched.
+    favicons: Cache<Url, Option<OwnedMxcUri>>,
+    messages: MessageStore,
+    metrics: Arc<Metrics>,
+    receipts: Arc<ReceiptTracker>,
+    refresh_cooldown: Cache<OwnedUserId, ()>,
+    clients: ClientRegistry,
+    #[cfg(feature = "rewrite")]
+    rewriter: Rewriter,
+    scheduler: Arc<Scheduler>,
+    /// The settings of each room, invalidated whenever they change.
+    settings: Cache<OwnedRoomId, RoomSettings>,
+    tasks: Arc<Supervisor>,
+    /// Held while appending to the messages collecting the previews of threads, so concurrent
+    /// previews don't overwrite each other.
+    thread_aggregates: tokio::sync::Mutex<()>,
+    /// How many previews were posted in each thread within the current window.
+    thread_previews: Cache<(OwnedRoomId, OwnedEventId), Arc<AtomicUsize>>,
+    /// Messages received, but not handled yet.
+    incoming: EventQueue<IncomingMessage>,
 }
 
-#[derive(Clone, Debug)]
-struct OpenGraphMedia {
-    pub url: String,
-    pub thumb_url: Option<String>,
-    pub content_type: String,
+/// A message with URLs, waiting in the event queue.
+pub struct IncomingMessage {
+    pub room: Room,
+    pub sender: OwnedUserId,
+    pub thread_id: Option<OwnedEventId>,
+    pub original_event_id: OwnedEventId,
+    /// Whether the message replaces an earlier one, which may have a preview to update.
+    pub is_edit: bool,
+    pub links: MessageLinks,
+}
+
+/// The room, the user who posted the URLs, or `None` for anyone, and the hash of the URLs.
+type DedupKey = (OwnedRoomId, Option<OwnedUserId>, u64);
+
+/// URLs recently posted in a room, to avoid previewing them twice.
+#[derive(Clone)]
+struct DedupEntry {
+    is_bridged: bool,
+    /// The preview, if the URLs were posted in a thread.
+    thread_response_id: Option<OwnedEventId>,
+}
+
+/// A placeholder to fill in with the URL preview.
+struct PreviewJob {
+    room: Room,
+    original_event_id: OwnedEventId,
+    original_event_link: String,
+    response_id: OwnedEventId,
+    is_edit: bool,
+    priority: Priority,
+    links: MessageLinks,
+    /// The thread whose previews are collected in `response_id`, which the preview is appended
+    /// to, if `aggregate_thread_previews` is set.
+    thread_id: Option<OwnedEventId>,
+}
+
+/// Previews are shared across rooms only if they were fetched the same way.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    url: Url,
+    accept_language: String,
+    handler: &'static str,
+}
+
+impl CacheKey {
+    /// The key of the preview in the disk cache.
+    fn to_disk_key(&self) -> String {
+        format!("{}\n{}\n{}", self.handler, self.accept_language, self.url)
+    }
 }
 
 #[derive(Clone, Debug)]
 struct EmbedMedia {
-    pub url: String,
     pub data: Vec<u8>,
     pub thumb_data: Option<Vec<u8>>,
     pub filename: String,
@@ -50,28 +168,42 @@ struct EmbedMedia {
     pub thumb_content_type: Option<Mime>,
 }
 
-#[derive(Clone, Debug)]
-struct OpenGraph {
-    pub description: String,
-    pub site_name: String,
-    pub title: String,
-    pub url: String,
-    pub media_urls: Vec<OpenGraphMedia>,
-}
-
 impl Worker {
+    pub fn config(&self) -> &config::Config {
+        &self.config
+    }
+
+    /// Opens the database. The tables are created by [`Worker::new`].
+    pub fn open_db(config: &config::Config) -> Result<Pool> {
+        let db_config = deadpool_sqlite::Config::new(config.data_dir.join("url-previewer.sqlite3"));
+        Ok(db_config.create_pool(Runtime::Tokio1)?)
+    }
+
     #[instrument(skip_all)]
     pub async fn new(config: Arc<config::Config>) -> Result<Arc<Worker>> {
+        let metrics = Arc::new(Metrics::new());
         let cache = CacheBuilder::new(config.cache_entries)
             .time_to_live(config.cache_duration)
+            .eviction_listener({
+                let metrics = metrics.clone();
+                move |_, _, cause| {
+                    metrics.record_cache_eviction(match cause {
+                        RemovalCause::Expired => "expired",
+                        RemovalCause::Explicit => "explicit",
+                        RemovalCause::Replaced => "replaced",
+                        RemovalCause::Size => "size",
+                    })
+                }
+            })
             .build();
 
-        let db_config = deadpool_sqlite::Config::new(config.data_dir.join("url-previewer.sqlite3"));
-        let db = db_config.create_pool(Runtime::Tokio1)?;
+        let scheduler = Scheduler::new(config.max_concurrent_previews);
+        let tasks = Arc::new(Supervisor::new(metrics.clone()));
+        let incoming = EventQueue::new(config.event_queue_size, config.event_queue_overflow);
+        let db = Self::open_db(&config)?;
         let conn = db.get().await?;
         conn.interact(|conn| {
-            conn.execute_batch(
-                "PRAGMA journal_mode = WAL;
+            let schema = "PRAGMA journal_mode = WAL;
 PRAGMA optimize = 0x10002;
 BEGIN TRANSACTION;
 CREATE TABLE IF NOT EXISTS messages (
@@ -79,222 +211,2197 @@ CREATE TABLE IF NOT EXISTS messages (
     room_id TEXT NOT NULL,
     event_id TEXT NOT NULL,
     response_id TEXT NOT NULL,
+    urls_hash INTEGER,
+    UNIQUE(room_id, event_id)
+);
+CREATE INDEX IF NOT EXISTS messages_response_id ON messages (room_id, response_id);
+CREATE TABLE IF NOT EXISTS event_claims (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
     UNIQUE(room_id, event_id)
 );
+CREATE INDEX IF NOT EXISTS event_claims_timestamp ON event_claims (timestamp);
+CREATE TABLE IF NOT EXISTS room_settings (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    UNIQUE(room_id, key)
+);
+CREATE TABLE IF NOT EXISTS pending_jobs (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    original_event_link TEXT NOT NULL,
+    urls TEXT NOT NULL,
+    UNIQUE(room_id, response_id)
+);
+CREATE TABLE IF NOT EXISTS forget_failures (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    UNIQUE(room_id)
+);
+CREATE TABLE IF NOT EXISTS preview_sources (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    handler TEXT NOT NULL,
+    url TEXT NOT NULL DEFAULT '',
+    timestamp INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(room_id, response_id, domain, handler, url)
+);
+CREATE TABLE IF NOT EXISTS feedback (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    reaction_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    handler TEXT NOT NULL,
+    vote INTEGER NOT NULL,
+    UNIQUE(room_id, response_id, user_id, domain, handler)
+);
+CREATE TABLE IF NOT EXISTS preview_log (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    response_id TEXT,
+    state TEXT NOT NULL,
+    detail TEXT NOT NULL,
+    timestamp INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS preview_log_event_id ON preview_log (event_id);
+CREATE INDEX IF NOT EXISTS preview_log_response_id ON preview_log (response_id);
+CREATE INDEX IF NOT EXISTS preview_log_timestamp ON preview_log (timestamp);
+CREATE TABLE IF NOT EXISTS preview_cache (
+    id INTEGER PRIMARY KEY NOT NULL,
+    key TEXT NOT NULL,
+    url TEXT NOT NULL,
+    data BLOB NOT NULL,
+    size INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    accessed INTEGER NOT NULL,
+    UNIQUE(key)
+);
+CREATE INDEX IF NOT EXISTS preview_cache_url ON preview_cache (url);
+CREATE INDEX IF NOT EXISTS preview_cache_accessed ON preview_cache (accessed);
+CREATE TABLE IF NOT EXISTS pinned_previews (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    original_event_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    UNIQUE(room_id, response_id)
+);
+CREATE TABLE IF NOT EXISTS unsent_previews (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    original_event_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    urls_hash INTEGER,
+    content TEXT NOT NULL,
+    UNIQUE(room_id, response_id)
+);
+CREATE TABLE IF NOT EXISTS thread_aggregates (
+    id INTEGER PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    thread_id TEXT NOT NULL,
+    response_id TEXT NOT NULL,
+    text TEXT NOT NULL,
+    html TEXT NOT NULL,
+    UNIQUE(room_id, thread_id)
+);
 COMMIT;
 PRAGMA optimize;
-",
-            )?;
+";
+            conn.execute_batch(schema)?;
+            // Added after the first release
+            for (table, column, definition) in [
+                ("messages", "urls_hash", "INTEGER"),
+                ("preview_sources", "url", "TEXT NOT NULL DEFAULT ''"),
+                ("preview_sources", "timestamp", "INTEGER NOT NULL DEFAULT 0"),
+            ] {
+                let has_column = conn.query_row(
+                    "SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?;",
+                    (table, column),
+                    |row| row.get::<_, i64>(0),
+                )? != 0;
+                if !has_column {
+                    conn.execute_batch(&format!(
+                        "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+                    ))?;
+                }
+            }
+            // Sources used to be unique per domain and handler of a preview, rather than per URL.
+            let is_per_domain = conn.query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'preview_sources' AND sql LIKE '%UNIQUE(room_id, response_id, domain, handler)%';",
+                (),
+                |row| row.get::<_, i64>(0),
+            )? != 0;
+            if is_per_domain {
+                conn.execute_batch("ALTER TABLE preview_sources RENAME TO preview_sources_old;")?;
+                conn.execute_batch(schema)?;
+                conn.execute_batch(
+                    "BEGIN TRANSACTION;
+INSERT INTO preview_sources (room_id, response_id, domain, handler, url, timestamp)
+SELECT room_id, response_id, domain, handler, url, timestamp FROM preview_sources_old;
+DROP TABLE preview_sources_old;
+COMMIT;",
+                )?;
+            }
             Ok::<_, Report>(())
         })
         .await
         .unwrap()?;
 
-        let mut reqwest_headers = reqwest::header::HeaderMap::new();
-        reqwest_headers.insert(
-            reqwest::header::ACCEPT_LANGUAGE,
-            config.crawler_accept_language.parse()?,
-        );
-        let mut reqwest_builder = reqwest::ClientBuilder::new()
-            .default_headers(reqwest_headers)
-            .user_agent(&config.crawler_user_agent);
-        if !config.crawler_proxy.is_empty() {
-            reqwest_builder = reqwest_builder.proxy(reqwest::Proxy::all(&config.crawler_proxy)?);
+        let storage: Arc<dyn Storage> = if config.database_url.is_empty() {
+            Arc::new(SqliteStorage::new(db.clone()))
+        } else if config.database_url.starts_with("postgres://")
+            || config.database_url.starts_with("postgresql://")
+        {
+            #[cfg(feature = "postgres")]
+            {
+                Arc::new(PostgresStorage::new(&config.database_url).await?)
+            }
+            #[cfg(not(feature = "postgres"))]
+            eyre::bail!(
+                "PostgreSQL support is not enabled, please rebuild with `--features postgres`."
+            );
+        } else {
+            eyre::bail!("Unsupported database URL: {}", config.database_url);
+        };
+        let messages = MessageStore::new(storage, config.cache_entries);
+        let receipts = ReceiptTracker::new(config.read_receipt_interval);
+
+        let clients = ClientRegistry::new(config.clone())?;
+        domain::load_public_suffix_list(&config.data_dir);
+        redact::set_enabled(config.redact_logs);
+
+        #[cfg(feature = "rewrite")]
+        let rewriter = Rewriter::new(&config)?;
+        #[cfg(not(feature = "rewrite"))]
+        if !config.rewrite_url.is_empty() || !config.unwrap_redirects.is_empty() {
+            warn!("URL rewrite rules are ignored, please rebuild with `--features rewrite`.");
         }
-        let reqwest_client = reqwest_builder.build()?;
 
-        let rewrite_url = config
-            .rewrite_url
+        let bridge_namespaces = config
+            .bridge_namespaces
             .iter()
-            .map(|[from, to]| Ok((Regex::new(from)?, to.clone())))
+            .map(|namespace| Ok(Regex::new(namespace)?))
             .collect::<Result<Vec<_>>>()?;
+        let dedup = config.dedup_window.map(|dedup_window| {
+            CacheBuilder::new(config.cache_entries)
+                .time_to_live(dedup_window)
+                .build()
+        });
+
+        let settings = CacheBuilder::new(config.cache_entries).build();
+
+        let refresh_cooldown = CacheBuilder::new(config.cache_entries)
+            .time_to_live(config.refresh_cooldown)
+            .build();
 
-        Ok(Arc::new(Worker {
+        let favicons = CacheBuilder::new(config.cache_entries)
+            .time_to_live(config.cache_duration)
+            .build();
+
+        let head_unsupported = CacheBuilder::new(config.cache_entries)
+            .time_to_live(HEAD_UNSUPPORTED_TTL)
+            .build();
+
+        let thread_previews = CacheBuilder::new(config.cache_entries)
+            .time_to_live(THREAD_PREVIEW_WINDOW)
+            .build();
+
+        let worker = Arc::new(Worker {
+            bridge_namespaces,
             cache,
+            clients,
             config,
             db,
-            reqwest_client,
-            rewrite_url,
-        }))
+            dedup,
+            favicons,
+            head_unsupported,
+            messages,
+            metrics,
+            receipts,
+            refresh_cooldown,
+            #[cfg(feature = "rewrite")]
+            rewriter,
+            scheduler,
+            settings,
+            tasks,
+            thread_aggregates: tokio::sync::Mutex::new(()),
+            thread_previews,
+            incoming,
+        });
+
+        if !worker.config.metrics_listen.is_empty() {
+            let render = {
+                let worker = Arc::downgrade(&worker);
+                Arc::new(move || {
+                    worker
+                        .upgrade()
+                        .map(|worker| {
+                            worker
+                                .metrics
+                                .openmetrics(&worker.cache_snapshot(), &worker.queue_snapshot())
+                        })
+                        .unwrap_or_default()
+                })
+            };
+            let listen = worker.config.metrics_listen.clone();
+            let tasks = worker.tasks.clone();
+            worker.spawn_service("metrics", async move {
+                metrics::serve(&listen, render, tasks).await
+            });
+        }
+        if worker.receipts.enabled() {
+            worker.spawn_service("receipts", worker.receipts.clone().run());
+        }
+        worker.spawn_service(
+            "public_suffix_list",
+            domain::refresh_public_suffix_list(
+                worker.config.data_dir.clone(),
+                worker.clients.default_client().clone(),
+            ),
+        );
+        worker.spawn_service("event_queue", worker.clone().handle_messages());
+        worker.spawn_service("event_claims", {
+            let worker = worker.clone();
+            async move { worker.messages.expire_claims().await }
+        });
+        Ok(worker)
+    }
+
+    /// Runs a task in the background, logging and counting its errors and panics.
+    pub fn spawn<F>(&self, label: &'static str, task: F) -> AbortHandle
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.tasks.spawn(label, task)
+    }
+
+    /// Runs a task that never finishes by itself, cancelling it on shutdown.
+    pub fn spawn_service<F>(&self, label: &'static str, task: F) -> AbortHandle
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.tasks.spawn_service(label, task)
+    }
+
+    fn spawn_preview(self: Arc<Self>, job: PreviewJob) {
+        let worker = self.clone();
+        self.spawn("preview", async move {
+            let room = job.room.clone();
+            let original_event_id = job.original_event_id.clone();
+            let original_event_link = job.original_event_link.clone();
+            let response_id = job.response_id.clone();
+            let is_edit = job.is_edit;
+            let answered = AtomicBool::new(false);
+            // The requests have their own timeouts, but not the retries, the media, or the
+            // database, so a stuck preview would hold its scheduler slot forever.
+            let preview = worker.clone().create_url_preview(job, &answered);
+            if tokio::time::timeout(worker.config.preview_timeout, preview)
+                .await
+                .is_err()
+            {
+                worker.metrics.record_error("preview_timeout");
+                let is_private = worker.is_private_room(&room);
+                redact::scope(
+                    is_private,
+                    worker.abandon_url_preview(
+                        &room,
+                        &original_event_id,
+                        &original_event_link,
+                        &response_id,
+                        is_edit || answered.load(Ordering::Relaxed),
+                    ),
+                )
+                .await;
+            }
+            Ok(())
+        });
+    }
+
+    /// Waits for the running tasks, such as previews being made, to finish, then for their
+    /// responses and read receipts to be saved.
+    pub async fn shutdown(&self) {
+        self.tasks.shutdown(SHUTDOWN_TIMEOUT).await;
+        self.messages.shutdown().await;
+        self.receipts.flush().await;
+    }
+
+    /// Whether to handle the messages sent from our own account, for testing.
+    ///
+    /// Our previews are notices, and their deletions don't match any message, so they are never
+    /// handled again.
+    pub fn previews_own_messages(&self, room: &Room) -> bool {
+        self.config.preview_own_messages
+            && (self.config.test_rooms.is_empty()
+                || self
+                    .config
+                    .test_rooms
+                    .iter()
+                    .any(|room_id| room_id == room.room_id()))
+    }
+
+    pub fn is_oracle_room(&self, room: &Room) -> bool {
+        self.config
+            .oracle_rooms
+            .iter()
+            .any(|room_id| room_id == room.room_id())
+    }
+
+    fn cache_snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            entry_count: self.cache.entry_count(),
+            weighted_size: self.cache.weighted_size(),
+            capacity: self.config.cache_entries,
+        }
+    }
+
+    fn queue_snapshot(&self) -> QueueSnapshot {
+        let (running, waiting) = self.scheduler.load();
+        QueueSnapshot {
+            running,
+            waiting,
+            events: self.incoming.queued(),
+            events_capacity: self.incoming.capacity(),
+        }
+    }
+
+    /// Queues a message to handle, so the sync loop doesn't wait for the database and the
+    /// placeholder.
+    pub async fn enqueue_message(&self, message: IncomingMessage) {
+        let Some(dropped) = self.incoming.push(message) else {
+            return;
+        };
+        warn!(
+            "Event queue is full, dropping message {}.",
+            dropped.original_event_id
+        );
+        self.metrics.record_event_dropped();
+        self.log_preview(
+            dropped.room.room_id(),
+            &dropped.original_event_id,
+            None,
+            preview_log::State::Skipped,
+            "Event queue full",
+        )
+        .await;
+    }
+
+    /// Handles the queued messages in order, so edits never overtake their originals.
+    async fn handle_messages(self: Arc<Self>) -> Result<()> {
+        loop {
+            let message = self.incoming.pop().await;
+            if let Err(err) = self
+                .clone()
+                .on_message(
+                    message.room,
+                    &message.sender,
+                    message.thread_id,
+                    message.original_event_id,
+                    message.is_edit,
+                    message.links,
+                )
+                .await
+            {
+                error!("Failed to handle message: {}", err);
+            }
+        }
+    }
+
+    pub fn record_sync(&self, response: &SyncResponse) {
+        self.metrics.record_sync(response.to_device.len());
     }
 
     #[instrument(skip_all)]
     pub async fn on_message(
         self: Arc<Self>,
         room: Room,
+        sender: &UserId,
         thread_id: Option<OwnedEventId>,
         original_event_id: OwnedEventId,
-        urls: IndexSet<Url>,
+        is_edit: bool,
+        mut links: MessageLinks,
     ) -> Result<Option<OwnedEventId>> {
-        let stmt_query = "SELECT response_id FROM messages WHERE room_id = ? AND event_id = ?;";
-        let stmt_insert =
-            "INSERT OR REPLACE INTO messages (room_id, event_id, response_id) VALUES (?, ?, ?)";
-        let conn = self.db.get().await?;
+        self.remove_blocked_urls(room.room_id(), &mut links).await?;
+        let urls = &links.urls;
+        // Most messages have no URLs, and only edits may have a preview to remove.
+        if !is_edit && urls.is_empty() {
+            return Ok(None);
+        }
+        let response = self
+            .messages
+            .get_response(room.room_id(), &original_event_id)
+            .await?;
 
-        let room_id_str = room.room_id().to_string();
-        let original_event_id_str = original_event_id.to_string();
-        let response_id = conn
-            .interact(move |conn| {
-                let mut stmt = conn.prepare_cached(stmt_query)?;
-                Ok::<_, Report>(
-                    stmt.query_row((room_id_str, original_event_id_str), |row| {
-                        row.get::<_, String>(0)
-                    })
-                    .optional()?,
-                )
-            })
-            .await
-            .unwrap()?;
+        let original_event_link = Self::event_link(&room, &original_event_id).await;
 
-        // This is basically `room.matrix_to_event_permalink`, but can't fail.
-        let original_event_link = room
-            .room_id()
-            .matrix_to_event_uri_via(
-                original_event_id.clone(),
-                room.route().await.unwrap_or_default(),
+        let mut aggregate_thread_id = None;
+        let (response_id, is_edit) = if let Some(response) = response {
+            // Edits that don't change the URLs, such as fixing a typo, keep the preview as is.
+            if response.urls_hash == Some(Self::urls_hash(urls)) {
+                debug!("URLs are unchanged, keeping the preview.");
+                return Ok(Some(response.response_id));
+            }
+            self.log_preview(
+                room.room_id(),
+                &original_event_id,
+                Some(&response.response_id),
+                preview_log::State::Edited,
+                "",
             )
-            .to_string();
-
-        let (response_id, is_edit) = if let Some(response_id) = response_id {
-            (OwnedEventId::try_from(response_id)?, true)
+            .await;
+            (response.response_id, true)
         } else if urls.is_empty() {
             return Ok(None);
         } else {
-            let relates_to = match thread_id {
-                Some(thread_id) => Some(Relation::Thread(Thread::plain(
-                    thread_id,
-                    original_event_id.to_owned(),
-                ))),
-                _ => None,
+            // After a reconnect, events may be redelivered before their responses are saved.
+            if !self
+                .messages
+                .claim(room.room_id(), &original_event_id)
+                .await?
+            {
+                info!("Skipping redelivered event {}.", original_event_id);
+                return Ok(None);
+            }
+            // With double puppeting or bridge echoes, the same user posts the same URLs twice in
+            // quick succession, where either copy comes from a bridge.
+            let real_sender = self.real_sender(&room, sender).await;
+            let dedup_key = Self::dedup_key(room.room_id(), Some(&real_sender), urls);
+            let thread_key = Self::dedup_key(room.room_id(), None, urls);
+            let is_bridged = self.is_bridged(sender);
+            let (earlier, thread_response_id) = match &self.dedup {
+                Some(dedup) => (
+                    dedup.get(&dedup_key).await,
+                    dedup
+                        .get(&thread_key)
+                        .await
+                        .and_then(|earlier| earlier.thread_response_id),
+                ),
+                None => (None, None),
             };
+            if let Some(earlier) = &earlier
+                && (earlier.is_bridged || is_bridged)
+            {
+                info!("Skipping URLs already previewed through a bridge.");
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    None,
+                    preview_log::State::Skipped,
+                    "Already previewed through a bridge",
+                )
+                .await;
+                return Ok(None);
+            }
 
-            let response = RoomMessageEventContentWithoutRelation::notice_html(
-                "\u{23f3}\u{fe0f} (Loading…)",
-                format!(
-                    "<blockquote><div class=\"m13253-url-preview-headline\"><a class=\"m13253-url-preview-backref\" href=\"{}\">\u{23f3}\u{fe0f}</a> <span class=\"m13253-url-preview-loading\"><em>Loading…</em></span></div></blockquote>",
-                    html_escape::attr(&original_event_link)
-                ),
-            )
-            .add_mentions(Mentions::new())
-            .with_relation(relates_to);
-            let response_id = room.send(response).await?.event_id;
+            let room_settings = self.room_settings(room.room_id()).await?;
+            if !room_settings.enabled() || room_settings.read_only() {
+                let detail = if room_settings.read_only() {
+                    "Not allowed to send messages"
+                } else {
+                    "Previews are disabled in this room"
+                };
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    None,
+                    preview_log::State::Skipped,
+                    detail,
+                )
+                .await;
+                return Ok(None);
+            }
+            if room_settings.dry_run() {
+                self.report_dry_run(&room, &room_settings, &original_event_link, urls)
+                    .await;
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    None,
+                    preview_log::State::Skipped,
+                    "Dry run",
+                )
+                .await;
+                return Ok(None);
+            }
 
-            let room_id_str = room.room_id().to_string();
-            let original_event_id_str = original_event_id.to_string();
-            let response_id_str = response_id.to_string();
+            // URLs already previewed in a thread only get a pointer in the main timeline.
+            if thread_id.is_none()
+                && let Some(thread_response_id) = thread_response_id
+            {
+                let response_id = self
+                    .send_thread_pointer(
+                        &room,
+                        &room_settings,
+                        &original_event_id,
+                        &original_event_link,
+                        &thread_response_id,
+                        urls,
+                    )
+                    .await?;
+                if response_id.is_some()
+                    && let Some(dedup) = &self.dedup
+                {
+                    dedup
+                        .insert(
+                            dedup_key,
+                            DedupEntry {
+                                is_bridged,
+                                thread_response_id: None,
+                            },
+                        )
+                        .await;
+                }
+                return Ok(response_id);
+            }
+            if let Some(thread_id) = &thread_id
+                && !self.is_within_thread_limit(room.room_id(), thread_id).await
+            {
+                info!(
+                    "Skipping {}: Too many previews in the thread.",
+                    original_event_id
+                );
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    None,
+                    preview_log::State::Skipped,
+                    "Too many previews in the thread this hour",
+                )
+                .await;
+                return Ok(None);
+            }
+            let is_in_thread = thread_id.is_some();
+            if self.config.aggregate_thread_previews {
+                aggregate_thread_id = thread_id.clone();
+            }
+            let aggregate = match &aggregate_thread_id {
+                Some(thread_id) => thread_aggregate::get(&self.db, room.room_id(), thread_id)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!("Failed to load the previews of the thread: {}", err);
+                        None
+                    }),
+                None => None,
+            };
+            let is_appended = aggregate.is_some();
 
-            conn.interact(move |conn| {
-                let mut stmt = conn.prepare_cached(stmt_insert)?;
-                stmt.execute((room_id_str, original_event_id_str, response_id_str))?;
-                Ok::<_, Report>(())
-            })
-            .await
-            .unwrap()?;
+            let response_id = if let Some(aggregate) = aggregate {
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    Some(&aggregate.response_id),
+                    preview_log::State::Placeholder,
+                    "Appending to the previews of the thread",
+                )
+                .await;
+                aggregate.response_id
+            } else {
+                let relates_to = thread_id.clone().map(|thread_id| {
+                    Relation::Thread(Thread::plain(thread_id, original_event_id.to_owned()))
+                });
+
+                let class_prefix = &self.config.css_class_prefix;
+                let placeholder_text = room_settings.placeholder_text(&self.config);
+                let response = RoomMessageEventContentWithoutRelation::notice_html(
+                    format!("{} ({placeholder_text})", self.config.placeholder_emoji),
+                    format!(
+                        "<blockquote><div class=\"{class_prefix}-headline\">{} <span class=\"{class_prefix}-loading\"><em>{}</em></span></div></blockquote>",
+                        self.headline_emoji(
+                            &self.config.placeholder_emoji,
+                            &original_event_link,
+                            room_settings.show_backref(&self.config)
+                        ),
+                        html_escape::text(&placeholder_text)
+                    ),
+                )
+                .add_mentions(Mentions::new())
+                .with_relation(relates_to);
+                let response_id = match Self::send_with_retry(&room, &response).await {
+                    Ok(response) => response.event_id,
+                    Err(err) => {
+                        self.log_preview(
+                            room.room_id(),
+                            &original_event_id,
+                            None,
+                            preview_log::State::Failed,
+                            &err.to_string(),
+                        )
+                        .await;
+                        if Self::is_forbidden(&err) {
+                            self.mark_read_only(&room).await;
+                            return Ok(None);
+                        }
+                        return Err(err.into());
+                    }
+                };
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    Some(&response_id),
+                    preview_log::State::Placeholder,
+                    "",
+                )
+                .await;
+                response_id
+            };
+            if let Some(thread_id) = &thread_id {
+                self.count_thread_preview(room.room_id(), thread_id).await;
+            }
+
+            // Only once answered, so skipped copies don't keep the next ones from a preview.
+            if let Some(dedup) = &self.dedup {
+                dedup
+                    .insert(
+                        dedup_key,
+                        DedupEntry {
+                            is_bridged,
+                            thread_response_id: None,
+                        },
+                    )
+                    .await;
+                // Previews in threads are pointed to when anyone posts the URLs again.
+                if is_in_thread {
+                    dedup
+                        .insert(
+                            thread_key,
+                            DedupEntry {
+                                is_bridged,
+                                thread_response_id: Some(response_id.clone()),
+                            },
+                        )
+                        .await;
+                }
+            }
+
+            match &aggregate_thread_id {
+                // Not mapped to the message, as its edits and deletions would replace the previews
+                // of the whole thread.
+                Some(thread_id) => {
+                    if !is_appended {
+                        let aggregate = ThreadAggregate {
+                            response_id: response_id.clone(),
+                            text: String::new(),
+                            html: String::new(),
+                        };
+                        if let Err(err) = thread_aggregate::insert(
+                            &self.db,
+                            room.room_id(),
+                            thread_id,
+                            &aggregate,
+                        )
+                        .await
+                        {
+                            error!("Failed to save the previews of the thread: {}", err);
+                        }
+                    }
+                }
+                None => {
+                    self.messages
+                        .insert(room.room_id(), &original_event_id, &response_id)
+                        .await;
+                }
+            }
+            // If we crash before finishing, the placeholder is completed on the next startup.
+            // The URLs of private rooms are extracted from the message again instead of stored.
+            if !is_appended {
+                let pending_urls = if self.is_private_room(&room) {
+                    IndexSet::new()
+                } else {
+                    urls.clone()
+                };
+                self.insert_pending_job(
+                    room.room_id(),
+                    &response_id,
+                    &original_event_link,
+                    &pending_urls,
+                )
+                .await?;
+            }
 
-            (response_id, false)
+            (response_id, is_appended)
         };
 
-        tokio::spawn(self.create_url_preview(
+        self.spawn_preview(PreviewJob {
             room,
+            original_event_id,
             original_event_link,
-            response_id.clone(),
+            response_id: response_id.clone(),
             is_edit,
-            urls,
-        ));
+            priority: if is_edit && aggregate_thread_id.is_none() {
+                Priority::Edit
+            } else {
+                Priority::Live
+            },
+            links,
+            thread_id: aggregate_thread_id,
+        });
 
         Ok(Some(response_id))
     }
 
     #[instrument(skip_all)]
-    pub async fn on_deletion(
+    pub async fn on_command(
         self: Arc<Self>,
         room: Room,
-        original_event_id: &EventId,
-    ) -> Result<Option<OwnedEventId>> {
-        let stmt_query = "SELECT response_id FROM messages WHERE room_id = ? AND event_id = ?;";
-        let conn = self.db.get().await?;
-
-        let room_id_str = room.room_id().to_string();
-        let original_event_id_str = original_event_id.to_string();
-        let response_id = conn
-            .interact(move |conn| {
-                let mut stmt = conn.prepare_cached(stmt_query)?;
-                Ok::<_, Report>(
-                    stmt.query_row((room_id_str, original_event_id_str), |row| {
-                        row.get::<_, String>(0)
-                    })
-                    .optional()?,
-                )
-            })
-            .await
-            .unwrap()?;
-
-        let response_id = if let Some(response_id) = response_id {
-            OwnedEventId::try_from(response_id)?
-        } else {
-            return Ok(None);
+        sender: &UserId,
+        event_id: OwnedEventId,
+        in_reply_to: Option<OwnedEventId>,
+        command: Command,
+    ) -> Result<()> {
+        let is_admin = self.config.admin_users.iter().any(|admin| admin == sender);
+        let is_permitted = match command.permission() {
+            Permission::Anyone => true,
+            Permission::Moderator => {
+                is_admin
+                    || room
+                        .get_member(sender)
+                        .await?
+                        .is_some_and(|member| member.can_redact_other())
+            }
+            Permission::Admin => is_admin,
         };
+        if !is_permitted {
+            info!(
+                "Ignoring command {:?} from {}: Not permitted.",
+                command, sender
+            );
+            return Ok(());
+        }
 
-        let response_id_clone = response_id.clone();
-        tokio::spawn(
-            async move {
-                if let Err(err) = room.redact(&response_id_clone, None, None).await {
-                    error!("Failed to delete URL preview message: {}", err);
+        let reply = match command {
+            Command::Help => commands::help_card(is_admin),
+            Command::Refresh => {
+                match self
+                    .clone()
+                    .refresh_preview(&room, sender, in_reply_to)
+                    .await?
+                {
+                    Some(reply) => reply,
+                    None => return Ok(()),
                 }
             }
-            .in_current_span(),
-        );
-
-        Ok(Some(response_id))
-    }
-
-    #[instrument(skip_all)]
-    async fn create_url_preview(
-        self: Arc<Self>,
-        room: Room,
-        original_event_link: String,
-        response_id: OwnedEventId,
-        is_edit: bool,
-        urls: IndexSet<Url>,
-    ) {
-        let mut reply_text = String::new();
-        let mut reply_html = String::new();
-        let mut reply_images = Vec::new();
-
-        for mut url in urls.into_iter().take(MAX_URL_COUNTS_PER_MESSAGE) {
-            info!("Fetching URL preview for: {}", url);
-
-            let mut url_str = Cow::from(url.as_str());
-            for (from, to) in self.rewrite_url.iter() {
-                match from.replace_all(&url_str, to) {
-                    Cow::Borrowed(_) => (),
-                    Cow::Owned(result) => {
-                        debug!("URL rewrite: {url_str} => {from} => {result}");
-                        url_str = result.into()
-                    }
+            Command::Pin => self.pin_preview(&room, sender, in_reply_to).await?,
+            Command::Disable => {
+                self.set_room_setting(&room, "enabled", "false").await?;
+                info!("Disabled by {}.", sender);
+                "URL previews are disabled in this room. Type `!preview enable` to resume."
+                    .to_owned()
+            }
+            Command::Enable => {
+                self.set_room_setting(&room, "enabled", "true").await?;
+                info!("Enabled by {}.", sender);
+                "URL previews are enabled in this room.".to_owned()
+            }
+            Command::Set(setting) => {
+                match setting.value() {
+                    Some(value) => self.set_room_setting(&room, setting.key(), &value).await?,
+                    None => self.remove_room_setting(&room, setting.key()).await?,
+                }
+                info!(
+                    "{} set to {:?} by {}.",
+                    setting.key(),
+                    setting.value(),
+                    sender
+                );
+                match setting.value() {
+                    Some(value) => format!("`{}` is {} in this room.", setting.key(), value),
+                    None => format!(
+                        "`{}` follows the bot's configuration in this room again.",
+                        setting.key()
+                    ),
                 }
             }
-            if let Cow::Owned(url_str) = url_str {
-                url = match Url::parse(&url_str) {
-                    Ok(url) => url,
-                    Err(err) => {
-                        error!("Failed to parse the URL after rewrite: {}", err);
-                        continue;
-                    }
+            Command::DryRun { enabled, report } => {
+                self.set_room_setting(&room, "dry_run", &enabled.to_string())
+                    .await?;
+                let report_to = if report { sender.as_str() } else { "" };
+                self.set_room_setting(&room, "dry_run_report_to", report_to)
+                    .await?;
+                info!(
+                    "Dry run {} by {}.",
+                    if enabled { "enabled" } else { "disabled" },
+                    sender
+                );
+                match (enabled, report) {
+                    (true, false) => "Dry run: URL previews in this room are only logged. Type `!preview dryrun off` to post them.".to_owned(),
+                    (true, true) => format!("Dry run: URL previews in this room are only logged, and sent to {sender} in a direct message. Type `!preview dryrun off` to post them."),
+                    (false, _) => "URL previews are posted in this room again.".to_owned(),
                 }
             }
-
-            // Previously we used Synapse's URL preview API.
-            //
-            // let request = matrix_sdk::ruma::api::client::authenticated_media::get_media_preview::v1::Request::new(
-            //     url.to_string()
-            // );
-            // let response = match room
-            //     .client()
-            //     .send(request)
-            //     .with_request_config(Some(room.client().request_config().clone().disable_retry()))
-            //     .await
-            // {
+            Command::Language(None) => {
+                self.remove_room_setting(&room, "language").await?;
+                info!("Language reset by {}.", sender);
+                "The bot's messages follow its configuration in this room again.".to_owned()
+            }
+            Command::Language(Some(tag)) => match i18n::find(&tag) {
+                Some(language) => {
+                    self.set_room_setting(&room, "language", language).await?;
+                    info!("Language set to {} by {}.", language, sender);
+                    format!("The bot's messages are in `{language}` in this room.")
+                }
+                None => format!(
+                    "There is no translation for `{}`. Available languages: {}.",
+                    tag,
+                    i18n::languages().collect::<Vec<_>>().join(", ")
+                ),
+            },
+            Command::Blocklist => {
+                let room_settings = self.room_settings(room.room_id()).await?;
+                if room_settings.blocked_urls().is_empty() {
+                    "Nothing is blocked in this room.".to_owned()
+                } else {
+                    format!(
+                        "Blocked in this room: {}",
+                        room_settings.blocked_urls().join(", ")
+                    )
+                }
+            }
+            Command::Block(pattern) => self.update_blocklist(&room, sender, pattern, true).await?,
+            Command::Unblock(pattern) => {
+                self.update_blocklist(&room, sender, pattern, false).await?
+            }
+            Command::Stats => {
+                format!(
+                    "{}\n{}",
+                    self.metrics
+                        .report(&self.cache_snapshot(), &self.queue_snapshot()),
+                    feedback::report(&self.db).await?
+                )
+            }
+            Command::CacheStats => {
+                self.cache.run_pending_tasks().await;
+                let mut reply = format!(
+                    "Cached previews: {} / {}\nCache duration: {:?}",
+                    self.cache.entry_count(),
+                    self.config.cache_entries,
+                    self.config.cache_duration
+                );
+                if self.config.cache_max_disk_usage != 0 {
+                    let (count, size) = disk_cache::usage(&self.db).await?;
+                    reply.push_str(&format!(
+                        "\nPreviews on disk: {} ({} / {} bytes)",
+                        count, size, self.config.cache_max_disk_usage
+                    ));
+                }
+                reply
+            }
+            Command::CachePurge(url) => match self.rewrite(url) {
+                Some(url) => {
+                    let count = self.purge_cached_previews(&url).await;
+                    info!("Purged {} cached previews of {}.", count, redact::url(&url));
+                    format!("Purged {count} cached previews of {url}.")
+                }
+                None => "The URL is invalid after rewrite.".to_owned(),
+            },
+            Command::CacheWarm(url) => match self.rewrite(url) {
+                Some(url) if Self::is_never_cached(&url) => {
+                    "Previews of events and internal hosts are never cached.".to_owned()
+                }
+                Some(url) => {
+                    let key = CacheKey {
+                        url: url.clone(),
+                        accept_language: self.config.crawler_accept_language.clone(),
+                        handler: "opengraph",
+                    };
+                    let preview = self
+                        .clone()
+                        .fetch_single_url_preview(url.clone(), key.accept_language.clone())
+                        .await;
+                    self.store_url_preview(&key, &preview).await;
+                    let reply = match preview {
+                        Some(ref preview) => format!(
+                            "Cached preview of {}: {}",
+                            url,
+                            limit::length_in_chars(
+                                render::collapse_whitespace(&preview.title),
+                                MAX_RESPONSE_TEXT_CHARS
+                            )
+                        ),
+                        None => format!("Cached the absence of a preview for {url}."),
+                    };
+                    self.cache.insert(key, preview).await;
+                    reply
+                }
+                None => "The URL is invalid after rewrite.".to_owned(),
+            },
+            Command::Unknown(_) => format!("Unknown command. {}", commands::help_text(is_admin)),
+        };
+        let reply = RoomMessageEventContentWithoutRelation::notice_plain(reply)
+            .add_mentions(Mentions::new())
+            .with_relation(Some(Relation::Reply {
+                in_reply_to: InReplyTo::new(event_id),
+            }));
+        room.send(reply).await?;
+        Ok(())
+    }
+
+    /// Pins a preview and the message it previews. Returns the reply to the command.
+    async fn pin_preview(
+        &self,
+        room: &Room,
+        sender: &UserId,
+        response_id: Option<OwnedEventId>,
+    ) -> Result<String> {
+        let Some(response_id) = response_id else {
+            return Ok("Reply to a preview with `!preview pin` to pin it.".to_owned());
+        };
+        let Some(original_event_id) = self
+            .messages
+            .get_original_event_id(room.room_id(), &response_id)
+            .await?
+        else {
+            return Ok("Only URL previews can be pinned.".to_owned());
+        };
+        let can_pin = async |user_id: &UserId| {
+            Ok::<_, Report>(
+                room.get_member(user_id)
+                    .await?
+                    .is_some_and(|member| member.can_send_state(StateEventType::RoomPinnedEvents)),
+            )
+        };
+        let is_admin = self.config.admin_users.iter().any(|admin| admin == sender);
+        if !is_admin && !can_pin(sender).await? {
+            return Ok("You aren't allowed to pin messages in this room.".to_owned());
+        }
+        let Some(own_user_id) = room.client().user_id().map(ToOwned::to_owned) else {
+            return Ok("I'm not logged in.".to_owned());
+        };
+        if !can_pin(&own_user_id).await? {
+            return Ok("I'm not allowed to pin messages in this room.".to_owned());
+        }
+
+        pinned::pin(room, &[original_event_id.clone(), response_id.clone()]).await?;
+        pinned::insert(&self.db, room.room_id(), &original_event_id, &response_id).await?;
+        info!("Pinned {} by {}.", response_id, sender);
+        Ok("Pinned the message and its preview.".to_owned())
+    }
+
+    /// Adds a pattern to the blocklist of the room, or removes it. Returns the reply to the command.
+    async fn update_blocklist(
+        &self,
+        room: &Room,
+        sender: &UserId,
+        pattern: String,
+        block: bool,
+    ) -> Result<String> {
+        let mut blocked_urls = self
+            .room_settings(room.room_id())
+            .await?
+            .blocked_urls()
+            .to_vec();
+        if block {
+            if !extract_url::is_valid_block_pattern(&pattern) {
+                return Ok(format!(
+                    "Invalid pattern: {pattern}. Use a domain, a URL, or a URL ending with `*`."
+                ));
+            }
+            if blocked_urls.contains(&pattern) {
+                return Ok(format!("{pattern} is already blocked in this room."));
+            }
+            if blocked_urls.len() >= MAX_ROOM_BLOCKED_URLS {
+                return Ok(format!(
+                    "This room already blocks {MAX_ROOM_BLOCKED_URLS} patterns, the maximum."
+                ));
+            }
+            blocked_urls.push(pattern.clone());
+        } else {
+            let len = blocked_urls.len();
+            blocked_urls.retain(|blocked_url| *blocked_url != pattern);
+            if blocked_urls.len() == len {
+                return Ok(format!("{pattern} isn't blocked in this room."));
+            }
+        }
+        self.set_room_setting(room, "blocked_urls", &blocked_urls.join("\n"))
+            .await?;
+        if block {
+            info!("Blocked {} by {}.", redact::url_str(&pattern), sender);
+            Ok(format!("Stopped previewing {pattern} in this room."))
+        } else {
+            info!("Unblocked {} by {}.", redact::url_str(&pattern), sender);
+            Ok(format!("Resumed previewing {pattern} in this room."))
+        }
+    }
+
+    /// Drops the URLs blocked by `blocked_urls` or by `!preview block` in the room.
+    async fn remove_blocked_urls(&self, room_id: &RoomId, links: &mut MessageLinks) -> Result<()> {
+        if links.urls.is_empty() {
+            return Ok(());
+        }
+        let room_settings = self.room_settings(room_id).await?;
+        let patterns = self
+            .config
+            .blocked_urls
+            .iter()
+            .chain(room_settings.blocked_urls());
+        links.urls.retain(|url| {
+            let is_blocked = patterns
+                .clone()
+                .any(|pattern| extract_url::is_blocked_by(url, pattern));
+            if is_blocked {
+                info!("Not previewing {}: Blocked.", redact::url(url));
+            }
+            !is_blocked
+        });
+        Ok(())
+    }
+
+    /// Answers a request for the preview of a URL from another bot in one of the `oracle_rooms`.
+    pub async fn on_preview_request(
+        self: Arc<Self>,
+        room: Room,
+        request_id: OwnedEventId,
+        request: PreviewRequestEventContent,
+    ) -> Result<()> {
+        info!("Answering preview request {}.", request_id);
+        let response = match self.clone().answer_preview_request(&room, &request).await {
+            Ok(preview) => {
+                self.metrics.record_preview_served();
+                PreviewResponseEventContent::preview(request_id, request.url, &preview)
+            }
+            Err(reason) => {
+                info!("No preview for request {}: {}", request_id, reason);
+                PreviewResponseEventContent::error(request_id, request.url, reason)
+            }
+        };
+        room.send(response).await?;
+        Ok(())
+    }
+
+    /// Looks up the preview for a preview request, the same way as for a message.
+    ///
+    /// Returns the reason if there's no preview.
+    async fn answer_preview_request(
+        self: Arc<Self>,
+        room: &Room,
+        request: &PreviewRequestEventContent,
+    ) -> Result<OpenGraph, &'static str> {
+        let room_settings = match self.room_settings(room.room_id()).await {
+            Ok(room_settings) => room_settings,
+            Err(err) => {
+                error!("Failed to load room settings: {}", err);
+                RoomSettings::default()
+            }
+        };
+        let accept_language = request
+            .accept_language
+            .as_deref()
+            .unwrap_or(room_settings.accept_language(&self.config))
+            .to_owned();
+        if accept_language.len() > MAX_ACCEPT_LANGUAGE_LENGTH
+            || reqwest::header::HeaderValue::from_str(&accept_language).is_err()
+        {
+            return Err("Invalid accept_language");
+        }
+        let persist = !self.is_private_room(room);
+        self.lookup_preview(
+            &request.url,
+            room_settings.blocked_urls(),
+            &accept_language,
+            persist,
+        )
+        .await
+    }
+
+    /// Looks up the preview of a URL outside of any room, for the `pipe` command.
+    ///
+    /// Returns the reason if there's no preview.
+    pub async fn preview_url(self: Arc<Self>, url: &str) -> Result<OpenGraph, &'static str> {
+        let accept_language = self.config.crawler_accept_language.clone();
+        self.lookup_preview(url, &[], &accept_language, true).await
+    }
+
+    /// Checks a URL against the block lists, the overrides, and the rewrite rules, then looks up
+    /// its preview in the cache, loading it if missing.
+    ///
+    /// Returns the reason if there's no preview.
+    async fn lookup_preview(
+        self: Arc<Self>,
+        url: &str,
+        blocked_urls: &[String],
+        accept_language: &str,
+        persist: bool,
+    ) -> Result<OpenGraph, &'static str> {
+        let Ok(url) = Url::parse(url) else {
+            return Err("Invalid URL");
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("Not a web page");
+        }
+        if self
+            .config
+            .blocked_urls
+            .iter()
+            .chain(blocked_urls)
+            .any(|pattern| extract_url::is_blocked_by(&url, pattern))
+        {
+            return Err("Blocked");
+        }
+        if let Some(preview_override) = self.config.preview_override(&url) {
+            return Ok(preview_override.to_opengraph(&url));
+        }
+        if classify::classify(&url) == UrlClass::Internal {
+            return Err("Internal host");
+        }
+        let Some(url) = self.rewrite(url) else {
+            return Err("Blocked by a rewrite rule");
+        };
+        // Event previews depend on who is asking, and the bot may see more than the requester.
+        if extract_url::parse_event_permalink(&url).is_some() {
+            return Err("Not a web page");
+        }
+        let _permit = self.scheduler.acquire(Priority::Live).await;
+        self.clone()
+            .cached_url_preview(&url, accept_language, persist)
+            .await
+            .ok_or("No preview available")
+    }
+
+    /// Fetches the URLs in a preview again, bypassing the cache, and edits the preview in place.
+    ///
+    /// Returns the reason if the preview can't be refreshed.
+    async fn refresh_preview(
+        self: Arc<Self>,
+        room: &Room,
+        sender: &UserId,
+        response_id: Option<OwnedEventId>,
+    ) -> Result<Option<String>> {
+        let Some(response_id) = response_id else {
+            return Ok(Some(
+                "Reply to a preview with `!preview refresh` to refresh it.".to_owned(),
+            ));
+        };
+        let Some(original_event_id) = self
+            .messages
+            .get_original_event_id(room.room_id(), &response_id)
+            .await?
+        else {
+            return Ok(Some("Only URL previews can be refreshed.".to_owned()));
+        };
+        if self.refresh_cooldown.contains_key(sender) {
+            return Ok(Some(format!(
+                "Please wait {:?} between refreshes.",
+                self.config.refresh_cooldown
+            )));
+        }
+        self.refresh_cooldown.insert(sender.to_owned(), ()).await;
+
+        let Some(links) = self.load_message_urls(room, &original_event_id).await? else {
+            return Ok(Some("The original message is unavailable.".to_owned()));
+        };
+
+        info!("Refreshing {} URLs for {}.", links.urls.len(), sender);
+        for url in links
+            .urls
+            .iter()
+            .cloned()
+            .filter_map(|url| self.rewrite(url))
+        {
+            self.purge_cached_previews(&url).await;
+        }
+        let original_event_link = Self::event_link(room, &original_event_id).await;
+        self.spawn_preview(PreviewJob {
+            room: room.clone(),
+            original_event_id,
+            original_event_link,
+            response_id,
+            is_edit: true,
+            priority: Priority::Edit,
+            links,
+            thread_id: None,
+        });
+        Ok(None)
+    }
+
+    /// Extracts the URLs from the latest version of a message.
+    ///
+    /// Returns `None` if it's not a text message.
+    async fn load_message_urls(
+        &self,
+        room: &Room,
+        event_id: &EventId,
+    ) -> Result<Option<MessageLinks>> {
+        let event = room.load_or_fetch_event(event_id, None).await?;
+        let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(mut message),
+        ))) = event.raw().deserialize()
+        else {
+            return Ok(None);
+        };
+        let content = match message
+            .unsigned
+            .relations
+            .replace
+            .take()
+            .and_then(|edit| edit.content.relates_to)
+        {
+            Some(Relation::Replacement(replacement)) => replacement.new_content,
+            _ => message.content.into(),
+        };
+        let MessageType::Text(text) = content.msgtype else {
+            return Ok(None);
+        };
+        let mut links = extract_url::extract_urls_from_message(&text, self.config.max_dom_nodes);
+        self.remove_blocked_urls(room.room_id(), &mut links).await?;
+        Ok(Some(links))
+    }
+
+    /// Returns whether URLs and previews in the room must stay out of logs and the database.
+    ///
+    /// With `private_room_mode`, rooms are private if they are encrypted, or their history is only
+    /// visible to members.
+    pub fn is_private_room(&self, room: &Room) -> bool {
+        self.config.private_room_mode
+            && (!matches!(room.encryption_state(), EncryptionState::NotEncrypted)
+                || matches!(
+                    room.history_visibility_or_default(),
+                    HistoryVisibility::Joined | HistoryVisibility::Invited
+                ))
+    }
+
+    /// Logs the URLs a message would be previewed with, and sends them to the user who asked for
+    /// dry run reports, if any.
+    async fn report_dry_run(
+        &self,
+        room: &Room,
+        room_settings: &RoomSettings,
+        original_event_link: &str,
+        urls: &IndexSet<Url>,
+    ) {
+        let urls = urls
+            .iter()
+            .take(
+                room_settings
+                    .max_urls_per_message(&self.config)
+                    .min(MAX_URL_COUNTS_PER_MESSAGE),
+            )
+            .map(|url| redact::url(url).into_owned())
+            .collect::<Vec<_>>()
+            .join(", ");
+        info!("Dry run, would preview: {}", urls);
+        let Some(user_id) = room_settings.dry_run_report_to() else {
+            return;
+        };
+        let client = room.client();
+        let direct_chat = match client.get_dm_room(user_id) {
+            Some(direct_chat) => direct_chat,
+            None => match client.create_dm(user_id).await {
+                Ok(direct_chat) => direct_chat,
+                Err(err) => {
+                    error!("Failed to create direct chat for dry run reports: {}", err);
+                    return;
+                }
+            },
+        };
+        let report = RoomMessageEventContent::notice_plain(format!(
+            "Dry run in {}: Would preview {} for {}",
+            room.room_id(),
+            urls,
+            original_event_link
+        ))
+        .add_mentions(Mentions::new());
+        if let Err(err) = Self::send_with_retry(&direct_chat, &report).await {
+            error!("Failed to send dry run report: {}", err);
+        }
+    }
+
+    /// Posts the greeting after joining a room, if configured.
+    #[instrument(skip_all)]
+    pub async fn on_join(self: Arc<Self>, room: Room) -> Result<()> {
+        if self.config.join_greeting.is_empty() {
+            return Ok(());
+        }
+        info!("Greeting room {}.", room.room_id());
+        let greeting =
+            RoomMessageEventContentWithoutRelation::notice_plain(&self.config.join_greeting)
+                .add_mentions(Mentions::new())
+                .with_relation(None);
+        room.send(greeting).await?;
+        Ok(())
+    }
+
+    /// Returns whether the room is marked as a direct chat in the bot's account data.
+    pub async fn is_direct_chat(room: &Room) -> bool {
+        room.is_direct().await.unwrap_or(false)
+    }
+
+    /// Replies to the first message in a direct chat with the help card, instead of previewing
+    /// it.
+    #[instrument(skip_all)]
+    pub async fn on_direct_message(
+        self: Arc<Self>,
+        room: Room,
+        sender: &UserId,
+        event_id: OwnedEventId,
+    ) -> Result<()> {
+        if self.room_settings(room.room_id()).await?.help_sent() {
+            debug!("Help card already sent to {}.", room.room_id());
+            return Ok(());
+        }
+        info!("Replying help card to {}.", sender);
+        self.clone()
+            .on_command(room.clone(), sender, event_id, None, Command::Help)
+            .await?;
+        self.set_room_setting(&room, "help_sent", "true").await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn on_reaction(
+        self: Arc<Self>,
+        room: Room,
+        sender: &UserId,
+        reaction_id: &EventId,
+        response_id: &EventId,
+        key: &str,
+    ) -> Result<()> {
+        let Some(vote) = feedback::parse_vote(key) else {
+            return Ok(());
+        };
+        if feedback::insert_vote(
+            &self.db,
+            room.room_id(),
+            response_id,
+            reaction_id,
+            sender,
+            vote,
+        )
+        .await?
+        {
+            info!("Received feedback {} for {}.", vote, response_id);
+        }
+        Ok(())
+    }
+
+    fn is_bridged(&self, sender: &UserId) -> bool {
+        self.bridge_namespaces
+            .iter()
+            .any(|namespace| namespace.is_match(sender.as_str()))
+    }
+
+    /// Returns the user behind `sender`, so both copies of a bridge echo are from the same user.
+    ///
+    /// A bridge ghost is mapped to the only other member of the room with the same display name,
+    /// if any.
+    async fn real_sender(&self, room: &Room, sender: &UserId) -> OwnedUserId {
+        if self.dedup.is_none() || !self.is_bridged(sender) {
+            return sender.to_owned();
+        }
+        let Ok(Some(ghost)) = room.get_member_no_sync(sender).await else {
+            return sender.to_owned();
+        };
+        let Some(name) = ghost.display_name() else {
+            return sender.to_owned();
+        };
+        let members = match room.members_no_sync(RoomMemberships::JOIN).await {
+            Ok(members) => members,
+            Err(err) => {
+                warn!(
+                    "Failed to list the members of room {}: {}",
+                    room.room_id(),
+                    err
+                );
+                return sender.to_owned();
+            }
+        };
+        let mut real_users = members.iter().filter(|member| {
+            !self.is_bridged(member.user_id()) && member.display_name() == Some(name)
+        });
+        match (real_users.next(), real_users.next()) {
+            (Some(member), None) => member.user_id().to_owned(),
+            _ => sender.to_owned(),
+        }
+    }
+
+    /// Identifies the same URLs posted by `sender` in the same room, regardless of their order.
+    fn dedup_key(room_id: &RoomId, sender: Option<&UserId>, urls: &IndexSet<Url>) -> DedupKey {
+        let mut sorted_urls = urls.iter().map(Url::as_str).collect::<Vec<_>>();
+        sorted_urls.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        sorted_urls.hash(&mut hasher);
+        (
+            room_id.to_owned(),
+            sender.map(UserId::to_owned),
+            hasher.finish(),
+        )
+    }
+
+    /// Returns whether another preview in a thread stays within
+    /// `max_previews_per_thread_per_hour`.
+    async fn is_within_thread_limit(&self, room_id: &RoomId, thread_id: &EventId) -> bool {
+        let max_previews = self.config.max_previews_per_thread_per_hour;
+        if max_previews == 0 {
+            return true;
+        }
+        match self
+            .thread_previews
+            .get(&(room_id.to_owned(), thread_id.to_owned()))
+            .await
+        {
+            Some(count) => count.load(Ordering::Relaxed) < max_previews,
+            None => true,
+        }
+    }
+
+    /// Counts a preview posted in a thread towards `max_previews_per_thread_per_hour`.
+    async fn count_thread_preview(&self, room_id: &RoomId, thread_id: &EventId) {
+        if self.config.max_previews_per_thread_per_hour == 0 {
+            return;
+        }
+        self.thread_previews
+            .get_with((room_id.to_owned(), thread_id.to_owned()), async {
+                Arc::new(AtomicUsize::new(0))
+            })
+            .await
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Replies with a link to the preview of the same URLs in a thread, instead of duplicating it.
+    async fn send_thread_pointer(
+        &self,
+        room: &Room,
+        room_settings: &RoomSettings,
+        original_event_id: &EventId,
+        original_event_link: &str,
+        thread_response_id: &EventId,
+        urls: &IndexSet<Url>,
+    ) -> Result<Option<OwnedEventId>> {
+        info!("URLs already previewed in thread, replying with a pointer.");
+        let thread_response_link = Self::event_link(room, thread_response_id).await;
+        let class_prefix = &self.config.css_class_prefix;
+        let pointer_text = room_settings.thread_pointer_text(&self.config);
+        let response = RoomMessageEventContentWithoutRelation::notice_html(
+            format!("{} {pointer_text}", self.config.preview_emoji),
+            format!(
+                "<blockquote><div class=\"{class_prefix}-headline\">{} <a class=\"{class_prefix}-thread\" href=\"{}\"><em>{}</em></a></div></blockquote>",
+                self.headline_emoji(
+                    &self.config.preview_emoji,
+                    original_event_link,
+                    room_settings.show_backref(&self.config)
+                ),
+                html_escape::attr(&thread_response_link),
+                html_escape::text(&pointer_text)
+            ),
+        )
+        .add_mentions(Mentions::new())
+        .with_relation(None);
+        let response_id = match Self::send_with_retry(room, &response).await {
+            Ok(response) => response.event_id,
+            Err(err) if Self::is_forbidden(&err) => {
+                self.mark_read_only(room).await;
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.log_preview(
+            room.room_id(),
+            original_event_id,
+            Some(&response_id),
+            preview_log::State::Skipped,
+            "Already previewed in thread",
+        )
+        .await;
+        // Edits changing the URLs turn the pointer into a full preview.
+        self.messages
+            .insert(room.room_id(), original_event_id, &response_id)
+            .await;
+        self.messages
+            .set_urls_hash(
+                room.room_id(),
+                original_event_id,
+                &response_id,
+                Self::urls_hash(urls),
+            )
+            .await;
+        Ok(Some(response_id))
+    }
+
+    /// Periodically posts the digest of the most shared links to the rooms opted in.
+    #[instrument(skip_all)]
+    pub async fn send_digests(self: Arc<Self>, client: Client) {
+        let mut ticker = tokio::time::interval(DIGEST_CHECK_INTERVAL);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            for room in client.joined_rooms() {
+                if let Err(err) = self.send_digest(&room).await {
+                    error!("Failed to send digest to room {}: {}", room.room_id(), err);
+                }
+            }
+        }
+    }
+
+    async fn send_digest(&self, room: &Room) -> Result<()> {
+        let room_settings = self.room_settings(room.room_id()).await?;
+        if !room_settings.weekly_digest(&self.config)
+            || !room_settings.enabled()
+            || room_settings.read_only()
+            || room_settings.dry_run()
+            || Self::is_direct_chat(room).await
+        {
+            return Ok(());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let Some(last_sent) = room_settings.digest_last_sent else {
+            // Start counting from when the room opted in.
+            return self
+                .set_room_setting(room, "digest_last_sent", &now.to_string())
+                .await;
+        };
+        if now - last_sent < self.config.digest_interval.as_secs() as i64 {
+            return Ok(());
+        }
+        if let Some((text, html)) = digest::report(
+            &self.db,
+            room.room_id(),
+            last_sent,
+            self.config.digest_top_count,
+            room_settings.language(),
+        )
+        .await?
+        {
+            info!("Sending digest to room {}.", room.room_id());
+            let content = RoomMessageEventContentWithoutRelation::notice_html(text, html)
+                .add_mentions(Mentions::new())
+                .with_relation(None);
+            room.send(content).await?;
+        }
+        self.set_room_setting(room, "digest_last_sent", &now.to_string())
+            .await
+    }
+
+    pub async fn forget_left_rooms(self: Arc<Self>, client: Client) {
+        room_cleanup::forget_left_rooms(client, self.db.clone()).await;
+    }
+
+    pub fn mark_processed(&self, room: &Room, event_id: OwnedEventId) {
+        self.receipts.mark_processed(room, event_id);
+    }
+
+    #[instrument(skip_all)]
+    pub async fn on_deletion(
+        self: Arc<Self>,
+        room: Room,
+        original_event_id: &EventId,
+    ) -> Result<Option<OwnedEventId>> {
+        // The redacted event may be a vote.
+        feedback::remove_vote(&self.db, room.room_id(), original_event_id).await?;
+
+        let Some(response_id) = self
+            .messages
+            .get_response_id(room.room_id(), original_event_id)
+            .await?
+        else {
+            return Ok(None);
+        };
+        self.log_preview(
+            room.room_id(),
+            original_event_id,
+            Some(&response_id),
+            preview_log::State::Redacted,
+            "",
+        )
+        .await;
+
+        let is_pinned = pinned::remove(&self.db, room.room_id(), &response_id).await?;
+        let event_ids = [original_event_id.to_owned(), response_id.clone()];
+        self.spawn("delete_preview", async move {
+            if let Err(err) = room.redact(&event_ids[1], None, None).await {
+                error!("Failed to delete URL preview message: {}", err);
+            }
+            if is_pinned {
+                pinned::unpin(&room, &event_ids).await?;
+            }
+            Ok(())
+        });
+
+        Ok(Some(response_id))
+    }
+
+    /// Completes the placeholders left behind by a previous run, so rooms never keep eternal
+    /// spinners. Placeholders without any preview are converted to the error card.
+    #[instrument(skip_all)]
+    pub async fn reconcile_pending_jobs(self: Arc<Self>, client: &Client) -> Result<()> {
+        self.deliver_unsent_previews(client).await?;
+
+        let stmt_query =
+            "SELECT room_id, response_id, original_event_link, urls FROM pending_jobs;";
+        let conn = self.db.get().await?;
+
+        let jobs = conn
+            .interact(move |conn| {
+                let mut stmt = conn.prepare_cached(stmt_query)?;
+                let rows = stmt
+                    .query_map((), |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok::<_, Report>(rows)
+            })
+            .await
+            .unwrap()?;
+        if !jobs.is_empty() {
+            info!("Completing {} pending URL previews.", jobs.len());
+        }
+
+        for (room_id, response_id, original_event_link, urls) in jobs {
+            let room_id = OwnedRoomId::try_from(room_id)?;
+            let response_id = OwnedEventId::try_from(response_id)?;
+            let Some((_, original_event_id)) = Url::parse(&original_event_link)
+                .ok()
+                .as_ref()
+                .and_then(extract_url::parse_event_permalink)
+            else {
+                error!(
+                    "Dropping pending job with invalid link {}.",
+                    original_event_link
+                );
+                self.remove_pending_job(&room_id, &response_id).await?;
+                continue;
+            };
+            let Some(room) = client
+                .get_room(&room_id)
+                .filter(|room| room.state() == RoomState::Joined)
+            else {
+                info!("Dropping pending job in room {}: Not joined.", room_id);
+                self.log_preview(
+                    &room_id,
+                    &original_event_id,
+                    Some(&response_id),
+                    preview_log::State::Expired,
+                    "Left the room before completing",
+                )
+                .await;
+                self.remove_pending_job(&room_id, &response_id).await?;
+                continue;
+            };
+            self.log_preview(
+                &room_id,
+                &original_event_id,
+                Some(&response_id),
+                preview_log::State::Expired,
+                "Completing after restart",
+            )
+            .await;
+            let links = if urls.is_empty() {
+                // The URLs of private rooms aren't stored.
+                match self.load_message_urls(&room, &original_event_id).await {
+                    Ok(Some(urls)) => urls,
+                    Ok(None) => Default::default(),
+                    Err(err) => {
+                        error!("Failed to load the original message: {}", err);
+                        Default::default()
+                    }
+                }
+            } else {
+                MessageLinks {
+                    urls: urls
+                        .lines()
+                        .filter_map(|url| Url::parse(url).ok())
+                        .collect(),
+                    ..Default::default()
+                }
+            };
+            self.clone().spawn_preview(PreviewJob {
+                room,
+                original_event_id,
+                original_event_link,
+                response_id,
+                is_edit: false,
+                priority: Priority::Retry,
+                links,
+                thread_id: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Sends the finished previews that couldn't be sent during the last run.
+    async fn deliver_unsent_previews(&self, client: &Client) -> Result<()> {
+        let previews = outbox::list(&self.db).await?;
+        if !previews.is_empty() {
+            info!("Delivering {} unsent URL previews.", previews.len());
+        }
+
+        for preview in previews {
+            let Some(room) = client
+                .get_room(&preview.room_id)
+                .filter(|room| room.state() == RoomState::Joined)
+            else {
+                info!(
+                    "Dropping unsent preview in room {}: Not joined.",
+                    preview.room_id
+                );
+                outbox::remove(&self.db, &preview.room_id, &preview.response_id).await?;
+                continue;
+            };
+            match Self::send_with_retry(&room, &preview.content).await {
+                Ok(_) => {
+                    self.log_preview(
+                        room.room_id(),
+                        &preview.original_event_id,
+                        Some(&preview.response_id),
+                        preview_log::State::Fetched,
+                        "Delivered after restart",
+                    )
+                    .await;
+                    if let Some(urls_hash) = preview.urls_hash {
+                        self.metrics.record_preview_served();
+                        self.messages
+                            .set_urls_hash(
+                                room.room_id(),
+                                &preview.original_event_id,
+                                &preview.response_id,
+                                urls_hash,
+                            )
+                            .await;
+                    }
+                }
+                Err(err) if Self::send_retry_delay(&err, SEND_MIN_BACKOFF).is_some() => {
+                    error!("Failed to deliver unsent URL preview, keeping it: {}", err);
+                    continue;
+                }
+                Err(err) => {
+                    error!("Failed to deliver unsent URL preview: {}", err);
+                    self.log_preview(
+                        room.room_id(),
+                        &preview.original_event_id,
+                        Some(&preview.response_id),
+                        preview_log::State::Failed,
+                        &err.to_string(),
+                    )
+                    .await;
+                }
+            }
+            outbox::remove(&self.db, &preview.room_id, &preview.response_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_pending_job(
+        &self,
+        room_id: &RoomId,
+        response_id: &EventId,
+        original_event_link: &str,
+        urls: &IndexSet<Url>,
+    ) -> Result<()> {
+        let stmt_insert = "INSERT OR REPLACE INTO pending_jobs (room_id, response_id, original_event_link, urls) VALUES (?, ?, ?, ?);";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room_id.to_string();
+        let response_id_str = response_id.to_string();
+        let original_event_link = original_event_link.to_owned();
+        let urls_str = urls.iter().map(Url::as_str).collect::<Vec<_>>().join("\n");
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_insert)?;
+            stmt.execute((room_id_str, response_id_str, original_event_link, urls_str))?;
+            Ok::<_, Report>(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn remove_pending_job(&self, room_id: &RoomId, response_id: &EventId) -> Result<()> {
+        let stmt_delete = "DELETE FROM pending_jobs WHERE room_id = ? AND response_id = ?;";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room_id.to_string();
+        let response_id_str = response_id.to_string();
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_delete)?;
+            stmt.execute((room_id_str, response_id_str))?;
+            Ok::<_, Report>(())
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn room_settings(&self, room_id: &RoomId) -> Result<RoomSettings> {
+        self.settings
+            .try_get_with_by_ref(room_id, self.load_room_settings(room_id))
+            .await
+            .map_err(|err| eyre!("{}", err))
+    }
+
+    async fn load_room_settings(&self, room_id: &RoomId) -> Result<RoomSettings> {
+        let stmt_query = "SELECT key, value FROM room_settings WHERE room_id = ?;";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room_id.to_string();
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_query)?;
+            let rows = stmt
+                .query_map((room_id_str,), |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, Report>(RoomSettings::from_rows(rows))
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn set_room_setting(&self, room: &Room, key: &str, value: &str) -> Result<()> {
+        let stmt_insert =
+            "INSERT OR REPLACE INTO room_settings (room_id, key, value) VALUES (?, ?, ?);";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room.room_id().to_string();
+        let key_owned = key.to_owned();
+        let value = value.to_owned();
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_insert)?;
+            stmt.execute((room_id_str, key_owned, value))?;
+            Ok::<_, Report>(())
+        })
+        .await
+        .unwrap()?;
+        self.on_room_setting_changed(room, key).await;
+        Ok(())
+    }
+
+    /// Goes back to the global configuration for `key`.
+    async fn remove_room_setting(&self, room: &Room, key: &str) -> Result<()> {
+        let stmt_delete = "DELETE FROM room_settings WHERE room_id = ? AND key = ?;";
+        let conn = self.db.get().await?;
+
+        let room_id_str = room.room_id().to_string();
+        let key_owned = key.to_owned();
+        conn.interact(move |conn| {
+            let mut stmt = conn.prepare_cached(stmt_delete)?;
+            stmt.execute((room_id_str, key_owned))?;
+            Ok::<_, Report>(())
+        })
+        .await
+        .unwrap()?;
+        self.on_room_setting_changed(room, key).await;
+        Ok(())
+    }
+
+    async fn on_room_setting_changed(&self, room: &Room, key: &str) {
+        self.settings.invalidate(room.room_id()).await;
+        if self.config.sync_settings && settings_sync::is_synced(key) {
+            let client = room.client();
+            let db = self.db.clone();
+            self.spawn("settings_sync", async move {
+                settings_sync::upload(&client, &db).await
+            });
+        }
+    }
+
+    /// Restores the room settings missing from the database, such as after moving to a new data
+    /// directory, then saves the merged settings back to account data.
+    pub async fn restore_settings(&self, client: &Client) -> Result<()> {
+        if !self.config.sync_settings {
+            return Ok(());
+        }
+        let restored = settings_sync::restore(client, &self.db).await?;
+        self.settings.invalidate_all();
+        if restored != 0 {
+            info!("Restored {} room settings from account data.", restored);
+        }
+        settings_sync::upload(client, &self.db).await
+    }
+
+    /// Records a transition of the preview lifecycle, for investigating missing previews.
+    async fn log_preview(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+        response_id: Option<&EventId>,
+        state: preview_log::State,
+        detail: &str,
+    ) {
+        if let Err(err) = preview_log::insert(
+            &self.db,
+            room_id,
+            event_id,
+            response_id,
+            state,
+            detail,
+            self.config.preview_log_retention,
+        )
+        .await
+        {
+            error!("Failed to write preview log: {}", err);
+        }
+    }
+
+    /// Sends an event, retrying with backoff on rate limits, network errors, and server errors.
+    ///
+    /// The transaction ID is kept across attempts, so the server ignores a repeated attempt that
+    /// went through despite the error.
+    async fn send_with_retry(
+        room: &Room,
+        content: &(impl MessageLikeEventContent + Clone),
+    ) -> matrix_sdk::Result<send_message_event::v3::Response> {
+        let txn_id = TransactionId::new();
+        let mut backoff = SEND_MIN_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let err = match room
+                .send(content.clone())
+                .with_transaction_id(txn_id.clone())
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            let Some(delay) = Self::send_retry_delay(&err, backoff) else {
+                return Err(err);
+            };
+            if attempt >= SEND_MAX_ATTEMPTS {
+                return Err(err);
+            }
+            warn!("Failed to send, retrying in {:?}: {}", delay, err);
+            tokio::time::sleep(delay).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// Returns how long to wait before sending again, or `None` if retrying won't help.
+    fn send_retry_delay(err: &matrix_sdk::Error, backoff: Duration) -> Option<Duration> {
+        if let Some(ErrorKind::LimitExceeded { retry_after }) = err.client_api_error_kind() {
+            return Some(match retry_after {
+                Some(RetryAfter::Delay(delay)) => *delay,
+                Some(RetryAfter::DateTime(time)) => {
+                    time.duration_since(SystemTime::now()).unwrap_or_default()
+                }
+                None => backoff,
+            });
+        }
+        let matrix_sdk::Error::Http(err) = err else {
+            return None;
+        };
+        let status_code = match err.as_ref() {
+            HttpError::Reqwest(_) => return Some(backoff),
+            _ => match err.as_ruma_api_error() {
+                Some(RumaApiError::ClientApi(err)) => err.status_code,
+                Some(RumaApiError::Other(err)) => err.status_code,
+                _ => return None,
+            },
+        };
+        status_code.is_server_error().then_some(backoff)
+    }
+
+    fn is_forbidden(err: &matrix_sdk::Error) -> bool {
+        matches!(
+            err.client_api_error_kind(),
+            Some(ErrorKind::Forbidden { .. })
+        )
+    }
+
+    /// Stops previewing in a room where we aren't allowed to send messages.
+    async fn mark_read_only(&self, room: &Room) {
+        warn!(
+            "Not allowed to send messages in {}, pausing previews until the power levels change.",
+            room.room_id()
+        );
+        if let Err(err) = self.set_room_setting(room, "read_only", "true").await {
+            error!("Failed to mark room as read-only: {}", err);
+        }
+    }
+
+    /// Resumes previewing in a read-only room, as we may be allowed to send messages now.
+    #[instrument(skip_all)]
+    pub async fn on_power_levels(self: Arc<Self>, room: Room) -> Result<()> {
+        if self.room_settings(room.room_id()).await?.read_only() {
+            info!(
+                "Power levels changed in {}, resuming previews.",
+                room.room_id()
+            );
+            self.set_room_setting(&room, "read_only", "false").await?;
+        }
+        Ok(())
+    }
+
+    /// Fills in the placeholder of `job`, setting `answered` once it's no longer a placeholder.
+    async fn create_url_preview(self: Arc<Self>, job: PreviewJob, answered: &AtomicBool) {
+        let is_private = self.is_private_room(&job.room);
+        redact::scope(
+            is_private,
+            self.build_url_preview(job, is_private, answered),
+        )
+        .await
+    }
+
+    /// Replaces the placeholder of a preview that took longer than `preview_timeout` with an
+    /// error, unless `is_answered`, which keeps the preview it already has.
+    async fn abandon_url_preview(
+        &self,
+        room: &Room,
+        original_event_id: &EventId,
+        original_event_link: &str,
+        response_id: &EventId,
+        is_answered: bool,
+    ) {
+        error!(
+            "URL preview took longer than {:?}, giving up.",
+            self.config.preview_timeout
+        );
+        if !is_answered {
+            let room_settings = match self.room_settings(room.room_id()).await {
+                Ok(room_settings) => room_settings,
+                Err(err) => {
+                    error!("Failed to load room settings: {}", err);
+                    RoomSettings::default()
+                }
+            };
+            let (reply_text, reply_html) = render::error_card(
+                &self.config.css_class_prefix,
+                &self.config.warning_emoji,
+                &room_settings.timeout_text(&self.config),
+                room_settings
+                    .show_backref(&self.config)
+                    .then_some(original_event_link),
+            );
+            let reply = Self::replacement(response_id, reply_text, reply_html);
+            if let Err(err) = Self::send_with_retry(room, &reply).await {
+                error!("Failed to send URL preview timeout: {}", err);
+            }
+        }
+        self.log_preview(
+            room.room_id(),
+            original_event_id,
+            Some(response_id),
+            preview_log::State::Failed,
+            "Timed out",
+        )
+        .await;
+        if let Err(err) = self.remove_pending_job(room.room_id(), response_id).await {
+            error!("Failed to remove pending job: {}", err);
+        }
+    }
+
+    /// Makes the edit replacing `response_id` with a preview.
+    fn replacement(
+        response_id: &EventId,
+        reply_text: String,
+        reply_html: String,
+    ) -> RoomMessageEventContent {
+        RoomMessageEventContentWithoutRelation::notice_html(reply_text.clone(), reply_html.clone())
+            .add_mentions(Mentions::new())
+            .with_relation(Some(Relation::Replacement(Replacement::new(
+                response_id.to_owned(),
+                RoomMessageEventContentWithoutRelation::notice_html(reply_text, reply_html)
+                    .add_mentions(Mentions::new()),
+            ))))
+    }
+
+    #[instrument(skip_all)]
+    async fn build_url_preview(
+        self: Arc<Self>,
+        job: PreviewJob,
+        is_private: bool,
+        answered: &AtomicBool,
+    ) {
+        let PreviewJob {
+            room,
+            original_event_id,
+            original_event_link,
+            response_id,
+            is_edit,
+            priority,
+            links,
+            thread_id,
+        } = job;
+        let MessageLinks {
+            urls,
+            mismatched: mismatched_urls,
+            texts: link_texts,
+            sections: link_sections,
+        } = links;
+        let _permit = self.scheduler.acquire(priority).await;
+        let room_settings = match self.room_settings(room.room_id()).await {
+            Ok(room_settings) => room_settings,
+            Err(err) => {
+                error!("Failed to load room settings: {}", err);
+                RoomSettings::default()
+            }
+        };
+        let max_description_chars = room_settings.max_description_chars(&self.config);
+        let compact_mode = room_settings.compact_mode(&self.config);
+        let preview_fields = room_settings.preview_fields(&self.config);
+        let accept_language = room_settings.accept_language(&self.config);
+        let show_backref = room_settings.show_backref(&self.config);
+        let debug_footer = room_settings.debug_footer(&self.config);
+        let error_text = room_settings.error_text(&self.config);
+
+        let urls_hash = Self::urls_hash(&urls);
+        let class_prefix = &self.config.css_class_prefix;
+        let mut previews = Vec::new();
+        let mut reply_images = Vec::new();
+        let mut reply_media = Vec::new();
+        let mut preview_sources = Vec::new();
+        let mut preview_data = Vec::new();
+        let mut skipped_described = false;
+        let mut available_count = 0;
+        let failure_options = RenderOptions {
+            class_prefix,
+            preview_emoji: &self.config.preview_emoji,
+            warning_emoji: &self.config.warning_emoji,
+            backref: show_backref.then_some(original_event_link.as_str()),
+            clean_titles: self.config.clean_titles,
+            compact_mode,
+            preview_fields,
+            max_description_chars,
+            debug_footer,
+            byline: self.config.show_byline,
+            citation: false,
+        };
+
+        for url in urls.into_iter().take(
+            room_settings
+                .max_urls_per_message(&self.config)
+                .min(MAX_URL_COUNTS_PER_MESSAGE),
+        ) {
+            info!("Fetching URL preview for: {}", redact::url(&url));
+            let is_mismatched = self.config.warn_mismatched_links && mismatched_urls.contains(&url);
+            let shared_url = url.to_string();
+            let link_text = link_texts.get(&url);
+            let sections = link_sections.get(&url).map_or(&[][..], Vec::as_slice);
+
+            // Overrides are never fetched, so they are looked up before anything else.
+            let preview_override = self.config.preview_override(&url);
+            let url = if preview_override.is_some() {
+                url
+            } else {
+                // Rewrite rules may point to services on the local network, but users may not.
+                if classify::classify(&url) == UrlClass::Internal {
+                    info!("Not previewing {}: Internal host.", redact::url(&url));
+                    continue;
+                }
+                let Some(url) = self.rewrite(url) else {
+                    continue;
+                };
+                url
+            };
+            let url_class = classify::classify(&url);
+            debug!("URL class: {}", url_class);
+
+            // Previously we used Synapse's URL preview API.
+            //
+            // let request = matrix_sdk::ruma::api::client::authenticated_media::get_media_preview::v1::Request::new(
+            //     url.to_string()
+            // );
+            // let response = match room
+            //     .client()
+            //     .send(request)
+            //     .with_request_config(Some(room.client().request_config().clone().disable_retry()))
+            //     .await
+            // {
             //     Ok(response) => response,
             //     Err(err) => {
             //         error!("Failed to fetch URL preview for {}: {}", url, err);
@@ -305,463 +2412,1221 @@ PRAGMA optimize;
             //     continue;
             // };
 
-            let Some(preview) = self
-                .cache
-                .get_with_by_ref(&url, self.clone().fetch_single_url_preview(url.clone()))
-                .await
-            else {
+            let (preview, handler) = if let Some(preview_override) = preview_override {
+                (Some(preview_override.to_opengraph(&url)), "override")
+            } else if let Some((room_or_alias_id, event_id)) =
+                extract_url::parse_event_permalink(&url)
+            {
+                // Event previews depend on who is asking, so they are never cached.
+                (
+                    Self::fetch_event_preview(&room, &url, room_or_alias_id, &event_id).await,
+                    "matrix_event",
+                )
+            } else {
+                (
+                    self.clone()
+                        .cached_url_preview(&url, accept_language, !is_private)
+                        .await,
+                    "opengraph",
+                )
+            };
+            let Some(preview) = preview else {
                 warn!("URL has no preview.");
+                // Shown only next to the URLs that have one, see below.
+                previews.push(render::failure_block(&url, &error_text, &failure_options));
                 continue;
             };
-            info!("{:?}", preview);
+            if self.config.skip_described_links
+                && let Some(link_text) = link_text
+                && link_text.chars().count() >= MIN_DESCRIBED_LINK_CHARS
+                && title::similarity(link_text, &preview.title)
+                    >= self.config.described_link_similarity
+            {
+                info!(
+                    "Not previewing {}: The link text already describes it.",
+                    redact::url(&url)
+                );
+                skipped_described = true;
+                continue;
+            }
+            // Shorteners only redirect, so credit the site they lead to.
+            let source_url = match url_class {
+                UrlClass::Shortener => Url::parse(&preview.url).unwrap_or_else(|_| url.clone()),
+                _ => url.clone(),
+            };
+            let domain = source_url
+                .domain()
+                .and_then(domain::registrable_domain)
+                .unwrap_or_else(|| {
+                    source_url
+                        .host_str()
+                        .unwrap_or(source_url.scheme())
+                        .to_owned()
+                });
+            preview_sources.push(feedback::PreviewSource {
+                domain,
+                handler,
+                url: shared_url,
+            });
+            if !redact::is_enabled() {
+                info!("{:?}", preview);
+            }
 
-            if preview.media_urls.len() > 0 {
-                for media in preview.media_urls {
-                    let Some(canonical_url) = Url::parse(&media.url)
-                        .ok()
-                        .filter(|url| url.as_str().len() <= SAFE_URL_LENGTH)
+            let content_class = classify::refine(url_class, &preview.og_type);
+            let style = room_settings
+                .style
+                .or_else(|| {
+                    source_url
+                        .host_str()
+                        .and_then(|host| self.config.style_profile(host))
+                })
+                .or_else(|| self.config.class_styles.get(&content_class).copied());
+            let (compact_mode, preview_fields) = match style {
+                Some(profile) => profile.apply(compact_mode, preview_fields),
+                None => (compact_mode, preview_fields.to_vec()),
+            };
+
+            let mut inline_image = None;
+            // Media may be relative to the page.
+            let page_url = Url::parse(&preview.url).unwrap_or_else(|_| url.clone());
+            // HTML can't show encrypted media, so encrypted rooms get attachments instead.
+            let is_unencrypted = matches!(room.encryption_state(), EncryptionState::NotEncrypted);
+            if preview_fields.contains(&PreviewField::Image) {
+                for media in preview.images.iter() {
+                    let Some(canonical_url) = page_url.join(media.best_url()).ok().filter(|url| {
+                        url.as_str().len() <= SAFE_URL_LENGTH
+                            && classify::classify(url) != UrlClass::Internal
+                    }) else {
+                        continue;
+                    };
+
+                    let Some(img) = self
+                        .clone()
+                        .get_media_data(
+                            canonical_url,
+                            None,
+                            "image/*",
+                            self.config.crawler_max_size,
+                        )
+                        .await
                     else {
                         continue;
                     };
 
-                    let canonical_thumb_url = media.thumb_url.and_then(|url| {
-                        Url::parse(&url)
-                            .ok()
-                            .filter(|url| url.as_str().len() <= SAFE_URL_LENGTH)
-                    });
+                    if inline_image.is_none()
+                        && is_unencrypted
+                        && img.content_type.type_() == mime::IMAGE
+                    {
+                        let max_size = CardLayout::of(&preview).max_image_size();
+                        match Self::upload_inline_image(&room, &img, max_size).await {
+                            Ok(image) => {
+                                inline_image = Some(image);
+                                continue;
+                            }
+                            Err(err) => error!("Failed to upload URL preview image: {}", err),
+                        }
+                    }
+                    reply_images.push(img);
+                }
+            }
+            if self.config.embed_videos
+                && preview_fields.contains(&PreviewField::Image)
+                && let Some(video) = preview.playable_video()
+                && let Some(video_url) = page_url.join(video.best_url()).ok().filter(|url| {
+                    url.as_str().len() <= SAFE_URL_LENGTH
+                        && classify::classify(url) != UrlClass::Internal
+                })
+            {
+                let thumb_url = preview
+                    .images
+                    .first()
+                    .and_then(|thumb| page_url.join(thumb.best_url()).ok())
+                    .filter(|url| {
+                        url.as_str().len() <= SAFE_URL_LENGTH
+                            && classify::classify(url) != UrlClass::Internal
+                    });
+                match self
+                    .clone()
+                    .get_media_data(video_url, thumb_url, "video/*", self.config.max_video_size)
+                    .await
+                {
+                    // The declared type may be wrong, and only these play inline.
+                    Some(media)
+                        if PLAYABLE_VIDEO_TYPES.contains(&media.content_type.essence_str()) =>
+                    {
+                        let info = BaseVideoInfo {
+                            duration: preview
+                                .duration
+                                .map(|duration| Duration::from_secs(duration.into())),
+                            width: video.width.map(UInt::from),
+                            height: video.height.map(UInt::from),
+                            size: UInt::new(media.data.len() as u64),
+                            ..Default::default()
+                        };
+                        reply_media.push((media, AttachmentInfo::Video(info)));
+                    }
+                    Some(media) => info!(
+                        "Not embedding the video of {}: Unplayable type {}.",
+                        redact::url(&url),
+                        media.content_type
+                    ),
+                    None => (),
+                }
+            }
+            if self.config.embed_audio
+                && preview_fields.contains(&PreviewField::Image)
+                && let Some(audio) = preview.playable_audio()
+                && let Some(audio_url) = page_url.join(audio.best_url()).ok().filter(|url| {
+                    url.as_str().len() <= SAFE_URL_LENGTH
+                        && classify::classify(url) != UrlClass::Internal
+                })
+            {
+                match self
+                    .clone()
+                    .get_media_data(audio_url, None, "audio/*", self.config.max_audio_size)
+                    .await
+                {
+                    Some(media) if media.content_type.type_() == mime::AUDIO => {
+                        let info = BaseAudioInfo {
+                            duration: preview
+                                .duration
+                                .map(|duration| Duration::from_secs(duration.into())),
+                            size: UInt::new(media.data.len() as u64),
+                        };
+                        reply_media.push((media, AttachmentInfo::Audio(info)));
+                    }
+                    Some(media) => info!(
+                        "Not embedding the audio of {}: Unplayable type {}.",
+                        redact::url(&url),
+                        media.content_type
+                    ),
+                    None => (),
+                }
+            }
+
+            let icon = if self.config.show_favicons
+                && is_unencrypted
+                && preview_fields.contains(&PreviewField::SiteName)
+            {
+                self.favicon(&room, &page_url, &preview).await
+            } else {
+                None
+            };
+
+            let render_options = RenderOptions {
+                class_prefix,
+                preview_emoji: &self.config.preview_emoji,
+                warning_emoji: &self.config.warning_emoji,
+                backref: show_backref.then_some(original_event_link.as_str()),
+                clean_titles: self.config.clean_titles,
+                compact_mode,
+                preview_fields: &preview_fields,
+                max_description_chars,
+                debug_footer,
+                byline: self.config.show_byline,
+                citation: style == Some(StyleProfile::Citation),
+            };
+            if self.config.send_preview_data {
+                preview_data.push(PreviewData {
+                    url: extract_url::validate_url(&preview.url)
+                        .unwrap_or_else(|| url.clone())
+                        .to_string(),
+                    image_mxc: inline_image.as_ref().map(|image| image.url.clone()),
+                    ..PreviewData::from_opengraph(&preview)
+                });
+            }
+            previews.extend(render::section_blocks(
+                &preview,
+                url,
+                sections,
+                is_mismatched,
+                inline_image.as_ref(),
+                icon.as_deref(),
+                &render_options,
+            ));
+            available_count += 1;
+        }
+
+        let templates = &self.config.templates;
+        render::fit_event_size(&mut previews, class_prefix, templates);
+        let (mut reply_text, mut reply_html) =
+            render::render_previews(&previews, class_prefix, templates);
+        let is_available = available_count != 0;
+        // Previews collected in a thread are appended to the ones already in its message.
+        let _aggregate_guard = match &thread_id {
+            Some(_) => Some(self.thread_aggregates.lock().await),
+            None => None,
+        };
+        let aggregate = match &thread_id {
+            Some(thread_id) => thread_aggregate::get(&self.db, room.room_id(), thread_id)
+                .await
+                .unwrap_or_else(|err| {
+                    error!("Failed to load the previews of the thread: {}", err);
+                    None
+                }),
+            None => None,
+        };
+        let has_previews = aggregate
+            .as_ref()
+            .is_some_and(|aggregate| !aggregate.text.is_empty());
+        if !is_available && skipped_described && !is_edit && !has_previews {
+            self.log_preview(
+                room.room_id(),
+                &original_event_id,
+                Some(&response_id),
+                preview_log::State::Skipped,
+                "The message already describes its links",
+            )
+            .await;
+            answered.store(true, Ordering::Relaxed);
+            if let Err(err) = room.redact(&response_id, None, None).await {
+                error!("Failed to delete URL preview placeholder: {}", err);
+            }
+            if let Some(thread_id) = &thread_id
+                && let Err(err) =
+                    thread_aggregate::remove(&self.db, room.room_id(), thread_id).await
+            {
+                error!("Failed to forget the previews of the thread: {}", err);
+            }
+            if let Err(err) = self.remove_pending_job(room.room_id(), &response_id).await {
+                error!("Failed to remove pending job: {}", err);
+            }
+            return;
+        }
+        if !is_available {
+            if is_edit || has_previews {
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    Some(&response_id),
+                    preview_log::State::Failed,
+                    "No preview available, keeping the previous one",
+                )
+                .await;
+                if !is_edit
+                    && let Err(err) = self.remove_pending_job(room.room_id(), &response_id).await
+                {
+                    error!("Failed to remove pending job: {}", err);
+                }
+                return;
+            }
+            (reply_text, reply_html) = render::error_card(
+                class_prefix,
+                &self.config.warning_emoji,
+                &error_text,
+                show_backref.then_some(original_event_link.as_str()),
+            );
+        }
+
+        // The placeholder of the job, which may differ from the message collecting the previews
+        // of the thread if that one filled up in the meantime.
+        let placeholder_id = response_id.clone();
+        let mut response_id = aggregate
+            .as_ref()
+            .map_or(response_id, |aggregate| aggregate.response_id.clone());
+        let mut is_new_message = false;
+        let mut is_appended = false;
+        if is_available
+            && has_previews
+            && let Some(aggregate) = &aggregate
+        {
+            let text = format!("{}\n\n{}", aggregate.text, reply_text);
+            let html = format!("{}{}", aggregate.html, reply_html);
+            // Once full, a new message collects the following previews of the thread.
+            if render::content_size(&text, &html) > MAX_PREVIEW_CONTENT_BYTES {
+                is_new_message = true;
+            } else {
+                (reply_text, reply_html) = (text, html);
+                is_appended = true;
+            }
+        }
+
+        let reply = match &thread_id {
+            Some(thread_id) if is_new_message => {
+                RoomMessageEventContentWithoutRelation::notice_html(
+                    reply_text.clone(),
+                    reply_html.clone(),
+                )
+                .add_mentions(Mentions::new())
+                .with_relation(Some(Relation::Thread(Thread::plain(
+                    thread_id.clone(),
+                    original_event_id.clone(),
+                ))))
+            }
+            _ => Self::replacement(&response_id, reply_text.clone(), reply_html.clone()),
+        };
+        match Self::send_with_retry(&room, &reply).await {
+            Ok(sent) => {
+                answered.store(true, Ordering::Relaxed);
+                if is_new_message {
+                    response_id = sent.event_id;
+                }
+                if is_available && let Some(thread_id) = &thread_id {
+                    let aggregate = ThreadAggregate {
+                        response_id: response_id.clone(),
+                        text: reply_text,
+                        html: reply_html,
+                    };
+                    if let Err(err) =
+                        thread_aggregate::insert(&self.db, room.room_id(), thread_id, &aggregate)
+                            .await
+                    {
+                        error!("Failed to save the previews of the thread: {}", err);
+                    }
+                }
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    Some(&response_id),
+                    preview_log::State::Fetched,
+                    if is_available {
+                        ""
+                    } else {
+                        "No preview available"
+                    },
+                )
+                .await;
+                if is_available {
+                    self.metrics.record_preview_served();
+                }
+                if is_available && thread_id.is_none() {
+                    self.messages
+                        .set_urls_hash(room.room_id(), &original_event_id, &response_id, urls_hash)
+                        .await;
+                }
+                if let Err(err) = self
+                    .remove_pending_job(room.room_id(), &placeholder_id)
+                    .await
+                {
+                    error!("Failed to remove pending job: {}", err);
+                }
+                if !preview_data.is_empty() {
+                    let content = UrlPreviewEventContent {
+                        previews: preview_data,
+                        relates_to: Reference::new(response_id.clone()),
+                    };
+                    if let Err(err) = Self::send_with_retry(&room, &content).await {
+                        error!("Failed to send URL preview data: {}", err);
+                    }
+                }
+                if is_private {
+                    for source in preview_sources.iter_mut() {
+                        source.url = redact::hash(&source.url);
+                    }
+                }
+                if let Err(err) = feedback::insert_sources(
+                    &self.db,
+                    room.room_id(),
+                    &response_id,
+                    preview_sources,
+                    is_appended,
+                )
+                .await
+                {
+                    error!("Failed to save preview sources: {}", err);
+                }
+            }
+            Err(err) => {
+                error!("Failed to send URL preview: {}", err);
+                self.metrics.record_error("send");
+                self.log_preview(
+                    room.room_id(),
+                    &original_event_id,
+                    Some(&response_id),
+                    preview_log::State::Failed,
+                    &err.to_string(),
+                )
+                .await;
+                if Self::is_forbidden(&err) {
+                    self.mark_read_only(&room).await;
+                } else if !is_private
+                    && !is_new_message
+                    && Self::send_retry_delay(&err, SEND_MIN_BACKOFF).is_some()
+                {
+                    // Deliver it on the next startup, instead of fetching it again.
+                    match outbox::insert(
+                        &self.db,
+                        room.room_id(),
+                        &original_event_id,
+                        &response_id,
+                        (is_available && thread_id.is_none()).then_some(urls_hash),
+                        &reply,
+                    )
+                    .await
+                    {
+                        Ok(()) => {
+                            if let Err(err) = self
+                                .remove_pending_job(room.room_id(), &placeholder_id)
+                                .await
+                            {
+                                error!("Failed to remove pending job: {}", err);
+                            }
+                        }
+                        Err(err) => error!("Failed to save unsent URL preview: {}", err),
+                    }
+                }
+            }
+        }
+
+        for img in reply_images {
+            let thumbnail = Self::attachment_thumbnail(img.thumb_data, img.thumb_content_type);
+            if let Err(err) = room
+                .send_attachment(
+                    img.filename,
+                    &img.content_type,
+                    img.data,
+                    AttachmentConfig::new().thumbnail(thumbnail),
+                )
+                .await
+            {
+                error!("Failed to send URL preview image: {}", err);
+            }
+        }
+        // Videos and audio reply to the preview, so clients show which link they belong to.
+        for (media, info) in reply_media {
+            let thumbnail = Self::attachment_thumbnail(media.thumb_data, media.thumb_content_type);
+            let config = AttachmentConfig::new()
+                .thumbnail(thumbnail)
+                .info(info)
+                .mentions(Some(Mentions::new()))
+                .reply(Some(Reply {
+                    event_id: response_id.clone(),
+                    enforce_thread: EnforceThread::MaybeThreaded,
+                }));
+            if let Err(err) = room
+                .send_attachment(media.filename, &media.content_type, media.data, config)
+                .await
+            {
+                error!("Failed to send URL preview media: {}", err);
+            }
+        }
+    }
 
-                    let img = self
-                        .clone()
-                        .get_image_data(canonical_url, canonical_thumb_url)
-                        .await
-                        .unwrap();
+    /// Describes the thumbnail of an attachment, or returns `None` if it isn't an image.
+    fn attachment_thumbnail(
+        data: Option<Vec<u8>>,
+        content_type: Option<Mime>,
+    ) -> Option<Thumbnail> {
+        let (data, content_type) = data.zip(content_type)?;
+        // Only the header is read, as decoding the whole image would block the runtime.
+        let (width, height) = ImageReader::new(Cursor::new(&data))
+            .with_guessed_format()
+            .ok()?
+            .into_dimensions()
+            .ok()?;
+        Some(Thumbnail {
+            size: UInt::new(data.len() as u64)?,
+            data,
+            content_type,
+            width: width.into(),
+            height: height.into(),
+        })
+    }
 
-                    reply_images.push(img);
-                }
+    /// Removes the cached previews of a URL in all languages. Returns the number removed.
+    async fn purge_cached_previews(&self, url: &Url) -> usize {
+        let keys = self
+            .cache
+            .iter()
+            .filter(|(key, _)| key.url == *url)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            self.cache.invalidate(key.as_ref()).await;
+        }
+        let mut count = keys.len();
+        if self.config.cache_max_disk_usage != 0 {
+            match disk_cache::purge(&self.db, url.as_str()).await {
+                Ok(disk_count) => count = count.max(disk_count),
+                Err(err) => error!("Failed to purge the disk cache: {}", err),
             }
+        }
+        count
+    }
 
-            // Extract metadata from OpenGraph, while keeping length limited
-            let title = limit::length_in_chars(
-                Self::collapse_whitespace(&preview.title),
-                MAX_RESPONSE_TEXT_CHARS,
-            );
-            let site_name = limit::length_in_chars(
-                Self::collapse_whitespace(&preview.site_name),
-                MAX_RESPONSE_TEXT_CHARS,
-            );
-            let description = limit::length_in_chars(
-                Self::collapse_whitespace(&preview.description),
-                MAX_RESPONSE_TEXT_CHARS,
-            );
-            let canonical_url = Url::parse(&preview.url)
-                .ok()
-                .filter(|url| url.as_str().len() <= SAFE_URL_LENGTH)
-                .unwrap_or(url);
-
-            if title.is_empty() {
-                reply_html = format!(
-                    "<blockquote><div class=\"m13253-url-preview-headline\"><a class=\"m13253-url-preview-backref\" href=\"{}\">\u{26a0}\u{fe0f}</a> <em><a class=\"m13253-url-preview-empty-title\" href=\"{}\">No title</a></em>",
-                    html_escape::attr(&original_event_link),
-                    html_escape::attr(canonical_url.as_str())
-                );
-                reply_text = "\u{26a0}\u{fe0f} (No title)".to_owned();
-            } else {
-                reply_html = format!(
-                    "<blockquote><div class=\"m13253-url-preview-headline\"><a class=\"m13253-url-preview-backref\" href=\"{}\">\u{1f517}\u{fe0f}</a> <strong><a class=\"m13253-url-preview-title\" href=\"{}\">{}</a></strong>",
-                    html_escape::attr(&original_event_link),
-                    html_escape::attr(canonical_url.as_str()),
-                    html_escape::text(&title)
+    /// Loads the previews of `urls` into the cache, in the default language, so the first message
+    /// with each of them doesn't wait for the site. Returns how many have a preview.
+    pub async fn warm_cache(self: Arc<Self>, urls: Vec<Url>) -> usize {
+        let mut count = 0;
+        for url in urls {
+            let Some(url) = self.rewrite(url) else {
+                continue;
+            };
+            if Self::is_never_cached(&url) {
+                warn!(
+                    "Not warming the cache for {}: Never cached.",
+                    redact::url(&url)
                 );
-                reply_text = format!("\u{1f517}\u{fe0f} {title}");
-            }
-            if !site_name.is_empty() {
-                reply_text.push_str(" \u{2013} ");
-                reply_text.push_str(&site_name);
-                reply_html.push_str(" \u{2013} <span class=\"m13253-url-preview-site-name\">");
-                reply_html.push_str(&html_escape::text(&site_name));
-                reply_html.push_str("</span>");
+                continue;
             }
-            reply_html.push_str("</div>");
-            if !description.is_empty() {
-                reply_text.push_str("\n> ");
-                reply_text.push_str(&description);
-                reply_html.push_str("<div class=\"m13253-url-preview-description\">");
-                reply_html.push_str(&html_escape::text(&description));
-                reply_html.push_str("</div>");
+            let _permit = self.scheduler.acquire(Priority::Backfill).await;
+            let preview = self
+                .clone()
+                .cached_url_preview(&url, &self.config.crawler_accept_language, true)
+                .await;
+            if preview.is_some() {
+                count += 1;
             }
-            reply_html.push_str("</blockquote>");
-            break;
         }
+        count
+    }
 
-        if reply_text.is_empty() {
-            if is_edit {
-                return;
+    /// Returns whether the preview of `url` is kept out of the cache when warming it, as events
+    /// depend on who is asking, and internal hosts are never fetched.
+    fn is_never_cached(url: &Url) -> bool {
+        extract_url::parse_event_permalink(url).is_some()
+            || classify::classify(url) == UrlClass::Internal
+    }
+
+    /// Looks up the preview of a web page in the cache, loading it if missing.
+    async fn cached_url_preview(
+        self: Arc<Self>,
+        url: &Url,
+        accept_language: &str,
+        persist: bool,
+    ) -> Option<OpenGraph> {
+        let key = CacheKey {
+            url: url.clone(),
+            accept_language: accept_language.to_owned(),
+            handler: "opengraph",
+        };
+        self.metrics.record_cache_lookup();
+        self.cache
+            .get_with_by_ref(&key, self.clone().load_url_preview(key.clone(), persist))
+            .await
+    }
+
+    /// Loads a preview missing from the memory cache, from the disk cache if enabled, or by
+    /// fetching it.
+    ///
+    /// Unless `persist` is false, the fetched preview is saved to the disk cache.
+    async fn load_url_preview(self: Arc<Self>, key: CacheKey, persist: bool) -> Option<OpenGraph> {
+        if self.config.cache_max_disk_usage != 0 {
+            match disk_cache::get(&self.db, &key.to_disk_key(), self.config.cache_duration).await {
+                Ok(Some(preview)) => {
+                    debug!("Loaded the preview from the disk cache.");
+                    return preview;
+                }
+                Ok(None) => (),
+                Err(err) => error!("Failed to read the disk cache: {}", err),
             }
-            reply_text = "\u{26a0}\u{fe0f} (URL preview is unavailable.)".to_string();
-            reply_html = format!(
-                "<blockquote><div class=\"m13253-url-preview-headline\"><a class=\"m13253-url-preview-backref\" href=\"{}\">\u{26a0}\u{fe0f}</a> <span class=\"m13253-url-preview-error\"><em>URL preview is unavailable.</em></span></div></blockquote>",
-                html_escape::attr(&original_event_link)
-            );
         }
+        let preview = self
+            .clone()
+            .fetch_single_url_preview(key.url.clone(), key.accept_language.clone())
+            .await;
+        if persist {
+            self.store_url_preview(&key, &preview).await;
+        }
+        preview
+    }
 
-        let reply = RoomMessageEventContentWithoutRelation::notice_html(
-            reply_text.clone(),
-            reply_html.clone(),
+    /// Saves a preview to the disk cache if enabled.
+    async fn store_url_preview(&self, key: &CacheKey, preview: &Option<OpenGraph>) {
+        if self.config.cache_max_disk_usage == 0 {
+            return;
+        }
+        if let Err(err) = disk_cache::insert(
+            &self.db,
+            &key.to_disk_key(),
+            key.url.as_str(),
+            preview,
+            self.config.cache_duration,
+            self.config.cache_max_disk_usage,
         )
-        .add_mentions(Mentions::new())
-        .with_relation(Some(Relation::Replacement(Replacement::new(
-            response_id,
-            RoomMessageEventContentWithoutRelation::notice_html(reply_text, reply_html)
-                .add_mentions(Mentions::new()),
-        ))));
-        if let Err(err) = room.send(reply).await {
-            error!("Failed to send URL preview: {}", err);
+        .await
+        {
+            error!("Failed to write the disk cache: {}", err);
         }
+    }
 
-        for img in reply_images {
-            if let Err(err) = room
-                .send_attachment(
-                    img.filename.clone(),
-                    &img.content_type,
-                    img.data.clone(),
-                    img.thumb_data
-                        .and_then(|thumb| {
-                            let decoded_image = ImageReader::new(Cursor::new(thumb.clone()))
-                                .with_guessed_format()
-                                .and_then(|dec| Ok(dec.decode()))
-                                .expect("Couldn't decode image!")
-                                .expect("Couldn't decode image (worse!)");
-                            Some(AttachmentConfig::new().thumbnail(Some(Thumbnail {
-                                data: thumb.clone(),
-                                content_type: img.thumb_content_type.unwrap(),
-                                width: decoded_image.width().into(),
-                                height: decoded_image.height().into(),
-                                size: UInt::new(thumb.len().try_into().unwrap()).unwrap(),
-                            })))
-                        })
-                        .unwrap_or_default(),
-                )
-                .await
+    /// Renders the emoji leading the headline, which links back to the original message if
+    /// `show_backref` is set.
+    fn headline_emoji(&self, emoji: &str, original_event_link: &str, show_backref: bool) -> String {
+        render::headline_emoji(
+            &self.config.css_class_prefix,
+            emoji,
+            show_backref.then_some(original_event_link),
+        )
+    }
+
+    /// This is basically `room.matrix_to_event_permalink`, but can't fail.
+    ///
+    /// `Room::route` already leaves out the servers denied by the room's `m.room.server_acl`, and
+    /// IP literals, so the link never points through a server that can't take part in the room.
+    async fn event_link(room: &Room, event_id: &EventId) -> String {
+        room.room_id()
+            .matrix_to_event_uri_via(event_id.to_owned(), room.route().await.unwrap_or_default())
+            .to_string()
+    }
+
+    #[instrument(skip_all)]
+    async fn fetch_single_url_preview(
+        self: Arc<Self>,
+        url: Url,
+        accept_language: String,
+    ) -> Option<OpenGraph> {
+        self.metrics.record_cache_miss();
+        let mut preview = self.fetch_page(&url, &accept_language).await?;
+        // Titles in other languages within the page itself don't need another request.
+        preview.localize(&accept_language);
+        if !self.config.follow_hreflang {
+            return Some(preview);
+        }
+
+        // Only one hop, as the alternate may point back to a page in yet another language.
+        let Some(alternate_url) = preview
+            .find_alternate(&accept_language)
+            .and_then(|alternate| url.join(alternate).ok())
+            .filter(|alternate_url| {
+                matches!(alternate_url.scheme(), "http" | "https")
+                    && *alternate_url != url
+                    && classify::classify(alternate_url) != UrlClass::Internal
+            })
+        else {
+            return Some(preview);
+        };
+        info!(
+            "Page {} is in {}, fetching the alternate {}.",
+            redact::url(&url),
+            preview.language,
+            redact::url(&alternate_url)
+        );
+        match self.fetch_page(&alternate_url, &accept_language).await {
+            Some(mut alternate)
+                if !alternate.title.is_empty() || !alternate.description.is_empty() =>
             {
-                error!("Failed to send URL preview image: {}", err);
+                alternate.localize(&accept_language);
+                Some(alternate)
             }
+            _ => Some(preview),
         }
     }
 
-    #[instrument(skip_all)]
-    async fn fetch_single_url_preview(self: Arc<Self>, url: Url) -> Option<OpenGraph> {
-        // Selectors
-        static META_CHARSET: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[charset]").unwrap());
-        static META_HTTP_EQUIV_CONTENT_TYPE: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[http-equiv=\"Content-Type\" i]").unwrap());
-        static META_OG_DESCRIPTION: LazyLock<[Selector; 3]> = LazyLock::new(|| {
-            [
-                Selector::parse("meta[property=\"og:description\" i]").unwrap(),
-                Selector::parse("meta[property=\"twitter:description\" i]").unwrap(),
-                Selector::parse("meta[name=\"description\" i]").unwrap(),
-            ]
-        });
-        static META_OG_SITE_NAME: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[property=\"og:site_name\" i]").unwrap());
-        static META_OG_TITLE: LazyLock<[Selector; 2]> = LazyLock::new(|| {
-            [
-                Selector::parse("meta[property=\"og:title\" i]").unwrap(),
-                Selector::parse("meta[property=\"twitter:title\" i]").unwrap(),
-            ]
-        });
-        static META_OG_TITLE_FALLBACK: LazyLock<[Selector; 4]> = LazyLock::new(|| {
-            [
-                Selector::parse("title").unwrap(),
-                Selector::parse("h1").unwrap(),
-                Selector::parse("h2").unwrap(),
-                Selector::parse("h3").unwrap(),
-            ]
-        });
-        static META_OG_URL: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[property=\"og:url\" i]").unwrap());
-        static META_OG_URL_FALLBACK: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("link[rel=\"canonical\" i]").unwrap());
-        static META_OG_TYPE: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[property=\"og:type\" i]").unwrap());
-        static META_OG_IMAGE: LazyLock<[Selector; 2]> = LazyLock::new(|| {
-            [
-                Selector::parse("meta[property=\"og:image\" i]").unwrap(),
-                Selector::parse("meta[property=\"twitter:image\" i]").unwrap(),
-            ]
-        });
-        static META_OG_VIDEO: LazyLock<[Selector; 2]> = LazyLock::new(|| {
-            [
-                Selector::parse("meta[property=\"og:video\" i]").unwrap(),
-                Selector::parse("meta[property=\"twitter:player:stream\" i]").unwrap(),
-            ]
-        });
-        static META_OG_AUDIO: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[property=\"og:audio\" i]").unwrap());
-        static META_OG_IMAGE_TYPE: LazyLock<[Selector; 2]> = LazyLock::new(|| {
-            [
-                Selector::parse("meta[property=\"og:image:type\" i]").unwrap(),
-                Selector::parse("meta[property=\"twitter:image:type\" i]").unwrap(),
-            ]
-        });
-        static META_OG_VIDEO_TYPE: LazyLock<[Selector; 2]> = LazyLock::new(|| {
-            [
-                Selector::parse("meta[property=\"og:video:type\" i]").unwrap(),
-                Selector::parse("meta[property=\"twitter:player:stream:type\" i]").unwrap(),
-            ]
-        });
-        static META_OG_AUDIO_TYPE: LazyLock<Selector> =
-            LazyLock::new(|| Selector::parse("meta[property=\"og:audio:type\" i]").unwrap());
+    /// Downloads and parses a page.
+    async fn fetch_page(&self, url: &Url, accept_language: &str) -> Option<OpenGraph> {
+        let started_at = Instant::now();
 
+        if !self.head_check(url, accept_language).await {
+            return None;
+        }
+
+        let client = match self.clients.get(url).await {
+            Ok(client) => client,
+            Err(err) => {
+                info!("Not fetching {}: {}.", redact::url(url), err);
+                return None;
+            }
+        };
         // Send out the request
-        let mut response = match self
-            .reqwest_client
+        let request = client
             .get(url.clone())
+            .header(reqwest::header::ACCEPT, self.config.accept(url))
+            .header(reqwest::header::ACCEPT_LANGUAGE, accept_language)
+            // Servers supporting partial content only send what we would keep anyway.
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes=0-{}", self.config.crawler_max_size.saturating_sub(1)),
+            )
             .timeout(self.config.crawler_timeout)
-            .send()
-            .await
-            .and_then(|response| response.error_for_status())
-        {
+            .send();
+        let Ok(response) =
+            tokio::time::timeout(self.config.crawler_first_byte_timeout, request).await
+        else {
+            error!(
+                "Failed to fetch URL preview for {}: No response within {:?}.",
+                redact::url(url),
+                self.config.crawler_first_byte_timeout
+            );
+            self.metrics.record_error("fetch");
+            return None;
+        };
+        let mut response = match response.and_then(|response| response.error_for_status()) {
             Ok(response) => response,
             Err(err) => {
-                error!("Failed to fetch URL preview for {}: {}", url, err);
+                error!(
+                    "Failed to fetch URL preview for {}: {}",
+                    redact::url(url),
+                    redact::reqwest_error(err)
+                );
+                self.metrics.record_error("fetch");
                 return None;
             }
         };
 
         // Download the response
-        let charset = response
+        let status = response.status().as_u16();
+        let content_type = response
             .headers()
             .get(reqwest::header::CONTENT_TYPE)
             .and_then(|content_type| {
-                Encoding::for_label(
-                    Mime::from_str(&String::from_utf8_lossy(content_type.as_bytes()))
-                        .unwrap_or(mime::TEXT_HTML)
-                        .get_param(mime::CHARSET)?
-                        .as_str()
-                        .as_bytes(),
-                )
-            })
-            .unwrap_or(encoding_rs::UTF_8);
+                Mime::from_str(&String::from_utf8_lossy(content_type.as_bytes())).ok()
+            });
+        let charset = content_type.as_ref().and_then(|content_type| {
+            Encoding::for_label(content_type.get_param(mime::CHARSET)?.as_str().as_bytes())
+        });
+        let charset_hints = charset::hints(url.host_str().unwrap_or_default(), accept_language);
+        let total_size = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            // Content-Range: bytes 0-1023/146515
+            response
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .and_then(|content_range| content_range.to_str().ok()?.rsplit_once('/'))
+                .and_then(|(_, size)| size.parse::<u64>().ok())
+        } else {
+            response.content_length()
+        };
+        // Links to files are described from their headers, so only the header of images, and the
+        // beginning of PDF documents, are read.
+        let is_text = content_type.as_ref().is_some_and(snippet::is_text);
+        let media_type = content_type.clone().filter(|content_type| {
+            self.config.preview_media_links && !Self::is_previewable(content_type)
+        });
+        let read_limit = match &media_type {
+            Some(media_type) if media_type.type_() == mime::IMAGE => MEDIA_LINK_PROBE_BYTES,
+            Some(media_type) if media_type.essence_str() == "application/pdf" => {
+                MAX_PDF_PROBE_BYTES.min(self.config.crawler_max_size)
+            }
+            Some(_) => 0,
+            None if is_text => TEXT_SNIPPET_PROBE_BYTES.min(self.config.crawler_max_size),
+            None => self.config.crawler_max_size,
+        };
         let mut document = Vec::new();
-        while document.len() < self.config.crawler_max_size {
-            match response.chunk().await {
-                Ok(Some(chunk)) => document.extend(chunk),
+        while document.len() < read_limit {
+            let Ok(chunk) =
+                tokio::time::timeout(self.config.crawler_idle_timeout, response.chunk()).await
+            else {
+                warn!(
+                    "No data from {} within {:?}, using partial data.",
+                    redact::url(url),
+                    self.config.crawler_idle_timeout
+                );
+                break;
+            };
+            match chunk {
+                Ok(Some(chunk)) => {
+                    document.extend(chunk);
+                    debug!(
+                        "Downloaded {} of {} bytes from {}.",
+                        document.len(),
+                        total_size.map_or_else(|| "?".to_owned(), |size| size.to_string()),
+                        redact::url(url)
+                    );
+                }
                 Ok(None) => break,
                 Err(err) => {
-                    warn!("Error reading from {}, using partial data: {}", url, err);
+                    warn!(
+                        "Error reading from {}, using partial data: {}",
+                        redact::url(url),
+                        redact::reqwest_error(err)
+                    );
                     break;
                 }
             }
         }
+        let is_truncated = document.len() > self.config.crawler_max_size
+            || total_size.is_some_and(|size| size > self.config.crawler_max_size as u64);
         document.truncate(self.config.crawler_max_size);
+        let fetch_duration = started_at.elapsed();
+        if let Some(domain) = url.domain().and_then(domain::registrable_domain) {
+            self.metrics.record_fetch_duration(&domain, fetch_duration);
+        }
+        let fetch = FetchInfo {
+            status,
+            content_type: content_type
+                .as_ref()
+                .map(|content_type| content_type.essence_str().to_owned())
+                .unwrap_or_default(),
+            duration_ms: fetch_duration.as_millis().try_into().unwrap_or(u64::MAX),
+        };
+        if let Some(media_type) = media_type {
+            info!(
+                "Previewing {} as a file of type {}.",
+                redact::url(url),
+                media_type.essence_str()
+            );
+            let mut preview = media_link::describe(url, &media_type, total_size, &document);
+            preview.fetch = Some(fetch);
+            return Some(preview);
+        }
+        if is_text && let Some(content_type) = &content_type {
+            let mut preview = snippet::describe(
+                url,
+                content_type,
+                total_size,
+                &document,
+                charset,
+                self.config.max_snippet_lines,
+            );
+            preview.fetch = Some(fetch);
+            return Some(preview);
+        }
 
-        // Determine the text encoding
-        let mut dom = Html::parse_document(&encoding_rs::UTF_8.decode(&document).0);
-        let charset = dom
-            .select(&META_CHARSET)
-            .filter_map(|element| Encoding::for_label(element.attr("charset")?.as_bytes()))
-            .next()
-            .or_else(|| {
-                dom.select(&META_HTTP_EQUIV_CONTENT_TYPE)
-                    .filter_map(|element| {
-                        Encoding::for_label(
-                            Mime::from_str(element.attr("content")?)
-                                .ok()?
-                                .get_param(mime::CHARSET)?
-                                .as_str()
-                                .as_bytes(),
-                        )
-                    })
-                    .next()
+        // Parse large documents off the async runtime, within the time budget, so they don't hold
+        // up the sync loop. Small ones take less time than the trip to the blocking pool.
+        let max_dom_nodes = self.config.max_dom_nodes;
+        // The parser stops on its own at the deadline, so the blocking thread isn't left running
+        // after the timeout below gives up on it.
+        let deadline = Instant::now() + self.config.crawler_parse_timeout;
+        let span = Span::current();
+        let is_small = document.len() <= INLINE_PARSE_MAX_BYTES;
+        let is_feed = content_type.as_ref().is_some_and(feed::is_feed);
+        let parse = move || {
+            span.in_scope(|| {
+                if is_feed && let Some(feed) = feed::parse(&document, charset) {
+                    return feed;
+                }
+                opengraph::parse(&document, charset, &charset_hints, max_dom_nodes, deadline)
             })
-            .unwrap_or(charset);
-        if charset != encoding_rs::UTF_8 {
-            dom = Html::parse_document(&charset.decode(&document).0);
+        };
+        let parsed = if is_small {
+            Ok(Ok(parse()))
+        } else {
+            tokio::time::timeout(
+                self.config.crawler_parse_timeout,
+                tokio::task::spawn_blocking(parse),
+            )
+            .await
+        };
+        match parsed {
+            Ok(Ok(mut open_graph)) => {
+                open_graph.fetch = Some(fetch);
+                if is_truncated && open_graph.title.is_empty() && open_graph.description.is_empty()
+                {
+                    warn!(
+                        "No metadata in the first {} bytes of {}, it is probably after the truncation point.",
+                        self.config.crawler_max_size,
+                        redact::url(url)
+                    );
+                    self.metrics.record_error("truncated");
+                }
+                Some(open_graph)
+            }
+            Ok(Err(err)) => {
+                error!(
+                    "Failed to parse URL preview for {}: {}",
+                    redact::url(url),
+                    err
+                );
+                self.metrics.record_error("parse");
+                None
+            }
+            Err(_) => {
+                warn!(
+                    "Gave up parsing URL preview for {} after {:?}.",
+                    redact::url(url),
+                    self.config.crawler_parse_timeout
+                );
+                self.metrics.record_error("parse");
+                None
+            }
         }
+    }
 
-        let og_type = dom
-            .select(&META_OG_TYPE)
-            .filter_map(|element| element.attr("content"))
-            .filter(|&content| !content.is_empty())
-            .next()
-            .unwrap_or_default()
-            .to_owned();
-
-        info!(og_type);
+    /// Asks for the headers of a URL with a HEAD request, and returns whether its body is worth
+    /// downloading.
+    ///
+    /// Bodies that aren't web pages, unless `preview_media_links` is on, or are larger than
+    /// `crawler_max_content_length`, are skipped.
+    /// Hosts that mishandle HEAD requests, or are listed in `crawler_head_skip_domains`, are always
+    /// worth a try.
+    async fn head_check(&self, url: &Url, accept_language: &str) -> bool {
+        if !self.config.crawler_head_check {
+            return true;
+        }
+        let Some(host) = url.host_str() else {
+            return true;
+        };
+        if self.head_unsupported.contains_key(host)
+            || self
+                .config
+                .crawler_head_skip_domains
+                .iter()
+                .any(|pattern| domain::matches(host, pattern))
+        {
+            return true;
+        }
 
-        let mut selector_list = Vec::new();
-        match og_type.as_str() {
-            "image" | "gifv" => selector_list.extend(META_OG_IMAGE.iter()),
-            "video" => selector_list.extend(META_OG_VIDEO.iter()),
-            "audio" => selector_list.push(&META_OG_AUDIO),
-            _ => {
-                selector_list.extend(META_OG_IMAGE.iter());
-                selector_list.extend(META_OG_VIDEO.iter());
-                selector_list.push(&META_OG_AUDIO);
+        let Ok(client) = self.clients.get(url).await else {
+            // Let the GET request report it.
+            return true;
+        };
+        let request = client
+            .head(url.clone())
+            .header(reqwest::header::ACCEPT, self.config.accept(url))
+            .header(reqwest::header::ACCEPT_LANGUAGE, accept_language)
+            .timeout(self.config.crawler_first_byte_timeout)
+            .send();
+        let response = match request.await {
+            Ok(response)
+                if response.status() != reqwest::StatusCode::METHOD_NOT_ALLOWED
+                    && response.status() != reqwest::StatusCode::NOT_IMPLEMENTED =>
+            {
+                response
+            }
+            // Some servers reject HEAD, or even time out on it, but serve GET just fine.
+            result => {
+                debug!(
+                    "HEAD request to {} failed, using GET from now on: {}",
+                    redact::url(url),
+                    match result {
+                        Ok(response) => response.status().to_string(),
+                        Err(err) => redact::reqwest_error(err).to_string(),
+                    }
+                );
+                self.head_unsupported.insert(host.to_owned(), ()).await;
+                return true;
             }
         };
+        if !response.status().is_success() {
+            // Let the GET request report the error.
+            return true;
+        }
 
-        let get_images = || {
-            META_OG_IMAGE
-                .iter()
-                .flat_map(|selector| dom.select(selector))
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .map(|content| content.to_owned())
-                .zip(
-                    META_OG_IMAGE_TYPE
-                        .iter()
-                        .flat_map(|selector| dom.select(selector))
-                        .filter_map(|element| element.attr("content"))
-                        .filter(|&content| !content.is_empty())
-                        .map(|content| content.to_owned()),
-                )
-                .map(|zipped| OpenGraphMedia {
-                    url: zipped.0,
-                    thumb_url: None,
-                    content_type: zipped.1,
-                })
-                .collect::<Vec<OpenGraphMedia>>()
+        if let Some(content_type) = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| Mime::from_str(content_type).ok())
+            && !Self::is_previewable(&content_type)
+        {
+            if self.config.preview_media_links {
+                // Only the headers are downloaded, whatever the size.
+                return true;
+            }
+            info!(
+                "Not previewing {}: Content type is {}.",
+                redact::url(url),
+                content_type.essence_str()
+            );
+            return false;
+        }
+        // HEAD responses declare the length of the body GET would return.
+        if let Some(content_length) = response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|content_length| content_length.to_str().ok()?.parse::<u64>().ok())
+            && self.config.crawler_max_content_length != 0
+            && content_length > self.config.crawler_max_content_length
+        {
+            info!(
+                "Not previewing {}: Content length of {} bytes is over the limit.",
+                redact::url(url),
+                content_length
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Returns whether a document of this type may have metadata, or text, to preview.
+    fn is_previewable(content_type: &Mime) -> bool {
+        content_type.essence_str() == "text/html"
+            || content_type.essence_str() == "application/xhtml+xml"
+            || feed::is_feed(content_type)
+            || snippet::is_text(content_type)
+    }
+
+    #[instrument(skip_all)]
+    async fn fetch_event_preview(
+        room: &Room,
+        url: &Url,
+        room_or_alias_id: OwnedRoomOrAliasId,
+        event_id: &EventId,
+    ) -> Option<OpenGraph> {
+        let client = room.client();
+        let room_id = match OwnedRoomId::try_from(room_or_alias_id) {
+            Ok(room_id) => room_id,
+            Err(room_alias_id) => match client.resolve_room_alias(&room_alias_id).await {
+                Ok(response) => response.room_id,
+                Err(err) => {
+                    error!("Failed to resolve room alias {}: {}", room_alias_id, err);
+                    return None;
+                }
+            },
         };
 
-        let get_videos = || {
-            META_OG_VIDEO
-                .iter()
-                .flat_map(|selector| dom.select(selector))
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .map(|content| content.to_owned())
-                .zip(
-                    META_OG_VIDEO_TYPE
-                        .iter()
-                        .flat_map(|selector| dom.select(selector))
-                        .filter_map(|element| element.attr("content"))
-                        .filter(|&content| !content.is_empty())
-                        .map(|content| content.to_owned()),
-                )
-                .map(|zipped| OpenGraphMedia {
-                    url: zipped.0,
-                    thumb_url: get_images()
-                        .iter()
-                        .next()
-                        .and_then(|url| Some(url.url.clone())),
-                    content_type: zipped.1,
-                })
-                .collect()
-        };
-
-        let get_audios = || {
-            dom.select(&META_OG_AUDIO)
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .map(|content| content.to_owned())
-                .zip(
-                    dom.select(&META_OG_AUDIO_TYPE)
-                        .filter_map(|element| element.attr("content"))
-                        .filter(|&content| !content.is_empty())
-                        .map(|content| content.to_owned()),
-                )
-                .map(|zipped| OpenGraphMedia {
-                    url: zipped.0,
-                    thumb_url: None,
-                    content_type: zipped.1,
-                })
-                .collect()
+        let Some(target_room) = client
+            .get_room(&room_id)
+            .filter(|target_room| target_room.state() == RoomState::Joined)
+        else {
+            info!("Not previewing event from room {}: Not joined.", room_id);
+            return None;
         };
+        // Quoting from another room must not leak its messages to this room's members.
+        if target_room.room_id() != room.room_id()
+            && target_room.history_visibility_or_default() != HistoryVisibility::WorldReadable
+        {
+            info!(
+                "Not previewing event from room {}: History is not world-readable.",
+                room_id
+            );
+            return None;
+        }
 
-        let urls: Vec<OpenGraphMedia> = match og_type.as_str() {
-            "image" | "gifv" => get_images(),
-            "video" => get_videos(),
-            "audio" => get_audios(),
-            _ => {
-                let mut media: Vec<OpenGraphMedia> = Vec::new();
-                media.extend(get_images());
-                media.extend(get_videos());
-                media.extend(get_audios());
-                media
+        let event = match target_room.load_or_fetch_event(event_id, None).await {
+            Ok(event) => event,
+            Err(err) => {
+                error!(
+                    "Failed to fetch event {} in room {}: {}",
+                    event_id, room_id, err
+                );
+                return None;
             }
         };
+        let Ok(AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(message),
+        ))) = event.raw().deserialize()
+        else {
+            warn!("Event {} is not a message.", event_id);
+            return None;
+        };
+
+        let sender_name = match target_room.get_member_no_sync(&message.sender).await {
+            Ok(Some(member)) => member.name().to_owned(),
+            _ => message.sender.to_string(),
+        };
+        let room_name = match target_room.display_name().await {
+            Ok(room_name) => room_name.to_string(),
+            Err(_) => room_id.to_string(),
+        };
+        let body = message
+            .content
+            .msgtype
+            .body()
+            .lines()
+            .skip_while(|&line| line.starts_with("> "))
+            .collect::<Vec<_>>()
+            .join("\n");
 
-        // Generate the output
-        // Ref: https://github.com/element-hq/synapse/blob/v1.132.0/synapse/media/preview_html.py#L237
         Some(OpenGraph {
-            description: META_OG_DESCRIPTION
-                .iter()
-                .flat_map(|selector| dom.select(selector))
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .next()
-                .unwrap_or_default()
-                .to_owned(),
-            site_name: dom
-                .select(&META_OG_SITE_NAME)
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .next()
-                .unwrap_or_default()
-                .to_owned(),
-            title: META_OG_TITLE
-                .iter()
-                .flat_map(|selector| dom.select(selector))
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .map(|content| content.to_owned())
-                .next()
-                .or_else(|| {
-                    META_OG_TITLE_FALLBACK
-                        .iter()
-                        .flat_map(|selector| dom.select(selector))
-                        .map(|element| element.text().collect::<String>())
-                        .filter(|content| !content.is_empty())
-                        .next()
-                })
-                .unwrap_or_default(),
-            url: dom
-                .select(&META_OG_URL)
-                .filter_map(|element| element.attr("content"))
-                .filter(|&content| !content.is_empty())
-                .next()
-                .or_else(|| {
-                    dom.select(&META_OG_URL_FALLBACK)
-                        .filter_map(|element| element.attr("href"))
-                        .filter(|&content| !content.is_empty())
-                        .next()
-                })
-                .unwrap_or_default()
-                .to_owned(),
-            media_urls: urls,
+            description: body,
+            site_name: room_name,
+            title: sender_name,
+            url: url.to_string(),
+            ..Default::default()
         })
     }
 
-    async fn get_image_data(
+    /// Downloads media of the types in `accept`, such as `video/*`, and its thumbnail, giving up if
+    /// the media is larger than `max_size`.
+    async fn get_media_data(
         self: Arc<Self>,
         url: Url,
         thumb_url: Option<Url>,
+        accept: &str,
+        max_size: usize,
     ) -> Option<EmbedMedia> {
-        let Some(main) = self.clone().download_image(url.clone()).await else {
-            return None;
-        };
+        let main = self
+            .clone()
+            .download_image(url.clone(), accept, max_size)
+            .await?;
         let thumb = match thumb_url {
-            Some(thumb) => self.clone().download_image(thumb).await,
+            Some(thumb) => {
+                self.clone()
+                    .download_image(thumb, "image/*", self.config.crawler_max_size)
+                    .await
+            }
             None => None,
         };
 
-        return Some(EmbedMedia {
-            url: url.clone().to_string(),
+        Some(EmbedMedia {
             data: main.0,
             content_type: main.1,
             filename: url.to_string(),
-            thumb_data: thumb.clone().and_then(|t| Some(t.0)),
-            thumb_content_type: thumb.and_then(|t| Some(t.1)),
-        });
+            thumb_data: thumb.clone().map(|t| t.0),
+            thumb_content_type: thumb.map(|t| t.1),
+        })
+    }
+
+    /// Returns the icon of a site, from `<link rel="icon">` or `/favicon.ico`, uploading it the
+    /// first time.
+    async fn favicon(
+        self: &Arc<Self>,
+        room: &Room,
+        page_url: &Url,
+        preview: &OpenGraph,
+    ) -> Option<OwnedMxcUri> {
+        let icon_url = page_url
+            .join(&preview.icon)
+            .ok()
+            .filter(|_| !preview.icon.is_empty())
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .or_else(|| page_url.join("/favicon.ico").ok())
+            .filter(|url| {
+                url.as_str().len() <= SAFE_URL_LENGTH
+                    && classify::classify(url) != UrlClass::Internal
+            })?;
+        self.favicons
+            .get_with_by_ref(&icon_url, async {
+                let (data, content_type) = self
+                    .clone()
+                    .download_image(icon_url.clone(), "image/*", MAX_FAVICON_BYTES)
+                    .await?;
+                if content_type.type_() != mime::IMAGE {
+                    return None;
+                }
+                match room
+                    .client()
+                    .media()
+                    .upload(&content_type, data, None)
+                    .await
+                {
+                    Ok(response) => Some(response.content_uri),
+                    Err(err) => {
+                        error!("Failed to upload site icon: {}", err);
+                        None
+                    }
+                }
+            })
+            .await
     }
 
-    async fn download_image(self: Arc<Self>, url: Url) -> Option<(Vec<u8>, Mime)> {
+    /// Uploads the thumbnail of an image, or the image itself, to show it within the preview.
+    async fn upload_inline_image(
+        room: &Room,
+        img: &EmbedMedia,
+        max_size: u32,
+    ) -> Result<InlineImage> {
+        let (data, content_type) = match (&img.thumb_data, &img.thumb_content_type) {
+            (Some(data), Some(content_type)) => (data, content_type),
+            _ => (&img.data, &img.content_type),
+        };
+        let (width, height) = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        let scale = (max_size as f64 / width.max(height).max(1) as f64).min(1.0);
+        let response = room
+            .client()
+            .media()
+            .upload(content_type, data.clone(), None)
+            .await?;
+        Ok(InlineImage {
+            url: response.content_uri,
+            width: ((width as f64 * scale).round() as u32).max(1),
+            height: ((height as f64 * scale).round() as u32).max(1),
+        })
+    }
+
+    /// Downloads an image, or other media of the types in `accept`, giving up if it's larger than
+    /// `max_size`.
+    async fn download_image(
+        self: Arc<Self>,
+        url: Url,
+        accept: &str,
+        max_size: usize,
+    ) -> Option<(Vec<u8>, Mime)> {
+        let client = match self.clients.get(&url).await {
+            Ok(client) => client,
+            Err(err) => {
+                info!("Not fetching {}: {}.", redact::url(&url), err);
+                return None;
+            }
+        };
         // Send out the request
-        let response = match self
-            .reqwest_client
+        let mut response = match client
             .get(url.clone())
+            .header(reqwest::header::ACCEPT, accept)
             .timeout(self.config.crawler_timeout)
             .send()
             .await
@@ -769,40 +3634,74 @@ PRAGMA optimize;
         {
             Ok(response) => response,
             Err(err) => {
-                error!("Failed to fetch URL preview for {}: {}", url, err);
+                error!(
+                    "Failed to fetch URL preview for {}: {}",
+                    redact::url(&url),
+                    redact::reqwest_error(err)
+                );
                 return None;
             }
         };
 
-        let content_type = Mime::from_str(&String::from_utf8_lossy(
-            response
-                .headers()
-                .get(reqwest::header::CONTENT_TYPE)
-                .unwrap()
-                .as_bytes(),
-        ))
-        .unwrap();
+        let Some(content_type) = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .and_then(|content_type| Mime::from_str(content_type).ok())
+        else {
+            info!("Not embedding {}: No content type.", redact::url(&url));
+            return None;
+        };
+        if response
+            .content_length()
+            .is_some_and(|size| size > max_size as u64)
+        {
+            info!("Not embedding {}: Too large.", redact::url(&url));
+            return None;
+        }
 
         // Download the response
-        let bytes = match response.bytes().await {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                error!("Failed to fetch URL preview for {}: {}", url, err);
-                return None;
+        let mut bytes = Vec::new();
+        loop {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    if bytes.len() + chunk.len() > max_size {
+                        info!("Not embedding {}: Too large.", redact::url(&url));
+                        return None;
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    error!(
+                        "Failed to fetch URL preview for {}: {}",
+                        redact::url(&url),
+                        redact::reqwest_error(err)
+                    );
+                    return None;
+                }
             }
         }
-        .to_vec();
 
         Some((bytes, content_type))
     }
 
-    fn collapse_whitespace(s: &str) -> String {
-        // https://developer.mozilla.org/en-US/docs/Glossary/Whitespace
-        static CONSECUTIVE_WHITESPACES: LazyLock<Regex> =
-            LazyLock::new(|| Regex::new("[\t\n\x0c\r ]+").unwrap());
-        CONSECUTIVE_WHITESPACES
-            .replace_all(s, " ")
-            .trim()
-            .to_owned()
+    /// Hashes the URLs in order, as the order decides which ones get previewed.
+    ///
+    /// `DefaultHasher` may change between Rust releases, which only causes one extra refetch.
+    fn urls_hash(urls: &IndexSet<Url>) -> i64 {
+        let mut hasher = DefaultHasher::new();
+        for url in urls {
+            url.as_str().hash(&mut hasher);
+        }
+        hasher.finish() as i64
+    }
+
+    /// Applies the URL rewrite rules, if built with them. Returns `None` if the result is not a
+    /// valid URL.
+    fn rewrite(&self, url: Url) -> Option<Url> {
+        #[cfg(feature = "rewrite")]
+        let url = self.rewriter.apply(url)?;
+        Some(url)
     }
 }